@@ -0,0 +1,134 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+/// A least-recently-used cache that is bounded by the total size of its values rather than by the number of entries.
+///
+/// Recency is tracked with a monotonically increasing tick; the ordered map from tick to key allows the least recently
+/// used entry to be found without scanning every entry.
+pub struct LruCache<K, V> {
+    entries: HashMap<K, Entry<V>>,
+    recency: BTreeMap<u64, K>,
+    tick: u64,
+    size: usize,
+    capacity: usize,
+}
+
+struct Entry<V> {
+    value: V,
+    size: usize,
+    last_used: u64,
+}
+
+impl<K: Clone + Eq + Hash, V> LruCache<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        LruCache {
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            tick: 0,
+            size: 0,
+            capacity,
+        }
+    }
+
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        let tick = self.next_tick();
+        let entry = self.entries.get_mut(key)?;
+        self.recency.remove(&entry.last_used);
+        self.recency.insert(tick, key.clone());
+        entry.last_used = tick;
+        Some(&mut entry.value)
+    }
+
+    /// Inserts a value that occupies `size` bytes, evicting least recently used entries until it fits. Values larger
+    /// than the whole cache are not inserted. Returns the number of entries that were evicted.
+    pub fn insert(&mut self, key: K, value: V, size: usize) -> usize {
+        self.remove(&key);
+        if size > self.capacity {
+            return 0;
+        }
+        let mut evicted = 0;
+        while self.size + size > self.capacity {
+            if self.evict_one() {
+                evicted += 1;
+            } else {
+                break;
+            }
+        }
+        let tick = self.next_tick();
+        self.recency.insert(tick, key.clone());
+        self.entries.insert(key, Entry { value, size, last_used: tick });
+        self.size += size;
+        evicted
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let entry = self.entries.remove(key)?;
+        self.recency.remove(&entry.last_used);
+        self.size -= entry.size;
+        Some(entry.value)
+    }
+
+    /// Removes every entry whose key matches the predicate. Returns the number of entries that were removed.
+    pub fn remove_matching<F: Fn(&K) -> bool>(&mut self, predicate: F) -> usize {
+        let keys: Vec<K> = self.entries.keys().filter(|key| predicate(key)).cloned().collect();
+        for key in keys.iter() {
+            self.remove(key);
+        }
+        keys.len()
+    }
+
+    pub fn entry_count(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    fn evict_one(&mut self) -> bool {
+        let oldest = self.recency.keys().next().copied();
+        match oldest.and_then(|tick| self.recency.remove(&tick)) {
+            Some(key) => {
+                if let Some(entry) = self.entries.remove(&key) {
+                    self.size -= entry.size;
+                }
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn least_recently_used_entries_are_evicted_to_make_room() {
+        let mut cache = LruCache::new(10);
+        assert_eq!(cache.insert("a", 1, 4), 0);
+        assert_eq!(cache.insert("b", 2, 4), 0);
+        assert_eq!(cache.get_mut(&"a"), Some(&mut 1));
+        // `b` is now the least recently used, so it makes room for `c`
+        assert_eq!(cache.insert("c", 3, 4), 1);
+        assert_eq!(cache.get_mut(&"b"), None);
+        assert_eq!((cache.entry_count(), cache.size()), (2, 8));
+
+        // values larger than the whole cache are not inserted, and replacing a value frees the old one's size
+        assert_eq!(cache.insert("d", 4, 11), 0);
+        assert_eq!(cache.get_mut(&"d"), None);
+        assert_eq!(cache.insert("a", 5, 2), 0);
+        assert_eq!((cache.entry_count(), cache.size()), (2, 6));
+        assert_eq!(cache.remove(&"c"), Some(3));
+        assert_eq!(cache.size(), 2);
+    }
+}
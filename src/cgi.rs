@@ -1,109 +1,982 @@
 use std::collections::HashMap;
-use std::io::{Read, Write};
+use std::fs;
+use std::io::{self, Read, Write};
 use std::path;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::process::CommandExt;
 use std::process;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time;
 use std::str::FromStr;
 use crate::config::*;
 use crate::error::{Error,HttpError};
 use crate::http::*;
+use crate::time::now_1123;
 
-pub struct Cgi {
-    server_config: ServerConfig,
+#[derive(Clone, Default)]
+pub struct Cgi {}
+
+/// A script to run for a request and where it was found.
+pub struct Script<'a> {
+    /// The script on disk.
+    pub path: path::PathBuf,
+    /// The URL path that names the script.
+    pub script_name: String,
+    /// The part of the URL path that follows the script's name, if any.
+    pub path_info: &'a str,
+    /// The document root on disk that the script was found under.
+    pub document_root: &'a path::Path,
 }
+
+/// Request headers that are not passed to scripts as `HTTP_*` variables: those already given by `CONTENT_LENGTH` and
+/// `CONTENT_TYPE`, credentials, and `Proxy`, which scripts would otherwise read as `HTTP_PROXY` (httpoxy).
+const HIDDEN_HEADERS: &[&str] = &["Authorization", "Content-Length", "Content-Type", "Proxy"];
+
+/// What a script asked the server to do, as described by [RFC 3875](https://datatracker.ietf.org/doc/html/rfc3875#section-6.2).
+pub enum Output {
+    /// Send a response to the client.
+    Response(Response),
+    /// Answer as if the client had requested this local URL path (with any query string) instead.
+    LocalRedirect(String),
+}
+
+/// The most output read from a script while looking for the end of its headers.
+const MAX_HEADER_SIZE: usize = 64 * 1024;
+
+/// The most of a request body, and of a script's output and error output, written to the `ScriptLog` for each failed
+/// script; also the longest line of error output held before it is logged, so that a script cannot make the server
+/// buffer its error output without limit.
+const SCRIPT_LOG_LENGTH: usize = 10 * 1024;
+
+/// How long to wait for the rest of a failed script's error output before writing its transcript to the `ScriptLog`.
+const STDERR_GRACE_PERIOD: time::Duration = time::Duration::from_secs(1);
+
+/// How long a script that has timed out is given to exit after `SIGTERM` before it is sent `SIGKILL`.
+const KILL_GRACE_PERIOD: time::Duration = time::Duration::from_secs(3);
+
 impl Cgi {
-    pub fn new(server_config: ServerConfig) -> Cgi {
-        Cgi { server_config }
+    /// Starts a script for a request. Its output is then read either by `RunningScript::wait` or, without blocking, by an
+    /// event loop through its pipes.
+    pub fn start(&self, script: Script, request: &Request, virtual_host: &VirtualHost) -> Result<RunningScript, HttpError> {
+        let envs = meta_variables(&script, request, virtual_host);
+        spawn(script.path, envs, request, virtual_host)
+            .map_err(|e| HttpError { status: StatusCode::InternalServerError, message: Some(e.to_string()) })
     }
 }
-impl Cgi {
-    pub fn handle(&self, path: path::PathBuf, request: &Request, virtual_host: &VirtualHost) -> Result<Response, HttpError> {
-        let remote_addr = request.remote.addr.to_string();
-        let request_method = request.header.request_line.method.to_string();
-        let internal_error = |message| -> HttpError { HttpError { status: StatusCode::InternalServerError, message: Some(message) } };
-        let server_port = self.server_config.directives.get(&Directive::ListenPort).ok_or_else(|| internal_error("Could not get ListenPort from server config".to_string()))?.to_string();
-        let server_name = virtual_host.directives.get(&Directive::ServerName).ok_or_else(|| internal_error("Could not get ServerName from virtual host".to_string()))?;
-        let envs: HashMap<&str, &str> = [
-            ("QUERY_STRING", request.header.request_line.query_string.as_str()),
-            ("REMOTE_ADDR", &remote_addr),
-            // ("REMOTE_HOST", ""), NULL if not provided
-            // ("REMOTE_IDENT", ""), MAY
-            // ("REMOTE_USER", ""), MUST if AUTH_TYPE is Basic or Digest
-            ("REQUEST_METHOD", &request_method),
-            ("SERVER_NAME", &server_name),
-            ("SERVER_PORT", &server_port),
-            ("SERVER_PROTOCOL", ""),
-            ("SERVER_SOFTWARE", ""),
-        ].iter()
-            .cloned()
-            .collect();
 
-        process::Command::new(path)
-            .envs(envs)
-            .stdin(process::Stdio::piped())
-            .stdout(process::Stdio::piped())
-            .spawn()
-            .map_err(|e| e.into())
-            .and_then(|mut child| {
-                // println!("-- body: {} --", request.body);
-                child.stdin
-                    .take()
-                    .unwrap()
-                    .write_all(request.body.as_bytes())
-                    .map(|_| child)
-                    .map_err(|e| e.into())
-            })
-            .and_then(|mut child| {
-                let mut s = String::new();
-                child.stdout
-                    .take()
-                    .unwrap()
-                    .read_to_string(&mut s)
-                    .map(|_| s)
-                    .map_err(|e| e.into())
-            })
-            .and_then(|mut stdout| process_cgi_output(&mut stdout))
-            .map(|(mut headers, body)| {
-                headers.insert(ResponseHeaderField::ContentLength, (body.len() + 2).to_string());
-                Response {
-                    header: ResponseHeader {
-                        status_line: StatusLine {
-                            status_code: StatusCode::Ok,
-                            http_version: String::from(HTTP_VERSION),
-                        },
-                        header_lines: headers,
-                    },
-                    body,
+/// Starts a script with its input, output and error output piped, and with only `PATH` inherited from the server's
+/// environment. The script runs in a process group of its own under the `RLimit*` limits, and the group is killed if it
+/// runs for longer than `CGITimeout`.
+fn spawn(path: path::PathBuf, envs: HashMap<String, String>, request: &Request, virtual_host: &VirtualHost) -> io::Result<RunningScript> {
+    let limits = &virtual_host.cgi_limits;
+    let resource_limits = [(libc::RLIMIT_CPU, limits.cpu), (libc::RLIMIT_AS, limits.memory), (libc::RLIMIT_NPROC, limits.processes)];
+    let mut command = process::Command::new(&path);
+    command
+        .env_clear()
+        .envs(std::env::var_os("PATH").map(|path| ("PATH", path)))
+        .envs(envs)
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::piped())
+        .process_group(0);
+    // runs in the child between fork and exec, where only system calls are safe
+    unsafe {
+        command.pre_exec(move || {
+            for (resource, limit) in resource_limits.iter() {
+                let limit = match limit {
+                    Some(limit) => limit,
+                    None => continue,
+                };
+                let mut current = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+                if libc::getrlimit(*resource, &mut current) < 0 {
+                    return Err(io::Error::last_os_error());
                 }
-            })
+                let hard = limit.hard.map(|hard| hard as libc::rlim_t).unwrap_or(current.rlim_max);
+                let soft = limit.soft.map(|soft| soft as libc::rlim_t).unwrap_or(hard).min(hard);
+                if libc::setrlimit(*resource, &libc::rlimit { rlim_cur: soft, rlim_max: hard }) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+    let child = command.spawn()?;
+
+    let timed_out = Arc::new(AtomicBool::new(false));
+    let watchdog = limits.timeout.map(|timeout| Watchdog::start(child.id(), timeout, path.clone(), request.id, timed_out.clone()));
+    let context = format!(
+        "[request {}] [client {}] [{}] {}",
+        request.id,
+        request.remote.addr,
+        virtual_host.server_name.as_deref().unwrap_or("_default_"),
+        path.display(),
+    );
+    let transcript = virtual_host.script_log.as_ref()
+        .map(|script_log| Arc::new(Mutex::new(Transcript::new(path::PathBuf::from(script_log), &path, request))));
+    Ok(RunningScript {
+        non_parsed: path.file_name().map(|name| name.to_string_lossy().starts_with("nph-")).unwrap_or(false),
+        child: Some(child),
+        body: request.body.clone().into_bytes(),
+        context,
+        released: false,
+        timed_out,
+        watchdog,
+        transcript,
+    })
+}
+
+/// A script that has been started. The script is reaped once this is dropped, after being killed unless it was
+/// released, since nothing will read the rest of its output.
+pub struct RunningScript {
+    /// Whether the script's name starts with `nph-`, in which case it writes the whole response itself.
+    pub non_parsed: bool,
+    child: Option<process::Child>,
+    /// The request body, until it is given to whatever writes it to the script's input.
+    body: Vec<u8>,
+    /// Prefixes each line of the script's error output in the log.
+    context: String,
+    released: bool,
+    /// Set once the script has run for longer than `CGITimeout`.
+    timed_out: Arc<AtomicBool>,
+    watchdog: Option<Watchdog>,
+    transcript: Option<Arc<Mutex<Transcript>>>,
+}
+
+/// The pipes of a running script, along with what is to be written to and done with them.
+pub struct ScriptPipes {
+    /// The script's input and the request body to write to it, unless the request has no body.
+    pub input: Option<(process::ChildStdin, Vec<u8>)>,
+    pub output: process::ChildStdout,
+    pub errors: (process::ChildStderr, ErrorLog),
+}
+
+impl RunningScript {
+    /// Feeds the script its input and reads its output up to the end of its headers; the rest of the output is streamed
+    /// as the body of the response. The input is written from a thread of its own, so that a script that writes output
+    /// before it has read all of its input cannot fill one pipe while the server is blocked on the other, and the error
+    /// output is logged from another.
+    pub fn wait(mut self) -> Result<Output, HttpError> {
+        let pipes = self.take_pipes(false).map_err(|e| self.fail(e.into()))?;
+        if let Some((mut stdin, body)) = pipes.input {
+            // a script need not read its input, so a broken pipe is not an error
+            thread::spawn(move || stdin.write_all(&body));
+        }
+        let (mut stderr, mut error_log) = pipes.errors;
+        let (stderr_open, stderr_closed) = mpsc::channel::<()>();
+        thread::spawn(move || {
+            let _stderr_open = stderr_open;
+            let mut buf = [0; 4096];
+            while let Ok(bytes_read @ 1..) = stderr.read(&mut buf) {
+                error_log.write(&buf[..bytes_read]);
+            }
+            error_log.close();
+        });
+
+        let timed_out = self.timed_out.clone();
+        let transcript = self.transcript.clone();
+        let non_parsed = self.non_parsed;
+        process_cgi_output(ScriptOutput { script: self, stdout: pipes.output }, non_parsed)
             .map_err(|e| {
-                println!("-- bad cgi --");
-                HttpError { status: StatusCode::InternalServerError, message: Some(e.to_string()) }
+                let error = failed(e, &timed_out, transcript.as_deref());
+                if let Some(transcript) = transcript {
+                    // the script has been killed, but what it last wrote to its error output may still be on its way
+                    let _ = stderr_closed.recv_timeout(STDERR_GRACE_PERIOD);
+                    transcript.lock().unwrap().write();
+                }
+                error
             })
     }
-}
 
-fn process_cgi_output(s: &mut str) -> Result<(HashMap<ResponseHeaderField, String>, String), Error> {
-    // println!("-- cgi output --");
-    // println!("{}", s);
-    let mut sections = s.split("\n\n");
-    let headers = sections.next()
-        .and_then(|headers| {
-            headers.split("\n")
-                .fold(Some(HashMap::new()), |map, header| {
-                    map.and_then(|mut map| {
-                        header.split_once(":")
-                            .and_then(|(field, value)| {
-                                ResponseHeaderField::from_str(field).map(|field| (field, value.trim().to_string())).ok()
-                            })
-                            .map(|(field, value)| map.insert(field, value))
-                            .map(|_| map)
-                    })
-                })
+    /// Takes the script's pipes, switching them to non-blocking mode if asked, for an event loop that waits on them
+    /// alongside its other sources.
+    pub fn take_pipes(&mut self, nonblocking: bool) -> io::Result<ScriptPipes> {
+        let child = self.child.as_mut().unwrap();
+        let (stdin, stdout, stderr) = (child.stdin.take().unwrap(), child.stdout.take().unwrap(), child.stderr.take().unwrap());
+        if nonblocking {
+            for fd in [stdin.as_raw_fd(), stdout.as_raw_fd(), stderr.as_raw_fd()].iter() {
+                set_nonblocking(*fd)?;
+            }
+        }
+        let body = std::mem::take(&mut self.body);
+        let error_log = ErrorLog { context: std::mem::take(&mut self.context), line: Vec::new(), transcript: self.transcript.clone() };
+        Ok(ScriptPipes {
+            input: if body.is_empty() { None } else { Some((stdin, body)) },
+            output: stdout,
+            errors: (stderr, error_log),
         })
-        .ok_or(Error::new("Could not parse headers from CGI response".to_string()))?;
-    let body = sections.next().unwrap_or("").to_string();
-    // println!("-- cgi parsed --");
-    // println!("{:?}", headers);
-    // println!("{:?}", body);
-    Ok((headers, body))
+    }
+
+    /// Parses the output the script has written so far once it holds all of the headers, or once the script has closed
+    /// its output, returning what the script asked for along with where the body starts in `output`.
+    pub fn parse_output(&self, output: &[u8], finished: bool) -> Option<Result<(Output, usize), HttpError>> {
+        let header_len = match header_len(output) {
+            Some(header_len) => header_len,
+            None if finished && self.timed_out() => return Some(Err(self.fail(Error::new("CGI script timed out".to_string())))),
+            None if finished => output.len(),
+            None if output.len() > MAX_HEADER_SIZE => return Some(Err(self.fail(unterminated_headers()))),
+            None => return None,
+        };
+        let head = String::from_utf8_lossy(&output[..header_len]);
+        let body_start = if self.non_parsed { 0 } else { header_len };
+        Some(parse_head(&head, self.non_parsed).map(|output| (output, body_start)).map_err(|e| self.fail(e)))
+    }
+
+    /// Keeps the start of the script's output for its transcript, should it fail.
+    pub fn record_output(&self, bytes: &[u8]) {
+        if let Some(transcript) = &self.transcript {
+            let stdout = &mut transcript.lock().unwrap().stdout;
+            let len = bytes.len().min(SCRIPT_LOG_LENGTH.saturating_sub(stdout.len()));
+            stdout.extend_from_slice(&bytes[..len]);
+        }
+    }
+
+    pub fn timed_out(&self) -> bool {
+        self.timed_out.load(Ordering::SeqCst)
+    }
+
+    /// Lets the script run to its end rather than be killed when this is dropped, once its output has been read to the
+    /// end or is no longer wanted.
+    pub fn release(&mut self) {
+        self.released = true;
+    }
+
+    fn fail(&self, error: Error) -> HttpError {
+        failed(error, &self.timed_out, self.transcript.as_deref())
+    }
+}
+
+impl Drop for RunningScript {
+    fn drop(&mut self) {
+        let mut child = match self.child.take() {
+            Some(child) => child,
+            None => return,
+        };
+        if !self.released {
+            kill_group(child.id(), libc::SIGKILL);
+        }
+        // a script that has closed its output may still be running, so the watchdog stays until it has exited; such a
+        // script is reaped from a thread of its own, so that nothing waits on it
+        let watchdog = self.watchdog.take();
+        if let Ok(Some(_)) = child.try_wait() {
+            if let Some(watchdog) = watchdog {
+                watchdog.stop();
+            }
+            return;
+        }
+        thread::spawn(move || {
+            if let Err(e) = child.wait() {
+                println!("Could not reap CGI script: {}", e);
+            }
+            if let Some(watchdog) = watchdog {
+                watchdog.stop();
+            }
+        });
+    }
+}
+
+/// Describes the failure of a script to the client, as `504 Gateway Timeout` if it ran for longer than `CGITimeout`,
+/// and records it in the script's transcript.
+fn failed(error: Error, timed_out: &AtomicBool, transcript: Option<&Mutex<Transcript>>) -> HttpError {
+    let status = if timed_out.load(Ordering::SeqCst) {
+        StatusCode::GatewayTimeout
+    } else {
+        println!("-- bad cgi --");
+        StatusCode::InternalServerError
+    };
+    let error = HttpError { status, message: Some(error.to_string()) };
+    if let Some(transcript) = transcript {
+        transcript.lock().unwrap().fail(&error);
+    }
+    error
+}
+
+pub fn set_nonblocking(fd: RawFd) -> io::Result<()> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// The output of a script, as read by `RunningScript::wait`.
+struct ScriptOutput {
+    script: RunningScript,
+    stdout: process::ChildStdout,
+}
+
+impl Read for ScriptOutput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes_read = self.stdout.read(buf)?;
+        self.script.record_output(&buf[..bytes_read]);
+        if bytes_read == 0 && !buf.is_empty() {
+            // the output of a script that was killed is cut short, and must not be sent as though it were complete
+            if self.script.timed_out() {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "CGI script timed out"));
+            }
+            self.script.release();
+        }
+        Ok(bytes_read)
+    }
+}
+
+/// Logs what a script writes to its error output a line at a time, along with the request it was serving.
+pub struct ErrorLog {
+    context: String,
+    /// The line being written, until it is ended.
+    line: Vec<u8>,
+    transcript: Option<Arc<Mutex<Transcript>>>,
+}
+
+impl ErrorLog {
+    /// Creates a log for error output that is not kept for a transcript, such as that of a FastCGI application.
+    pub fn new(context: String) -> ErrorLog {
+        ErrorLog { context, line: Vec::new(), transcript: None }
+    }
+
+    /// Logs each line as it is ended. A line longer than `SCRIPT_LOG_LENGTH` is logged in pieces of that length.
+    pub fn write(&mut self, mut bytes: &[u8]) {
+        loop {
+            let room = SCRIPT_LOG_LENGTH - self.line.len();
+            match bytes.iter().take(room).position(|byte| *byte == b'\n') {
+                Some(line_len) => {
+                    self.line.extend_from_slice(&bytes[..line_len]);
+                    self.log_line();
+                    bytes = &bytes[line_len + 1..];
+                },
+                None if bytes.len() >= room => {
+                    self.line.extend_from_slice(&bytes[..room]);
+                    self.log_line();
+                    bytes = &bytes[room..];
+                },
+                None => {
+                    self.line.extend_from_slice(bytes);
+                    return;
+                },
+            }
+        }
+    }
+
+    /// Logs the last line if the script did not end it, once the script has closed its error output.
+    pub fn close(&mut self) {
+        if !self.line.is_empty() {
+            self.log_line();
+        }
+        if let Some(transcript) = &self.transcript {
+            transcript.lock().unwrap().close_stderr();
+        }
+    }
+
+    fn log_line(&mut self) {
+        let line = String::from_utf8_lossy(&self.line).trim_end_matches('\r').to_string();
+        println!("{}: {}", self.context, line);
+        if let Some(transcript) = &self.transcript {
+            transcript.lock().unwrap().push_stderr(&line);
+        }
+        self.line.clear();
+    }
+}
+
+/// A transcript of a failed script for the `ScriptLog`, in the style of Apache's mod_cgi: the request, the error, and
+/// what the script wrote to its output and to its error output. It is written once the script has both failed and
+/// closed its error output, in whichever order those happen.
+struct Transcript {
+    script_log: path::PathBuf,
+    script: path::PathBuf,
+    request_line: String,
+    /// The request headers and the start of the body.
+    request: String,
+    /// The start of the script's output, up to `SCRIPT_LOG_LENGTH` bytes.
+    stdout: Vec<u8>,
+    /// The first lines of the script's error output, up to `SCRIPT_LOG_LENGTH` bytes.
+    stderr: Vec<u8>,
+    /// The status the client was sent and why, once the script has failed.
+    error: Option<(u16, String)>,
+    stderr_closed: bool,
+    written: bool,
+}
+
+impl Transcript {
+    fn new(script_log: path::PathBuf, script: &path::Path, request: &Request) -> Transcript {
+        let request_line = &request.header.request_line;
+        let query = if request_line.query_string.is_empty() { String::new() } else { format!("?{}", request_line.query_string) };
+        let mut header_lines: Vec<String> = request.header.header_lines.iter()
+            .filter(|(field, _)| **field != RequestHeaderField::Authorization)
+            .map(|(field, value)| format!("{}: {}\n", field, value))
+            .collect();
+        header_lines.sort();
+        let mut request_text = header_lines.concat();
+        if !request.body.is_empty() {
+            let body: String = request.body.chars().take(SCRIPT_LOG_LENGTH).collect();
+            request_text.push_str(&format!("\n{}\n", body));
+        }
+        Transcript {
+            script_log,
+            script: script.to_path_buf(),
+            request_line: format!("{} {}{} {}", request_line.method, request_line.request_path, query, request_line.http_version),
+            request: request_text,
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+            error: None,
+            stderr_closed: false,
+            written: false,
+        }
+    }
+
+    fn fail(&mut self, error: &HttpError) {
+        self.error = Some((error.status.code(), error.message.clone().unwrap_or_default()));
+        if self.stderr_closed {
+            self.write();
+        }
+    }
+
+    fn push_stderr(&mut self, line: &str) {
+        let line = format!("{}\n", line);
+        let len = line.len().min(SCRIPT_LOG_LENGTH.saturating_sub(self.stderr.len()));
+        self.stderr.extend_from_slice(&line.as_bytes()[..len]);
+    }
+
+    fn close_stderr(&mut self) {
+        self.stderr_closed = true;
+        self.write();
+    }
+
+    /// Appends the transcript to the `ScriptLog` if the script has failed and it has not been written already.
+    fn write(&mut self) {
+        let (code, message) = match &self.error {
+            Some(error) if !self.written => error,
+            _ => return,
+        };
+        self.written = true;
+        let mut transcript = format!("%% [{}] {}\n", now_1123(), self.request_line);
+        transcript.push_str(&format!("%% {} {}\n", code, self.script.display()));
+        transcript.push_str(&format!("%error\n{}\n", message));
+        transcript.push_str(&format!("%request\n{}", self.request));
+        transcript.push_str(&format!("%stdout\n{}\n", String::from_utf8_lossy(&self.stdout)));
+        transcript.push_str(&format!("%stderr\n{}", String::from_utf8_lossy(&self.stderr)));
+
+        let written = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.script_log)
+            .and_then(|mut file| file.write_all(transcript.as_bytes()));
+        if let Err(e) = written {
+            println!("Could not write to ScriptLog {}: {}", self.script_log.display(), e);
+        }
+    }
+}
+
+/// Kills a script's process group once it has run for longer than `CGITimeout`: first with `SIGTERM`, then with
+/// `SIGKILL` if it has not exited within `KILL_GRACE_PERIOD`.
+struct Watchdog {
+    stop: mpsc::Sender<()>,
+    thread: thread::JoinHandle<()>,
+}
+
+impl Watchdog {
+    fn start(pid: u32, timeout: time::Duration, path: path::PathBuf, request_id: u64, timed_out: Arc<AtomicBool>) -> Watchdog {
+        let (stop, stopped) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            if stopped.recv_timeout(timeout) != Err(mpsc::RecvTimeoutError::Timeout) {
+                return;
+            }
+            timed_out.store(true, Ordering::SeqCst);
+            println!("CGI script {} ran for longer than {}s; sending SIGTERM (request {})", path.display(), timeout.as_secs(), request_id);
+            kill_group(pid, libc::SIGTERM);
+            if stopped.recv_timeout(KILL_GRACE_PERIOD) == Err(mpsc::RecvTimeoutError::Timeout) {
+                println!("CGI script {} did not exit after SIGTERM; sending SIGKILL (request {})", path.display(), request_id);
+                kill_group(pid, libc::SIGKILL);
+            }
+        });
+        Watchdog { stop, thread }
+    }
+
+    fn stop(self) {
+        drop(self.stop);
+        let _ = self.thread.join();
+    }
+}
+
+/// Signals every process in the group a script leads, so that processes it started are stopped along with it.
+fn kill_group(pid: u32, signal: libc::c_int) {
+    unsafe { libc::kill(-(pid as libc::pid_t), signal) };
+}
+
+/// Builds the request meta-variables of [RFC 3875](https://datatracker.ietf.org/doc/html/rfc3875#section-4.1).
+/// `SERVER_PORT` is that of the listener that accepted the connection, `REMOTE_HOST` is the client's address since
+/// names are not looked up, and `AUTH_TYPE` and `REMOTE_USER` are passed as the client sent them, unchecked. Other
+/// request headers become `HTTP_*` variables, except `HIDDEN_HEADERS`.
+pub fn meta_variables(script: &Script, request: &Request, virtual_host: &VirtualHost) -> HashMap<String, String> {
+    let request_line = &request.header.request_line;
+    let header_lines = &request.header.header_lines;
+    let mut envs: HashMap<String, String> = HashMap::new();
+    let mut set = |name: &str, value: String| { envs.insert(name.to_string(), value); };
+
+    set("GATEWAY_INTERFACE", "CGI/1.1".to_string());
+    set("SERVER_SOFTWARE", SERVER_SOFTWARE.to_string());
+    set("SERVER_PROTOCOL", request_line.http_version.clone());
+    let host_name = header_lines.get(&RequestHeaderField::Host)
+        .map(|host| host.rsplit_once(':').filter(|(_, port)| port.chars().all(|c| c.is_ascii_digit())).map(|(name, _)| name).unwrap_or(host));
+    let server_name = virtual_host.server_name.clone()
+        .or_else(|| host_name.map(|name| name.to_string()))
+        .unwrap_or_else(|| request.remote.local_addr.ip().to_string());
+    set("SERVER_NAME", server_name);
+    set("SERVER_ADDR", request.remote.local_addr.ip().to_string());
+    set("SERVER_PORT", request.remote.local_addr.port().to_string());
+    set("HTTPS", "off".to_string());
+    set("REMOTE_ADDR", request.remote.addr.ip().to_string());
+    // names are not looked up, so the address stands in for the name as the RFC allows
+    set("REMOTE_HOST", request.remote.addr.ip().to_string());
+    set("REMOTE_PORT", request.remote.addr.port().to_string());
+
+    set("REQUEST_METHOD", request_line.method.to_string());
+    let mut request_uri = request_line.request_path.clone();
+    if !request_line.query_string.is_empty() {
+        request_uri.push('?');
+        request_uri.push_str(&request_line.query_string);
+    }
+    set("REQUEST_URI", request_uri);
+    set("QUERY_STRING", request_line.query_string.clone());
+    set("DOCUMENT_ROOT", script.document_root.display().to_string());
+    set("SCRIPT_NAME", script.script_name.clone());
+    set("SCRIPT_FILENAME", script.path.display().to_string());
+    if !script.path_info.is_empty() {
+        set("PATH_INFO", script.path_info.to_string());
+        set("PATH_TRANSLATED", script.document_root.join(script.path_info.trim_start_matches('/')).display().to_string());
+    }
+
+    if !request.body.is_empty() {
+        set("CONTENT_LENGTH", request.body.len().to_string());
+    }
+    if let Some(content_type) = header_lines.get(&RequestHeaderField::ContentType) {
+        set("CONTENT_TYPE", content_type.clone());
+    }
+    // credentials are not checked, so these only describe what the client sent
+    if let Some((scheme, credentials)) = header_lines.get(&RequestHeaderField::Authorization).map(|value| value.split_once(' ').unwrap_or((value, ""))) {
+        set("AUTH_TYPE", scheme.to_string());
+        if scheme.eq_ignore_ascii_case("Basic") {
+            if let Some(user) = decode_base64(credentials.trim()).and_then(|decoded| String::from_utf8(decoded).ok())
+                .and_then(|decoded| decoded.split_once(':').map(|(user, _)| user.to_string())) {
+                set("REMOTE_USER", user);
+            }
+        }
+    }
+
+    for (field, value) in header_lines.iter() {
+        let name = field.to_string();
+        if HIDDEN_HEADERS.iter().any(|hidden| hidden.eq_ignore_ascii_case(&name)) {
+            continue;
+        }
+        let name: String = name.chars().map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' }).collect();
+        set(&format!("HTTP_{}", name), value.clone());
+    }
+    envs
+}
+
+fn decode_base64(s: &str) -> Option<Vec<u8>> {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut decoded = Vec::new();
+    let mut bits: u32 = 0;
+    let mut bit_count = 0;
+    for byte in s.trim_end_matches('=').bytes() {
+        let value = ALPHABET.iter().position(|c| *c == byte)? as u32;
+        bits = (bits << 6) | value;
+        bit_count += 6;
+        if bit_count >= 8 {
+            bit_count -= 8;
+            decoded.push((bits >> bit_count) as u8);
+            bits &= (1 << bit_count) - 1;
+        }
+    }
+    Some(decoded)
+}
+
+/// Parses the output of a script into a response whose body is streamed from the rest of the output, reading up to the
+/// end of the headers.
+pub fn process_cgi_output(mut output: impl Read + Send + 'static, non_parsed: bool) -> Result<Output, Error> {
+    let (buffered, header_len) = read_headers(&mut output)?;
+    let head = String::from_utf8_lossy(&buffered[..header_len]).into_owned();
+    let body_start = if non_parsed { 0 } else { header_len };
+    let mut body = io::Cursor::new(buffered[body_start..].to_vec()).chain(output);
+    match parse_head(&head, non_parsed)? {
+        Output::Response(mut response) => {
+            response.stream = Some(BodyStream::new(body, non_parsed));
+            Ok(Output::Response(response))
+        },
+        Output::LocalRedirect(location) => {
+            // let the script finish rather than kill it; the body of a local redirect is discarded
+            io::copy(&mut body, &mut io::sink())?;
+            Ok(Output::LocalRedirect(location))
+        },
+    }
+}
+
+/// Parses the headers a script wrote into a response without a body. The headers end at the first empty line, with
+/// lines ending in either `\n` or `\r\n`. `Status` sets the status of the response and `Location` redirects, either
+/// the client (for an absolute URL) or the server (for a local path); other headers are passed on to the client. The
+/// head of the output of a non-parsed-header script is only read for its status.
+fn parse_head(head: &str, non_parsed: bool) -> Result<Output, Error> {
+    if non_parsed {
+        return Ok(non_parsed_output(head));
+    }
+
+    let malformed = |message: String| Error::new(format!("Malformed header from CGI script: {}", message));
+    let mut header_lines: HashMap<ResponseHeaderField, String> = HashMap::new();
+    let mut status_code = None;
+    let mut location = None;
+    for line in head.lines() {
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line.split_once(':').ok_or_else(|| malformed(format!("`{}`", line)))?;
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("Status") {
+            status_code = Some(parse_status(value).ok_or_else(|| malformed(format!("invalid status `{}`", value)))?);
+        } else if name.eq_ignore_ascii_case("Location") {
+            location = Some(value.to_string());
+        } else {
+            // header names are compared without case, keeping the first spelling given
+            let field = header_lines.keys()
+                .find(|field| field.to_string().eq_ignore_ascii_case(name))
+                .cloned()
+                .unwrap_or_else(|| ResponseHeaderField::from_name(name));
+            match field {
+                // the server frames the body itself, trusting a length that the script gives
+                ResponseHeaderField::TransferEncoding => continue,
+                ResponseHeaderField::ContentLength if u64::from_str(value).is_err() => continue,
+                _ => (),
+            }
+            header_lines.entry(field)
+                .and_modify(|joined| { joined.push_str(", "); joined.push_str(value); })
+                .or_insert_with(|| value.to_string());
+        }
+    }
+    if status_code.is_none() && location.is_none() && !header_lines.contains_key(&ResponseHeaderField::ContentType) {
+        return Err(malformed("expected at least one of `Content-Type`, `Location` or `Status`".to_string()));
+    }
+
+    if let Some(location) = location {
+        if location.starts_with('/') && status_code.is_none() {
+            return Ok(Output::LocalRedirect(location));
+        }
+        header_lines.insert(ResponseHeaderField::Other("Location".to_string()), location);
+        status_code.get_or_insert(StatusCode::Found);
+    }
+    Ok(Output::Response(Response {
+        header: ResponseHeader {
+            status_line: StatusLine {
+                status_code: status_code.unwrap_or(StatusCode::Ok),
+                http_version: String::from(HTTP_VERSION),
+            },
+            header_lines,
+        },
+        body: String::new(),
+        stream: None,
+    }))
+}
+
+/// Reads output until the empty line that ends the headers, or the end of the output. Returns what was read and the
+/// length of the headers within it, including the empty line.
+fn read_headers(output: &mut impl Read) -> Result<(Vec<u8>, usize), Error> {
+    let mut buffered = Vec::new();
+    let mut buf = [0; 4096];
+    loop {
+        if let Some(header_len) = header_len(&buffered) {
+            return Ok((buffered, header_len));
+        }
+        if buffered.len() > MAX_HEADER_SIZE {
+            return Err(unterminated_headers());
+        }
+        let bytes_read = match output.read(&mut buf) {
+            Ok(bytes_read) => bytes_read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        if bytes_read == 0 {
+            let header_len = buffered.len();
+            return Ok((buffered, header_len));
+        }
+        buffered.extend_from_slice(&buf[..bytes_read]);
+    }
+}
+
+/// Finds the length of the headers at the start of `output`, including the empty line that ends them, if it holds them
+/// all.
+fn header_len(output: &[u8]) -> Option<usize> {
+    let mut line_start = 0;
+    while let Some(line_len) = output[line_start..].iter().position(|byte| *byte == b'\n') {
+        let line = &output[line_start..line_start + line_len];
+        line_start += line_len + 1;
+        if line.is_empty() || line == b"\r" {
+            return Some(line_start);
+        }
+    }
+    None
+}
+
+fn unterminated_headers() -> Error {
+    Error::new(format!("CGI script wrote more than {} bytes without ending its headers", MAX_HEADER_SIZE))
+}
+
+/// Describes the output of a non-parsed-header script, which writes the whole response itself, by its status line.
+fn non_parsed_output(head: &str) -> Output {
+    let status_code = head.lines().next()
+        .and_then(|status_line| status_line.split_once(' '))
+        .and_then(|(_, status)| parse_status(status.trim_end()))
+        .unwrap_or(StatusCode::Ok);
+    Output::Response(Response {
+        header: ResponseHeader {
+            status_line: StatusLine { status_code, http_version: String::from(HTTP_VERSION) },
+            header_lines: HashMap::new(),
+        },
+        body: String::new(),
+        stream: None,
+    })
+}
+
+/// Parses a status such as `404 Not Found`; the reason phrase may be left out.
+fn parse_status(status: &str) -> Option<StatusCode> {
+    let (code, reason) = status.split_once(' ').unwrap_or((status, ""));
+    if code.len() != 3 {
+        return None;
+    }
+    u16::from_str(code).ok()
+        .filter(|code| (100..600).contains(code))
+        .map(|code| StatusCode::from_code(code, reason.trim()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WWW: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/www");
+
+    #[test]
+    fn printenv_sees_rfc_3875_meta_variables() {
+        let raw = concat!(
+            "POST /cgi-bin/printenv.pl/extra/path?x=1&y=2 HTTP/1.1\r\n",
+            "Host: www.example.com:3333\r\n",
+            "Content-Type: application/x-www-form-urlencoded\r\n",
+            "Content-Length: 3\r\n",
+            "Authorization: Basic YWxpY2U6c2VjcmV0\r\n",
+            "Proxy: http://evil.example.com\r\n",
+            "x-custom-header: custom\r\n",
+            "\r\n",
+            "a=1",
+        );
+        let request = parse_test_request(raw, "10.0.0.2:50000", "10.0.0.1:3333");
+        let document_root = path::Path::new(WWW);
+        let script = Script {
+            path: document_root.join("cgi-bin/printenv.pl"),
+            script_name: "/cgi-bin/printenv.pl".to_string(),
+            path_info: "/extra/path",
+            document_root,
+        };
+        let virtual_host = VirtualHost { server_name: Some("www.example.com".to_string()), ..VirtualHost::default() };
+        let response = match run(script, &request, &virtual_host).unwrap() {
+            Output::Response(response) => response,
+            Output::LocalRedirect(location) => panic!("unexpected redirect to {}", location),
+        };
+        let body = response.into_body().unwrap();
+        let envs: HashMap<&str, &str> = body.lines()
+            .filter_map(|line| line.split_once('='))
+            .map(|(name, value)| (name, value.trim_matches('"')))
+            .collect();
+
+        let expected = [
+            ("AUTH_TYPE", "Basic".to_string()),
+            ("CONTENT_LENGTH", "3".to_string()),
+            ("CONTENT_TYPE", "application/x-www-form-urlencoded".to_string()),
+            ("DOCUMENT_ROOT", WWW.to_string()),
+            ("GATEWAY_INTERFACE", "CGI/1.1".to_string()),
+            ("HTTPS", "off".to_string()),
+            ("HTTP_HOST", "www.example.com:3333".to_string()),
+            ("HTTP_X_CUSTOM_HEADER", "custom".to_string()),
+            ("PATH_INFO", "/extra/path".to_string()),
+            ("PATH_TRANSLATED", format!("{}/extra/path", WWW)),
+            ("QUERY_STRING", "x=1&y=2".to_string()),
+            ("REMOTE_ADDR", "10.0.0.2".to_string()),
+            ("REMOTE_PORT", "50000".to_string()),
+            ("REMOTE_USER", "alice".to_string()),
+            ("REQUEST_METHOD", "POST".to_string()),
+            ("REQUEST_URI", "/cgi-bin/printenv.pl/extra/path?x=1&y=2".to_string()),
+            ("SCRIPT_FILENAME", format!("{}/cgi-bin/printenv.pl", WWW)),
+            ("SCRIPT_NAME", "/cgi-bin/printenv.pl".to_string()),
+            ("SERVER_ADDR", "10.0.0.1".to_string()),
+            ("SERVER_NAME", "www.example.com".to_string()),
+            ("SERVER_PORT", "3333".to_string()),
+            ("SERVER_PROTOCOL", "HTTP/1.1".to_string()),
+            ("SERVER_SOFTWARE", SERVER_SOFTWARE.to_string()),
+        ];
+        for (name, value) in expected.iter() {
+            assert_eq!(envs.get(name), Some(&value.as_str()), "{}", name);
+        }
+        for name in ["HTTP_PROXY", "HTTP_AUTHORIZATION", "HTTP_CONTENT_LENGTH", "HTTP_CONTENT_TYPE"].iter() {
+            assert!(!envs.contains_key(name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn parses_status_location_and_headers_from_script_output() {
+        let output = |raw: &str| process_cgi_output(io::Cursor::new(raw.to_string()), false);
+        let response = |raw: &str| match output(raw).unwrap() {
+            Output::Response(response) => response,
+            Output::LocalRedirect(location) => panic!("unexpected redirect to {}", location),
+        };
+
+        let document = response("Content-Type: text/plain\r\nStatus: 418 I'm a teapot\r\nX-Thing: a\r\nx-thing: b\r\nContent-Length: 9\r\n\r\none\n\ntwo\n");
+        assert_eq!(document.header.status_line.status_code.to_string(), "418 I'm a teapot");
+        assert_eq!(document.header.header_lines.get(&ResponseHeaderField::Other("X-Thing".to_string())).map(String::as_str), Some("a, b"));
+        assert_eq!(document.header.header_lines.get(&ResponseHeaderField::ContentLength).map(String::as_str), Some("9"));
+        assert_eq!(document.into_body().unwrap(), "one\n\ntwo\n");
+        let unsized_document = response("Content-Type: text/plain\nContent-Length: many\n\nbody");
+        assert!(!unsized_document.header.header_lines.contains_key(&ResponseHeaderField::ContentLength));
+
+        let client_redirect = response("Location: http://example.com/\n\n");
+        assert!(matches!(client_redirect.header.status_line.status_code, StatusCode::Found));
+        assert_eq!(client_redirect.header.header_lines.get(&ResponseHeaderField::Other("Location".to_string())).map(String::as_str), Some("http://example.com/"));
+
+        assert!(matches!(output("Location: /index.html?a=1\n\n").unwrap(), Output::LocalRedirect(location) if location == "/index.html?a=1"));
+        assert!(output("X-Thing: a\n\nbody").is_err());
+        assert!(output("Status: two hundred\n\n").is_err());
+        assert!(output("not a header\n\n").is_err());
+        assert!(output(&"X-Thing: a\n".repeat(MAX_HEADER_SIZE)).is_err());
+    }
+
+    #[test]
+    fn scripts_can_echo_bodies_larger_than_a_pipe() {
+        let line = "the quick brown fox jumps over the lazy dog\n";
+        let body = line.repeat(1 << 16);
+        let raw = format!("POST /cgi-bin/uppercase.pl HTTP/1.1\r\nHost: www.example.com\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+        let request = parse_test_request(&raw, "10.0.0.2:50000", "10.0.0.1:3333");
+        let document_root = path::Path::new(WWW);
+        let script = Script {
+            path: document_root.join("cgi-bin/uppercase.pl"),
+            script_name: "/cgi-bin/uppercase.pl".to_string(),
+            path_info: "",
+            document_root,
+        };
+        let response = match run(script, &request, &VirtualHost::default()).unwrap() {
+            Output::Response(response) => response,
+            Output::LocalRedirect(location) => panic!("unexpected redirect to {}", location),
+        };
+        let mut bytes = Vec::new();
+        write_response_to(&mut bytes, response).unwrap();
+        let written = String::from_utf8(bytes).unwrap();
+        assert!(written.contains("Transfer-Encoding: chunked\r\n"));
+        let chunks: String = written.split_once("\r\n\r\n").unwrap().1
+            .split("\r\n")
+            .collect::<Vec<_>>()
+            .chunks(2)
+            .filter_map(|chunk| chunk.get(1).copied())
+            .collect();
+        assert_eq!(chunks, line.to_uppercase().repeat(1 << 16));
+    }
+
+    /// Runs a script, waiting until it has written its headers.
+    fn run(script: Script, request: &Request, virtual_host: &VirtualHost) -> Result<Output, HttpError> {
+        Cgi::default().start(script, request, virtual_host)?.wait()
+    }
+
+    /// Writes a shell script to a temporary directory and returns it ready to run.
+    fn shell_script(name: &str, body: &str) -> Script<'static> {
+        use std::os::unix::fs::PermissionsExt;
+        let directory = std::env::temp_dir().join(format!("p1-cgi-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = directory.join(name);
+        std::fs::write(&path, format!("#!/bin/sh\n{}", body)).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        Script { path, script_name: format!("/{}", name), path_info: "", document_root: path::Path::new("/") }
+    }
+
+    #[test]
+    fn scripts_are_limited_in_time_and_resources() {
+        let raw = "GET /script HTTP/1.1\r\nHost: www.example.com\r\n\r\n";
+        let request = parse_test_request(raw, "10.0.0.2:50000", "10.0.0.1:3333");
+        let mut virtual_host = VirtualHost::default();
+        virtual_host.cgi_limits.timeout = Some(time::Duration::from_secs(1));
+        virtual_host.cgi_limits.cpu = Some(ResourceLimit { soft: Some(7), hard: None });
+
+        let limited = shell_script("ulimit.sh", "printf 'Content-Type: text/plain\\n\\n'; ulimit -t\n");
+        let body = match run(limited, &request, &virtual_host).unwrap() {
+            Output::Response(response) => response.into_body().unwrap(),
+            Output::LocalRedirect(location) => panic!("unexpected redirect to {}", location),
+        };
+        assert_eq!(body, "7\n");
+
+        let started = time::Instant::now();
+        let hung = shell_script("hung.sh", "sleep 30\n");
+        let error = run(hung, &request, &virtual_host).err().unwrap();
+        assert!(matches!(error.status, StatusCode::GatewayTimeout));
+        assert!(started.elapsed() < time::Duration::from_secs(10));
+    }
+
+    #[test]
+    fn scripts_can_be_read_without_blocking() {
+        let raw = "GET /script HTTP/1.1\r\nHost: www.example.com\r\n\r\n";
+        let request = parse_test_request(raw, "10.0.0.2:50000", "10.0.0.1:3333");
+        let pausing = shell_script("pausing.sh", "printf 'Content-Type: text/plain\\n'; sleep 1; printf 'X-Thing: a\\n\\nbody'\n");
+        let mut script = Cgi::default().start(pausing, &request, &VirtualHost::default()).unwrap();
+        let mut output = script.take_pipes(true).unwrap().output;
+
+        let mut buf = [0; 64];
+        let mut read = Vec::new();
+        let mut would_block = false;
+        let (parsed, body_start) = loop {
+            let finished = match output.read(&mut buf) {
+                Ok(bytes_read) => {
+                    read.extend_from_slice(&buf[..bytes_read]);
+                    bytes_read == 0
+                },
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    would_block = true;
+                    thread::sleep(time::Duration::from_millis(10));
+                    false
+                },
+                Err(e) => panic!("{}", e),
+            };
+            if let Some(parsed) = script.parse_output(&read, finished) {
+                break parsed.unwrap();
+            }
+        };
+        assert!(would_block);
+        match parsed {
+            Output::Response(response) => assert_eq!(response.header.header_lines.get(&ResponseHeaderField::Other("X-Thing".to_string())).map(String::as_str), Some("a")),
+            Output::LocalRedirect(location) => panic!("unexpected redirect to {}", location),
+        }
+        assert_eq!(&read[body_start..], &b"body"[..read.len() - body_start]);
+    }
+
+    #[test]
+    fn failing_scripts_are_transcribed_to_the_script_log() {
+        let raw = "POST /script?debug=1 HTTP/1.1\r\nHost: www.example.com\r\nAuthorization: Basic c2VjcmV0\r\nContent-Length: 5\r\n\r\nhello";
+        let request = parse_test_request(raw, "10.0.0.2:50000", "10.0.0.1:3333");
+        let script_log = std::env::temp_dir().join(format!("p1-cgi-{}", std::process::id())).join("script.log");
+        let virtual_host = VirtualHost { script_log: Some(script_log.to_string_lossy().into_owned()), ..VirtualHost::default() };
+
+        let broken = shell_script("broken.sh", "echo 'about to fail' >&2\necho 'no headers here'\n");
+        let error = run(broken, &request, &virtual_host).err().unwrap();
+        assert!(matches!(error.status, StatusCode::InternalServerError));
+
+        let transcript = std::fs::read_to_string(&script_log).unwrap();
+        assert!(transcript.contains(" POST /script?debug=1 HTTP/1.1\n%% 500 "), "{}", transcript);
+        assert!(transcript.contains("%request\nContent-Length: 5\nHost: www.example.com\n"), "{}", transcript);
+        assert!(!transcript.contains("c2VjcmV0"), "{}", transcript);
+        assert!(transcript.contains("\nhello\n%stdout\nno headers here\n"), "{}", transcript);
+        assert!(transcript.ends_with("%stderr\nabout to fail\n"), "{}", transcript);
+
+        // neither a line without an end nor a flood of lines is held in full
+        let flood = shell_script("flood.sh", "head -c 50000 /dev/zero | tr '\\0' x >&2\nyes line | head -n 5000 >&2\nexit 1\n");
+        let error = run(flood, &request, &virtual_host).err().unwrap();
+        assert!(matches!(error.status, StatusCode::InternalServerError));
+        let transcript = std::fs::read_to_string(&script_log).unwrap();
+        let stderr = &transcript[transcript.rfind("%stderr\n").unwrap() + "%stderr\n".len()..];
+        assert_eq!(stderr, "x".repeat(SCRIPT_LOG_LENGTH));
+
+        let mut error_log = ErrorLog::new("test".to_string());
+        error_log.write(&[b'x'; SCRIPT_LOG_LENGTH * 3 + 1]);
+        assert_eq!(error_log.line.len(), 1);
+    }
 }
@@ -0,0 +1,140 @@
+use std::net::SocketAddr;
+use std::path;
+use std::str::FromStr;
+use crate::config;
+use crate::error::Error;
+
+pub const USAGE: &str = "\
+Usage: p1 [options]
+
+Options:
+  -f, --config <path>    configuration file (default httpd.conf)         [P1_CONFIG]
+  -m, --model <model>    concurrency model: single, pool or select        [P1_MODEL]
+  -l, --listen <addr>    [addr:]port to listen on instead of Listen       [P1_LISTEN]
+  -n, --threads <count>  threads in the pool instead of ThreadPoolSize    [P1_THREADS]
+  -D <name>              define a parameter for <IfDefine name> sections  [P1_DEFINE, comma-separated]
+  -t, --configtest       check the configuration and exit
+  -S, --dump-vhosts      print the virtual hosts serving each listener and exit
+      --dump-config      print the configuration after inheritance and exit
+  -v, --version          print the version and exit
+  -h, --help             print this help and exit
+
+Command-line options take precedence over the environment.";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum MultiModel {
+    Single, ThreadPool, SelectMultiplex
+}
+impl FromStr for MultiModel {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "single" => Ok(MultiModel::Single),
+            "pool" => Ok(MultiModel::ThreadPool),
+            "select" => Ok(MultiModel::SelectMultiplex),
+            _ => Err(Error::new(format!("invalid model `{}`; expected single, pool or select", s))),
+        }
+    }
+}
+
+/// What the binary was asked to do with the configuration.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    Serve, ConfigTest, DumpVirtualHosts, DumpConfig, Help, Version
+}
+
+#[derive(Clone, Debug)]
+pub struct Options {
+    pub command: Command,
+    pub config: path::PathBuf,
+    pub model: MultiModel,
+    pub listen: Option<SocketAddr>,
+    pub threads: Option<usize>,
+    pub defines: Vec<String>,
+}
+
+/// Parses the command line (without the program name), falling back to `P1_*` environment variables, as read by
+/// `env`, for anything not given on it.
+pub fn parse_args(args: impl IntoIterator<Item = String>, env: impl Fn(&str) -> Option<String>) -> Result<Options, Error> {
+    let mut command = Command::Serve;
+    let mut config = env("P1_CONFIG");
+    let mut model = env("P1_MODEL");
+    let mut listen = env("P1_LISTEN");
+    let mut threads = env("P1_THREADS");
+    let mut defines: Vec<String> = env("P1_DEFINE")
+        .map(|defines| defines.split(',').map(|define| define.trim().to_string()).filter(|define| !define.is_empty()).collect())
+        .unwrap_or_default();
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        // accept both `--flag value` and `--flag=value`
+        let (flag, inline_value) = match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => (flag.to_string(), Some(value.to_string())),
+            _ => (arg.clone(), None),
+        };
+        let mut value = || inline_value.clone().or_else(|| args.next())
+            .ok_or_else(|| Error::new(format!("{} requires a value\n\n{}", flag, USAGE)));
+        match flag.as_str() {
+            "-f" | "--config" => config = Some(value()?),
+            "-m" | "--model" => model = Some(value()?),
+            "-l" | "--listen" => listen = Some(value()?),
+            "-n" | "--threads" => threads = Some(value()?),
+            "-D" => defines.push(value()?),
+            "-t" | "--configtest" => command = Command::ConfigTest,
+            "-S" | "--dump-vhosts" => command = Command::DumpVirtualHosts,
+            "--dump-config" => command = Command::DumpConfig,
+            "-v" | "--version" => command = Command::Version,
+            "-h" | "--help" => command = Command::Help,
+            _ => match flag.strip_prefix("-D") {
+                Some(define) if !define.is_empty() => defines.push(define.to_string()),
+                _ => return Err(Error::new(format!("unexpected argument `{}`\n\n{}", arg, USAGE))),
+            },
+        }
+    }
+
+    Ok(Options {
+        command,
+        config: path::PathBuf::from(config.unwrap_or_else(|| "httpd.conf".to_string())),
+        model: model.map(|model| MultiModel::from_str(&model)).transpose()?.unwrap_or(MultiModel::Single),
+        listen: listen.map(|listen| config::parse_listen(&listen)
+            .map_err(|message| Error::new(format!("invalid listen address: {}", message))))
+            .transpose()?,
+        threads: threads.map(|threads| usize::from_str(&threads).ok().filter(|threads| *threads != 0)
+            .ok_or_else(|| Error::new(format!("invalid thread count `{}`; expected a positive number", threads))))
+            .transpose()?,
+        defines,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str], env: &[(&str, &str)]) -> Result<Options, Error> {
+        let env: Vec<(String, String)> = env.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect();
+        parse_args(args.iter().map(|arg| arg.to_string()), |key| env.iter().find(|(k, _)| k == key).map(|(_, value)| value.clone()))
+    }
+
+    #[test]
+    fn flags_override_the_environment() {
+        let options = parse(
+            &["--config=site.conf", "-m", "pool", "-DSSL", "-D", "DEBUG", "--threads", "4"],
+            &[("P1_MODEL", "select"), ("P1_LISTEN", "8080"), ("P1_DEFINE", "EXTRA")],
+        ).unwrap();
+        assert_eq!(options.command, Command::Serve);
+        assert_eq!(options.config, path::PathBuf::from("site.conf"));
+        assert_eq!(options.model, MultiModel::ThreadPool);
+        assert_eq!(options.listen, Some("0.0.0.0:8080".parse().unwrap()));
+        assert_eq!(options.threads, Some(4));
+        assert_eq!(options.defines, vec!("EXTRA", "SSL", "DEBUG"));
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        assert!(parse(&["--model", "threads"], &[]).unwrap_err().message.starts_with("invalid model `threads`"));
+        assert_eq!(parse(&[], &[("P1_LISTEN", "70000")]).unwrap_err().message, "invalid listen address: port must be between 1 and 65535, found `70000`");
+        assert!(parse(&["--threads", "0"], &[]).unwrap_err().message.starts_with("invalid thread count `0`"));
+        assert!(parse(&["--listen"], &[]).unwrap_err().message.starts_with("--listen requires a value"));
+        assert!(parse(&["httpd.conf"], &[]).unwrap_err().message.starts_with("unexpected argument `httpd.conf`"));
+    }
+}
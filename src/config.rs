@@ -1,106 +1,1825 @@
 use std::collections::HashMap;
+use std::fmt;
 use std::fs;
+use std::iter::Peekable;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::path;
-use std::str::FromStr;
+use std::str::{Chars, FromStr};
+use std::time;
+use regex::Regex;
 use crate::error::Error;
-use crate::parse::{discard_char, discard_string};
+use crate::http::ResponseHeaderField;
+use crate::vfs;
+use crate::vhost_alias;
 
+const DEFAULT_THREAD_POOL_SIZE: usize = 1;
+const DEFAULT_CACHE_SIZE: usize = 1024 * 1024;
+const DEFAULT_REVALIDATE_INTERVAL: time::Duration = time::Duration::from_secs(1);
+/// As Apache's default `Timeout`.
+const DEFAULT_CGI_TIMEOUT: time::Duration = time::Duration::from_secs(60);
+
+/// The validated server configuration. Directives outside of any `<VirtualHost>` configure the main server; those that
+/// may also appear in a virtual host act as defaults that each virtual host inherits and may override.
 #[derive(Clone, Debug)]
 pub struct ServerConfig {
-    pub directives: HashMap<Directive, String>,
+    /// The addresses to accept connections on, one listener each.
+    pub listen: Vec<SocketAddr>,
+    pub thread_pool_size: usize,
+    pub cache: CacheConfig,
+    /// The virtual hosts in the order they were declared. When the file declares none, the main server is the only
+    /// virtual host.
     pub virtual_hosts: Vec<VirtualHost>,
+    /// The directives outside of any `<VirtualHost>`, which serve connections to addresses no virtual host is declared
+    /// for.
+    pub main_server: VirtualHost,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
+pub struct CacheConfig {
+    /// Total size of the file cache in bytes.
+    pub size: usize,
+    /// Size in bytes of the largest file that will be cached.
+    pub max_file_size: usize,
+    pub revalidate_interval: time::Duration,
+    pub watch: bool,
+}
+
+#[derive(Clone, Debug, Default)]
 pub struct VirtualHost {
-    pub directives: HashMap<Directive, String>,
+    /// The addresses given in `<VirtualHost>`, empty for the main server.
+    pub addresses: Vec<VirtualHostAddress>,
+    /// The file and line of the `<VirtualHost>`, or `None` for the main server.
+    pub defined_at: Option<String>,
+    pub server_name: Option<String>,
+    /// Other names the virtual host answers to, which may contain `*` and `?` wildcards.
+    pub server_aliases: Vec<glob::Pattern>,
+    /// The `DocumentRoot` as written, either a directory or an `archive:` path to a tar file; either way it is known
+    /// to exist.
+    pub document_root: Option<String>,
+    /// A `VirtualDocumentRoot`, from which the document root of each request is derived from its `Host`. Requests
+    /// whose directory does not exist fall back to `document_root`.
+    pub virtual_document_root: Option<vhost_alias::Template>,
+    /// Globs relative to the document root whose files are loaded into the cache at startup.
+    pub cache_warm: Vec<String>,
+    pub cgi_limits: CgiLimits,
+    /// The file that transcripts of failing CGI scripts are appended to, from `ScriptLog`.
+    pub script_log: Option<String>,
+    /// URL paths answered by FastCGI, SCGI and uWSGI applications, from `ProxyPass`, whether given with a path or
+    /// inside a `<Location>`.
+    pub proxy_passes: Vec<ProxyPass>,
+    /// URL paths mapped to directories of CGI scripts, from `ScriptAlias`.
+    pub script_aliases: Vec<ScriptAlias>,
+    /// Settings that apply everywhere in the virtual host unless a section overrides them.
+    pub directory: DirectoryConfig,
+    /// `<Directory>`, `<Files>` and `<Location>` sections, those of the main server first.
+    pub sections: Vec<PathSection>,
+}
+
+/// Limits on the CGI scripts a virtual host runs, from `CGITimeout` and the `RLimit*` directives.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CgiLimits {
+    /// How long a script may run before it is killed, if limited.
+    pub timeout: Option<time::Duration>,
+    /// Seconds of CPU time, from `RLimitCPU`.
+    pub cpu: Option<ResourceLimit>,
+    /// Bytes of address space, from `RLimitMEM`.
+    pub memory: Option<ResourceLimit>,
+    /// Processes per user, from `RLimitNPROC`.
+    pub processes: Option<ResourceLimit>,
+}
+impl Default for CgiLimits {
+    fn default() -> Self {
+        CgiLimits { timeout: Some(DEFAULT_CGI_TIMEOUT), cpu: None, memory: None, processes: None }
+    }
+}
+
+/// The soft and hard limits for a resource, where `None` stands for `max`: the hard limit of the server itself, which
+/// is also the hard limit when only one is given.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ResourceLimit {
+    pub soft: Option<u64>,
+    pub hard: Option<u64>,
+}
+
+/// A `ProxyPass` that sends requests for URL paths starting with `path` to a FastCGI, SCGI or uWSGI application, such as
+/// `ProxyPass /app/ fcgi://127.0.0.1:9000/srv/app/` or `ProxyPass /app/ unix:/run/app.sock|scgi://localhost/`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProxyPass {
+    pub path: String,
+    pub protocol: Protocol,
+    pub address: BackendAddress,
+    /// The path given in the URL, to which the rest of the request path is appended to name the script.
+    pub target: String,
+}
+
+impl ProxyPass {
+    /// The URL of the application, as written in the configuration.
+    pub fn url(&self) -> String {
+        let scheme = self.protocol.scheme();
+        match &self.address {
+            BackendAddress::Tcp(address) => format!("{}://{}{}", scheme, address, self.target),
+            BackendAddress::Unix(path) => format!("unix:{}|{}://localhost{}", path.display(), scheme, self.target),
+        }
+    }
+}
+
+/// A `ScriptAlias` that maps URL paths starting with `path` to the files in `directory`, every one of which is run as a
+/// CGI script.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScriptAlias {
+    pub path: String,
+    /// The canonical path of the directory.
+    pub directory: path::PathBuf,
+}
+
+/// The protocol an application speaks, given by the scheme of its URL.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Protocol {
+    FastCgi,
+    Scgi,
+    Uwsgi,
+}
+
+impl Protocol {
+    const ALL: [Protocol; 3] = [Protocol::FastCgi, Protocol::Scgi, Protocol::Uwsgi];
+
+    fn scheme(&self) -> &'static str {
+        match self {
+            Protocol::FastCgi => "fcgi",
+            Protocol::Scgi => "scgi",
+            Protocol::Uwsgi => "uwsgi",
+        }
+    }
+}
+
+/// Where an application listens: a host and port, or a Unix domain socket.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum BackendAddress {
+    Tcp(String),
+    Unix(path::PathBuf),
+}
+
+/// An address given in `<VirtualHost>`, where `None` stands for `*` (or `_default_`): any IP address or any port.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct VirtualHostAddress {
+    pub ip: Option<IpAddr>,
+    pub port: Option<u16>,
+}
+
+impl VirtualHostAddress {
+    /// Whether connections accepted on `address` may be served by this virtual host. A listener bound to the
+    /// unspecified address may accept connections for any IP address.
+    pub fn matches(&self, address: SocketAddr) -> bool {
+        let ip_matches = match self.ip {
+            Some(ip) => address.ip().is_unspecified() || ip == address.ip(),
+            None => true,
+        };
+        ip_matches && self.port.map(|port| port == address.port()).unwrap_or(true)
+    }
+}
+
+impl FromStr for VirtualHostAddress {
+    type Err = String;
+    /// Parses `*`, `*:80`, `127.0.0.1:80`, `[::1]:*` and the like. Host names are not resolved.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || format!("expected an IP address or `*`, optionally followed by `:port`, found `{}`", s);
+        let (ip, port) = match s.strip_prefix('[') {
+            Some(rest) => match rest.split_once(']').ok_or_else(error)? {
+                (ip, "") => (ip, None),
+                (ip, port) => (ip, Some(port.strip_prefix(':').ok_or_else(error)?)),
+            },
+            // an IPv6 address without brackets cannot be followed by a port
+            None if s.matches(':').count() == 1 => s.split_once(':').map(|(ip, port)| (ip, Some(port))).unwrap(),
+            None => (s, None),
+        };
+        let ip = match ip {
+            "*" | "_default_" => None,
+            ip => Some(IpAddr::from_str(ip).map_err(|_| error())?),
+        };
+        let port = match port {
+            None | Some("*") => None,
+            Some(port) => Some(u16::from_str(port).ok().filter(|port| *port != 0).ok_or_else(error)?),
+        };
+        Ok(VirtualHostAddress { ip, port })
+    }
+}
+
+impl fmt::Display for VirtualHostAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.ip {
+            Some(IpAddr::V6(ip)) => write!(f, "[{}]", ip)?,
+            Some(ip) => write!(f, "{}", ip)?,
+            None => f.write_str("*")?,
+        }
+        match self.port {
+            Some(port) => write!(f, ":{}", port),
+            None => f.write_str(":*"),
+        }
+    }
+}
+
+/// The settings that may differ between parts of a site, as set by the directives allowed in `<Directory>`, `<Files>`
+/// and `<Location>` sections.
+#[derive(Clone, Debug)]
+pub struct DirectoryConfig {
+    pub options: Options,
+    /// Output filters by file extension (lowercase, without the leading dot), from `AddOutputFilter`.
+    pub output_filters: HashMap<String, Vec<String>>,
+    /// Handlers by file extension (lowercase, without the leading dot), from `AddHandler`.
+    pub handlers: HashMap<String, String>,
+    /// The handler for every file, from `SetHandler`, which takes precedence over `handlers`.
+    pub handler: Option<String>,
+    /// The files to look for, in order, when a directory is requested.
+    pub directory_index: Vec<String>,
+    /// False when access is refused with `Require all denied`.
+    pub access_granted: bool,
+    /// Changes to the headers of successful responses, in the order they are applied.
+    pub headers: Vec<HeaderAction>,
+    /// The largest request body in bytes that will be accepted, if limited.
+    pub limit_request_body: Option<usize>,
+    /// Whether a request may name a path below a file, from `AcceptPathInfo`. By default only CGI scripts accept it.
+    pub accept_path_info: Option<bool>,
+}
+impl Default for DirectoryConfig {
+    fn default() -> Self {
+        DirectoryConfig {
+            options: Options::default(),
+            output_filters: HashMap::new(),
+            handlers: HashMap::new(),
+            handler: None,
+            directory_index: vec!("index.html".to_string()),
+            access_granted: true,
+            headers: Vec::new(),
+            limit_request_body: None,
+            accept_path_info: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Options {
+    pub exec_cgi: bool,
+    pub includes: bool,
+    pub indexes: bool,
+}
+
+#[derive(Clone, Debug)]
+pub enum HeaderAction {
+    Set(ResponseHeaderField, String),
+    Append(ResponseHeaderField, String),
+    Unset(ResponseHeaderField),
+}
+
+/// A `<Directory>`, `<Files>` or `<Location>` section (or one of their `Match` variants) and the settings within it.
+#[derive(Clone, Debug)]
+pub struct PathSection {
+    matcher: Matcher,
+    settings: Vec<Setting>,
+}
+
+#[derive(Clone, Debug)]
+enum Matcher {
+    /// Matches a file system path if the pattern matches the path or any directory above it.
+    Directory(glob::Pattern),
+    DirectoryMatch(Regex),
+    /// Matches the file name of a file system path.
+    Files(glob::Pattern),
+    FilesMatch(Regex),
+    /// Matches a URL path if the pattern matches the path or any prefix of it ending at a `/`.
+    Location(glob::Pattern),
+    LocationMatch(Regex),
 }
 
+/// A validated directive from the set allowed in `<Directory>`, `<Files>` and `<Location>` sections.
+#[derive(Clone, Debug)]
+enum Setting {
+    Options { replace: bool, enable: Options, disable: Options },
+    AddOutputFilter(Vec<String>, Vec<String>),
+    AddHandler(String, Vec<String>),
+    SetHandler(Option<String>),
+    DirectoryIndex(Vec<String>),
+    Require(bool),
+    Header(HeaderAction),
+    LimitRequestBody(Option<usize>),
+    AcceptPathInfo(Option<bool>),
+}
+
+/// The output filters that `AddOutputFilter` may name.
+const OUTPUT_FILTERS: &[&str] = &["INCLUDES"];
+
+/// The handler that runs files as CGI scripts.
+pub const CGI_SCRIPT: &str = "cgi-script";
+/// The handler that reports the file cache's counters in place of any file, for `SetHandler` in a `<Location>`.
+pub const CACHE_STATUS: &str = "cache-status";
+/// The handlers that `AddHandler` and `SetHandler` may name. `default-handler` serves files as they are, which lets
+/// `SetHandler` undo an `AddHandler` for part of a site.
+const HANDLERS: &[&str] = &[CACHE_STATUS, CGI_SCRIPT, "default-handler"];
+
+/// Options accepted from Apache configurations; those other than `ExecCGI`, `Includes` and `Indexes` have no effect.
+const OPTIONS: &[&str] = &["All", "ExecCGI", "FollowSymLinks", "Includes", "IncludesNOEXEC", "Indexes", "MultiViews", "SymLinksIfOwnerMatch"];
+
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
-pub enum Directive {
-    CacheSize, DocumentRoot, ListenPort, ServerName, ThreadPoolSize
+enum Directive {
+    AcceptPathInfo, AddHandler, AddOutputFilter, CacheMaxFileSize, CacheRevalidateInterval, CacheSize, CacheWarm, CacheWatch, CGITimeout, DirectoryIndex, DocumentRoot, Header, LimitRequestBody, Listen, Options, ProxyPass, Require, RLimitCPU, RLimitMEM, RLimitNPROC, ScriptAlias, ScriptLog, ServerAlias, ServerName, SetHandler, ThreadPoolSize, VirtualDocumentRoot
 }
+const DIRECTIVES: &[(&str, Directive)] = &[
+    ("AcceptPathInfo", Directive::AcceptPathInfo),
+    ("AddHandler", Directive::AddHandler),
+    ("AddOutputFilter", Directive::AddOutputFilter),
+    ("CacheMaxFileSize", Directive::CacheMaxFileSize),
+    ("CacheRevalidateInterval", Directive::CacheRevalidateInterval),
+    ("CacheSize", Directive::CacheSize),
+    ("CacheWarm", Directive::CacheWarm),
+    ("CacheWatch", Directive::CacheWatch),
+    ("CGITimeout", Directive::CGITimeout),
+    ("DirectoryIndex", Directive::DirectoryIndex),
+    ("DocumentRoot", Directive::DocumentRoot),
+    ("Header", Directive::Header),
+    ("LimitRequestBody", Directive::LimitRequestBody),
+    ("Listen", Directive::Listen),
+    ("Options", Directive::Options),
+    ("ProxyPass", Directive::ProxyPass),
+    ("Require", Directive::Require),
+    ("RLimitCPU", Directive::RLimitCPU),
+    ("RLimitMEM", Directive::RLimitMEM),
+    ("RLimitNPROC", Directive::RLimitNPROC),
+    ("ScriptAlias", Directive::ScriptAlias),
+    ("ScriptLog", Directive::ScriptLog),
+    ("ServerAlias", Directive::ServerAlias),
+    ("ServerName", Directive::ServerName),
+    ("SetHandler", Directive::SetHandler),
+    ("ThreadPoolSize", Directive::ThreadPoolSize),
+    ("VirtualDocumentRoot", Directive::VirtualDocumentRoot),
+];
 impl FromStr for Directive {
     type Err = ();
+    /// Directive names are case-insensitive, as in Apache.
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "CacheSize" => Ok(Directive::CacheSize),
-            "DocumentRoot" => Ok(Directive::DocumentRoot),
-            "Listen" => Ok(Directive::ListenPort),
-            "ServerName" => Ok(Directive::ServerName),
-            "ThreadPoolSize" => Ok(Directive::ThreadPoolSize),
-            _ => Err(())
+        DIRECTIVES.iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(s))
+            .map(|(_, directive)| directive.clone())
+            .ok_or(())
+    }
+}
+impl Directive {
+    /// Whether the directive may appear in the given context. Directives that may appear in a narrower context may
+    /// also appear in the wider ones, where they act as defaults.
+    fn allowed_in(&self, context: Context) -> bool {
+        match self {
+            Directive::AcceptPathInfo | Directive::AddHandler | Directive::AddOutputFilter | Directive::DirectoryIndex
+                | Directive::Header | Directive::LimitRequestBody | Directive::Options | Directive::ProxyPass
+                | Directive::Require | Directive::SetHandler => true,
+            Directive::CacheWarm | Directive::CGITimeout | Directive::DocumentRoot | Directive::RLimitCPU
+                | Directive::RLimitMEM | Directive::RLimitNPROC | Directive::ScriptAlias | Directive::ScriptLog
+                | Directive::ServerAlias
+                | Directive::ServerName | Directive::VirtualDocumentRoot => context != Context::Path,
+            _ => context == Context::Server,
         }
     }
 }
 
-pub fn load_config(config_path: &path::Path) -> Result<ServerConfig, Error> {
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Context {
+    Server, VirtualHost, Path
+}
+
+const SECTIONS: &[&str] = &["Directory", "DirectoryMatch", "Files", "FilesMatch", "IfDefine", "Location", "LocationMatch", "VirtualHost"];
+
+impl VirtualHost {
+    /// Whether the `Host` of a request names this virtual host, through its `ServerName` or one of its `ServerAlias`
+    /// patterns. Names are compared without regard to case or to a port given with them.
+    pub fn matches_name(&self, host: &str) -> bool {
+        let host = strip_port(host);
+        let options = glob::MatchOptions { case_sensitive: false, ..glob::MatchOptions::new() };
+        self.server_name.as_deref().map(|server_name| strip_port(server_name).eq_ignore_ascii_case(host)).unwrap_or(false)
+            || self.server_aliases.iter().any(|alias| alias.matches_with(host, options))
+    }
+
+    /// Finds the first `ProxyPass` whose path is a prefix of the URL path of a request, as for `script_alias`.
+    pub fn proxy_pass(&self, url_path: &str) -> Option<&ProxyPass> {
+        self.proxy_passes.iter().find(|proxy_pass| is_path_prefix(&proxy_pass.path, url_path))
+    }
+
+    /// Finds the first `ScriptAlias` whose path is a prefix of the URL path of a request.
+    pub fn script_alias(&self, url_path: &str) -> Option<&ScriptAlias> {
+        self.script_aliases.iter().find(|script_alias| is_path_prefix(&script_alias.path, url_path))
+    }
+
+    /// Computes the settings in effect for a request, given the file system path it resolved to and its URL path, which
+    /// must be normalized so that `<Location>` sections cannot be sidestepped with `//` or `/./`.
+    /// Matching sections are merged in the order Apache documents: `<Directory>` from the shortest path to the
+    /// longest, then `<DirectoryMatch>`, then `<Files>` and `<FilesMatch>`, then `<Location>` and `<LocationMatch>`;
+    /// within each group, later sections override earlier ones.
+    pub fn directory_config(&self, path: &path::Path, url_path: &str) -> DirectoryConfig {
+        let mut config = self.directory.clone();
+        for group in 0..4 {
+            let mut sections: Vec<&PathSection> = self.sections.iter()
+                .filter(|section| section.matcher.group() == group && section.matcher.matches(path, url_path))
+                .collect();
+            if group == 0 {
+                sections.sort_by_key(|section| section.matcher.depth());
+            }
+            for section in sections {
+                for setting in section.settings.iter() {
+                    setting.apply(&mut config);
+                }
+            }
+        }
+        config
+    }
+}
+
+/// Whether `url_path` starts with `prefix` at a `/` or the end of the path, so that `/cgi-bin` does not take in
+/// `/cgi-binaries`.
+fn is_path_prefix(prefix: &str, url_path: &str) -> bool {
+    match url_path.strip_prefix(prefix) {
+        Some(rest) => prefix.ends_with('/') || rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+impl Matcher {
+    fn group(&self) -> u8 {
+        match self {
+            Matcher::Directory(_) => 0,
+            Matcher::DirectoryMatch(_) => 1,
+            Matcher::Files(_) | Matcher::FilesMatch(_) => 2,
+            Matcher::Location(_) | Matcher::LocationMatch(_) => 3,
+        }
+    }
+
+    fn depth(&self) -> usize {
+        match self {
+            Matcher::Directory(pattern) => path::Path::new(pattern.as_str()).components().count(),
+            _ => 0,
+        }
+    }
+
+    fn matches(&self, path: &path::Path, url_path: &str) -> bool {
+        let options = glob::MatchOptions { require_literal_separator: true, ..glob::MatchOptions::new() };
+        let file_name = path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+        match self {
+            Matcher::Directory(pattern) => path.ancestors().any(|directory| pattern.matches_path_with(directory, options)),
+            Matcher::DirectoryMatch(regex) => path.ancestors().any(|directory| regex.is_match(&directory.to_string_lossy())),
+            Matcher::Files(pattern) => pattern.matches_with(&file_name, options),
+            Matcher::FilesMatch(regex) => regex.is_match(&file_name),
+            Matcher::Location(pattern) => url_path.match_indices('/')
+                .flat_map(|(i, _)| vec!(&url_path[..i], &url_path[..=i]))
+                .chain(std::iter::once(url_path))
+                .any(|prefix| pattern.matches_with(prefix, options)),
+            Matcher::LocationMatch(regex) => regex.is_match(url_path),
+        }
+    }
+}
+
+impl Setting {
+    fn apply(&self, config: &mut DirectoryConfig) {
+        match self {
+            Setting::Options { replace, enable, disable } => {
+                let options = if *replace { Options::default() } else { config.options };
+                config.options = Options {
+                    exec_cgi: (options.exec_cgi || enable.exec_cgi) && !disable.exec_cgi,
+                    includes: (options.includes || enable.includes) && !disable.includes,
+                    indexes: (options.indexes || enable.indexes) && !disable.indexes,
+                };
+            },
+            Setting::AddOutputFilter(filters, extensions) => {
+                for extension in extensions {
+                    config.output_filters.insert(extension.clone(), filters.clone());
+                }
+            },
+            Setting::AddHandler(handler, extensions) => {
+                for extension in extensions {
+                    config.handlers.insert(extension.clone(), handler.clone());
+                }
+            },
+            Setting::SetHandler(handler) => config.handler = handler.clone(),
+            Setting::DirectoryIndex(files) => config.directory_index = files.clone(),
+            Setting::Require(granted) => config.access_granted = *granted,
+            Setting::Header(action) => config.headers.push(action.clone()),
+            Setting::LimitRequestBody(limit) => config.limit_request_body = *limit,
+            Setting::AcceptPathInfo(accept) => config.accept_path_info = *accept,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// The virtual hosts that may serve connections accepted on `address`, in the order they were declared. As in
+    /// Apache, virtual hosts declared for the exact IP address take precedence over those declared for `*`; when
+    /// none is declared for the address, the main server serves it alone.
+    pub fn virtual_hosts_for(&self, address: SocketAddr) -> Vec<&VirtualHost> {
+        let declared_for = |exact: bool| -> Vec<&VirtualHost> {
+            self.virtual_hosts.iter()
+                .filter(|virtual_host| (virtual_host.addresses.is_empty() && !exact)
+                    || virtual_host.addresses.iter().any(|spec| spec.ip.is_some() == exact && spec.matches(address)))
+                .collect()
+        };
+        let exact = declared_for(true);
+        if !exact.is_empty() {
+            return exact;
+        }
+        let wildcard = declared_for(false);
+        if !wildcard.is_empty() {
+            return wildcard;
+        }
+        vec!(&self.main_server)
+    }
+
+    /// Describes which virtual host serves each listener, in the style of `httpd -S`. The first virtual host for a
+    /// listener is the default, serving requests whose `Host` matches no `ServerName` or `ServerAlias`.
+    pub fn virtual_host_summary(&self) -> String {
+        let mut summary = "VirtualHost configuration:\n".to_string();
+        for address in self.listen.iter() {
+            summary.push_str(&format!("{}\n", address));
+            for (i, virtual_host) in self.virtual_hosts_for(*address).into_iter().enumerate() {
+                let role = if i == 0 { "default server".to_string() } else { format!("port {} namevhost", address.port()) };
+                summary.push_str(&format!(
+                    "    {} {} ({})\n",
+                    role,
+                    virtual_host.server_name.as_deref().unwrap_or("(no ServerName)"),
+                    virtual_host.defined_at.as_deref().unwrap_or("main server"),
+                ));
+                for alias in virtual_host.server_aliases.iter() {
+                    summary.push_str(&format!("        alias {}\n", alias));
+                }
+                if let Some(virtual_document_root) = &virtual_host.virtual_document_root {
+                    summary.push_str(&format!("        VirtualDocumentRoot {}\n", virtual_document_root));
+                }
+                summary.push_str(&format!("        DocumentRoot {}\n", virtual_host.document_root.as_deref().unwrap_or("(none)")));
+            }
+        }
+        summary
+    }
+}
+
+/// Writes the configuration back out as directives, after inheritance has been applied to every virtual host.
+impl fmt::Display for ServerConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for address in self.listen.iter() {
+            writeln!(f, "Listen {}", address)?;
+        }
+        writeln!(f, "ThreadPoolSize {}", self.thread_pool_size)?;
+        writeln!(f, "CacheSize {}K", self.cache.size / 1024)?;
+        writeln!(f, "CacheMaxFileSize {}K", self.cache.max_file_size / 1024)?;
+        writeln!(f, "CacheRevalidateInterval {}", self.cache.revalidate_interval.as_secs())?;
+        writeln!(f, "CacheWatch {}", if self.cache.watch { "On" } else { "Off" })?;
+        for virtual_host in self.virtual_hosts.iter() {
+            writeln!(f)?;
+            if let Some(defined_at) = &virtual_host.defined_at {
+                writeln!(f, "# {}", defined_at)?;
+            }
+            let addresses: Vec<String> = virtual_host.addresses.iter().map(|address| address.to_string()).collect();
+            writeln!(f, "<VirtualHost {}>", if addresses.is_empty() { "*".to_string() } else { addresses.join(" ") })?;
+            write!(f, "{}", virtual_host)?;
+            writeln!(f, "</VirtualHost>")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for VirtualHost {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(server_name) = &self.server_name {
+            writeln!(f, "    ServerName {}", quote(server_name))?;
+        }
+        if !self.server_aliases.is_empty() {
+            writeln!(f, "    ServerAlias {}", self.server_aliases.iter().map(|alias| quote(alias.as_str())).collect::<Vec<_>>().join(" "))?;
+        }
+        if let Some(document_root) = &self.document_root {
+            writeln!(f, "    DocumentRoot {}", quote(document_root))?;
+        }
+        if let Some(virtual_document_root) = &self.virtual_document_root {
+            writeln!(f, "    VirtualDocumentRoot {}", quote(&virtual_document_root.to_string()))?;
+        }
+        if !self.cache_warm.is_empty() {
+            writeln!(f, "    CacheWarm {}", self.cache_warm.iter().map(|pattern| quote(pattern)).collect::<Vec<_>>().join(" "))?;
+        }
+        writeln!(f, "    CGITimeout {}", self.cgi_limits.timeout.map(|timeout| timeout.as_secs()).unwrap_or(0))?;
+        let limits = [("RLimitCPU", self.cgi_limits.cpu), ("RLimitMEM", self.cgi_limits.memory), ("RLimitNPROC", self.cgi_limits.processes)];
+        for (name, limit) in limits.iter() {
+            if let Some(ResourceLimit { soft, hard }) = limit {
+                let value = |limit: Option<u64>| limit.map(|limit| limit.to_string()).unwrap_or_else(|| "max".to_string());
+                writeln!(f, "    {} {}{}", name, value(*soft), hard.map(|hard| format!(" {}", hard)).unwrap_or_default())?;
+            }
+        }
+        if let Some(script_log) = &self.script_log {
+            writeln!(f, "    ScriptLog {}", quote(script_log))?;
+        }
+        for proxy_pass in self.proxy_passes.iter() {
+            writeln!(f, "    ProxyPass {} {}", quote(&proxy_pass.path), quote(&proxy_pass.url()))?;
+        }
+        for script_alias in self.script_aliases.iter() {
+            writeln!(f, "    ScriptAlias {} {}", quote(&script_alias.path), quote(&script_alias.directory.to_string_lossy()))?;
+        }
+        write!(f, "{}", self.directory)?;
+        for section in self.sections.iter() {
+            let (name, argument) = match &section.matcher {
+                Matcher::Directory(pattern) => ("Directory", pattern.as_str()),
+                Matcher::DirectoryMatch(regex) => ("DirectoryMatch", regex.as_str()),
+                Matcher::Files(pattern) => ("Files", pattern.as_str()),
+                Matcher::FilesMatch(regex) => ("FilesMatch", regex.as_str()),
+                Matcher::Location(pattern) => ("Location", pattern.as_str()),
+                Matcher::LocationMatch(regex) => ("LocationMatch", regex.as_str()),
+            };
+            writeln!(f, "    <{} {}>", name, quote(argument))?;
+            for setting in section.settings.iter() {
+                writeln!(f, "        {}", setting)?;
+            }
+            writeln!(f, "    </{}>", name)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for DirectoryConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let settings = vec!(
+            Setting::Options { replace: true, enable: self.options, disable: Options::default() },
+            Setting::DirectoryIndex(self.directory_index.clone()),
+            Setting::Require(self.access_granted),
+            Setting::LimitRequestBody(self.limit_request_body),
+            Setting::AcceptPathInfo(self.accept_path_info),
+            Setting::SetHandler(self.handler.clone()),
+        );
+        let mut extensions: Vec<&String> = self.output_filters.keys().collect();
+        extensions.sort();
+        let filters = extensions.into_iter()
+            .map(|extension| Setting::AddOutputFilter(self.output_filters[extension].clone(), vec!(extension.clone())));
+        let mut extensions: Vec<&String> = self.handlers.keys().collect();
+        extensions.sort();
+        let handlers = extensions.into_iter()
+            .map(|extension| Setting::AddHandler(self.handlers[extension].clone(), vec!(extension.clone())));
+        let headers = self.headers.iter().cloned().map(Setting::Header);
+        for setting in settings.into_iter().chain(filters).chain(handlers).chain(headers) {
+            writeln!(f, "    {}", setting)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Setting {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Setting::Options { replace, enable, disable } => {
+                let mut options = Vec::new();
+                let prefix = |enabled: bool| if *replace { "" } else if enabled { "+" } else { "-" };
+                let options_by_name = [
+                    ("ExecCGI", enable.exec_cgi, disable.exec_cgi),
+                    ("Includes", enable.includes, disable.includes),
+                    ("Indexes", enable.indexes, disable.indexes),
+                ];
+                for (name, enabled, disabled) in options_by_name {
+                    if enabled || disabled {
+                        options.push(format!("{}{}", prefix(enabled), name));
+                    }
+                }
+                if options.is_empty() {
+                    options.push("None".to_string());
+                }
+                write!(f, "Options {}", options.join(" "))
+            },
+            Setting::AddOutputFilter(filters, extensions) => write!(f, "AddOutputFilter {} {}", filters.join(";"), extensions.iter().map(|extension| format!(".{}", extension)).collect::<Vec<_>>().join(" ")),
+            Setting::AddHandler(handler, extensions) => write!(f, "AddHandler {} {}", handler, extensions.iter().map(|extension| format!(".{}", extension)).collect::<Vec<_>>().join(" ")),
+            Setting::SetHandler(handler) => write!(f, "SetHandler {}", handler.as_deref().unwrap_or("None")),
+            Setting::DirectoryIndex(files) if files.is_empty() => write!(f, "DirectoryIndex disabled"),
+            Setting::DirectoryIndex(files) => write!(f, "DirectoryIndex {}", files.iter().map(|file| quote(file)).collect::<Vec<_>>().join(" ")),
+            Setting::Require(granted) => write!(f, "Require all {}", if *granted { "granted" } else { "denied" }),
+            Setting::Header(HeaderAction::Set(field, value)) => write!(f, "Header set {} {}", field, quote(value)),
+            Setting::Header(HeaderAction::Append(field, value)) => write!(f, "Header append {} {}", field, quote(value)),
+            Setting::Header(HeaderAction::Unset(field)) => write!(f, "Header unset {}", field),
+            Setting::LimitRequestBody(limit) => write!(f, "LimitRequestBody {}", limit.unwrap_or(0)),
+            Setting::AcceptPathInfo(accept) => write!(f, "AcceptPathInfo {}", match accept {
+                Some(true) => "On",
+                Some(false) => "Off",
+                None => "Default",
+            }),
+        }
+    }
+}
+
+/// Quotes an argument if it would not otherwise read back as a single token.
+fn quote(argument: &str) -> String {
+    if !argument.is_empty() && !argument.contains(|c: char| c.is_whitespace() || c == '"' || c == '\'' || c == '#' || c == '\\') {
+        return argument.to_string();
+    }
+    format!("\"{}\"", argument.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Loads a configuration file. `defines` are the parameters, given with `-D` on the command line, that enable the
+/// matching `<IfDefine>` sections.
+pub fn load_config(config_path: &path::Path, defines: &[String]) -> Result<ServerConfig, Error> {
     let s = fs::read_to_string(config_path)?;
 
-    parse_server_config(&s)
+    parse_server_config(&s, &config_path.display().to_string(), defines)
+}
+
+/// Parses a configuration file. Relative paths within it, including those of included files, are resolved against
+/// the directory of the file they appear in.
+fn parse_server_config(s: &str, source_name: &str, defines: &[String]) -> Result<ServerConfig, Error> {
+    let mut sources = Vec::new();
+    let mut including = vec!(path::Path::new(source_name).canonicalize().unwrap_or_else(|_| source_name.into()));
+    let lines = load_source(source_name.to_string(), s.to_string(), None, &mut sources, &mut including)
+        .map_err(|e| render_error(&sources, e))?;
+    let block = parse_block(&lines, &mut 0, None, defines).map_err(|e| render_error(&sources, e))?;
+    build_server_config(&block, &sources).map_err(|e| render_error(&sources, e))?
+        .ok_or_else(|| Error::new(format!("{}: no Listen directive", source_name)))
+}
+
+/// A configuration file, recorded so that errors can quote it.
+struct SourceFile {
+    name: String,
+    text: String,
+    /// The `Include` directive that brought this file in, if it is not the main configuration file.
+    included_from: Option<Location>,
+}
+
+/// Lexes a file and splices the lines of any files it includes in place of its `Include` and `IncludeOptional`
+/// directives. `including` holds the canonical paths of the files currently being included, to detect cycles.
+fn load_source(name: String, text: String, included_from: Option<Location>, sources: &mut Vec<SourceFile>, including: &mut Vec<path::PathBuf>) -> Result<Vec<Vec<Token>>, SyntaxError> {
+    let source = sources.len();
+    let directory = path::Path::new(&name).parent().map(|directory| directory.to_path_buf()).unwrap_or_default();
+    sources.push(SourceFile { name, text, included_from });
+    let mut lines = Vec::new();
+    for line in lex(&sources[source].text, source)? {
+        let optional = line[0].text.eq_ignore_ascii_case("IncludeOptional");
+        if !optional && !line[0].text.eq_ignore_ascii_case("Include") {
+            lines.push(line);
+            continue;
+        }
+        let arg = match line.as_slice() {
+            [_, arg] => arg,
+            _ => return Err(line[0].location.error(format!("{} takes one argument", line[0].text))),
+        };
+        for path in expand_include(&directory, arg, optional)? {
+            let canonical = path.canonicalize().unwrap_or_else(|_| path.clone());
+            if including.contains(&canonical) {
+                let chain: Vec<String> = including.iter().chain(std::iter::once(&canonical)).map(|path| path.display().to_string()).collect();
+                return Err(arg.location.error(format!("include cycle: {}", chain.join(" -> "))));
+            }
+            let text = fs::read_to_string(&path)
+                .map_err(|e| arg.location.error(format!("cannot read `{}`: {}", path.display(), e)))?;
+            including.push(canonical);
+            lines.extend(load_source(path.display().to_string(), text, Some(line[0].location), sources, including)?);
+            including.pop();
+        }
+    }
+    Ok(lines)
 }
 
-fn parse_server_config(mut s: &str) -> Result<ServerConfig, Error> {
-    let mut directives = HashMap::new();
+/// Finds the files named by an `Include`, relative to the directory of the including file. A directory includes every
+/// file within it and a glob includes every file it matches, in sorted order so that the result does not depend on
+/// the file system. Only `IncludeOptional` tolerates a path or glob that matches nothing.
+fn expand_include(directory: &path::Path, arg: &Token, optional: bool) -> Result<Vec<path::PathBuf>, SyntaxError> {
+    let pattern = directory.join(&arg.text);
+    let mut paths: Vec<path::PathBuf> = if arg.text.contains(['*', '?', '[']) {
+        glob::glob(&pattern.to_string_lossy())
+            .map_err(|e| arg.location.error(format!("invalid pattern `{}`: {}", arg.text, e)))?
+            .filter_map(|path| path.ok())
+            .filter(|path| path.is_file())
+            .collect()
+    } else if pattern.is_dir() {
+        fs::read_dir(&pattern)
+            .map_err(|e| arg.location.error(format!("cannot read `{}`: {}", pattern.display(), e)))?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.is_file())
+            .collect()
+    } else if pattern.exists() || !optional {
+        vec!(pattern)
+    } else {
+        Vec::new()
+    };
+    if paths.is_empty() && !optional {
+        return Err(arg.location.error(format!("no files match `{}`", arg.text)));
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// Resolves a path from the configuration against the directory of the file it appears in.
+fn resolve_path(sources: &[SourceFile], location: Location, value: &str) -> path::PathBuf {
+    path::Path::new(&sources[location.source].name)
+        .parent()
+        .unwrap_or_else(|| path::Path::new(""))
+        .join(value)
+}
+
+/// Validates the parsed file and converts it into the typed model. Returns `None` if there is no `Listen` directive,
+/// which is the one directive without a default.
+fn build_server_config(block: &Block, sources: &[SourceFile]) -> Result<Option<ServerConfig>, SyntaxError> {
+    let mut listen = vec!();
+    let mut thread_pool_size = DEFAULT_THREAD_POOL_SIZE;
+    let mut cache_size = DEFAULT_CACHE_SIZE;
+    let mut max_file_size = None;
+    let mut revalidate_interval = DEFAULT_REVALIDATE_INTERVAL;
+    let mut watch = false;
+    let mut main_server = VirtualHost::default();
+    for node in block.directives.iter() {
+        match node.directive {
+            Directive::Listen => {
+                let arg = node.single_arg()?;
+                let address = parse_listen(&arg.text).map_err(|message| arg.location.error(message))?;
+                if listen.contains(&address) {
+                    return Err(arg.location.error(format!("already listening on {}", address)));
+                }
+                listen.push(address);
+            },
+            Directive::ThreadPoolSize => {
+                let arg = node.single_arg()?;
+                thread_pool_size = usize::from_str(&arg.text).ok().filter(|size| *size != 0)
+                    .ok_or_else(|| arg.location.error(format!("expected a positive number of threads, found `{}`", arg.text)))?;
+            },
+            Directive::CacheSize => cache_size = parse_size(node.single_arg()?)?,
+            Directive::CacheMaxFileSize => max_file_size = Some(parse_size(node.single_arg()?)?),
+            Directive::CacheRevalidateInterval => {
+                let arg = node.single_arg()?;
+                revalidate_interval = u64::from_str(&arg.text).map(time::Duration::from_secs)
+                    .map_err(|_| arg.location.error(format!("expected a number of seconds, found `{}`", arg.text)))?;
+            },
+            Directive::CacheWatch => watch = parse_flag(node.single_arg()?)?,
+            Directive::ServerAlias => return Err(node.location.error(format!("{} is only allowed inside <VirtualHost>", node.name))),
+            _ => apply_host_directive(&mut main_server, node, sources)?,
+        }
+    }
+
+    let is_virtual_host = |section: &&Section| section.name.eq_ignore_ascii_case("VirtualHost");
+    for section in block.sections.iter().filter(|section| !is_virtual_host(section)) {
+        main_server.sections.push(build_path_section(section, sources, &mut main_server.proxy_passes)?);
+    }
+
     let mut virtual_hosts = vec!();
-    while !s.is_empty() {
-        if let Ok(rest) = discard_char('\n', s) {
-            s = rest;
-        } else if let Ok(((directive_field, directive_value), rest)) = parse_directive(s) {
-            directives.insert(directive_field, directive_value);
-            s = rest;
-        } else if let Ok((virtual_host, rest)) = parse_virtual_host(s) {
-            virtual_hosts.push(virtual_host);
-            s = rest;
-        } else {
-            return Err(Error::new("Could not parse file content into Apache-style configuration file".to_string()))
+    for section in block.sections.iter().filter(is_virtual_host) {
+        let mut virtual_host = main_server.clone();
+        if section.args.is_empty() {
+            return Err(section.location.error(format!("<{}> requires at least one address", section.name)));
+        }
+        virtual_host.addresses = section.args.iter()
+            .map(|address| VirtualHostAddress::from_str(address).map_err(|message| section.location.error(message)))
+            .collect::<Result<_, _>>()?;
+        virtual_host.defined_at = Some(format!("{}:{}", sources[section.location.source].name, section.location.line));
+        for node in section.block.directives.iter() {
+            check_context(node, Context::VirtualHost, &section.name)?;
+            apply_host_directive(&mut virtual_host, node, sources)?;
+        }
+        for nested in section.block.sections.iter() {
+            if is_virtual_host(&nested) {
+                return Err(nested.location.error(format!("<{}> is not allowed inside <{}>", nested.name, section.name)));
+            }
+            virtual_host.sections.push(build_path_section(nested, sources, &mut virtual_host.proxy_passes)?);
+        }
+        virtual_hosts.push(virtual_host);
+    }
+    if virtual_hosts.is_empty() {
+        virtual_hosts.push(main_server.clone());
+    }
+
+    if listen.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(ServerConfig {
+        listen,
+        thread_pool_size,
+        cache: CacheConfig {
+            size: cache_size,
+            max_file_size: max_file_size.unwrap_or(cache_size),
+            revalidate_interval,
+            watch,
+        },
+        virtual_hosts,
+        main_server,
+    }))
+}
+
+fn check_context(node: &DirectiveNode, context: Context, section: &str) -> Result<(), SyntaxError> {
+    if node.directive.allowed_in(context) {
+        Ok(())
+    } else {
+        Err(node.location.error(format!("{} is not allowed inside <{}>", node.name, section)))
+    }
+}
+
+fn apply_host_directive(virtual_host: &mut VirtualHost, node: &DirectiveNode, sources: &[SourceFile]) -> Result<(), SyntaxError> {
+    match node.directive {
+        Directive::ServerName => virtual_host.server_name = Some(node.single_arg()?.text.clone()),
+        Directive::ServerAlias => {
+            if node.args.is_empty() {
+                return Err(node.location.error(format!("{} requires at least one argument", node.name)));
+            }
+            for arg in node.args.iter() {
+                let alias = glob::Pattern::new(&arg.text)
+                    .map_err(|e| arg.location.error(format!("invalid pattern `{}`: {}", arg.text, e.msg)))?;
+                virtual_host.server_aliases.push(alias);
+            }
+        },
+        Directive::DocumentRoot => {
+            virtual_host.document_root = Some(check_document_root(node.single_arg()?, sources)?);
+        },
+        Directive::VirtualDocumentRoot => {
+            let arg = node.single_arg()?;
+            let template = match arg.text.strip_prefix(vfs::ARCHIVE_PREFIX) {
+                Some(archive) => format!("{}{}", vfs::ARCHIVE_PREFIX, resolve_path(sources, arg.location, archive).display()),
+                None => resolve_path(sources, arg.location, &arg.text).display().to_string(),
+            };
+            virtual_host.virtual_document_root = Some(vhost_alias::Template::from_str(&template).map_err(|message| arg.location.error(message))?);
+        },
+        Directive::CacheWarm => {
+            if node.args.is_empty() {
+                return Err(node.location.error(format!("{} requires at least one argument", node.name)));
+            }
+            virtual_host.cache_warm = node.args.iter().map(|arg| arg.text.clone()).collect();
+        },
+        Directive::CGITimeout => {
+            let arg = node.single_arg()?;
+            let seconds = u64::from_str(&arg.text)
+                .map_err(|_| arg.location.error(format!("expected a number of seconds, found `{}`", arg.text)))?;
+            // zero means unlimited, as with LimitRequestBody
+            virtual_host.cgi_limits.timeout = Some(time::Duration::from_secs(seconds)).filter(|_| seconds != 0);
+        },
+        Directive::RLimitCPU => virtual_host.cgi_limits.cpu = Some(parse_resource_limit(node)?),
+        Directive::RLimitMEM => virtual_host.cgi_limits.memory = Some(parse_resource_limit(node)?),
+        Directive::RLimitNPROC => virtual_host.cgi_limits.processes = Some(parse_resource_limit(node)?),
+        Directive::ScriptLog => {
+            let arg = node.single_arg()?;
+            virtual_host.script_log = Some(resolve_path(sources, arg.location, &arg.text).display().to_string());
+        },
+        Directive::ProxyPass => {
+            let (path, url) = match node.args.as_slice() {
+                [path, url] => (path, url),
+                _ => return Err(node.location.error(format!("{} takes a URL path and the URL of an application", node.name))),
+            };
+            if !path.text.starts_with('/') {
+                return Err(path.location.error(format!("expected a URL path starting with `/`, found `{}`", path.text)));
+            }
+            virtual_host.proxy_passes.push(parse_proxy_pass(&path.text, url, sources)?);
+        },
+        Directive::ScriptAlias => {
+            let (path, directory) = match node.args.as_slice() {
+                [path, directory] => (path, directory),
+                _ => return Err(node.location.error(format!("{} takes a URL path and a directory", node.name))),
+            };
+            if !path.text.starts_with('/') {
+                return Err(path.location.error(format!("expected a URL path starting with `/`, found `{}`", path.text)));
+            }
+            let resolved = resolve_path(sources, directory.location, &directory.text);
+            // compare against the canonical paths that requests resolve to
+            let directory = match resolved.canonicalize() {
+                Ok(canonical) if canonical.is_dir() => canonical,
+                Ok(_) => return Err(directory.location.error(format!("script directory `{}` is not a directory", resolved.display()))),
+                Err(e) => return Err(directory.location.error(format!("cannot open script directory `{}`: {}", resolved.display(), e))),
+            };
+            virtual_host.script_aliases.push(ScriptAlias { path: path.text.clone(), directory });
+        },
+        _ => parse_setting(node)?.apply(&mut virtual_host.directory),
+    }
+    Ok(())
+}
+
+/// Builds a path section from its directives. A `ProxyPass` inside a `<Location>` takes its path from the section and
+/// is added to `proxy_passes`, since it decides where a request goes before any file is looked for.
+fn build_path_section(section: &Section, sources: &[SourceFile], proxy_passes: &mut Vec<ProxyPass>) -> Result<PathSection, SyntaxError> {
+    if let Some(nested) = section.block.sections.first() {
+        return Err(nested.location.error(format!("<{}> is not allowed inside <{}>", nested.name, section.name)));
+    }
+    let name = SECTIONS.iter().find(|name| name.eq_ignore_ascii_case(&section.name)).unwrap();
+    let (regex, argument) = match section.args.as_slice() {
+        [tilde, regex] if tilde == "~" => (true, regex),
+        [argument] => (name.ends_with("Match"), argument),
+        _ => return Err(section.location.error(format!("<{}> takes one argument", name))),
+    };
+    let compile_regex = || Regex::new(argument)
+        .map_err(|e| section.location.error(format!("invalid regular expression `{}`: {}", argument, e)));
+    let compile_glob = |pattern: &str| glob::Pattern::new(pattern)
+        .map_err(|e| section.location.error(format!("invalid pattern `{}`: {}", pattern, e)));
+    let matcher = match (name.trim_end_matches("Match"), regex) {
+        ("Directory", true) => Matcher::DirectoryMatch(compile_regex()?),
+        ("Directory", false) => {
+            // compare against the canonical paths that requests resolve to
+            let directory = resolve_path(sources, section.location, argument);
+            let directory = directory.canonicalize()
+                .map(|directory| directory.to_string_lossy().to_string())
+                .unwrap_or_else(|_| directory.to_string_lossy().trim_end_matches('/').to_string());
+            Matcher::Directory(compile_glob(if directory.is_empty() { "/" } else { &directory })?)
+        },
+        ("Files", true) => Matcher::FilesMatch(compile_regex()?),
+        ("Files", false) => Matcher::Files(compile_glob(argument)?),
+        ("Location", true) => Matcher::LocationMatch(compile_regex()?),
+        ("Location", false) => Matcher::Location(compile_glob(argument)?),
+        _ => unreachable!("<{}> is not a path section", name),
+    };
+    let mut settings = Vec::new();
+    for node in section.block.directives.iter() {
+        check_context(node, Context::Path, name)?;
+        if node.directive == Directive::ProxyPass {
+            if !matches!(matcher, Matcher::Location(_)) {
+                return Err(node.location.error(format!("{} is not allowed inside <{}>; use <Location>", node.name, section.name)));
+            }
+            proxy_passes.push(parse_proxy_pass(argument, node.single_arg()?, sources)?);
+            continue;
         }
+        settings.push(parse_setting(node)?);
     }
-    Ok(ServerConfig { directives, virtual_hosts })
+    Ok(PathSection { matcher, settings })
 }
 
-fn parse_directive(s: &str) -> Result<((Directive, String), &str), ()> {
-    let (line, rest) = s.split_once('\n').ok_or(())?;
-    let (field, value) = line.trim().split_once(' ').ok_or(())?;
-    let directive = Directive::from_str(field)?;
-    Ok(((directive, value.to_string()), rest))
+fn parse_setting(node: &DirectiveNode) -> Result<Setting, SyntaxError> {
+    match node.directive {
+        Directive::Options => parse_options(node),
+        Directive::AddOutputFilter => {
+            let (filters, extensions) = node.args.split_first()
+                .filter(|(_, extensions)| !extensions.is_empty())
+                .ok_or_else(|| node.location.error(format!("{} takes a filter followed by one or more extensions", node.name)))?;
+            let filters: Vec<String> = filters.text.split(';').map(|filter| filter.to_uppercase()).collect();
+            if let Some(unknown) = filters.iter().find(|filter| !OUTPUT_FILTERS.contains(&filter.as_str())) {
+                return Err(node.args[0].location.error(format!("unknown output filter `{}`", unknown)));
+            }
+            let extensions = extensions.iter().map(|extension| extension.text.trim_start_matches('.').to_lowercase()).collect();
+            Ok(Setting::AddOutputFilter(filters, extensions))
+        },
+        Directive::AddHandler => {
+            let (handler, extensions) = node.args.split_first()
+                .filter(|(_, extensions)| !extensions.is_empty())
+                .ok_or_else(|| node.location.error(format!("{} takes a handler followed by one or more extensions", node.name)))?;
+            let extensions = extensions.iter().map(|extension| extension.text.trim_start_matches('.').to_lowercase()).collect();
+            Ok(Setting::AddHandler(parse_handler(handler)?, extensions))
+        },
+        Directive::SetHandler => {
+            let arg = node.single_arg()?;
+            if arg.text.eq_ignore_ascii_case("None") {
+                Ok(Setting::SetHandler(None))
+            } else {
+                Ok(Setting::SetHandler(Some(parse_handler(arg)?)))
+            }
+        },
+        Directive::DirectoryIndex => match node.args.as_slice() {
+            [] => Err(node.location.error(format!("{} requires at least one argument", node.name))),
+            [disabled] if disabled.text.eq_ignore_ascii_case("disabled") => Ok(Setting::DirectoryIndex(Vec::new())),
+            args => Ok(Setting::DirectoryIndex(args.iter().map(|arg| arg.text.clone()).collect())),
+        },
+        Directive::Require => match node.args.as_slice() {
+            [all, granted] if all.text.eq_ignore_ascii_case("all") && granted.text.eq_ignore_ascii_case("granted") => Ok(Setting::Require(true)),
+            [all, denied] if all.text.eq_ignore_ascii_case("all") && denied.text.eq_ignore_ascii_case("denied") => Ok(Setting::Require(false)),
+            _ => Err(node.location.error(format!("{} supports only `all granted` and `all denied`", node.name))),
+        },
+        Directive::Header => {
+            let action = match node.args.as_slice() {
+                [action, name, value] if action.text.eq_ignore_ascii_case("set") => HeaderAction::Set(ResponseHeaderField::from_name(&name.text), value.text.clone()),
+                [action, name, value] if action.text.eq_ignore_ascii_case("append") => HeaderAction::Append(ResponseHeaderField::from_name(&name.text), value.text.clone()),
+                [action, name] if action.text.eq_ignore_ascii_case("unset") => HeaderAction::Unset(ResponseHeaderField::from_name(&name.text)),
+                _ => return Err(node.location.error(format!("expected `{0} set|append <header> <value>` or `{0} unset <header>`", node.name))),
+            };
+            Ok(Setting::Header(action))
+        },
+        Directive::LimitRequestBody => {
+            let arg = node.single_arg()?;
+            let limit = usize::from_str(&arg.text)
+                .map_err(|_| arg.location.error(format!("expected a number of bytes, found `{}`", arg.text)))?;
+            // as in Apache, zero means unlimited
+            Ok(Setting::LimitRequestBody(Some(limit).filter(|limit| *limit != 0)))
+        },
+        Directive::AcceptPathInfo => {
+            let arg = node.single_arg()?;
+            if arg.text.eq_ignore_ascii_case("Default") {
+                Ok(Setting::AcceptPathInfo(None))
+            } else {
+                parse_flag(arg)
+                    .map_err(|_| arg.location.error(format!("expected `On`, `Off` or `Default`, found `{}`", arg.text)))
+                    .map(|accept| Setting::AcceptPathInfo(Some(accept)))
+            }
+        },
+        _ => unreachable!("{} is not allowed in a path section", node.name),
+    }
 }
 
-fn parse_virtual_host(s: &str) -> Result<(VirtualHost, &str), ()> {
-    let block_name = "VirtualHost";
-    let (_, mut s) = parse_open_block(block_name, s)?;
-    let mut directives = HashMap::new();
-    loop {
-        if let Ok(((directive_field, directive_value), rest)) = parse_directive(s) {
-            directives.insert(directive_field, directive_value);
-            s = rest;
-        } else {
-            break;
+/// Parses an `Options` directive. As in Apache, if every option is prefixed with `+` or `-` they adjust the inherited
+/// options; otherwise the listed options replace them.
+fn parse_options(node: &DirectiveNode) -> Result<Setting, SyntaxError> {
+    if node.args.is_empty() {
+        return Err(node.location.error(format!("{} requires at least one argument", node.name)));
+    }
+    let relative = node.args.iter().all(|arg| arg.text.starts_with('+') || arg.text.starts_with('-'));
+    if !relative && node.args.iter().any(|arg| arg.text.starts_with('+') || arg.text.starts_with('-')) {
+        return Err(node.location.error("options with and without `+`/`-` cannot be mixed".to_string()));
+    }
+    let mut enable = Options::default();
+    let mut disable = Options::default();
+    for arg in node.args.iter() {
+        let (options, name) = match arg.text.strip_prefix('-') {
+            Some(name) => (&mut disable, name),
+            None => (&mut enable, arg.text.trim_start_matches('+')),
+        };
+        if name.eq_ignore_ascii_case("None") && !relative {
+            continue;
+        } else if name.eq_ignore_ascii_case("All") {
+            options.exec_cgi = true;
+            options.includes = true;
+            options.indexes = true;
+        } else if name.eq_ignore_ascii_case("ExecCGI") {
+            options.exec_cgi = true;
+        } else if name.eq_ignore_ascii_case("Includes") {
+            options.includes = true;
+        } else if name.eq_ignore_ascii_case("Indexes") {
+            options.indexes = true;
+        } else if !OPTIONS.iter().any(|option| option.eq_ignore_ascii_case(name)) {
+            let mut message = format!("unknown option `{}`", name);
+            if let Some(suggestion) = suggest(name, OPTIONS.iter().copied()) {
+                message.push_str(&format!("; did you mean `{}`?", suggestion));
+            }
+            return Err(arg.location.error(message));
         }
     }
-    let (_, s) = parse_close_block(block_name, s)?;
-    Ok((VirtualHost { directives }, s))
+    Ok(Setting::Options { replace: !relative, enable, disable })
+}
+
+/// Parses the handler named by `AddHandler` or `SetHandler`. Handler names are case-insensitive.
+fn parse_handler(arg: &Token) -> Result<String, SyntaxError> {
+    HANDLERS.iter()
+        .find(|handler| handler.eq_ignore_ascii_case(&arg.text))
+        .map(|handler| handler.to_string())
+        .ok_or_else(|| {
+            let mut message = format!("unknown handler `{}`", arg.text);
+            if let Some(suggestion) = suggest(&arg.text, HANDLERS.iter().copied()) {
+                message.push_str(&format!("; did you mean `{}`?", suggestion));
+            }
+            arg.location.error(message)
+        })
 }
 
-fn parse_open_block<'a>(block_name: &str, s: &'a str) -> Result<((), &'a str), ()> {
-    let (s, rest) = s.split_once('\n').ok_or(())?;
-    let s = discard_char('<', s)?;
-    let s = discard_string(block_name, s)?;
-    if s.ends_with('>') {
-        Ok(((), rest))
+/// Parses the argument of `Listen`: a port, which listens on every IPv4 interface, or an address and port such as
+/// `127.0.0.1:8080` or `[::]:8080`.
+pub fn parse_listen(text: &str) -> Result<SocketAddr, String> {
+    if text.chars().all(|c| c.is_ascii_digit()) {
+        return u16::from_str(text).ok().filter(|port| *port != 0)
+            .map(|port| SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)))
+            .ok_or_else(|| format!("port must be between 1 and 65535, found `{}`", text));
+    }
+    SocketAddr::from_str(text).ok().filter(|address| address.port() != 0)
+        .ok_or_else(|| format!("expected a port or an address and port such as `[::]:8080`, found `{}`", text))
+}
+
+/// Removes the port from a host name such as `example.com:8080` or `[::1]:8080`, along with any trailing dot.
+fn strip_port(host: &str) -> &str {
+    let host = match host.rfind(':') {
+        Some(i) if !host[..i].contains(':') || host[..i].ends_with(']') => &host[..i],
+        _ => host,
+    };
+    host.trim_end_matches('.')
+}
+
+/// Parses a size in bytes. A bare number is in kilobytes; `K`, `M` and `G` suffixes give the unit explicitly.
+fn parse_size(arg: &Token) -> Result<usize, SyntaxError> {
+    let text = arg.text.as_str();
+    let (number, multiplier) = match text.char_indices().last() {
+        Some((i, 'K')) | Some((i, 'k')) => (&text[..i], 1 << 10),
+        Some((i, 'M')) | Some((i, 'm')) => (&text[..i], 1 << 20),
+        Some((i, 'G')) | Some((i, 'g')) => (&text[..i], 1 << 30),
+        _ => (text, 1 << 10),
+    };
+    usize::from_str(number).ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .ok_or_else(|| arg.location.error(format!("expected a size such as `512K` or `64M`, found `{}`", text)))
+}
+
+/// Parses a `ProxyPass` of `path` to the URL of an application: `fcgi://host:port/path`, `scgi://host:port/path` or
+/// `uwsgi://host:port/path`, or `unix:/path/to/socket|fcgi://localhost/path` and the like. A relative socket path is relative to the configuration
+/// file.
+fn parse_proxy_pass(path: &str, arg: &Token, sources: &[SourceFile]) -> Result<ProxyPass, SyntaxError> {
+    let error = || arg.location.error(format!(
+        "expected a URL such as `fcgi://127.0.0.1:9000/`, `scgi://127.0.0.1:4000/`, `uwsgi://127.0.0.1:3031/` or `unix:/path/to/socket|fcgi://localhost/`, found `{}`", arg.text));
+    let strip_scheme = |url: &str, scheme: &str| url.get(..scheme.len())
+        .filter(|prefix| prefix.eq_ignore_ascii_case(scheme))
+        .map(|_| url[scheme.len()..].to_string());
+    let (socket, url) = match strip_scheme(&arg.text, "unix:") {
+        Some(rest) => {
+            let (socket, url) = rest.split_once('|').ok_or_else(error)?;
+            (Some(socket.to_string()), url.to_string())
+        },
+        None => (None, arg.text.clone()),
+    };
+    let (protocol, rest) = Protocol::ALL.iter()
+        .find_map(|protocol| strip_scheme(&url, &format!("{}://", protocol.scheme())).map(|rest| (*protocol, rest)))
+        .ok_or_else(error)?;
+    let (authority, target) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest.as_str(), "/"),
+    };
+    let address = match socket {
+        Some(socket) if !socket.is_empty() => BackendAddress::Unix(resolve_path(sources, arg.location, &socket)),
+        Some(_) => return Err(error()),
+        None => match authority.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() && u16::from_str(port).map(|port| port != 0).unwrap_or(false) => BackendAddress::Tcp(authority.to_string()),
+            _ => return Err(error()),
+        },
+    };
+    Ok(ProxyPass { path: path.to_string(), protocol, address, target: target.to_string() })
+}
+
+/// Parses the soft and optional hard limit of an `RLimit*` directive, each a number or `max`.
+fn parse_resource_limit(node: &DirectiveNode) -> Result<ResourceLimit, SyntaxError> {
+    let parse = |arg: &Token| if arg.text.eq_ignore_ascii_case("max") {
+        Ok(None)
     } else {
-        Err(())
+        u64::from_str(&arg.text).map(Some)
+            .map_err(|_| arg.location.error(format!("expected a number or `max`, found `{}`", arg.text)))
+    };
+    let limit = match node.args.as_slice() {
+        [soft] => ResourceLimit { soft: parse(soft)?, hard: None },
+        [soft, hard] => ResourceLimit { soft: parse(soft)?, hard: parse(hard)? },
+        _ => return Err(node.location.error(format!("{} takes a soft limit and optionally a hard limit", node.name))),
+    };
+    if let ResourceLimit { soft: Some(soft), hard: Some(hard) } = limit {
+        if soft > hard {
+            return Err(node.location.error(format!("{} soft limit {} is above the hard limit {}", node.name, soft, hard)));
+        }
     }
+    Ok(limit)
 }
 
-fn parse_close_block<'a>(block_name: &str, s: &'a str) -> Result<((), &'a str), ()> {
-    let (s, rest) = s.split_once('\n').ok_or(())?;
-    let s = discard_char('<', s)?;
-    let s = discard_char('/', s)?;
-    let s = discard_string(block_name, s)?;
-    if s.ends_with('>') {
-        Ok(((), rest))
+fn parse_flag(arg: &Token) -> Result<bool, SyntaxError> {
+    if arg.text.eq_ignore_ascii_case("On") {
+        Ok(true)
+    } else if arg.text.eq_ignore_ascii_case("Off") {
+        Ok(false)
     } else {
-        Err(())
+        Err(arg.location.error(format!("expected `On` or `Off`, found `{}`", arg.text)))
+    }
+}
+
+/// Checks that a `DocumentRoot` exists, returning it with any relative path resolved.
+fn check_document_root(arg: &Token, sources: &[SourceFile]) -> Result<String, SyntaxError> {
+    match arg.text.strip_prefix(vfs::ARCHIVE_PREFIX) {
+        Some(archive) => {
+            let archive = resolve_path(sources, arg.location, archive);
+            match fs::metadata(&archive) {
+                Ok(metadata) if metadata.is_file() => Ok(format!("{}{}", vfs::ARCHIVE_PREFIX, archive.display())),
+                Ok(_) => Err(arg.location.error(format!("archive `{}` is not a file", archive.display()))),
+                Err(e) => Err(arg.location.error(format!("cannot open archive `{}`: {}", archive.display(), e))),
+            }
+        },
+        None => {
+            let directory = resolve_path(sources, arg.location, &arg.text);
+            match fs::metadata(&directory) {
+                Ok(metadata) if metadata.is_dir() => Ok(directory.display().to_string()),
+                Ok(_) => Err(arg.location.error(format!("document root `{}` is not a directory", directory.display()))),
+                Err(e) => Err(arg.location.error(format!("cannot open document root `{}`: {}", directory.display(), e))),
+            }
+        },
+    }
+}
+
+/// A position in the configuration source, counted from one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Location {
+    /// The index of the file among those loaded.
+    source: usize,
+    line: usize,
+    column: usize,
+}
+impl Location {
+    fn advance(&mut self, c: char) {
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+    }
+
+    fn error(self, message: String) -> SyntaxError {
+        SyntaxError { location: self, message }
+    }
+}
+
+#[derive(Debug)]
+struct SyntaxError {
+    location: Location,
+    message: String,
+}
+
+/// Renders a syntax error as `file:line:column: message` followed by the offending line and a caret under the column,
+/// in the style of a compiler diagnostic, and then the chain of `Include` directives that led to the file.
+fn render_error(sources: &[SourceFile], e: SyntaxError) -> Error {
+    let Location { source, line, column } = e.location;
+    let file = &sources[source];
+    let text = file.text.lines().nth(line - 1).unwrap_or("");
+    let padding: String = text.chars().take(column - 1).map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+    let mut message = format!("{}:{}:{}: {}\n{}\n{}^", file.name, line, column, e.message, text, padding);
+    let mut included_from = file.included_from;
+    while let Some(location) = included_from {
+        let including = &sources[location.source];
+        message.push_str(&format!("\n  included from {}:{}:{}", including.name, location.line, location.column));
+        included_from = including.included_from;
+    }
+    Error::new(message)
+}
+
+#[derive(Clone, Debug)]
+struct Token {
+    text: String,
+    location: Location,
+}
+
+/// Splits the source into logical lines of whitespace-separated tokens. Lines whose first token starts with `#` are
+/// comments, a backslash at the end of a line continues it onto the next, and arguments may be quoted with `"` or `'`
+/// to include whitespace (a backslash escapes the quote character inside).
+fn lex(s: &str, source: usize) -> Result<Vec<Vec<Token>>, SyntaxError> {
+    let mut lines = Vec::new();
+    let mut tokens: Vec<Token> = Vec::new();
+    let mut chars = s.chars().peekable();
+    let mut location = Location { source, line: 1, column: 1 };
+    let mut comment = false;
+    while let Some(c) = chars.next() {
+        let start = location;
+        location.advance(c);
+        match c {
+            '\n' => {
+                if !tokens.is_empty() {
+                    lines.push(std::mem::take(&mut tokens));
+                }
+                comment = false;
+            },
+            _ if comment => {},
+            '\\' if skip_continuation(&mut chars, &mut location) => {},
+            c if c.is_whitespace() => {},
+            '#' if tokens.is_empty() => comment = true,
+            '"' | '\'' => {
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some(end) if end == c => {
+                            location.advance(end);
+                            break;
+                        },
+                        Some('\\') if chars.peek() == Some(&c) || chars.peek() == Some(&'\\') => {
+                            location.advance('\\');
+                            let escaped = chars.next().unwrap();
+                            location.advance(escaped);
+                            text.push(escaped);
+                        },
+                        Some('\n') | None => return Err(start.error("unterminated quoted argument".to_string())),
+                        Some(c) => {
+                            location.advance(c);
+                            text.push(c);
+                        },
+                    }
+                }
+                tokens.push(Token { text, location: start });
+            },
+            c => {
+                let mut text = c.to_string();
+                // a backslash is literal inside a word unless it ends the line
+                while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                    location.advance(c);
+                    if c == '\\' && skip_continuation(&mut chars, &mut location) {
+                        break;
+                    }
+                    text.push(c);
+                }
+                tokens.push(Token { text, location: start });
+            },
+        }
+    }
+    if !tokens.is_empty() {
+        lines.push(tokens);
+    }
+    Ok(lines)
+}
+
+/// Called after a backslash: if only a line ending follows, consumes it and returns true.
+fn skip_continuation(chars: &mut Peekable<Chars>, location: &mut Location) -> bool {
+    if !chars.clone().take_while(|c| *c == '\r' || *c == '\n').any(|c| c == '\n') {
+        return false;
+    }
+    while let Some(c) = chars.next_if(|c| *c == '\r' || *c == '\n') {
+        location.advance(c);
+        if c == '\n' {
+            break;
+        }
+    }
+    true
+}
+
+struct Block {
+    directives: Vec<DirectiveNode>,
+    sections: Vec<Section>,
+}
+
+struct DirectiveNode {
+    name: String,
+    directive: Directive,
+    args: Vec<Token>,
+    location: Location,
+}
+impl DirectiveNode {
+    fn single_arg(&self) -> Result<&Token, SyntaxError> {
+        match self.args.as_slice() {
+            [arg] => Ok(arg),
+            [] => Err(self.location.error(format!("{} requires an argument", self.name))),
+            [_, extra, ..] => Err(extra.location.error(format!("{} takes one argument", self.name))),
+        }
+    }
+}
+
+struct Section {
+    name: String,
+    args: Vec<String>,
+    block: Block,
+    location: Location,
+}
+
+/// Parses lines into a block until the end of the input or, when inside a section, the matching close tag.
+fn parse_block(lines: &[Vec<Token>], index: &mut usize, open: Option<(&str, Location)>, defines: &[String]) -> Result<Block, SyntaxError> {
+    let mut block = Block { directives: Vec::new(), sections: Vec::new() };
+    while let Some(line) = lines.get(*index) {
+        *index += 1;
+        let first = &line[0];
+        if let Some(name) = first.text.strip_prefix("</") {
+            let name = name.trim_end_matches('>');
+            return match open {
+                Some((open_name, _)) if open_name.eq_ignore_ascii_case(name) => {
+                    if !line.last().unwrap().text.ends_with('>') {
+                        return Err(first.location.error(format!("</{}> is missing its closing '>'", name)));
+                    }
+                    Ok(block)
+                },
+                Some((open_name, _)) => Err(first.location.error(format!("expected </{}> but found </{}>", open_name, name))),
+                None => Err(first.location.error(format!("</{}> without matching <{}>", name, name))),
+            };
+        }
+        if let Some(name) = first.text.strip_prefix('<') {
+            let section = parse_section(name, line, lines, index, defines)?;
+            if section.name.eq_ignore_ascii_case("IfDefine") {
+                // the contents of an <IfDefine> belong to the enclosing block
+                block.directives.extend(section.block.directives);
+                block.sections.extend(section.block.sections);
+            } else {
+                block.sections.push(section);
+            }
+            continue;
+        }
+        let directive = Directive::from_str(&first.text).map_err(|_| {
+            let mut message = format!("unknown directive `{}`", first.text);
+            let names = DIRECTIVES.iter().map(|(name, _)| *name).chain(vec!("Include", "IncludeOptional"));
+            if let Some(suggestion) = suggest(&first.text, names) {
+                message.push_str(&format!("; did you mean `{}`?", suggestion));
+            }
+            first.location.error(message)
+        })?;
+        block.directives.push(DirectiveNode {
+            name: first.text.clone(),
+            directive,
+            args: line[1..].to_vec(),
+            location: first.location,
+        });
+    }
+    match open {
+        Some((name, location)) => Err(location.error(format!("<{}> is never closed", name))),
+        None => Ok(block),
+    }
+}
+
+fn parse_section(name: &str, line: &[Token], lines: &[Vec<Token>], index: &mut usize, defines: &[String]) -> Result<Section, SyntaxError> {
+    let first = &line[0];
+    let last = line.last().unwrap();
+    if !last.text.ends_with('>') {
+        return Err(first.location.error(format!("<{} is missing its closing '>'", name.trim_end_matches('>'))));
+    }
+    let mut args: Vec<String> = line[1..].iter().map(|token| token.text.clone()).collect();
+    let name = if line.len() == 1 { name.trim_end_matches('>') } else { name };
+    if let Some(last_arg) = args.last_mut() {
+        *last_arg = last_arg.trim_end_matches('>').to_string();
+        if last_arg.is_empty() {
+            args.pop();
+        }
+    }
+    if !SECTIONS.iter().any(|section| section.eq_ignore_ascii_case(name)) {
+        let mut message = format!("unknown section <{}>", name);
+        if let Some(suggestion) = suggest(name, SECTIONS.iter().copied()) {
+            message.push_str(&format!("; did you mean <{}>?", suggestion));
+        }
+        return Err(first.location.error(message));
+    }
+    if name.eq_ignore_ascii_case("IfDefine") {
+        let parameter = match args.as_slice() {
+            [parameter] => parameter,
+            _ => return Err(first.location.error("<IfDefine> takes one parameter".to_string())),
+        };
+        let defined = match parameter.strip_prefix('!') {
+            Some(parameter) => !defines.iter().any(|define| define == parameter),
+            None => defines.iter().any(|define| define == parameter),
+        };
+        if !defined {
+            // the contents may use directives that are only understood where the parameter is defined
+            skip_section(name, first.location, lines, index)?;
+            return Ok(Section { name: name.to_string(), args, block: Block { directives: Vec::new(), sections: Vec::new() }, location: first.location });
+        }
+    }
+    let block = parse_block(lines, index, Some((name, first.location)), defines)?;
+    Ok(Section { name: name.to_string(), args, block, location: first.location })
+}
+
+/// Skips past the close tag of a section without interpreting its contents.
+fn skip_section(name: &str, location: Location, lines: &[Vec<Token>], index: &mut usize) -> Result<(), SyntaxError> {
+    let mut depth = 0;
+    while let Some(line) = lines.get(*index) {
+        *index += 1;
+        if line[0].text.starts_with("</") {
+            if depth == 0 {
+                return Ok(());
+            }
+            depth -= 1;
+        } else if line[0].text.starts_with('<') {
+            depth += 1;
+        }
+    }
+    Err(location.error(format!("<{}> is never closed", name)))
+}
+
+/// Finds the candidate closest to `name` by edit distance, if any is close enough to plausibly be a typo.
+fn suggest<'a>(name: &str, candidates: impl Iterator<Item = &'a str>) -> Option<&'a str> {
+    let name = name.to_lowercase();
+    candidates
+        .map(|candidate| (edit_distance(&name, &candidate.to_lowercase()), candidate))
+        .filter(|(distance, candidate)| *distance <= std::cmp::max(2, candidate.len() / 3))
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate)
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + if a_char == *b_char { 0 } else { 1 };
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WWW: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/www");
+
+    fn words(s: &str) -> Vec<Vec<String>> {
+        lex(s, 0).unwrap().into_iter().map(|line| line.into_iter().map(|token| token.text).collect()).collect()
+    }
+
+    #[test]
+    fn lexes_comments_quotes_and_continuations() {
+        assert_eq!(words(concat!(
+            "# a comment\n",
+            "\tServerName 'my host'   # not a comment\n",
+            "CacheWarm /index.html \\\r\n",
+            "    /nested/*.html \"say \\\"hi\\\"\"",
+        )), vec!(
+            vec!("ServerName", "my host", "#", "not", "a", "comment"),
+            vec!("CacheWarm", "/index.html", "/nested/*.html", "say \"hi\""),
+        ));
+    }
+
+    #[test]
+    fn virtual_hosts_inherit_and_override_server_directives() {
+        let config = parse_server_config(&format!(concat!(
+            "listen 3333\n",
+            "CacheSize 64M\n",
+            "DocumentRoot {0}\n",
+            "Options Includes\n",
+            "AddOutputFilter INCLUDES .shtml\n",
+            "<virtualhost *:3333>\n",
+            "    ServerName www.example.com\n",
+            "</VirtualHost>\n",
+            "<VirtualHost *:3333>\n",
+            "    DocumentRoot \"{0}/nested\"\n",
+            "    Options -Includes\n",
+            "</VirtualHost>",
+        ), WWW), "httpd.conf", &[]).unwrap();
+        assert_eq!(config.listen, vec!("0.0.0.0:3333".parse().unwrap()));
+        assert_eq!(config.cache.size, 64 * 1024 * 1024);
+        assert_eq!(config.cache.max_file_size, config.cache.size);
+        assert_eq!(config.virtual_hosts.len(), 2);
+        let (first, second) = (&config.virtual_hosts[0], &config.virtual_hosts[1]);
+        assert_eq!(first.server_name.as_deref(), Some("www.example.com"));
+        assert_eq!(first.document_root.as_deref(), Some(WWW));
+        assert!(first.directory.options.includes);
+        assert_eq!(second.server_name, None);
+        assert_eq!(second.document_root, Some(format!("{}/nested", WWW)));
+        assert!(!second.directory.options.includes);
+        assert_eq!(second.directory.output_filters.get("shtml"), Some(&vec!("INCLUDES".to_string())));
+    }
+
+    #[test]
+    fn virtual_hosts_are_chosen_by_address_then_name() {
+        let config = parse_server_config(concat!(
+            "Listen 80\n",
+            "Listen 8080\n",
+            "Listen [::]:8081\n",
+            "<VirtualHost *:80>\n",
+            "    ServerName www.example.com:80\n",
+            "    ServerAlias example.com *.example.org\n",
+            "</VirtualHost>\n",
+            "<VirtualHost 127.0.0.2:80 [::1]:*>\n",
+            "    ServerName local\n",
+            "</VirtualHost>\n",
+            "<VirtualHost _default_:80>\n",
+            "    ServerName other\n",
+            "</VirtualHost>\n",
+        ), "httpd.conf", &[]).unwrap();
+        let names = |address: &str| -> Vec<&str> {
+            config.virtual_hosts_for(address.parse().unwrap()).iter().map(|virtual_host| virtual_host.server_name.as_deref().unwrap_or("main")).collect()
+        };
+        assert_eq!(names("127.0.0.1:80"), vec!("www.example.com:80", "other"));
+        assert_eq!(names("127.0.0.2:80"), vec!("local"));
+        assert_eq!(names("[::1]:8081"), vec!("local"));
+        assert_eq!(names("127.0.0.1:8080"), vec!("main"));
+
+        let virtual_host = &config.virtual_hosts[0];
+        assert!(virtual_host.matches_name("WWW.Example.com:8080"));
+        assert!(virtual_host.matches_name("example.com."));
+        assert!(virtual_host.matches_name("a.b.EXAMPLE.org"));
+        assert!(!virtual_host.matches_name("example.org"));
+        assert_eq!(config.virtual_hosts[1].addresses[1].to_string(), "[::1]:*");
+    }
+
+    #[test]
+    fn sections_merge_in_apache_order() {
+        let config = parse_server_config(&format!(concat!(
+            "Listen 3333\n",
+            "DocumentRoot {0}\n",
+            "<Location /nested>\n",
+            "    Require all denied\n",
+            "</Location>\n",
+            "<Directory {0}/nested>\n",
+            "    Options +Indexes\n",
+            "    Require all granted\n",
+            "</Directory>\n",
+            "<Directory {0}>\n",
+            "    Options Includes\n",
+            "    DirectoryIndex home.html\n",
+            "</Directory>\n",
+            "<FilesMatch \"\\.html$\">\n",
+            "    Header set Cache-Control no-cache\n",
+            "</FilesMatch>\n",
+        ), WWW), "httpd.conf", &[]).unwrap();
+        let virtual_host = &config.virtual_hosts[0];
+
+        let nested = virtual_host.directory_config(&path::Path::new(WWW).join("nested/index.html"), "/nested/index.html");
+        assert!(nested.options.includes && nested.options.indexes);
+        assert_eq!(nested.directory_index, vec!("home.html".to_string()));
+        assert!(!nested.access_granted);
+        assert!(matches!(nested.headers.as_slice(), [HeaderAction::Set(ResponseHeaderField::Other(name), value)] if name == "Cache-Control" && value == "no-cache"));
+
+        let root = virtual_host.directory_config(&path::Path::new(WWW).join("index_m.html"), "/nestedness/index_m.html");
+        assert!(root.options.includes && !root.options.indexes);
+        assert!(root.access_granted);
+        assert!(!root.headers.is_empty());
+        let other = virtual_host.directory_config(path::Path::new("/etc/hosts"), "/hosts");
+        assert!(!other.options.includes);
+        assert!(other.headers.is_empty());
+    }
+
+    #[test]
+    fn dumped_configuration_reads_back_the_same() {
+        let config = parse_server_config(&format!(concat!(
+            "Listen 3333\n",
+            "Options +Includes\n",
+            "RLimitCPU 10 max\n",
+            "<VirtualHost *:3333>\n",
+            "    DocumentRoot {}\n",
+            "    CGITimeout 5\n",
+            "    RLimitMEM max 1000000\n",
+            "    ProxyPass /app/ fcgi://127.0.0.1:9000/srv/app/\n",
+            "    ProxyPass /php unix:/run/php.sock|FCGI://localhost\n",
+            "    ScriptAlias /cgi-bin {}/cgi-bin/\n",
+            "    AddOutputFilter INCLUDES .shtml\n",
+            "    AddHandler CGI-Script .cgi .PL\n",
+            "    <Location /wsgi>\n",
+            "        ProxyPass scgi://127.0.0.1:4000/\n",
+            "    </Location>\n",
+            "    ProxyPass /uwsgi/ unix:uwsgi.sock|uwsgi://localhost/\n",
+            "    <Location /raw>\n",
+            "        SetHandler default-handler\n",
+            "        Options +ExecCGI -Includes\n",
+            "    </Location>\n",
+            "    <FilesMatch \"\\.(html|txt)$\">\n",
+            "        Header append Cache-Control \"max-age=60, public\"\n",
+            "    </FilesMatch>\n",
+            "</VirtualHost>\n",
+        ), WWW, WWW), "httpd.conf", &[]).unwrap();
+        let dump = config.to_string();
+        assert!(dump.contains("    Options Includes\n"), "{}", dump);
+        assert!(dump.contains("    CGITimeout 5\n    RLimitCPU 10\n    RLimitMEM max 1000000\n"), "{}", dump);
+        assert!(dump.contains("    ProxyPass /app/ fcgi://127.0.0.1:9000/srv/app/\n    ProxyPass /php unix:/run/php.sock|fcgi://localhost/\n"), "{}", dump);
+        let virtual_host = &config.virtual_hosts[0];
+        assert_eq!(virtual_host.proxy_pass("/app/index.php").map(|proxy_pass| &proxy_pass.address), Some(&BackendAddress::Tcp("127.0.0.1:9000".to_string())));
+        assert_eq!(virtual_host.proxy_pass("/php/info.php").map(|proxy_pass| &proxy_pass.address), Some(&BackendAddress::Unix(path::PathBuf::from("/run/php.sock"))));
+        assert_eq!(virtual_host.proxy_pass("/wsgi/users").map(|proxy_pass| proxy_pass.url()), Some("scgi://127.0.0.1:4000/".to_string()));
+        let uwsgi = virtual_host.proxy_pass("/uwsgi/app").unwrap();
+        assert_eq!(uwsgi.protocol, Protocol::Uwsgi);
+        assert_eq!(uwsgi.address, BackendAddress::Unix(path::PathBuf::from("uwsgi.sock")));
+        assert_eq!(virtual_host.proxy_pass("/application"), None);
+        assert_eq!(virtual_host.proxy_pass("/phpinfo.php"), None);
+        assert!(virtual_host.proxy_pass("/php").is_some());
+        assert!(dump.contains(&format!("    ScriptAlias /cgi-bin {}/cgi-bin\n", WWW)), "{}", dump);
+        assert!(dump.contains("    AddHandler cgi-script .cgi\n    AddHandler cgi-script .pl\n"), "{}", dump);
+        assert!(dump.contains("        SetHandler default-handler\n        Options +ExecCGI -Includes\n"), "{}", dump);
+        assert_eq!(virtual_host.script_alias("/cgi-bin/printenv.pl").map(|script_alias| script_alias.directory.clone()), Some(path::Path::new(WWW).join("cgi-bin")));
+        assert_eq!(virtual_host.script_alias("/cgi-binaries/printenv.pl"), None);
+        let raw = virtual_host.directory_config(&path::Path::new(WWW).join("raw/a.pl"), "/raw/a.pl");
+        assert_eq!(raw.handler.as_deref(), Some("default-handler"));
+        assert!(raw.options.exec_cgi && !raw.options.includes);
+        let reparsed = parse_server_config(&dump, "dump.conf", &[]).unwrap();
+        assert_eq!(reparsed.to_string().lines().filter(|line| !line.starts_with('#')).collect::<Vec<_>>(), dump.lines().filter(|line| !line.starts_with('#')).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn includes_files_in_sorted_order_and_reports_chains() {
+        let directory = std::env::temp_dir().join(format!("config-test-{}", std::process::id()));
+        fs::create_dir_all(directory.join("conf.d")).unwrap();
+        fs::write(directory.join("conf.d/b.conf"), "<VirtualHost *:80>\nServerName b\n</VirtualHost>\n").unwrap();
+        fs::write(directory.join("conf.d/a.conf"), format!("<VirtualHost *:80>\nServerName a\nDocumentRoot {}\n</VirtualHost>\n", WWW)).unwrap();
+        fs::write(directory.join("bad.conf"), "Listen 80\nServrName c\n").unwrap();
+        fs::write(directory.join("loop.conf"), "Include httpd.conf\n").unwrap();
+        let main = directory.join("httpd.conf");
+        let name = main.display().to_string();
+
+        let config = parse_server_config("Listen 80\nInclude conf.d/*.conf\nIncludeOptional missing/*.conf\n", &name, &[]).unwrap();
+        let names: Vec<_> = config.virtual_hosts.iter().map(|virtual_host| virtual_host.server_name.as_deref().unwrap()).collect();
+        assert_eq!(names, vec!("a", "b"));
+
+        let e = parse_server_config("Include bad.conf\n", &name, &[]).unwrap_err();
+        assert_eq!(e.message, format!("{0}/bad.conf:2:1: unknown directive `ServrName`; did you mean `ServerName`?\nServrName c\n^\n  included from {0}/httpd.conf:1:1", directory.display()));
+
+        fs::write(&main, "Listen 80\nInclude loop.conf\n").unwrap();
+        let e = parse_server_config("Listen 80\nInclude loop.conf\n", &name, &[]).unwrap_err();
+        assert!(e.message.contains(&format!("include cycle: {0}/httpd.conf -> {0}/loop.conf -> {0}/httpd.conf", directory.display())), "{}", e.message);
+
+        let e = parse_server_config("Include missing.conf\n", &name, &[]).unwrap_err();
+        assert!(e.message.starts_with(&format!("{0}/httpd.conf:1:9: cannot read `{0}/missing.conf`", directory.display())), "{}", e.message);
+        let e = parse_server_config("Include missing/*.conf\n", &name, &[]).unwrap_err();
+        assert!(e.message.starts_with(&format!("{}/httpd.conf:1:9: no files match `missing/*.conf`", directory.display())), "{}", e.message);
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn if_define_sections_follow_defines() {
+        let s = concat!(
+            "Listen 80\n",
+            "<IfDefine DEBUG>\n",
+            "    ThreadPoolSize 1\n",
+            "    <IfDefine !PRODUCTION>\n",
+            "        CacheSize 2M\n",
+            "    </IfDefine>\n",
+            "</IfDefine>\n",
+            "<IfDefine !DEBUG>\n",
+            "    ThreadPoolSize 8\n",
+            "    SSLEngine on\n",
+            "</IfDefine>\n",
+        );
+        let error = parse_server_config(s, "a.conf", &[]).unwrap_err();
+        assert!(error.message.starts_with("a.conf:10:5: unknown directive `SSLEngine`"), "{}", error.message);
+        let config = parse_server_config(s, "a.conf", &["DEBUG".to_string()]).unwrap();
+        assert_eq!(config.thread_pool_size, 1);
+        assert_eq!(config.cache.size, 2 * 1024 * 1024);
+        let config = parse_server_config(s, "a.conf", &["DEBUG".to_string(), "PRODUCTION".to_string()]).unwrap();
+        assert_eq!(config.cache.size, DEFAULT_CACHE_SIZE);
+    }
+
+    #[test]
+    fn reports_location_and_suggestion_for_unknown_directive() {
+        let e = parse_server_config("Listen 3333\n<VirtualHost *:3333>\n  DocumentRot /srv\n</VirtualHost>\n", "httpd.conf", &[]).unwrap_err();
+        assert_eq!(e.message, "httpd.conf:3:3: unknown directive `DocumentRot`; did you mean `DocumentRoot`?\n  DocumentRot /srv\n  ^");
+    }
+
+    #[test]
+    fn reports_unclosed_sections_and_quotes() {
+        let e = parse_server_config("<VirtualHost *:80>\nServerName a\n", "a.conf", &[]).unwrap_err();
+        assert!(e.message.starts_with("a.conf:1:1: <VirtualHost> is never closed"));
+        let e = parse_server_config("DocumentRoot \"/srv\n", "a.conf", &[]).unwrap_err();
+        assert!(e.message.starts_with("a.conf:1:14: unterminated quoted argument"));
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        let error = |s: &str| parse_server_config(s, "a.conf", &[]).unwrap_err().message.lines().next().unwrap().to_string();
+        assert_eq!(error("Listen 70000\n"), "a.conf:1:8: port must be between 1 and 65535, found `70000`");
+        assert_eq!(error("Listen 80\nCacheSize 64X\n"), "a.conf:2:11: expected a size such as `512K` or `64M`, found `64X`");
+        assert_eq!(error("Listen 80\n<VirtualHost *:80>\nThreadPoolSize 4\n</VirtualHost>\n"), "a.conf:3:1: ThreadPoolSize is not allowed inside <VirtualHost>");
+        assert_eq!(error(&format!("Listen 80\nDocumentRoot {}/index.html\n", WWW)), format!("a.conf:2:14: document root `{}/index.html` is not a directory", WWW));
+        assert_eq!(error("Listen 80\nListen [::]:80x\n"), "a.conf:2:8: expected a port or an address and port such as `[::]:8080`, found `[::]:80x`");
+        assert_eq!(error("Listen 80\nListen 0.0.0.0:80\n"), "a.conf:2:8: already listening on 0.0.0.0:80");
+        assert_eq!(error("Listen 80\n<VirtualHost www.example.com:80>\n</VirtualHost>\n"), "a.conf:2:1: expected an IP address or `*`, optionally followed by `:port`, found `www.example.com:80`");
+        assert_eq!(error("Listen 80\nServerAlias example.com\n"), "a.conf:2:1: ServerAlias is only allowed inside <VirtualHost>");
+        assert_eq!(error("Listen 80\nAcceptPathInfo Maybe\n"), "a.conf:2:16: expected `On`, `Off` or `Default`, found `Maybe`");
+        assert_eq!(error("Listen 80\nRLimitNPROC 20 10\n"), "a.conf:2:1: RLimitNPROC soft limit 20 is above the hard limit 10");
+        assert_eq!(error("Listen 80\nProxyPass /app/ http://127.0.0.1:9000/\n"), "a.conf:2:17: expected a URL such as `fcgi://127.0.0.1:9000/`, `scgi://127.0.0.1:4000/`, `uwsgi://127.0.0.1:3031/` or `unix:/path/to/socket|fcgi://localhost/`, found `http://127.0.0.1:9000/`");
+        assert_eq!(error("Listen 80\nProxyPass app/ fcgi://127.0.0.1:9000/\n"), "a.conf:2:11: expected a URL path starting with `/`, found `app/`");
+        assert_eq!(error("Listen 80\n<Directory />\nProxyPass scgi://127.0.0.1:4000/\n</Directory>\n"), "a.conf:3:1: ProxyPass is not allowed inside <Directory>; use <Location>");
+        assert_eq!(error("Listen 80\nAddHandler cgi-scripts .pl\n"), "a.conf:2:12: unknown handler `cgi-scripts`; did you mean `cgi-script`?");
+        assert_eq!(error("Listen 80\n<Location /raw>\nSetHandler text/plain\n</Location>\n"), "a.conf:3:12: unknown handler `text/plain`");
+        assert_eq!(error("Listen 80\nScriptAlias /cgi-bin/ /nonexistent/\n"), "a.conf:2:23: cannot open script directory `/nonexistent/`: No such file or directory (os error 2)");
+        assert_eq!(error("CacheSize 1\n"), "a.conf: no Listen directive");
     }
 }
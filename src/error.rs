@@ -75,7 +75,7 @@ impl std::fmt::Display for HttpError {
         write!(
             f,
             "{}{}{}",
-            self.status.to_string(),
+            self.status,
             if self.message.is_some() { ": " } else { "" },
             self.message.as_ref().unwrap_or(&String::new())
         )?;
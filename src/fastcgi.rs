@@ -0,0 +1,601 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::os::unix::net::UnixStream;
+use std::path;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time;
+use crate::cgi;
+use crate::config::*;
+use crate::error::HttpError;
+use crate::http::*;
+
+/// The protocol version and the record types of [FastCGI 1.0](https://fastcgi-archives.github.io/FastCGI_Specification.html#S8)
+/// that are used.
+const VERSION: u8 = 1;
+const BEGIN_REQUEST: u8 = 1;
+const ABORT_REQUEST: u8 = 2;
+const END_REQUEST: u8 = 3;
+const PARAMS: u8 = 4;
+const STDIN: u8 = 5;
+const STDOUT: u8 = 6;
+const STDERR: u8 = 7;
+const GET_VALUES: u8 = 9;
+const GET_VALUES_RESULT: u8 = 10;
+
+/// The role of an application that answers requests as a CGI script would.
+const RESPONDER: u16 = 1;
+/// Asks the application to leave the connection open once it has answered, so that it can be used again.
+const KEEP_CONN: u8 = 1;
+/// The protocol status of a request that the application answered.
+const REQUEST_COMPLETE: u8 = 0;
+
+const MAX_CONTENT_LENGTH: usize = 0xffff;
+
+/// How long a new connection waits for the application to say whether it takes several requests at once.
+const VALUES_TIMEOUT: time::Duration = time::Duration::from_secs(1);
+
+/// The most connections to each application kept open while no request is using them.
+const MAX_IDLE_CONNECTIONS: usize = 8;
+
+/// Sends requests to FastCGI applications, keeping connections to each open between requests. Applications that say
+/// they can are sent several requests at once over one connection; others are given a connection per request.
+#[derive(Default)]
+pub struct FastCgi {
+    pools: Mutex<HashMap<BackendAddress, Vec<Arc<Connection>>>>,
+}
+
+impl FastCgi {
+    /// Sends a request to the application that a `ProxyPass` names and reads its output up to the end of its headers,
+    /// which are processed as a CGI script's would be; the rest of the output is streamed as the body of the response.
+    /// `url_path` is the normalized path of the request, which the `ProxyPass` matched. The request ends with
+    /// `504 Gateway Timeout` if it takes longer than `CGITimeout`.
+    pub fn send(&self, proxy_pass: &ProxyPass, url_path: &str, request: &Request, virtual_host: &VirtualHost) -> Result<cgi::Output, HttpError> {
+        let document_root = path::PathBuf::from(virtual_host.document_root.as_deref().unwrap_or(""));
+        let script = cgi::Script {
+            path: path::PathBuf::from(format!("{}{}", proxy_pass.target, &url_path[proxy_pass.path.len()..])),
+            script_name: url_path.to_string(),
+            path_info: "",
+            document_root: &document_root,
+        };
+        let params = cgi::meta_variables(&script, request, virtual_host);
+        let context = format!(
+            "[request {}] [client {}] [{}] {}",
+            request.id,
+            request.remote.addr,
+            virtual_host.server_name.as_deref().unwrap_or("_default_"),
+            proxy_pass.url(),
+        );
+
+        let (connection, id, events) = self.connect(&proxy_pass.address).map_err(|e| {
+            println!("{}: could not connect: {}", context, e);
+            HttpError { status: StatusCode::ServiceUnavailable, message: Some(format!("Could not connect to FastCGI application: {}", e)) }
+        })?;
+        let timed_out = Arc::new(AtomicBool::new(false));
+        let output = ApplicationOutput {
+            connection,
+            id,
+            events,
+            stdout: io::Cursor::new(Vec::new()),
+            error_log: cgi::ErrorLog::new(context.clone()),
+            deadline: virtual_host.cgi_limits.timeout.map(|timeout| time::Instant::now() + timeout),
+            timed_out: timed_out.clone(),
+            ended: false,
+        };
+        output.connection.send(id, &params, request.body.as_bytes())
+            .map_err(|e| e.into())
+            .and_then(|_| cgi::process_cgi_output(output, false))
+            .map_err(|e| {
+                println!("{}: {}", context, e);
+                let status = if timed_out.load(Ordering::SeqCst) { StatusCode::GatewayTimeout } else { StatusCode::BadGateway };
+                HttpError { status, message: Some(e.to_string()) }
+            })
+    }
+
+    /// Finds a connection to an application that can take another request, opening one if there is none, and
+    /// registers the request on it. Connections the application has closed, and idle connections beyond
+    /// `MAX_IDLE_CONNECTIONS`, are dropped from the pool on the way.
+    fn connect(&self, address: &BackendAddress) -> io::Result<(Arc<Connection>, u16, mpsc::Receiver<Event>)> {
+        {
+            let mut pools = self.pools.lock().unwrap();
+            let connections = pools.entry(address.clone()).or_default();
+            let mut idle = 0;
+            connections.retain(|connection| {
+                let requests = connection.requests.lock().unwrap();
+                if requests.closed {
+                    return false;
+                }
+                if requests.senders.is_empty() {
+                    idle += 1;
+                    if idle > MAX_IDLE_CONNECTIONS {
+                        connection.close();
+                        return false;
+                    }
+                }
+                true
+            });
+            for connection in connections.iter() {
+                if let Some((id, events)) = connection.register() {
+                    return Ok((connection.clone(), id, events));
+                }
+            }
+        }
+        // connecting may take a while, so other requests are not kept waiting on the pool meanwhile
+        let connection = Connection::open(address)?;
+        let (id, events) = connection.register()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::ConnectionAborted, "the application closed the connection"))?;
+        self.pools.lock().unwrap().entry(address.clone()).or_default().push(connection.clone());
+        Ok((connection, id, events))
+    }
+}
+
+/// A connection to an application. Records are written under a lock, and read by a thread of the connection's own,
+/// which passes them on to the requests they belong to.
+struct Connection {
+    writer: Mutex<Stream>,
+    requests: Arc<Mutex<Requests>>,
+    /// Whether the application takes several requests at once over the connection.
+    multiplexed: bool,
+}
+
+#[derive(Default)]
+struct Requests {
+    /// Where the records for each request in progress go, by request ID.
+    senders: HashMap<u16, mpsc::Sender<Event>>,
+    closed: bool,
+}
+
+/// A record the application sent for a request.
+enum Event {
+    Stdout(Vec<u8>),
+    Stderr(Vec<u8>),
+    /// The end of the request, with the protocol status.
+    End(u8),
+}
+
+impl Connection {
+    fn open(address: &BackendAddress) -> io::Result<Arc<Connection>> {
+        let mut stream = Stream::connect(address)?;
+        let multiplexed = multiplexes(&mut stream)?;
+        let requests = Arc::new(Mutex::new(Requests::default()));
+        let reader = stream.try_clone()?;
+        let reader_requests = requests.clone();
+        thread::spawn(move || read_records(reader, reader_requests));
+        Ok(Arc::new(Connection { writer: Mutex::new(stream), requests, multiplexed }))
+    }
+
+    /// Allocates an ID for a new request and a channel for its records, unless the connection has been closed or is
+    /// already in use by an application that takes one request at a time.
+    fn register(&self) -> Option<(u16, mpsc::Receiver<Event>)> {
+        let mut requests = self.requests.lock().unwrap();
+        if requests.closed || (!self.multiplexed && !requests.senders.is_empty()) {
+            return None;
+        }
+        let id = (1..=u16::MAX).find(|id| !requests.senders.contains_key(id))?;
+        let (sender, receiver) = mpsc::channel();
+        requests.senders.insert(id, sender);
+        Some((id, receiver))
+    }
+
+    /// Begins a request and writes its parameters and input.
+    fn send(&self, id: u16, params: &HashMap<String, String>, body: &[u8]) -> io::Result<()> {
+        let mut begin = RESPONDER.to_be_bytes().to_vec();
+        begin.extend_from_slice(&[KEEP_CONN, 0, 0, 0, 0, 0]);
+        let params = encode_pairs(params.iter().map(|(name, value)| (name.as_str(), value.as_str())));
+        let mut writer = self.writer.lock().unwrap();
+        let mut writer = io::BufWriter::new(&mut *writer);
+        write_record(&mut writer, BEGIN_REQUEST, id, &begin)?;
+        write_stream(&mut writer, PARAMS, id, &params)?;
+        write_stream(&mut writer, STDIN, id, body)?;
+        writer.flush()
+    }
+
+    /// Gives up on a request before the application has ended it. A connection that carries only this request is
+    /// closed rather than left waiting for the application to notice.
+    fn abort(&self, id: u16) {
+        if self.multiplexed {
+            let _ = write_record(&mut *self.writer.lock().unwrap(), ABORT_REQUEST, id, &[]);
+        } else {
+            self.close();
+        }
+    }
+
+    fn close(&self) {
+        self.writer.lock().unwrap().shutdown();
+    }
+}
+
+/// Asks an application whether it takes several requests at once over a connection, taking it that it does not if it
+/// gives no answer within `VALUES_TIMEOUT`.
+fn multiplexes(stream: &mut Stream) -> io::Result<bool> {
+    write_record(stream, GET_VALUES, 0, &encode_pairs(std::iter::once(("FCGI_MPXS_CONNS", ""))))?;
+    stream.set_read_timeout(Some(VALUES_TIMEOUT))?;
+    let record = read_record(stream);
+    stream.set_read_timeout(None)?;
+    match record {
+        Ok(record) if record.kind == GET_VALUES_RESULT => Ok(decode_pairs(&record.content).unwrap_or_default().iter()
+            .any(|(name, value)| name == "FCGI_MPXS_CONNS" && value == "1")),
+        Ok(_) => Ok(false),
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Passes the records an application sends on to the requests they belong to, until the connection is closed; the
+/// requests still in progress then see their channels close.
+fn read_records(mut reader: Stream, requests: Arc<Mutex<Requests>>) {
+    while let Ok(record) = read_record(&mut reader) {
+        let event = match record.kind {
+            STDOUT if !record.content.is_empty() => Event::Stdout(record.content),
+            STDERR if !record.content.is_empty() => Event::Stderr(record.content),
+            END_REQUEST if record.content.len() >= 5 => Event::End(record.content[4]),
+            _ => continue,
+        };
+        let ended = matches!(event, Event::End(_));
+        let mut requests = requests.lock().unwrap();
+        if let Some(sender) = requests.senders.get(&record.id) {
+            // the request may have been given up on, in which case the record is dropped
+            let _ = sender.send(event);
+        }
+        if ended {
+            requests.senders.remove(&record.id);
+        }
+    }
+    let mut requests = requests.lock().unwrap();
+    requests.closed = true;
+    requests.senders.clear();
+}
+
+/// The output of an application for one request, read as its records arrive. Error output is logged along the way.
+struct ApplicationOutput {
+    connection: Arc<Connection>,
+    id: u16,
+    events: mpsc::Receiver<Event>,
+    /// The rest of the last output record.
+    stdout: io::Cursor<Vec<u8>>,
+    error_log: cgi::ErrorLog,
+    deadline: Option<time::Instant>,
+    timed_out: Arc<AtomicBool>,
+    ended: bool,
+}
+
+impl Read for ApplicationOutput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let bytes_read = self.stdout.read(buf)?;
+            if bytes_read > 0 || self.ended || buf.is_empty() {
+                return Ok(bytes_read);
+            }
+            let event = match self.deadline {
+                Some(deadline) => self.events.recv_timeout(deadline.saturating_duration_since(time::Instant::now())),
+                None => self.events.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+            };
+            match event {
+                Ok(Event::Stdout(bytes)) => self.stdout = io::Cursor::new(bytes),
+                Ok(Event::Stderr(bytes)) => self.error_log.write(&bytes),
+                Ok(Event::End(protocol_status)) => {
+                    self.ended = true;
+                    self.error_log.close();
+                    if protocol_status != REQUEST_COMPLETE {
+                        let reason = match protocol_status {
+                            1 => "it cannot take several requests over one connection",
+                            2 => "it is overloaded",
+                            3 => "it is not a responder",
+                            _ => "of an unknown protocol status",
+                        };
+                        return Err(io::Error::other(format!("FastCGI application refused the request because {}", reason)));
+                    }
+                },
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    self.timed_out.store(true, Ordering::SeqCst);
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "FastCGI application timed out"));
+                },
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "FastCGI application closed the connection"));
+                },
+            }
+        }
+    }
+}
+
+impl Drop for ApplicationOutput {
+    fn drop(&mut self) {
+        if !self.ended {
+            self.error_log.close();
+            self.connection.abort(self.id);
+        }
+    }
+}
+
+/// A connection to an application over TCP or a Unix domain socket.
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Stream {
+    pub fn connect(address: &BackendAddress) -> io::Result<Stream> {
+        match address {
+            BackendAddress::Tcp(address) => TcpStream::connect(address).map(Stream::Tcp),
+            BackendAddress::Unix(path) => UnixStream::connect(path).map(Stream::Unix),
+        }
+    }
+
+    fn try_clone(&self) -> io::Result<Stream> {
+        match self {
+            Stream::Tcp(stream) => stream.try_clone().map(Stream::Tcp),
+            Stream::Unix(stream) => stream.try_clone().map(Stream::Unix),
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<time::Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_read_timeout(timeout),
+            Stream::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<time::Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_write_timeout(timeout),
+            Stream::Unix(stream) => stream.set_write_timeout(timeout),
+        }
+    }
+
+    /// Closes the connection, which the thread reading from it then notices.
+    fn shutdown(&self) {
+        let _ = match self {
+            Stream::Tcp(stream) => stream.shutdown(Shutdown::Both),
+            Stream::Unix(stream) => stream.shutdown(Shutdown::Both),
+        };
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            Stream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            Stream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            Stream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+struct Record {
+    kind: u8,
+    id: u16,
+    content: Vec<u8>,
+}
+
+/// Writes a record, padded to a multiple of eight bytes as the specification recommends.
+fn write_record(writer: &mut impl Write, kind: u8, id: u16, content: &[u8]) -> io::Result<()> {
+    let padding = (8 - content.len() % 8) % 8;
+    let [id_high, id_low] = id.to_be_bytes();
+    let [length_high, length_low] = (content.len() as u16).to_be_bytes();
+    writer.write_all(&[VERSION, kind, id_high, id_low, length_high, length_low, padding as u8, 0])?;
+    writer.write_all(content)?;
+    writer.write_all(&[0; 8][..padding])
+}
+
+/// Writes a stream as records of at most `MAX_CONTENT_LENGTH` bytes, followed by the empty record that ends it.
+fn write_stream(writer: &mut impl Write, kind: u8, id: u16, content: &[u8]) -> io::Result<()> {
+    for chunk in content.chunks(MAX_CONTENT_LENGTH) {
+        write_record(writer, kind, id, chunk)?;
+    }
+    write_record(writer, kind, id, &[])
+}
+
+fn read_record(reader: &mut impl Read) -> io::Result<Record> {
+    let mut header = [0; 8];
+    reader.read_exact(&mut header)?;
+    if header[0] != VERSION {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unsupported FastCGI version {}", header[0])));
+    }
+    let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+    let mut content = vec![0; length + header[6] as usize];
+    reader.read_exact(&mut content)?;
+    content.truncate(length);
+    Ok(Record { kind: header[1], id: u16::from_be_bytes([header[2], header[3]]), content })
+}
+
+/// Encodes name-value pairs, giving each length in one byte if it is below 128 and otherwise in four bytes with the
+/// high bit set.
+fn encode_pairs<'a>(pairs: impl Iterator<Item = (&'a str, &'a str)>) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    for (name, value) in pairs {
+        for len in [name.len(), value.len()].iter() {
+            if *len < 0x80 {
+                encoded.push(*len as u8);
+            } else {
+                encoded.extend_from_slice(&(*len as u32 | 0x8000_0000).to_be_bytes());
+            }
+        }
+        encoded.extend_from_slice(name.as_bytes());
+        encoded.extend_from_slice(value.as_bytes());
+    }
+    encoded
+}
+
+fn decode_pairs(mut bytes: &[u8]) -> Option<Vec<(String, String)>> {
+    fn read_len(bytes: &mut &[u8]) -> Option<usize> {
+        let len = match bytes.first()? {
+            len if len & 0x80 == 0 => (*len as usize, 1),
+            _ => (u32::from_be_bytes(bytes.get(..4)?.try_into().ok()?) as usize & 0x7fff_ffff, 4),
+        };
+        *bytes = &bytes[len.1..];
+        Some(len.0)
+    }
+    let mut pairs = Vec::new();
+    while !bytes.is_empty() {
+        let (name_len, value_len) = (read_len(&mut bytes)?, read_len(&mut bytes)?);
+        let name = bytes.get(..name_len)?;
+        let value = bytes.get(name_len..name_len + value_len)?;
+        pairs.push((String::from_utf8_lossy(name).into_owned(), String::from_utf8_lossy(value).into_owned()));
+        bytes = &bytes[name_len + value_len..];
+    }
+    Some(pairs)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::net::TcpListener;
+    use std::os::unix::net::UnixListener;
+    use std::sync::atomic::AtomicUsize;
+    use super::*;
+
+    /// Serves a FastCGI stand-in on a local port, returning its address and a count of the connections it accepts.
+    pub(crate) fn serve_tcp(multiplexed: bool) -> (BackendAddress, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = BackendAddress::Tcp(listener.local_addr().unwrap().to_string());
+        let connections = Arc::new(AtomicUsize::new(0));
+        let accepted = connections.clone();
+        thread::spawn(move || for stream in listener.incoming() {
+            accepted.fetch_add(1, Ordering::SeqCst);
+            let stream = Stream::Tcp(stream.unwrap());
+            thread::spawn(move || answer(stream, multiplexed));
+        });
+        (address, connections)
+    }
+
+    fn serve_unix(name: &str) -> BackendAddress {
+        let directory = std::env::temp_dir().join(format!("p1-fastcgi-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = directory.join(name);
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        thread::spawn(move || for stream in listener.incoming() {
+            let stream = Stream::Unix(stream.unwrap());
+            thread::spawn(move || answer(stream, false));
+        });
+        BackendAddress::Unix(path)
+    }
+
+    /// Answers the requests on a connection as a FastCGI application would, each from a thread of its own so that
+    /// requests over a multiplexed connection may finish in any order.
+    fn answer(mut reader: Stream, multiplexed: bool) {
+        let writer = Arc::new(Mutex::new(reader.try_clone().unwrap()));
+        let mut requests: HashMap<u16, (Vec<u8>, Vec<u8>)> = HashMap::new();
+        while let Ok(record) = read_record(&mut reader) {
+            match record.kind {
+                GET_VALUES => {
+                    let pairs = encode_pairs(std::iter::once(("FCGI_MPXS_CONNS", if multiplexed { "1" } else { "0" })));
+                    write_record(&mut *writer.lock().unwrap(), GET_VALUES_RESULT, 0, &pairs).unwrap();
+                },
+                BEGIN_REQUEST => { requests.insert(record.id, (Vec::new(), Vec::new())); },
+                PARAMS => requests.get_mut(&record.id).unwrap().0.extend(record.content),
+                STDIN if !record.content.is_empty() => requests.get_mut(&record.id).unwrap().1.extend(record.content),
+                STDIN => {
+                    let (params, stdin) = requests.remove(&record.id).unwrap();
+                    let params = decode_pairs(&params).unwrap().into_iter().collect();
+                    let writer = writer.clone();
+                    thread::spawn(move || respond(&writer, record.id, params, stdin));
+                },
+                _ => {},
+            }
+        }
+    }
+
+    /// Answers a request with the script it names, the length of its `X-Long` header and its body, after sleeping for
+    /// the milliseconds in its query string, or never if the query string is `hang`.
+    fn respond(writer: &Mutex<Stream>, id: u16, params: HashMap<String, String>, stdin: Vec<u8>) {
+        match params["QUERY_STRING"].as_str() {
+            "hang" => return,
+            millis => thread::sleep(time::Duration::from_millis(millis.parse().unwrap_or(0))),
+        }
+        let long = params.get("HTTP_X_LONG").map(|value| value.len()).unwrap_or(0);
+        let output = format!("Status: 201 Created\r\nContent-Type: text/plain\r\n\r\n{} {}\n{}", params["SCRIPT_FILENAME"], long, String::from_utf8(stdin).unwrap());
+        let mut writer = writer.lock().unwrap();
+        write_stream(&mut *writer, STDERR, id, b"working on it\n").unwrap();
+        write_stream(&mut *writer, STDOUT, id, output.as_bytes()).unwrap();
+        write_record(&mut *writer, END_REQUEST, id, &[0, 0, 0, 0, REQUEST_COMPLETE, 0, 0, 0]).unwrap();
+    }
+
+    fn post(target: &str, body: &str) -> Request {
+        let raw = format!("POST {} HTTP/1.1\r\nHost: www.example.com\r\nX-Long: {}\r\nContent-Length: {}\r\n\r\n{}", target, "x".repeat(200), body.len(), body);
+        parse_test_request(&raw, "10.0.0.2:50000", "10.0.0.1:3333")
+    }
+
+    fn proxy_pass(address: BackendAddress) -> ProxyPass {
+        ProxyPass { path: "/app/".to_string(), protocol: Protocol::FastCgi, address, target: "/srv/app/".to_string() }
+    }
+
+    fn response(output: Result<cgi::Output, HttpError>) -> (u16, String) {
+        match output.unwrap() {
+            cgi::Output::Response(response) => (response.header.status_line.status_code.code(), response.into_body().unwrap()),
+            cgi::Output::LocalRedirect(location) => panic!("unexpected redirect to {}", location),
+        }
+    }
+
+    #[test]
+    fn answers_requests_over_unix_sockets() {
+        let fastcgi = FastCgi::default();
+        let address = serve_unix("app.sock");
+        let output = fastcgi.send(&proxy_pass(address), "/app/index.php", &post("/app/index.php?0", "hello"), &VirtualHost::default());
+        assert_eq!(response(output), (201, "/srv/app/index.php 200\nhello".to_string()));
+
+        let missing = proxy_pass(BackendAddress::Unix(std::env::temp_dir().join("p1-fastcgi-missing.sock")));
+        let error = fastcgi.send(&missing, "/app/index.php", &post("/app/index.php", ""), &VirtualHost::default()).err().unwrap();
+        assert!(matches!(error.status, StatusCode::ServiceUnavailable));
+    }
+
+    #[test]
+    fn multiplexes_concurrent_requests_over_one_connection() {
+        let fastcgi = Arc::new(FastCgi::default());
+        let (address, connections) = serve_tcp(true);
+        let proxy_pass = proxy_pass(address);
+        assert_eq!(response(fastcgi.send(&proxy_pass, "/app/first", &post("/app/first?0", ""), &VirtualHost::default())).0, 201);
+
+        let slow = {
+            let (fastcgi, proxy_pass) = (fastcgi.clone(), proxy_pass.clone());
+            thread::spawn(move || {
+                let output = response(fastcgi.send(&proxy_pass, "/app/slow", &post("/app/slow?500", "slow"), &VirtualHost::default()));
+                (output, time::Instant::now())
+            })
+        };
+        thread::sleep(time::Duration::from_millis(100));
+        let fast = response(fastcgi.send(&proxy_pass, "/app/fast", &post("/app/fast?0", "fast"), &VirtualHost::default()));
+        let fast_finished = time::Instant::now();
+        let (slow, slow_finished) = slow.join().unwrap();
+        assert_eq!(fast.1, "/srv/app/fast 200\nfast");
+        assert_eq!(slow.1, "/srv/app/slow 200\nslow");
+        assert!(fast_finished < slow_finished);
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn reuses_idle_connections_and_abandons_requests_that_time_out() {
+        let fastcgi = FastCgi::default();
+        let (address, connections) = serve_tcp(false);
+        let proxy_pass = proxy_pass(address);
+        for _ in 0..3 {
+            assert_eq!(response(fastcgi.send(&proxy_pass, "/app/index.php", &post("/app/index.php?0", "a=1"), &VirtualHost::default())).0, 201);
+        }
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+
+        let mut virtual_host = VirtualHost::default();
+        virtual_host.cgi_limits.timeout = Some(time::Duration::from_secs(1));
+        let error = fastcgi.send(&proxy_pass, "/app/index.php", &post("/app/index.php?hang", ""), &virtual_host).err().unwrap();
+        assert!(matches!(error.status, StatusCode::GatewayTimeout));
+        assert_eq!(response(fastcgi.send(&proxy_pass, "/app/index.php", &post("/app/index.php?0", ""), &virtual_host)).0, 201);
+        assert_eq!(connections.load(Ordering::SeqCst), 2);
+    }
+}
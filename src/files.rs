@@ -1,82 +1,120 @@
-use std::cell::RefCell;
-use std::collections;
 use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
 use std::convert::TryInto;
-use std::fs;
+use std::hash::{Hash, Hasher};
 use std::io;
+use std::io::Read;
 use std::path;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::time;
+use crate::cache::LruCache;
 use crate::error;
 use crate::http::*;
 use crate::time::to_1123;
+use crate::vfs;
 
-const BYTES_PER_KILOBYTE: u32 = 1024;
+const MAX_SHARDS: usize = 16;
 
+/// A file cache that can be shared between threads. Entries are spread over several independently locked shards by
+/// the hash of their path, so that requests for different files rarely contend for the same lock.
 pub struct Files {
-    cache: RefCell<collections::HashMap<path::PathBuf, File>>,
+    shards: Vec<Mutex<LruCache<path::PathBuf, File>>>,
+    counters: Counters,
+    max_entry_size: usize,
+    revalidate_interval: time::Duration,
 }
 
+#[derive(Default)]
+struct Counters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    invalidations: AtomicU64,
+}
+
+#[derive(Clone)]
 struct File {
-    content: String,
+    content: Arc<String>,
+    validator: Validator,
+    validated: time::Instant,
+}
+
+/// The parts of a file's metadata that identify a particular version of its content. If any of these differ from the
+/// values recorded when the file was cached, the cached content is considered stale.
+#[derive(Clone, Copy, PartialEq)]
+struct Validator {
     modified: time::SystemTime,
+    len: u64,
+    ino: u64,
+}
+impl Validator {
+    fn stat(vfs: &dyn vfs::Vfs, path: &path::Path) -> Result<Validator, io::Error> {
+        let metadata = vfs.stat(path)?;
+        Ok(Validator { modified: metadata.modified, len: metadata.len, ino: metadata.ino })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub invalidations: u64,
+    pub entries: usize,
+    pub size: usize,
+    pub capacity: usize,
+}
+impl std::fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "hits: {}", self.hits)?;
+        writeln!(f, "misses: {}", self.misses)?;
+        writeln!(f, "evictions: {}", self.evictions)?;
+        writeln!(f, "invalidations: {}", self.invalidations)?;
+        writeln!(f, "entries: {}", self.entries)?;
+        writeln!(f, "size: {}", self.size)?;
+        write!(f, "capacity: {}", self.capacity)
+    }
 }
 
 impl Files {
-    pub fn new(cache_size: u32) -> Files {
+    /// Creates a file cache holding at most `cache_size` bytes of content in total, where no single file larger than
+    /// `max_entry_size` bytes is cached. Cached files are checked against the disk once `revalidate_interval` has
+    /// passed since they were last checked.
+    ///
+    /// The total size is divided evenly between the shards, so fewer shards are used when the cache is small relative
+    /// to `max_entry_size`; every shard must be able to hold the largest cacheable file.
+    pub fn new(cache_size: usize, max_entry_size: usize, revalidate_interval: time::Duration) -> Files {
+        let max_entry_size = max_entry_size.min(cache_size);
+        let num_shards = (cache_size / max_entry_size.max(1)).clamp(1, MAX_SHARDS);
+        let shards = (0..num_shards)
+            .map(|_| Mutex::new(LruCache::new(cache_size / num_shards)))
+            .collect();
         Files {
-            cache: RefCell::new(
-                collections::HashMap::with_capacity(
-                    (cache_size * BYTES_PER_KILOBYTE)
-                        .try_into()
-                        .unwrap_or(usize::MAX)
-                )
-            )
+            shards,
+            counters: Counters::default(),
+            max_entry_size,
+            revalidate_interval,
         }
     }
 
-    pub fn get_content(&self, path: path::PathBuf) -> Result<Response, error::HttpError> {
-        let cached = {
-            self.cache.borrow().get(&path)
-                .map(|File { content, modified }| {
-                    // println!("-- cache hit --");
-                    File { content: content.to_string(), modified: modified.clone() }
-                })
-                .ok_or_else(|| {
-                    let s = "cache miss";
-                    // println!("-- {} --", s);
-                    s.to_string();
-                })
-        };
-        let File { content, modified } = cached
-            .or_else(|_| {
-                fs::read_to_string(&path)
-                    .map(|content| {
-                        let modified = path.metadata().unwrap().modified().unwrap();
-                        self.cache.borrow_mut().insert(path.clone(), File { content: content.clone(), modified, });
-                        // println!("-- cache insert --");
-                        File { content, modified }
-                    })
-                    .map_err(|e| {
-                        let status = match e.kind() {
-                            io::ErrorKind::NotFound => StatusCode::NotFound,
-                            _ => StatusCode::InternalServerError
-                        };
-                        error::HttpError { status, message: Some(e.to_string())}
-                    })
-            })?;
+    /// Serves a file, from the cache if it holds a current copy. The body is streamed from the cached content, which
+    /// is shared rather than copied.
+    pub fn get_content(&self, vfs: &dyn vfs::Vfs, path: path::PathBuf) -> Result<Response, error::HttpError> {
+        let File { content, validator, .. } = self.fetch(vfs, &path)?;
 
         let header_lines = {
             let modified_str = to_1123(
                 chrono::DateTime::from_utc(
                     chrono::naive::NaiveDateTime::from_timestamp(
-                        modified.duration_since(time::UNIX_EPOCH).unwrap().as_secs().try_into().unwrap(),
+                        validator.modified.duration_since(time::UNIX_EPOCH).unwrap().as_secs().try_into().unwrap(),
                         0
                     ),
                     chrono::offset::Utc
                 )
             );
             let mut header_lines = HashMap::new();
-            header_lines.insert(ResponseHeaderField::ContentLength, (content.len() + 2).to_string());
+            header_lines.insert(ResponseHeaderField::ContentLength, content.len().to_string());
             header_lines.insert(ResponseHeaderField::LastModified, modified_str);
             if let Some(extension) = path.extension().and_then(|ext| ext.to_str()) {
                 let content_type = match extension {
@@ -99,14 +137,165 @@ impl Files {
                 },
                 header_lines,
             },
-            body: content,
+            body: String::new(),
+            stream: Some(BodyStream::new(io::Cursor::new(Content(content)), false)),
         })
     }
 
-    pub fn modified_since(path: &path::PathBuf, start: time::Duration) -> Result<bool, error::Error> {
-        let metadata = fs::metadata(path)?;
-        let modified = metadata.modified()?;
+    /// The content of a file, from the cache if it holds a current copy, for documents that are processed rather than
+    /// sent as they are.
+    pub fn get(&self, vfs: &dyn vfs::Vfs, path: &path::Path) -> Result<Arc<String>, error::HttpError> {
+        self.fetch(vfs, path).map(|file| file.content)
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let mut stats = CacheStats {
+            hits: self.counters.hits.load(Ordering::Relaxed),
+            misses: self.counters.misses.load(Ordering::Relaxed),
+            evictions: self.counters.evictions.load(Ordering::Relaxed),
+            invalidations: self.counters.invalidations.load(Ordering::Relaxed),
+            ..CacheStats::default()
+        };
+        for shard in self.shards.iter() {
+            let shard = shard.lock().unwrap();
+            stats.entries += shard.entry_count();
+            stats.size += shard.size();
+            stats.capacity += shard.capacity();
+        }
+        stats
+    }
+
+    /// Loads a file into the cache ahead of the first request for it.
+    pub fn warm(&self, vfs: &dyn vfs::Vfs, path: &path::Path) -> Result<(), io::Error> {
+        self.load(vfs, path).map(|_| ())
+    }
+
+    /// Evicts a single file, e.g. because it is known to have changed on disk.
+    pub fn invalidate(&self, path: &path::Path) {
+        if self.shard(path).lock().unwrap().remove(&path.to_path_buf()).is_some() {
+            self.counters.invalidations.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Evicts every file at or below `directory`.
+    pub fn invalidate_tree(&self, directory: &path::Path) {
+        for shard in self.shards.iter() {
+            let removed = shard.lock().unwrap().remove_matching(|path| path.starts_with(directory));
+            self.counters.invalidations.fetch_add(removed as u64, Ordering::Relaxed);
+        }
+    }
+
+    pub fn modified_since(vfs: &dyn vfs::Vfs, path: &path::Path, start: time::Duration) -> Result<bool, error::Error> {
+        let modified = vfs.stat(path)?.modified;
         let duration = modified.duration_since(time::UNIX_EPOCH)?;
         Ok((duration - start).as_secs() > 0)
     }
+
+    fn shard(&self, path: &path::Path) -> &Mutex<LruCache<path::PathBuf, File>> {
+        let mut hasher = DefaultHasher::new();
+        path.hash(&mut hasher);
+        &self.shards[(hasher.finish() as usize) % self.shards.len()]
+    }
+
+    fn fetch(&self, vfs: &dyn vfs::Vfs, path: &path::Path) -> Result<File, error::HttpError> {
+        match self.lookup(vfs, path) {
+            Some(file) => Ok(file),
+            None => self.load(vfs, path).map_err(|e| {
+                let status = match e.kind() {
+                    io::ErrorKind::NotFound => StatusCode::NotFound,
+                    _ => StatusCode::InternalServerError
+                };
+                error::HttpError { status, message: Some(e.to_string())}
+            }),
+        }
+    }
+
+    /// Finds a file in the cache. The shard is only locked to find the entry and mark it as used, and again to record
+    /// the outcome of a revalidation; the file system is checked with the lock released.
+    fn lookup(&self, vfs: &dyn vfs::Vfs, path: &path::Path) -> Option<File> {
+        let shard = self.shard(path);
+        let path = path.to_path_buf();
+        let cached = shard.lock().unwrap().get_mut(&path).cloned();
+        let file = match cached {
+            Some(file) => file,
+            None => {
+                self.counters.misses.fetch_add(1, Ordering::Relaxed);
+                return None;
+            },
+        };
+        if file.validated.elapsed() >= self.revalidate_interval {
+            let current = Validator::stat(vfs, &path);
+            let mut cache = shard.lock().unwrap();
+            // another thread may have replaced the entry while the lock was released
+            let entry = cache.get_mut(&path).filter(|entry| Arc::ptr_eq(&entry.content, &file.content));
+            if !matches!(current, Ok(validator) if validator == file.validator) {
+                if entry.is_some() {
+                    cache.remove(&path);
+                    self.counters.invalidations.fetch_add(1, Ordering::Relaxed);
+                }
+                self.counters.misses.fetch_add(1, Ordering::Relaxed);
+                return None;
+            }
+            if let Some(entry) = entry {
+                entry.validated = time::Instant::now();
+            }
+        }
+        self.counters.hits.fetch_add(1, Ordering::Relaxed);
+        Some(file)
+    }
+
+    fn load(&self, vfs: &dyn vfs::Vfs, path: &path::Path) -> Result<File, io::Error> {
+        // take the validator before reading so that a concurrent write is detected on the next revalidation
+        let validator = Validator::stat(vfs, path)?;
+        let mut content = String::new();
+        vfs.open(path)?.take(validator.len).read_to_string(&mut content)?;
+        let content = Arc::new(content);
+        let file = File { content, validator, validated: time::Instant::now() };
+        let size = file.content.len();
+        if size <= self.max_entry_size {
+            let evicted = self.shard(path).lock().unwrap().insert(path.to_path_buf(), file.clone(), size);
+            self.counters.evictions.fetch_add(evicted as u64, Ordering::Relaxed);
+        }
+        Ok(file)
+    }
+}
+
+/// Cached content as the source of a response body.
+struct Content(Arc<String>);
+impl AsRef<[u8]> for Content {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfs::memory::MemoryVfs;
+
+    #[test]
+    fn stale_entries_are_reloaded_and_least_recently_used_entries_evicted() {
+        let files = Files::new(10, 10, time::Duration::from_secs(0));
+        let mut vfs = MemoryVfs::new();
+        vfs.insert("/a.txt", b"aaaa", 0o644);
+        vfs.insert("/b.txt", b"bbbb", 0o644);
+        vfs.insert("/c.txt", b"cccc", 0o644);
+
+        files.get_content(&vfs, path::PathBuf::from("/a.txt")).unwrap();
+        files.get_content(&vfs, path::PathBuf::from("/b.txt")).unwrap();
+        files.get_content(&vfs, path::PathBuf::from("/a.txt")).unwrap();
+        files.get_content(&vfs, path::PathBuf::from("/c.txt")).unwrap();
+        let stats = files.stats();
+        assert_eq!((stats.hits, stats.misses, stats.evictions), (1, 3, 1));
+        assert_eq!(stats.size, 8);
+
+        vfs.insert("/a.txt", b"changed", 0o644);
+        let response = files.get_content(&vfs, path::PathBuf::from("/a.txt")).unwrap();
+        assert_eq!(response.into_body().unwrap(), "changed");
+        assert_eq!(files.stats().invalidations, 1);
+
+        // hits share the cached content rather than copying it
+        let first = files.get(&vfs, path::Path::new("/a.txt")).unwrap();
+        assert!(Arc::ptr_eq(&first, &files.get(&vfs, path::Path::new("/a.txt")).unwrap()));
+    }
 }
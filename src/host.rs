@@ -1,85 +1,343 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
-use std::ops::BitAnd;
-use std::os::unix::fs::PermissionsExt;
+use std::io::{self, Write};
+use std::net::SocketAddr;
 use std::path;
-use std::str::FromStr;
+use std::sync::{Arc, Mutex};
 use std::time;
 use crate::config::*;
 use crate::cgi;
 use crate::error;
+use crate::fastcgi;
 use crate::files;
 use crate::http::*;
+use crate::pool;
+use crate::scgi;
+use crate::ssi;
+use crate::uwsgi;
+use crate::watch;
 use crate::time::{now_1123, parse_date_1123};
+use crate::vfs;
 
+/// Cloning a host is cheap, as the clones share its configuration and caches.
+#[derive(Clone)]
 pub struct Host {
-    server_config: ServerConfig,
+    server_config: Arc<ServerConfig>,
     cgi: cgi::Cgi,
-    files: files::Files,
+    scgi: scgi::Scgi,
+    uwsgi: uwsgi::Uwsgi,
+    files: Arc<files::Files>,
+    fastcgi: Arc<fastcgi::FastCgi>,
+    /// The threads that expand server-side includes, `ThreadPoolSize` of them.
+    workers: Arc<pool::Workers>,
+    document_roots: Arc<HashMap<String, Arc<DocumentRoot>>>,
+    /// Document roots derived from `VirtualDocumentRoot`, by path, so that each is only opened once.
+    virtual_document_roots: Arc<Mutex<HashMap<String, ResolvedRoot>>>,
+}
+
+/// The most derived document roots remembered at once; beyond this, they are all forgotten, since requests may name
+/// any number of hosts.
+const MAX_VIRTUAL_DOCUMENT_ROOTS: usize = 1024;
+
+/// The most internal redirects from CGI scripts followed for one request, as with Apache's `LimitInternalRecursion`.
+const MAX_INTERNAL_REDIRECTS: usize = 10;
+
+/// How a request is answered: with a response, by a CGI script that has been started and whose output is to become
+/// the response, or by an application that the request is still to be sent to.
+pub enum Handled {
+    Response(Response),
+    Script(PendingScript),
+    Proxy(Box<PendingProxy>),
+}
+
+/// A CGI script started for a request, along with what is still to be done to its response.
+pub struct PendingScript {
+    pub script: cgi::RunningScript,
+    /// The `Header` directives that apply to the script's response.
+    headers: Vec<HeaderAction>,
+    /// How many internal redirects led to the script.
+    redirects: usize,
+}
+
+/// A request for a FastCGI, SCGI or uWSGI application, which may take until `CGITimeout` to answer, along with what
+/// is still to be done to its response.
+pub struct PendingProxy {
+    proxy_pass: ProxyPass,
+    url_path: String,
+    request: Request,
+    virtual_host: VirtualHost,
+    /// The `Header` directives that apply to the application's response.
+    headers: Vec<HeaderAction>,
+}
+
+/// An opened `DocumentRoot`: the file system it is served from and the path of the root within that file system.
+#[derive(Clone)]
+struct DocumentRoot {
+    path: path::PathBuf,
+    vfs: Arc<dyn vfs::Vfs>,
+}
+
+/// A document root derived from `VirtualDocumentRoot`, or `None` if there was no such directory, as of `checked`.
+/// The result is trusted for `CacheRevalidateInterval`, after which sites that have since been created or removed
+/// are noticed.
+struct ResolvedRoot {
+    checked: time::Instant,
+    document_root: Option<Arc<DocumentRoot>>,
 }
 
 impl Host {
     pub fn new(server_config: ServerConfig) -> Host {
-        let files = files::Files::new(
-            server_config.directives.get(&Directive::CacheSize)
-                .and_then(|cache_size| u32::from_str(cache_size).ok())
-                .unwrap_or(1024)
-        );
-        let cgi = cgi::Cgi::new(server_config.clone());
+        let files = Arc::new(files::Files::new(
+            server_config.cache.size,
+            server_config.cache.max_file_size,
+            server_config.cache.revalidate_interval,
+        ));
+        let workers = Arc::new(pool::Workers::new(server_config.thread_pool_size));
+        Host::with_shared(server_config, files, Arc::new(fastcgi::FastCgi::default()), workers)
+    }
+
+    /// Creates a host for a reloaded configuration. The file cache, and so any watcher evicting from it, is carried
+    /// over from this host, as are the connections to FastCGI applications and the workers; the cache directives and
+    /// `ThreadPoolSize` only take effect on restart.
+    pub fn reload(&self, server_config: ServerConfig) -> Host {
+        Host::with_shared(server_config, self.files.clone(), self.fastcgi.clone(), self.workers.clone())
+    }
+
+    fn with_shared(server_config: ServerConfig, files: Arc<files::Files>, fastcgi: Arc<fastcgi::FastCgi>, workers: Arc<pool::Workers>) -> Host {
+        let cgi = cgi::Cgi::default();
+        let scgi = scgi::Scgi::default();
+        let uwsgi = uwsgi::Uwsgi::default();
+        let mut document_roots = HashMap::new();
+        for virtual_host in server_config.virtual_hosts.iter().chain(std::iter::once(&server_config.main_server)) {
+            if let Some(value) = &virtual_host.document_root {
+                if document_roots.contains_key(value) {
+                    continue;
+                }
+                match vfs::open_document_root(value) {
+                    Ok((path, vfs)) => { document_roots.insert(value.clone(), Arc::new(DocumentRoot { path, vfs })); },
+                    Err(e) => println!("{}", e),
+                }
+            }
+        }
         Host {
-            server_config,
+            server_config: Arc::new(server_config),
             cgi,
+            scgi,
+            uwsgi,
             files,
+            fastcgi,
+            workers,
+            document_roots: Arc::new(document_roots),
+            virtual_document_roots: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 
+    pub fn server_config(&self) -> &ServerConfig {
+        &self.server_config
+    }
+
     pub fn handle(&self, request: &Request, overloaded: bool) -> Response {
-        let mut response = self.handle_result(request, overloaded).unwrap_or_else(|e| error_response(e.status, e.message));
-        response.header.header_lines.insert(ResponseHeaderField::Server, "Rust/0.1".to_string());
-        response.header.header_lines.insert(ResponseHeaderField::Date, now_1123());
-        response
+        finish_response(self.handle_result(request, overloaded).and_then(|handled| self.wait_for(request, handled)))
+    }
+
+    /// Answers a request as `handle` does, except that a CGI script is handed back as soon as it has started, and a
+    /// request for an application before it is sent, for the caller to wait on them without blocking.
+    pub fn handle_nonblocking(&self, request: &Request, overloaded: bool) -> Handled {
+        finish_handled(self.handle_result(request, overloaded))
+    }
+
+    /// Answers a request with what its script asked for, once the script has written its headers, as parsed by
+    /// `cgi::RunningScript::parse_output`. A local redirect may start another script.
+    pub fn script_output(&self, request: &Request, script: &PendingScript, output: Result<cgi::Output, error::HttpError>) -> Handled {
+        let handled = output.and_then(|output| match output {
+            cgi::Output::Response(mut response) => {
+                apply_headers(&mut response, &script.headers);
+                Ok(Handled::Response(response))
+            },
+            cgi::Output::LocalRedirect(location) => self.redirect(request, script.redirects, &location),
+        });
+        finish_handled(handled)
+    }
+
+    /// Sends a request to its application on one of the workers, and hands what it answered with to `done`, as
+    /// `handle_nonblocking` would. A streamed body that cannot be waited on, such as the output of a FastCGI, SCGI or
+    /// uWSGI application, is copied into a pipe by the worker, and the response is given the pipe to read from instead.
+    pub fn forward_on_worker(&self, proxy: Box<PendingProxy>, done: impl FnOnce(Handled) + Send + 'static) {
+        let host = self.clone();
+        self.workers.spawn(move || {
+            let mut copy = None;
+            let handled = host.forward(proxy).and_then(|handled| match handled {
+                Handled::Response(mut response) if response.stream.as_ref().map(|stream| stream.as_raw_fd().is_none()).unwrap_or(false) => {
+                    let stream = response.stream.take().unwrap();
+                    let (reader, writer) = io::pipe()?;
+                    response.stream = Some(BodyStream::pollable(reader, stream.non_parsed));
+                    copy = Some((stream, writer));
+                    Ok(Handled::Response(response))
+                },
+                handled => Ok(handled),
+            });
+            done(finish_handled(handled));
+            if let Some((mut stream, mut writer)) = copy {
+                // an error here means that the client has gone away, or the application has failed partway
+                let _ = io::copy(&mut stream, &mut writer);
+            }
+        });
+    }
+
+    /// Loads the files matching each virtual host's `CacheWarm` patterns, and those of the main server, into the file
+    /// cache. Patterns are globs relative to the document root, e.g. `CacheWarm /index.html /nested/*.html`; a pattern
+    /// that several hosts share under the same document root is only matched once.
+    pub fn warm_cache(&self) {
+        let mut warmed = HashSet::new();
+        for virtual_host in self.server_config.virtual_hosts.iter().chain(std::iter::once(&self.server_config.main_server)) {
+            let document_root = match self.document_root(virtual_host) {
+                Some(document_root) => document_root,
+                None => continue,
+            };
+            for pattern in virtual_host.cache_warm.iter() {
+                let pattern = document_root.path.join(pattern.trim_start_matches('/'));
+                if !warmed.insert(pattern.clone()) {
+                    continue;
+                }
+                match glob::Pattern::new(&pattern.to_string_lossy()) {
+                    Ok(pattern) => self.warm_matching(document_root, &pattern, &document_root.path, &mut HashSet::new()),
+                    Err(e) => println!("Invalid CacheWarm pattern {}: {}", pattern.display(), e),
+                }
+            }
+        }
+    }
+
+    /// Creates an inotify watcher over every virtual host's document root if `CacheWatch On` is configured, so that
+    /// changed files are evicted from the file cache immediately.
+    pub fn watch_document_roots(&self) -> Result<Option<watch::Watcher>, error::Error> {
+        if !self.server_config.cache.watch {
+            return Ok(None);
+        }
+        let mut watcher = watch::Watcher::new(self.files.clone())?;
+        for document_root in self.document_roots.values() {
+            if let Some(local_root) = document_root.vfs.local_path(&document_root.path) {
+                watcher.watch_tree(&local_root)?;
+            }
+        }
+        Ok(Some(watcher))
     }
 }
 
 impl Host {
-    fn handle_result(&self, request: &Request, overloaded: bool) -> Result<Response, error::HttpError> {
+    fn handle_result(&self, request: &Request, overloaded: bool) -> Result<Handled, error::HttpError> {
         let host_path = request.header.header_lines.get(&RequestHeaderField::Host)
             .ok_or(error::HttpError { status: StatusCode::BadRequest, message: None })?;
-        let virtual_host = get_virtual_host(&self.server_config.virtual_hosts, host_path);
+        let virtual_host = get_virtual_host(&self.server_config, request.remote.local_addr, host_path);
+        // aliases and sections are matched against the same path the file system sees, so that `//cgi-bin/` or
+        // `/./private/` cannot slip past them
+        let url_path = &normalize_path(&request.header.request_line.request_path)?;
+
+        if request.header.request_line.method == Method::Get && url_path == "/load" {
+            return heartbeat(overloaded).map(Handled::Response);
+        }
+
+        // a handler that serves no file is chosen by `<Location>` alone, before the document root is looked at
+        let location_config = virtual_host.directory_config(path::Path::new(""), url_path);
+        if location_config.handler.as_deref() == Some(CACHE_STATUS) {
+            check_allowed(&location_config, request)?;
+            let mut response = match request.header.request_line.method {
+                Method::Get => cache_status(&self.files)?,
+                Method::Post => method_not_allowed(),
+            };
+            apply_headers(&mut response, &location_config.headers);
+            return Ok(Handled::Response(response));
+        }
 
-        if request.header.request_line.method == Method::Get && request.header.request_line.request_path == "/load" {
-            return heartbeat(overloaded);
+        if let Some(proxy_pass) = virtual_host.proxy_pass(url_path) {
+            return self.proxy(proxy_pass, url_path, request, virtual_host);
         }
 
-        let document_root = &virtual_host.directives.get(&Directive::DocumentRoot)
-            .and_then(|document_root| path::Path::new(document_root).canonicalize().ok())
-            .ok_or(error::HttpError { status: StatusCode::InternalServerError, message: Some("Could not determine document root for virtual host".to_string()) })?;
-        let request_target = parse_path(document_root, &request.header.request_line.request_path)?;
+        let document_root = self.request_document_root(virtual_host, host_path, request.remote.local_addr.port())?;
+        let document_root = document_root.as_ref();
+        let vfs = document_root.vfs.as_ref();
+        if let Some(script_alias) = virtual_host.script_alias(url_path) {
+            return self.handle_script_alias(document_root, script_alias, url_path, request, virtual_host);
+        }
+        let request_target = parse_path(document_root, url_path)?;
 
-        match request.header.request_line.method {
-            Method::Get => self.handle_get(request_target, request, virtual_host),
-            Method::Post => self.handle_post(request_target, request, virtual_host),
+        // settings for a directory may choose its index file, which may in turn have settings of its own
+        let mut directory_config = virtual_host.directory_config(&request_target.path, url_path);
+        let index = if request_target.is_dir {
+            find_index(vfs, &request_target.path, &directory_config, &request.header.header_lines)
+        } else {
+            None
+        };
+        if let Some(index) = &index {
+            directory_config = virtual_host.directory_config(index, url_path);
         }
+
+        check_allowed(&directory_config, request)?;
+        if !request_target.path_info.is_empty() {
+            // as in Apache, trailing path info is accepted by scripts unless `AcceptPathInfo` says otherwise
+            let is_script = is_cgi_script(&directory_config, &request_target.path) && vfs.local_path(&request_target.path).is_some();
+            if !directory_config.accept_path_info.unwrap_or(is_script) {
+                return Err(error::HttpError { status: StatusCode::NotFound, message: None });
+            }
+        }
+
+        let mut handled = match (index, &request.header.request_line.method) {
+            (None, Method::Get) if request_target.is_dir && directory_config.options.indexes => Handled::Response(directory_listing(vfs, &request_target.path, url_path)?),
+            (None, _) if request_target.is_dir => return Err(error::HttpError { status: StatusCode::NotFound, message: None }),
+            (index, Method::Get) => self.handle_get(document_root, index.unwrap_or(request_target.path), &request_target.path_info, request, virtual_host, &directory_config)?,
+            (index, Method::Post) => self.handle_post(document_root, index.unwrap_or(request_target.path), &request_target.path_info, request, virtual_host, &directory_config)?,
+        };
+        match &mut handled {
+            Handled::Response(response) => apply_headers(response, &directory_config.headers),
+            Handled::Script(script) => script.headers = directory_config.headers.clone(),
+            Handled::Proxy(proxy) => proxy.headers = directory_config.headers.clone(),
+        }
+        Ok(handled)
     }
 
-    fn handle_get(&self, request_target: RequestTarget, request: &Request, virtual_host: &VirtualHost) -> Result<Response, error::HttpError> {
-        let (path, metadata) = content_negotiation(request_target, &request.header.header_lines)?;
+    /// Runs the script that a URL path names below a `ScriptAlias`. Every file in the directory is run as a CGI script,
+    /// whatever its handler and `Options` say, and the directory itself is not listed.
+    fn handle_script_alias(&self, document_root: &DocumentRoot, script_alias: &ScriptAlias, url_path: &str, request: &Request, virtual_host: &VirtualHost) -> Result<Handled, error::HttpError> {
+        let scripts = DocumentRoot { path: script_alias.directory.clone(), vfs: Arc::new(vfs::DiskVfs) };
+        let request_target = parse_path(&scripts, &url_path[script_alias.path.len()..])?;
+        let directory_config = virtual_host.directory_config(&request_target.path, url_path);
+        check_allowed(&directory_config, request)?;
+        if request_target.is_dir || metadata_or_404(scripts.vfs.as_ref(), &request_target.path)?.is_dir {
+            return Err(error::HttpError { status: StatusCode::Forbidden, message: None });
+        }
+        if !request_target.path_info.is_empty() && directory_config.accept_path_info == Some(false) {
+            return Err(error::HttpError { status: StatusCode::NotFound, message: None });
+        }
+        let script_name = url_path.strip_suffix(request_target.path_info.as_str()).unwrap_or(url_path).to_string();
+        let mut handled = self.run_script(document_root, script_name, request_target.path.clone(), &request_target.path_info, request, virtual_host)?;
+        if let Handled::Script(script) = &mut handled {
+            script.headers = directory_config.headers;
+        }
+        Ok(handled)
+    }
 
-        if metadata.is_dir() {
+    fn handle_get(&self, document_root: &DocumentRoot, path: path::PathBuf, path_info: &str, request: &Request, virtual_host: &VirtualHost, directory_config: &DirectoryConfig) -> Result<Handled, error::HttpError> {
+        let vfs = document_root.vfs.as_ref();
+        let metadata = metadata_or_404(vfs, &path)?;
+
+        if metadata.is_dir {
             return Err(error::HttpError { status: StatusCode::NotFound, message: None });
         }
 
-        if metadata.permissions().mode().bitand(0o1).eq(&0o1) {
-            return self.cgi.handle(path, request, virtual_host);
+        if is_cgi_script(directory_config, &path) {
+            return self.run_mapped_script(document_root, &path, path_info, request, virtual_host, directory_config);
+        }
+
+        if includes_enabled(directory_config, &path) {
+            return self.handle_includes(document_root, path, metadata, request, virtual_host).map(Handled::Response);
         }
 
         if let Some(since) = request.header.header_lines.get(&RequestHeaderField::IfModifiedSince) {
             let since = parse_date_1123(since).map_err(|e| error::HttpError { status: StatusCode::BadRequest, message: Some(e.message) })?;
-            let mod_since = files::Files::modified_since(&path, time::Duration::from_secs(since.timestamp().try_into().unwrap())).unwrap_or(true);
+            let mod_since = files::Files::modified_since(vfs, &path, time::Duration::from_secs(since.timestamp().try_into().unwrap())).unwrap_or(true);
             if !mod_since {
                 let mut header_lines = HashMap::new();
                 header_lines.insert(ResponseHeaderField::ContentLength, "0".to_string());
-                return Ok(
+                return Ok(Handled::Response(
                     Response {
                         header: ResponseHeader {
                             status_line: StatusLine {
@@ -89,21 +347,337 @@ impl Host {
                             header_lines,
                         },
                         body: String::new(),
+                        stream: None,
+                    }
+                ));
+            }
+        }
+
+        self.files.get_content(vfs, path).map(Handled::Response)
+    }
+
+    /// Only CGI scripts accept a `POST`; anything else answers `405 Method Not Allowed`.
+    fn handle_post(&self, document_root: &DocumentRoot, path: path::PathBuf, path_info: &str, request: &Request, virtual_host: &VirtualHost, directory_config: &DirectoryConfig) -> Result<Handled, error::HttpError> {
+        if metadata_or_404(document_root.vfs.as_ref(), &path)?.is_dir {
+            return Err(error::HttpError { status: StatusCode::NotFound, message: None });
+        }
+        if !is_cgi_script(directory_config, &path) {
+            return Ok(Handled::Response(method_not_allowed()));
+        }
+        self.run_mapped_script(document_root, &path, path_info, request, virtual_host, directory_config)
+    }
+
+    /// Starts a file in the document root that a handler maps to `cgi-script`, which `Options ExecCGI` must allow.
+    fn run_mapped_script(&self, document_root: &DocumentRoot, path: &path::Path, path_info: &str, request: &Request, virtual_host: &VirtualHost, directory_config: &DirectoryConfig) -> Result<Handled, error::HttpError> {
+        if !directory_config.options.exec_cgi {
+            let message = format!("Options ExecCGI is off in this directory: {}", path.display());
+            return Err(error::HttpError { status: StatusCode::Forbidden, message: Some(message) });
+        }
+        let local_path = document_root.vfs.local_path(path)
+            .ok_or(error::HttpError { status: StatusCode::Forbidden, message: None })?;
+        let script_name = format!("/{}", path.strip_prefix(&document_root.path).unwrap_or(path).display());
+        self.run_script(document_root, script_name, local_path, path_info, request, virtual_host)
+    }
+
+    /// Starts the CGI script at `local_path` on disk, known to the request as `script_name`, passing it the part of the
+    /// request path below the script as `path_info`.
+    fn run_script(&self, document_root: &DocumentRoot, script_name: String, local_path: path::PathBuf, path_info: &str, request: &Request, virtual_host: &VirtualHost) -> Result<Handled, error::HttpError> {
+        let local_root = document_root.vfs.local_path(&document_root.path).unwrap_or_else(|| document_root.path.clone());
+        let script = cgi::Script {
+            path: local_path,
+            script_name,
+            path_info,
+            document_root: &local_root,
+        };
+        let script = self.cgi.start(script, request, virtual_host)?;
+        Ok(Handled::Script(PendingScript { script, headers: Vec::new(), redirects: request.redirects }))
+    }
+
+    /// Answers a request through the FastCGI, SCGI or uWSGI application that a `ProxyPass` names. The request does not
+    /// map onto the file system, so only `<Location>` sections apply to it. The request is handed back unsent, as the
+    /// application may take a while to answer.
+    fn proxy(&self, proxy_pass: &ProxyPass, url_path: &str, request: &Request, virtual_host: &VirtualHost) -> Result<Handled, error::HttpError> {
+        let directory_config = virtual_host.directory_config(path::Path::new(""), url_path);
+        check_allowed(&directory_config, request)?;
+        Ok(Handled::Proxy(Box::new(PendingProxy {
+            proxy_pass: proxy_pass.clone(),
+            url_path: url_path.to_string(),
+            request: request.clone(),
+            virtual_host: virtual_host.clone(),
+            headers: directory_config.headers.clone(),
+        })))
+    }
+
+    /// Sends a request to its application and waits for the headers of its answer. A local redirect is served as a
+    /// script's would be.
+    fn forward(&self, proxy: Box<PendingProxy>) -> Result<Handled, error::HttpError> {
+        let PendingProxy { proxy_pass, url_path, request, virtual_host, headers } = *proxy;
+        let output = match proxy_pass.protocol {
+            Protocol::FastCgi => self.fastcgi.send(&proxy_pass, &url_path, &request, &virtual_host)?,
+            Protocol::Scgi => self.scgi.send(&proxy_pass, &url_path, &request, &virtual_host)?,
+            Protocol::Uwsgi => self.uwsgi.send(&proxy_pass, &url_path, &request, &virtual_host)?,
+        };
+        match output {
+            cgi::Output::Response(mut response) => {
+                apply_headers(&mut response, &headers);
+                Ok(Handled::Response(response))
+            },
+            cgi::Output::LocalRedirect(location) => self.redirect(&request, request.redirects, &location),
+        }
+    }
+
+    /// Waits for a script to write its headers, or for an application to answer, following any local redirects.
+    fn wait_for(&self, request: &Request, handled: Handled) -> Result<Response, error::HttpError> {
+        let PendingScript { script, headers, redirects } = match handled {
+            Handled::Response(response) => return Ok(response),
+            Handled::Script(script) => script,
+            Handled::Proxy(proxy) => return self.wait_for(request, self.forward(proxy)?),
+        };
+        match script.wait()? {
+            cgi::Output::Response(mut response) => {
+                apply_headers(&mut response, &headers);
+                Ok(response)
+            },
+            cgi::Output::LocalRedirect(location) => self.wait_for(request, self.redirect(request, redirects, &location)?),
+        }
+    }
+
+    /// Serves a local redirect from a CGI script as though the client had sent a `GET` for `location` instead, after
+    /// `redirects` earlier redirects.
+    fn redirect(&self, request: &Request, redirects: usize, location: &str) -> Result<Handled, error::HttpError> {
+        if redirects >= MAX_INTERNAL_REDIRECTS {
+            let message = format!("More than {} internal redirects, the last to {}", MAX_INTERNAL_REDIRECTS, location);
+            return Err(error::HttpError { status: StatusCode::InternalServerError, message: Some(message) });
+        }
+        let (request_path, query_string) = location.split_once('?').unwrap_or((location, ""));
+        let mut header_lines = request.header.header_lines.clone();
+        header_lines.remove(&RequestHeaderField::ContentLength);
+        header_lines.remove(&RequestHeaderField::ContentType);
+        let redirected = Request {
+            header: RequestHeader {
+                request_line: RequestLine {
+                    method: Method::Get,
+                    request_path: request_path.to_string(),
+                    query_string: query_string.to_string(),
+                    http_version: request.header.request_line.http_version.clone(),
+                },
+                header_lines,
+            },
+            remote: Remote { addr: request.remote.addr, local_addr: request.remote.local_addr },
+            body: String::new(),
+            redirects: redirects + 1,
+            id: request.id,
+        };
+        self.handle_result(&redirected, false)
+    }
+
+    /// Expands server-side includes in a document. The document is processed by one of the workers and streamed to the
+    /// client through a pipe as it is expanded; the length of the result is not known until the end, so it is sent with
+    /// chunked transfer coding rather than a `Content-Length`. A document reached from a worker, through a script's
+    /// local redirect, is expanded in place instead.
+    fn handle_includes(&self, document_root: &DocumentRoot, path: path::PathBuf, metadata: vfs::Metadata, request: &Request, virtual_host: &VirtualHost) -> Result<Response, error::HttpError> {
+        let content = self.files.get(document_root.vfs.as_ref(), &path)?;
+        let document = ssi::Document {
+            uri: normalize_path(&request.header.request_line.request_path)?,
+            path,
+            content,
+            modified: metadata.modified,
+            parsed: true,
+        };
+        let resolver = IncludeResolver {
+            host: self.clone(),
+            document_root: document_root.clone(),
+            request: request.clone(),
+            virtual_host: virtual_host.clone(),
+        };
+        let stream = if pool::Workers::on_worker() {
+            let mut expanded = Vec::new();
+            ssi::process(&resolver, document, &resolver.request.header.request_line.query_string, &mut expanded)?;
+            BodyStream::new(io::Cursor::new(expanded), false)
+        } else {
+            let (reader, mut writer) = io::pipe()?;
+            self.workers.spawn(move || {
+                // an error here means that the client has gone away, and the rest of the document with it
+                let _ = ssi::process(&resolver, document, &resolver.request.header.request_line.query_string, &mut writer);
+            });
+            BodyStream::pollable(reader, false)
+        };
+
+        let mut header_lines = HashMap::new();
+        header_lines.insert(ResponseHeaderField::ContentType, "text/html".to_string());
+        Ok(
+            Response {
+                header: ResponseHeader {
+                    status_line: StatusLine {
+                        status_code: StatusCode::Ok,
+                        http_version: String::from(HTTP_VERSION),
+                    },
+                    header_lines,
+                },
+                body: String::new(),
+                stream: Some(stream),
+            }
+        )
+    }
+
+    fn document_root(&self, virtual_host: &VirtualHost) -> Option<&DocumentRoot> {
+        virtual_host.document_root.as_ref()
+            .and_then(|document_root| self.document_roots.get(document_root))
+            .map(|document_root| document_root.as_ref())
+    }
+
+    /// Finds the document root for a request to `host`, derived from the `VirtualDocumentRoot` if there is one and
+    /// its directory exists, or else the `DocumentRoot`.
+    fn request_document_root(&self, virtual_host: &VirtualHost, host: &str, port: u16) -> Result<Arc<DocumentRoot>, error::HttpError> {
+        if let Some(template) = &virtual_host.virtual_document_root {
+            let path = template.interpolate(host, port)
+                .ok_or(error::HttpError { status: StatusCode::BadRequest, message: Some("Invalid host name".to_string()) })?;
+            if let Some(document_root) = self.virtual_document_root(&path) {
+                return Ok(document_root);
+            }
+            if virtual_host.document_root.is_none() {
+                return Err(error::HttpError { status: StatusCode::NotFound, message: None });
+            }
+        }
+        virtual_host.document_root.as_ref()
+            .and_then(|document_root| self.document_roots.get(document_root))
+            .cloned()
+            .ok_or(error::HttpError { status: StatusCode::InternalServerError, message: Some("Could not determine document root for virtual host".to_string()) })
+    }
+
+    fn virtual_document_root(&self, path: &str) -> Option<Arc<DocumentRoot>> {
+        let mut resolved_roots = self.virtual_document_roots.lock().unwrap();
+        if let Some(resolved) = resolved_roots.get(path) {
+            if resolved.checked.elapsed() < self.server_config.cache.revalidate_interval {
+                return resolved.document_root.clone();
+            }
+        }
+        if resolved_roots.len() >= MAX_VIRTUAL_DOCUMENT_ROOTS {
+            resolved_roots.clear();
+        }
+        let document_root = vfs::open_document_root(path).ok()
+            .map(|(path, vfs)| DocumentRoot { path, vfs })
+            .filter(|document_root| document_root.vfs.stat(&document_root.path).map(|metadata| metadata.is_dir).unwrap_or(false))
+            .map(Arc::new);
+        resolved_roots.insert(path.to_string(), ResolvedRoot { checked: time::Instant::now(), document_root: document_root.clone() });
+        document_root
+    }
+
+    fn warm_matching(&self, document_root: &DocumentRoot, pattern: &glob::Pattern, directory: &path::Path, visited: &mut HashSet<path::PathBuf>) {
+        let vfs = document_root.vfs.as_ref();
+        // guard against symbolic links that lead back up the tree
+        if !vfs.canonicalize(directory).map(|directory| visited.insert(directory)).unwrap_or(false) {
+            return;
+        }
+        let options = glob::MatchOptions { require_literal_separator: true, ..glob::MatchOptions::new() };
+        for path in vfs.list_dir(directory).unwrap_or_default() {
+            match vfs.stat(&path) {
+                Ok(metadata) if metadata.is_dir => self.warm_matching(document_root, pattern, &path, visited),
+                Ok(_) if pattern.matches_path_with(&path, options) => {
+                    if let Err(e) = self.files.warm(vfs, &path) {
+                        println!("Could not warm cache with {}: {}", path.display(), e);
                     }
-                );
+                },
+                _ => {},
             }
         }
+    }
+}
 
-        self.files.get_content(path)
+/// Resolves the documents and scripts referred to by server-side includes within the same virtual host as the request.
+struct IncludeResolver {
+    host: Host,
+    document_root: DocumentRoot,
+    request: Request,
+    virtual_host: VirtualHost,
+}
+
+impl IncludeResolver {
+    /// Starts the CGI script that a normalized URL path names, as a request for it would, `Require` included; `None` if
+    /// it names no script.
+    fn start_script(&self, uri: &str) -> Result<Option<Handled>, error::HttpError> {
+        if let Some(script_alias) = self.virtual_host.script_alias(uri) {
+            return self.host.handle_script_alias(&self.document_root, script_alias, uri, &self.request, &self.virtual_host).map(Some);
+        }
+        let request_target = parse_path(&self.document_root, uri)?;
+        let metadata = self.document_root.vfs.stat(&request_target.path)?;
+        let directory_config = self.virtual_host.directory_config(&request_target.path, uri);
+        if metadata.is_dir || !is_cgi_script(&directory_config, &request_target.path) {
+            return Ok(None);
+        }
+        check_allowed(&directory_config, &self.request)?;
+        self.host.run_mapped_script(&self.document_root, &request_target.path, &request_target.path_info, &self.request, &self.virtual_host, &directory_config)
+            .map(Some)
     }
 
-    fn handle_post(&self, request_target: RequestTarget, request: &Request, virtual_host: &VirtualHost) -> Result<Response, error::HttpError> {
-        // assert executable
-        // assert not directory
-        return self.cgi.handle(request_target.path, request, virtual_host);
+    /// Waits for a script and copies the body of its response to `output` as the script writes it.
+    fn write_output(&self, handled: Handled, output: &mut dyn Write) -> Result<(), error::Error> {
+        let mut response = self.host.wait_for(&self.request, handled).map_err(|e| error::Error::new(e.to_string()))?;
+        output.write_all(response.body.as_bytes())?;
+        if let Some(stream) = response.stream.as_mut() {
+            io::copy(stream, output)?;
+        }
+        Ok(())
     }
 }
 
+impl ssi::Resolver for IncludeResolver {
+    fn include_virtual(&self, uri: &str, output: &mut dyn Write) -> Result<Option<ssi::Document>, error::Error> {
+        let uri = uri.split_once('?').map(|(uri, _)| uri).unwrap_or(uri);
+        let uri = &normalize_path(uri).map_err(|e| error::Error::new(e.to_string()))?;
+        // as in Apache, a script is run and its output included rather than its source
+        if let Some(handled) = self.start_script(uri).map_err(|e| error::Error::new(e.to_string()))? {
+            return self.write_output(handled, output).map(|_| None);
+        }
+        let request_target = parse_path(&self.document_root, uri).map_err(|e| error::Error::new(e.to_string()))?;
+        if !request_target.path_info.is_empty() {
+            return Err(error::Error::new(format!("Cannot include {}: no such file", uri)));
+        }
+        let vfs = self.document_root.vfs.as_ref();
+        let metadata = vfs.stat(&request_target.path)?;
+        if metadata.is_dir {
+            return Err(error::Error::new(format!("Cannot include directory {}", uri)));
+        }
+        // a document that would be refused to the client is not included for it either
+        let directory_config = self.virtual_host.directory_config(&request_target.path, uri);
+        check_allowed(&directory_config, &self.request).map_err(|e| error::Error::new(format!("Cannot include {}: {}", uri, e)))?;
+        let content = self.host.files.get(vfs, &request_target.path)
+            .map_err(|e| error::Error::new(e.to_string()))?;
+        Ok(Some(ssi::Document {
+            uri: uri.to_string(),
+            parsed: includes_enabled(&directory_config, &request_target.path),
+            path: request_target.path,
+            content,
+            modified: metadata.modified,
+        }))
+    }
+
+    fn exec_cgi(&self, uri: &str, output: &mut dyn Write) -> Result<(), error::Error> {
+        let uri = &normalize_path(uri).map_err(|e| error::Error::new(e.to_string()))?;
+        match self.start_script(uri).map_err(|e| error::Error::new(e.to_string()))? {
+            Some(handled) => self.write_output(handled, output),
+            None => Err(error::Error::new(format!("{} is not a CGI script", uri))),
+        }
+    }
+}
+
+/// Adds the headers sent with every response to one that is ready, as `finish_response` does.
+fn finish_handled(handled: Result<Handled, error::HttpError>) -> Handled {
+    match handled {
+        Ok(Handled::Response(response)) => Handled::Response(finish_response(Ok(response))),
+        Ok(handled) => handled,
+        Err(e) => Handled::Response(finish_response(Err(e))),
+    }
+}
+
+/// Adds the headers sent with every response, turning an error into a response first.
+fn finish_response(response: Result<Response, error::HttpError>) -> Response {
+    let mut response = response.unwrap_or_else(|e| error_response(e.status, e.message));
+    response.header.header_lines.insert(ResponseHeaderField::Server, SERVER_SOFTWARE.to_string());
+    response.header.header_lines.insert(ResponseHeaderField::Date, now_1123());
+    response
+}
+
 fn heartbeat(overloaded: bool) -> Result<Response, error::HttpError> {
     let mut header_lines = HashMap::new();
     header_lines.insert(ResponseHeaderField::ContentLength, "0".to_string());
@@ -118,63 +692,519 @@ fn heartbeat(overloaded: bool) -> Result<Response, error::HttpError> {
                 header_lines,
             },
             body: String::new(),
+            stream: None,
         }
     )
 }
 
-fn content_negotiation(request_target: RequestTarget, header_lines: &std::collections::HashMap<RequestHeaderField, String>) -> Result<(path::PathBuf, std::fs::Metadata), error::HttpError> {
-    let path = request_target.path;
-    if request_target.is_dir {
-        if let Some(user_agent) = header_lines.get(&RequestHeaderField::UserAgent) {
-            if user_agent.contains("iPhone") || user_agent.contains("Mobile") {
-                let mobile_path = path.join("index_m.html");
-                let metadata = metadata_or_400(&mobile_path);
-                if let Ok(metadata) = metadata {
-                    return Ok((mobile_path, metadata));
-                }
-            }
+fn cache_status(files: &files::Files) -> Result<Response, error::HttpError> {
+    let body = files.stats().to_string();
+    let mut header_lines = HashMap::new();
+    header_lines.insert(ResponseHeaderField::ContentLength, body.len().to_string());
+    header_lines.insert(ResponseHeaderField::ContentType, "text/plain".to_string());
+    Ok(
+        Response {
+            header: ResponseHeader {
+                status_line: StatusLine {
+                    status_code: StatusCode::Ok,
+                    http_version: String::from(HTTP_VERSION),
+                },
+                header_lines,
+            },
+            body,
+            stream: None,
         }
-        let index_path = path.join("index.html");
-        let metadata = metadata_or_400(&index_path)?;
-        return Ok((index_path, metadata));
+    )
+}
+
+/// Refuses a request with a method the resource does not support; only scripts accept `POST`.
+fn method_not_allowed() -> Response {
+    let mut response = error_response(StatusCode::MethodNotAllowed, None::<String>);
+    response.header.header_lines.insert(ResponseHeaderField::Other("Allow".to_string()), "GET".to_string());
+    response
+}
+
+/// Finds the first of the `DirectoryIndex` files that exists in a directory. Mobile browsers are served
+/// `index_m.html` in preference, where there is one.
+fn find_index(vfs: &dyn vfs::Vfs, directory: &path::Path, directory_config: &DirectoryConfig, header_lines: &HashMap<RequestHeaderField, String>) -> Option<path::PathBuf> {
+    let mobile = header_lines.get(&RequestHeaderField::UserAgent)
+        .map(|user_agent| user_agent.contains("iPhone") || user_agent.contains("Mobile"))
+        .unwrap_or(false);
+    let mobile_index = if mobile { Some("index_m.html") } else { None };
+    mobile_index.into_iter()
+        .chain(directory_config.directory_index.iter().map(|index| index.as_str()))
+        .map(|index| directory.join(index))
+        .find(|index| vfs.stat(index).map(|metadata| !metadata.is_dir).unwrap_or(false))
+}
+
+/// Whether `Options +Includes` is set and `AddOutputFilter INCLUDES` names the extension of the document.
+fn includes_enabled(directory_config: &DirectoryConfig, path: &path::Path) -> bool {
+    let extension = match path.extension().and_then(|extension| extension.to_str()) {
+        Some(extension) => extension.to_lowercase(),
+        None => return false,
+    };
+    directory_config.options.includes && directory_config.output_filters.get(&extension)
+        .map(|filters| filters.iter().any(|filter| filter == "INCLUDES"))
+        .unwrap_or(false)
+}
+
+/// Whether a file is run as a CGI script: `SetHandler` names `cgi-script`, or else `AddHandler` does for its extension.
+fn is_cgi_script(directory_config: &DirectoryConfig, path: &path::Path) -> bool {
+    let handler = match &directory_config.handler {
+        Some(handler) => Some(handler),
+        None => path.extension()
+            .and_then(|extension| extension.to_str())
+            .and_then(|extension| directory_config.handlers.get(&extension.to_lowercase())),
+    };
+    handler.map(|handler| handler == CGI_SCRIPT).unwrap_or(false)
+}
+
+/// Lists the entries of a directory that has no index file, for `Options +Indexes`.
+fn directory_listing(vfs: &dyn vfs::Vfs, directory: &path::Path, url_path: &str) -> Result<Response, error::HttpError> {
+    let mut entries = vfs.list_dir(directory).map_err(|_| error::HttpError { status: StatusCode::NotFound, message: None })?;
+    entries.sort();
+    let title = ssi::escape_html(url_path);
+    let mut body = format!("<html><head><title>Index of {0}</title></head><body><h1>Index of {0}</h1><ul>", title);
+    if url_path != "/" {
+        body.push_str("<li><a href=\"../\">../</a></li>");
     }
-    let metadata = metadata_or_400(&path)?;
-    Ok((path, metadata))
+    for entry in entries {
+        let mut name = entry.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+        if vfs.stat(&entry).map(|metadata| metadata.is_dir).unwrap_or(false) {
+            name.push('/');
+        }
+        body.push_str(&format!("<li><a href=\"{0}\">{0}</a></li>", ssi::escape_html(&name)));
+    }
+    body.push_str("</ul></body></html>");
+
+    let mut header_lines = HashMap::new();
+    header_lines.insert(ResponseHeaderField::ContentLength, body.len().to_string());
+    header_lines.insert(ResponseHeaderField::ContentType, "text/html".to_string());
+    Ok(
+        Response {
+            header: ResponseHeader {
+                status_line: StatusLine {
+                    status_code: StatusCode::Ok,
+                    http_version: String::from(HTTP_VERSION),
+                },
+                header_lines,
+            },
+            body,
+            stream: None,
+        }
+    )
 }
 
-fn metadata_or_400(path: &path::PathBuf) -> Result<std::fs::Metadata, error::HttpError> {
-    path.metadata().map_err(|_| error::HttpError { status: StatusCode::NotFound, message: None })
+/// Refuses a request that `Require` denies, or whose body is larger than `LimitRequestBody` allows.
+fn check_allowed(directory_config: &DirectoryConfig, request: &Request) -> Result<(), error::HttpError> {
+    if !directory_config.access_granted {
+        return Err(error::HttpError { status: StatusCode::Forbidden, message: None });
+    }
+    if directory_config.limit_request_body.map(|limit| request.body.len() > limit).unwrap_or(false) {
+        return Err(error::HttpError { status: StatusCode::PayloadTooLarge, message: None });
+    }
+    Ok(())
 }
 
-fn get_virtual_host<'a>(virtual_hosts: &'a Vec<VirtualHost>, host: &str) -> &'a VirtualHost {
-    for virtual_host in virtual_hosts.iter() {
-        if let Some(server_name) = virtual_host.directives.get(&Directive::ServerName) {
-            if server_name == host {
-                return &virtual_host;
-            }
+/// Applies `Header` directives to a successful response.
+fn apply_headers(response: &mut Response, headers: &[HeaderAction]) {
+    let header_lines = &mut response.header.header_lines;
+    for action in headers {
+        match action {
+            HeaderAction::Set(field, value) => { header_lines.insert(field.clone(), value.clone()); },
+            HeaderAction::Append(field, value) => {
+                let appended = match header_lines.get(field) {
+                    Some(existing) => format!("{}, {}", existing, value),
+                    None => value.clone(),
+                };
+                header_lines.insert(field.clone(), appended);
+            },
+            HeaderAction::Unset(field) => { header_lines.remove(field); },
         }
     }
-    return &virtual_hosts.first().unwrap();
 }
 
-fn parse_path(root_path: &path::PathBuf, request_target: &str) -> Result<RequestTarget, error::HttpError> {
-    let is_dir = request_target.ends_with("/");
-    let mut request_target = path::Path::new(&request_target);
-    if request_target.has_root() {
-        request_target = request_target.strip_prefix("/").unwrap();
+fn metadata_or_404(vfs: &dyn vfs::Vfs, path: &path::Path) -> Result<vfs::Metadata, error::HttpError> {
+    vfs.stat(path).map_err(|_| error::HttpError { status: StatusCode::NotFound, message: None })
+}
+
+/// Chooses the virtual host for a request from those declared for the address that accepted it: the first whose name
+/// matches the `Host`, or else the first of them.
+fn get_virtual_host<'a>(server_config: &'a ServerConfig, local_addr: SocketAddr, host: &str) -> &'a VirtualHost {
+    let virtual_hosts = server_config.virtual_hosts_for(local_addr);
+    virtual_hosts.iter()
+        .find(|virtual_host| virtual_host.matches_name(host))
+        .or_else(|| virtual_hosts.first())
+        .copied()
+        .unwrap_or(&server_config.main_server)
+}
+
+/// Decodes the path of a request and resolves it to the path it names: `%XX` escapes are decoded, repeated `/` are
+/// merged and `.` and `..` segments are resolved, keeping any trailing `/`. A path that does not start with `/`, that
+/// climbs above the root or that decodes to a NUL or to invalid UTF-8 is refused with `400 Bad Request`.
+fn normalize_path(request_path: &str) -> Result<String, error::HttpError> {
+    let bad_request = || error::HttpError { status: StatusCode::BadRequest, message: None };
+    if !request_path.starts_with('/') {
+        return Err(bad_request());
     }
-    let path = match path::Path::join(&root_path, request_target).canonicalize() {
-        Ok(path) => path,
-        Err(_) => return Err(error::HttpError { status: StatusCode::NotFound, message: None }),
-    };
-    // println!("-- path: {:#?} --", path);
-    if !path.starts_with(root_path) {
-        return Err(error::HttpError { status: StatusCode::Forbidden, message: None })
+    let bytes = request_path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'%' {
+            decoded.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+        let byte = bytes.get(i + 1..i + 3)
+            .filter(|hex| hex.iter().all(|digit| digit.is_ascii_hexdigit()))
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok())
+            .ok_or_else(bad_request)?;
+        decoded.push(byte);
+        i += 3;
     }
-    Ok(RequestTarget { path, is_dir })
+    let decoded = String::from_utf8(decoded).map_err(|_| bad_request())?;
+    if decoded.contains('\0') {
+        return Err(bad_request());
+    }
+    let mut segments = Vec::new();
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => {},
+            ".." => { segments.pop().ok_or_else(bad_request)?; },
+            segment => segments.push(segment),
+        }
+    }
+    let mut normalized = format!("/{}", segments.join("/"));
+    let last = decoded.rsplit('/').next().unwrap_or_default();
+    if !segments.is_empty() && (last.is_empty() || last == "." || last == "..") {
+        normalized.push('/');
+    }
+    Ok(normalized)
+}
+
+/// Maps a request path onto the document root. When no file has the whole path, the path is walked from the root until
+/// it reaches a file, and the rest of the path is returned as path info for that file, as for CGI scripts.
+fn parse_path(document_root: &DocumentRoot, request_target: &str) -> Result<RequestTarget, error::HttpError> {
+    let root_path = &document_root.path;
+    let vfs = document_root.vfs.as_ref();
+    let not_found = || error::HttpError { status: StatusCode::NotFound, message: None };
+    let relative = request_target.trim_start_matches('/');
+    if let Ok(path) = vfs.canonicalize(&root_path.join(relative)) {
+        // println!("-- path: {:#?} --", path);
+        if !path.starts_with(root_path) {
+            return Err(error::HttpError { status: StatusCode::Forbidden, message: None })
+        }
+        return Ok(RequestTarget { path, is_dir: request_target.ends_with('/'), path_info: String::new() });
+    }
+
+    let segments: Vec<&str> = relative.split('/').collect();
+    for (i, segment) in segments.iter().enumerate() {
+        let path = vfs.canonicalize(&root_path.join(segments[..=i].join("/"))).map_err(|_| not_found())?;
+        if !path.starts_with(root_path) {
+            return Err(error::HttpError { status: StatusCode::Forbidden, message: None })
+        }
+        if segment.is_empty() || vfs.stat(&path).map_err(|_| not_found())?.is_dir {
+            continue;
+        }
+        let rest = &segments[i + 1..];
+        // path info is handed to scripts as a path below the document root, so it may not climb out of it
+        if rest.iter().any(|segment| *segment == "." || *segment == "..") {
+            return Err(not_found());
+        }
+        return Ok(RequestTarget { path, is_dir: false, path_info: format!("/{}", rest.join("/")) });
+    }
+    Err(not_found())
 }
 
 struct RequestTarget {
     path: path::PathBuf,
     is_dir: bool,
+    /// The part of the request path below the file it names, starting with `/`, or empty if there is none.
+    path_info: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+    use super::*;
+
+    fn server_config() -> ServerConfig {
+        let virtual_host = VirtualHost {
+            server_name: Some("www.example.com".to_string()),
+            document_root: Some(concat!(env!("CARGO_MANIFEST_DIR"), "/www").to_string()),
+            script_aliases: vec!(ScriptAlias {
+                path: "/cgi-bin/".to_string(),
+                directory: path::Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/www/cgi-bin")).canonicalize().unwrap(),
+            }),
+            ..VirtualHost::default()
+        };
+        ServerConfig {
+            listen: vec!("127.0.0.1:3333".parse().unwrap()),
+            thread_pool_size: 1,
+            cache: CacheConfig {
+                size: 1024 * 1024,
+                max_file_size: 1024 * 1024,
+                revalidate_interval: time::Duration::from_secs(1),
+                watch: false,
+            },
+            virtual_hosts: vec!(virtual_host.clone()),
+            main_server: virtual_host,
+        }
+    }
+
+    fn get(path: &str) -> Request {
+        let raw = format!("GET {} HTTP/1.1\r\nHost: www.example.com\r\n\r\n", path);
+        parse_test_request(&raw, "127.0.0.1:50000", "127.0.0.1:3333")
+    }
+
+    fn post(path: &str, body: &str) -> Request {
+        let raw = format!("POST {} HTTP/1.1\r\nHost: www.example.com\r\nContent-Length: {}\r\n\r\n{}", path, body.len(), body);
+        parse_test_request(&raw, "127.0.0.1:50000", "127.0.0.1:3333")
+    }
+
+    /// Loads a configuration serving `www` on 127.0.0.1:3333 with the given directives added, as the server would.
+    fn host_with_config(directives: &str) -> Host {
+        load_host(&format!("Listen 127.0.0.1:3333\nDocumentRoot {}/www\n{}", env!("CARGO_MANIFEST_DIR"), directives))
+    }
+
+    fn load_host(config: &str) -> Host {
+        let name = format!("host-test-{}-{:?}.conf", std::process::id(), thread::current().id());
+        let config_path = std::env::temp_dir().join(name);
+        std::fs::write(&config_path, config).unwrap();
+        let server_config = load_config(&config_path, &[]).unwrap();
+        std::fs::remove_file(config_path).unwrap();
+        Host::new(server_config)
+    }
+
+    #[test]
+    fn file_cache_is_shared_between_threads() {
+        let host = Arc::new(Host::new(server_config()));
+        for _ in 0..2 {
+            let host = host.clone();
+            let response = thread::spawn(move || host.handle(&get("/index.html"), false)).join().unwrap();
+            assert!(matches!(response.header.status_line.status_code, StatusCode::Ok));
+        }
+        let stats = host.files.stats();
+        assert_eq!(stats.misses, 1);
+        assert_eq!(stats.hits, 1);
+        assert_eq!(stats.entries, 1);
+    }
+
+    #[test]
+    fn cache_is_warmed_from_globs_and_watched_when_enabled() {
+        let root = std::env::temp_dir().join(format!("host-warm-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        for (name, content) in [("a.html", "a"), ("b.html", "b"), ("c.txt", "c"), ("sub/d.html", "d")] {
+            std::fs::write(root.join(name), content).unwrap();
+        }
+        let config = format!("Listen 127.0.0.1:3333\nDocumentRoot {}\nCacheWarm *.html /sub/*.html\n", root.display());
+        let host = load_host(&config);
+        host.warm_cache();
+        let stats = host.files.stats();
+        assert_eq!((stats.entries, stats.size, stats.misses), (3, 3, 0));
+        assert!(host.watch_document_roots().unwrap().is_none());
+        host.handle(&get("/a.html"), false);
+        assert_eq!(host.files.stats().hits, 1);
+
+        let host = load_host(&format!("{}CacheWatch On\nCacheRevalidateInterval 3600\n", config));
+        host.warm_cache();
+        let mut watcher = host.watch_document_roots().unwrap().unwrap();
+        std::fs::write(root.join("sub/d.html"), "changed").unwrap();
+        watcher.read_events().unwrap();
+        assert_eq!((host.files.stats().entries, host.files.stats().invalidations), (2, 1));
+        assert_eq!(host.handle(&get("/sub/d.html"), false).into_body().unwrap(), "changed");
+
+        // the main server is warmed along with the virtual hosts
+        let host = load_host(&format!(
+            "{}<VirtualHost *:3333>\n    DocumentRoot {}/sub\n    CacheWarm d.html\n</VirtualHost>\n", config, root.display(),
+        ));
+        host.warm_cache();
+        assert_eq!(host.files.stats().entries, 3);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn cache_status_is_served_only_where_its_handler_is_set() {
+        let status = |host: &Host, path: &str| host.handle(&get(path), false).header.status_line.status_code.code();
+        assert_eq!(status(&host_with_config(""), "/cache"), 404);
+
+        let host = host_with_config("<Location /server-cache>\n    SetHandler cache-status\n</Location>\n");
+        host.handle(&get("/index.html"), false);
+        let response = host.handle(&get("//server-cache"), false);
+        assert_eq!(response.header.status_line.status_code.code(), 200);
+        assert_eq!(response.into_body().unwrap(), host.files.stats().to_string());
+        assert_eq!(status(&host, "/cache"), 404);
+
+        let host = host_with_config("<Location /server-cache>\n    SetHandler cache-status\n    Require all denied\n</Location>\n");
+        assert_eq!(status(&host, "/server-cache"), 403);
+    }
+
+    #[test]
+    fn path_info_is_passed_to_scripts_and_accepted_by_files_when_enabled() {
+        let host = Host::new(server_config());
+        let response = host.handle(&get("/cgi-bin/printenv.pl/users/42"), false);
+        assert!(matches!(response.header.status_line.status_code, StatusCode::Ok));
+        let body = response.into_body().unwrap();
+        assert!(body.contains("PATH_INFO=\"/users/42\"\n"));
+        assert!(body.contains("SCRIPT_NAME=\"/cgi-bin/printenv.pl\"\n"));
+        assert!(matches!(host.handle(&get("/cgi-bin/printenv.pl/a/../../x"), false).header.status_line.status_code, StatusCode::NotFound));
+        assert!(matches!(host.handle(&get("/index.html/extra"), false).header.status_line.status_code, StatusCode::NotFound));
+
+        let mut server_config = server_config();
+        server_config.virtual_hosts[0].directory.accept_path_info = Some(true);
+        let host = Host::new(server_config);
+        assert!(matches!(host.handle(&get("/index.html/extra"), false).header.status_line.status_code, StatusCode::Ok));
+    }
+
+    #[test]
+    fn scripts_redirect_and_write_non_parsed_responses() {
+        let host = Host::new(server_config());
+        let response = host.handle(&get("/cgi-bin/redirect.pl?/index.html"), false);
+        assert!(matches!(response.header.status_line.status_code, StatusCode::Ok));
+        assert_eq!(response.into_body().unwrap(), std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/www/index.html")).unwrap());
+
+        // each redirect passes the rest of the query string on as the next location
+        let chain = |redirects: usize| format!("{}/index.html", "/cgi-bin/redirect.pl?".repeat(redirects));
+        assert!(matches!(host.handle(&get(&chain(MAX_INTERNAL_REDIRECTS)), false).header.status_line.status_code, StatusCode::Ok));
+        assert!(matches!(host.handle(&get(&chain(MAX_INTERNAL_REDIRECTS + 1)), false).header.status_line.status_code, StatusCode::InternalServerError));
+
+        let response = host.handle(&get("/cgi-bin/nph-hello.pl"), false);
+        assert_eq!(String::from_utf8(write_response(response).unwrap().into_vec()).unwrap(), "HTTP/1.1 202 Accepted\r\nContent-Type: text/plain\r\nConnection: close\r\n\r\nhello\n");
+    }
+
+    #[test]
+    fn aliases_see_the_normalized_path() {
+        let host = Host::new(server_config());
+        for path in ["//cgi-bin/printenv.pl", "/./cgi-bin/printenv.pl", "/nested/../cgi-bin/printenv.pl", "/%63gi-bin//printenv.pl"] {
+            let response = host.handle(&get(path), false);
+            assert_eq!(response.header.status_line.status_code.code(), 200, "{}", path);
+            let body = response.into_body().unwrap();
+            assert!(!body.starts_with("#!"), "{} served the script's source", path);
+            assert!(body.contains("SCRIPT_NAME=\"/cgi-bin/printenv.pl\"\n"), "{}", path);
+        }
+        for path in ["/../cgi-bin/printenv.pl", "/cgi-bin/%00", "/cgi-bin/%zz"] {
+            assert_eq!(host.handle(&get(path), false).header.status_line.status_code.code(), 400, "{}", path);
+        }
+        assert_eq!(normalize_path("/a//b/./c/..").unwrap(), "/a/b/");
+        assert_eq!(normalize_path("/a/%2e%2e").unwrap(), "/");
+    }
+
+    #[test]
+    fn proxy_passes_match_whole_segments_of_the_normalized_path() {
+        // nothing listens on the port, so requests that reach the proxy fail with 503 rather than 404
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let mut server_config = server_config();
+        server_config.virtual_hosts[0].proxy_passes.push(ProxyPass {
+            path: "/app".to_string(),
+            protocol: Protocol::FastCgi,
+            address: BackendAddress::Tcp(closed.to_string()),
+            target: "/srv/app".to_string(),
+        });
+        let host = Host::new(server_config);
+        for path in ["/app", "/app/index.php", "//app/index.php", "/x/../app/index.php"] {
+            assert_eq!(host.handle(&get(path), false).header.status_line.status_code.code(), 503, "{}", path);
+        }
+        assert_eq!(host.handle(&get("/application"), false).header.status_line.status_code.code(), 404);
+    }
+
+    #[test]
+    fn location_sections_see_the_normalized_path() {
+        let host = host_with_config(concat!(
+            "<Location /nested>\n",
+            "    Require all denied\n",
+            "</Location>\n",
+            "<Location /cgi-bin/>\n",
+            "    SetHandler cgi-script\n",
+            "    Options +ExecCGI\n",
+            "</Location>\n",
+        ));
+        for path in ["/nested/index.html", "//nested/index.html", "/./nested/", "/%6eested/index.html", "/cgi-bin/../nested/"] {
+            assert_eq!(host.handle(&get(path), false).header.status_line.status_code.code(), 403, "{}", path);
+        }
+        for path in ["/cgi-bin/printenv.pl", "//cgi-bin/printenv.pl", "/./cgi-bin//printenv.pl"] {
+            let response = host.handle(&get(path), false);
+            assert_eq!(response.header.status_line.status_code.code(), 200, "{}", path);
+            assert!(!response.into_body().unwrap().starts_with("#!"), "{} served the script's source", path);
+        }
+    }
+
+    #[test]
+    fn only_files_mapped_to_a_handler_run_as_scripts() {
+        let status = |host: &Host, request: Request| host.handle(&request, false).header.status_line.status_code.code();
+        let host = Host::new(server_config());
+        assert_eq!(status(&host, post("/cgi-bin/uppercase.pl", "a=1")), 200);
+        assert_eq!(status(&host, post("/cgi-bin/", "")), 403);
+        let response = host.handle(&post("/index.html", "a=1"), false);
+        assert_eq!(response.header.status_line.status_code.code(), 405);
+        assert_eq!(response.header.header_lines.get(&ResponseHeaderField::Other("Allow".to_string())).map(|allow| allow.as_str()), Some("GET"));
+
+        // without the ScriptAlias, an executable file is served as it is
+        let mut server_config = server_config();
+        server_config.virtual_hosts[0].script_aliases.clear();
+        let host = Host::new(server_config.clone());
+        let response = host.handle(&get("/cgi-bin/printenv.pl"), false);
+        assert_eq!(response.header.status_line.status_code.code(), 200);
+        assert!(response.into_body().unwrap().starts_with("#!"));
+        assert_eq!(status(&host, post("/cgi-bin/printenv.pl", "")), 405);
+
+        let directory = &mut server_config.virtual_hosts[0].directory;
+        directory.handlers.insert("pl".to_string(), CGI_SCRIPT.to_string());
+        assert_eq!(status(&Host::new(server_config.clone()), get("/cgi-bin/printenv.pl")), 403);
+        server_config.virtual_hosts[0].directory.options.exec_cgi = true;
+        let host = Host::new(server_config.clone());
+        let response = host.handle(&get("/cgi-bin/printenv.pl/users/42"), false);
+        assert_eq!(response.header.status_line.status_code.code(), 200);
+        assert!(response.into_body().unwrap().contains("SCRIPT_NAME=\"/cgi-bin/printenv.pl\"\n"));
+
+        server_config.virtual_hosts[0].directory.handler = Some("default-handler".to_string());
+        assert_eq!(status(&Host::new(server_config), post("/cgi-bin/printenv.pl", "")), 405);
+    }
+
+    #[test]
+    fn includes_are_expanded_only_where_options_and_the_output_filter_allow() {
+        use std::os::unix::fs::PermissionsExt;
+        let root = std::env::temp_dir().join(format!("host-includes-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("cgi-bin")).unwrap();
+        let page = "<!--#set var=\"x\" value=\"1\" --><!--#echo var=\"x\" -->|<!--#include virtual=\"/cgi-bin/hello.sh\" -->";
+        std::fs::write(root.join("page.shtml"), page).unwrap();
+        std::fs::write(root.join("cgi-bin/hello.sh"), "#!/bin/sh\nprintf 'Content-Type: text/plain\\n\\nhello'\n").unwrap();
+        std::fs::set_permissions(root.join("cgi-bin/hello.sh"), std::fs::Permissions::from_mode(0o755)).unwrap();
+        let host = |directives: &str| load_host(&format!(
+            "Listen 127.0.0.1:3333\nDocumentRoot {0}\nScriptAlias /cgi-bin/ {0}/cgi-bin/\n{1}", root.display(), directives,
+        ));
+
+        // the result is streamed as it is expanded, and included scripts are run rather than sent as they are
+        let response = host("Options +Includes\nAddOutputFilter INCLUDES .shtml\n").handle(&get("/page.shtml"), false);
+        assert_eq!(response.header.status_line.status_code.code(), 200);
+        assert!(response.stream.is_some());
+        assert!(!response.header.header_lines.contains_key(&ResponseHeaderField::ContentLength));
+        assert_eq!(response.into_body().unwrap(), "1|hello");
+
+        // what a client may not request directly is not included for it either
+        std::fs::create_dir_all(root.join("private")).unwrap();
+        std::fs::write(root.join("private/secret.html"), "secret").unwrap();
+        std::fs::write(root.join("private/run.sh"), "#!/bin/sh\nprintf 'Content-Type: text/plain\\n\\nran'\n").unwrap();
+        std::fs::set_permissions(root.join("private/run.sh"), std::fs::Permissions::from_mode(0o755)).unwrap();
+        std::fs::write(root.join("denied.shtml"), concat!(
+            "<!--#config errmsg=\"[denied]\" -->",
+            "<!--#include virtual=\"/private/secret.html\" -->|<!--#exec cgi=\"/private/run.sh\" -->|",
+            "<!--#include virtual=\"/cgi-bin/hello.sh\" -->",
+        )).unwrap();
+        let denied = host(concat!(
+            "Options +Includes +ExecCGI\nAddOutputFilter INCLUDES .shtml\nAddHandler cgi-script .sh\n",
+            "<Location /private>\n    Require all denied\n</Location>\n<Location /cgi-bin/hello.sh>\n    Require all denied\n</Location>\n",
+        ));
+        assert_eq!(denied.handle(&get("/private/secret.html"), false).header.status_line.status_code.code(), 403);
+        assert_eq!(denied.handle(&get("/denied.shtml"), false).into_body().unwrap(), "[denied]|[denied]|[denied]");
+
+        for directives in ["Options +Includes\n", "Options -Includes\nAddOutputFilter INCLUDES .shtml\n"] {
+            let response = host(directives).handle(&get("/page.shtml"), false);
+            assert_eq!(response.header.status_line.status_code.code(), 200);
+            assert_eq!(response.into_body().unwrap(), page, "{}", directives);
+        }
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
 }
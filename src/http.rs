@@ -1,57 +1,143 @@
 use std::collections::HashMap;
 use std::convert::TryInto;
+use std::io::{self, Read, Write};
 use std::net::SocketAddr;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::str;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use crate::error::Error;
 
 pub const HTTP_VERSION: &str = "HTTP/1.1";
+pub const SERVER_SOFTWARE: &str = "Rust/0.1";
 const CRLF: &str = "\r\n";
 
+/// Arbitrarily choose a maximum response body length, after which responses will be encoded using chunked transfer
+/// coding. This is not necessarily the intended use case for chunked transfer coding, but will serve as a demo.
+const CHUNK_LEN: usize = 1024;
+
 pub fn write_response(response: Response) -> Result<Box<[u8]>, Error> {
-    let chunk_len: usize = 1024;
-    if response.header.header_lines.get(&ResponseHeaderField::ContentLength)
-        .and_then(|content_length| u32::from_str(content_length).ok())
-        .map(|content_length| content_length > chunk_len.try_into().unwrap())
-        .unwrap_or(true)
-    {
-        // arbitrarily choose a maximum response body length, after which responses will be encoded using chunked transfer coding
-        // this is not necessarily the intended use case for chunked transfer coding, but will serve as a demo
-        write_chunked(response, chunk_len)
-    } else {
-        Ok(response.to_string().into_boxed_str().into_boxed_bytes())
+    let mut bytes = Vec::new();
+    write_response_to(&mut bytes, response)?;
+    Ok(bytes.into_boxed_slice())
+}
+
+/// Writes a response to `writer`. A streamed body is written as it is read, with chunked transfer coding unless the
+/// response gives its `Content-Length`.
+pub fn write_response_to(writer: &mut impl Write, mut response: Response) -> Result<(), Error> {
+    let content_length = content_length(&response.header);
+    match response.stream.take() {
+        Some(mut stream) => {
+            let mut framing = Framing::write_head(writer, response.header, stream.non_parsed)?;
+            let mut chunk = [0; CHUNK_LEN];
+            while !framing.is_complete() {
+                let chunk_len = match stream.read(&mut chunk) {
+                    Ok(chunk_len) => chunk_len,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e.into()),
+                };
+                if chunk_len == 0 {
+                    framing.finish(writer)?;
+                    break;
+                }
+                framing.write(writer, &chunk[..chunk_len])?;
+            }
+        },
+        None if content_length.map(|content_length| content_length > CHUNK_LEN.try_into().unwrap()).unwrap_or(true) => {
+            let body = std::mem::take(&mut response.body);
+            write_chunked(writer, response, io::Cursor::new(body))?;
+        },
+        None => writer.write_all(response.to_string().as_bytes())?,
     }
+    Ok(())
 }
 
-fn write_chunked(mut response: Response, chunk_len: usize) -> Result<Box<[u8]>, Error> {
+fn write_chunked(writer: &mut impl Write, mut response: Response, mut body: impl Read) -> Result<(), Error> {
     response.header.header_lines.remove(&ResponseHeaderField::ContentLength);
     response.header.header_lines.insert(ResponseHeaderField::TransferEncoding, "chunked".to_string());
-    let mut bytes = Vec::from(response.header.to_string().as_bytes());
-    bytes.extend(CRLF.as_bytes());
+    writer.write_all(format!("{}{}", response.header, CRLF).as_bytes())?;
 
-    let mut body_iter = response.body.as_bytes().chunks_exact(chunk_len);
+    let mut chunk = [0; CHUNK_LEN];
     loop {
-        if let Some(chunk) = body_iter.next() {
-            bytes = write_chunk(chunk, chunk_len, bytes);
-        } else {
-            break;
+        let chunk_len = match body.read(&mut chunk) {
+            Ok(chunk_len) => chunk_len,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        write_chunk(writer, &chunk[..chunk_len])?;
+        if chunk_len == 0 {
+            return Ok(());
         }
     }
-    let remainder = body_iter.remainder();
-    if remainder.len() > 0 {
-        bytes = write_chunk(remainder, remainder.len(), bytes);
+}
+
+/// How a streamed response body is written, which is decided once the head of the response has been written. The body
+/// is written as it becomes available, a piece at a time.
+#[derive(Debug)]
+pub enum Framing {
+    /// Sent as it is, as the whole response, by a non-parsed-header CGI script.
+    Raw,
+    /// Sent as it is, up to the `Content-Length` given; holds the number of bytes still to be sent.
+    Length(u64),
+    /// Sent with chunked transfer coding.
+    Chunked,
+}
+impl Framing {
+    /// Writes the head of a response whose body is streamed, with chunked transfer coding unless it gives its
+    /// `Content-Length`. Nothing is written for a non-parsed response, whose head is part of the stream.
+    pub fn write_head(writer: &mut impl Write, mut header: ResponseHeader, non_parsed: bool) -> io::Result<Framing> {
+        if non_parsed {
+            return Ok(Framing::Raw);
+        }
+        let framing = match content_length(&header) {
+            Some(content_length) => Framing::Length(content_length),
+            None => {
+                header.header_lines.remove(&ResponseHeaderField::ContentLength);
+                header.header_lines.insert(ResponseHeaderField::TransferEncoding, "chunked".to_string());
+                Framing::Chunked
+            },
+        };
+        writer.write_all(format!("{}{}", header, CRLF).as_bytes())?;
+        Ok(framing)
     }
-    bytes = write_chunk(&[], 0, bytes);
 
-    Ok(bytes.into_boxed_slice())
+    /// Writes the next piece of the body; anything beyond the `Content-Length` is left out.
+    pub fn write(&mut self, writer: &mut impl Write, bytes: &[u8]) -> io::Result<()> {
+        match self {
+            Framing::Raw => writer.write_all(bytes),
+            Framing::Length(bytes_left) => {
+                let len = bytes.len().min((*bytes_left).try_into().unwrap_or(usize::MAX));
+                *bytes_left -= len as u64;
+                writer.write_all(&bytes[..len])
+            },
+            Framing::Chunked if bytes.is_empty() => Ok(()),
+            Framing::Chunked => write_chunk(writer, bytes),
+        }
+    }
+
+    /// Ends the body once the stream has ended.
+    pub fn finish(&mut self, writer: &mut impl Write) -> io::Result<()> {
+        match self {
+            Framing::Chunked => write_chunk(writer, &[]),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether the whole body has been written, before the stream has ended, as given by its `Content-Length`.
+    pub fn is_complete(&self) -> bool {
+        matches!(self, Framing::Length(0))
+    }
 }
 
-fn write_chunk(chunk: &[u8], chunk_len: usize, mut bytes: Vec<u8>) -> Vec<u8> {
-    bytes.extend(format!("{:x}", chunk_len).as_bytes());
-    bytes.extend(CRLF.as_bytes());
+fn content_length(header: &ResponseHeader) -> Option<u64> {
+    header.header_lines.get(&ResponseHeaderField::ContentLength).and_then(|content_length| u64::from_str(content_length).ok())
+}
+
+fn write_chunk(writer: &mut impl Write, chunk: &[u8]) -> io::Result<()> {
+    let mut bytes = Vec::from(format!("{:x}{}", chunk.len(), CRLF).as_bytes());
     bytes.extend(chunk);
     bytes.extend(CRLF.as_bytes());
-    bytes
+    writer.write_all(&bytes)
 }
 
 pub fn try_parse_request(latest: &[u8], incremental_request: IncrementalRequest) -> Result<IncrementalRequest, Error> {
@@ -196,6 +282,7 @@ pub fn error_response<T>(status_code: StatusCode, message: Option<T>) -> Respons
             header_lines,
         },
         body: String::new(),
+        stream: None,
     }
 }
 
@@ -206,21 +293,62 @@ pub enum IncrementalResponse {
     Done
 }
 
-#[derive(Clone, Debug)]
+#[derive(Debug)]
 pub struct Response {
     pub header: ResponseHeader,
     pub body: String,
+    /// A body that is sent as it is read, such as the output of a CGI script, in place of `body`.
+    pub stream: Option<BodyStream>,
 }
-impl ToString for Response {
-    fn to_string(&self) -> String {
-        let mut s = String::new();
-        s.push_str(&self.header.to_string());
-        s.push_str(CRLF);
-        if !self.body.is_empty() {
-            s.push_str(&self.body);
-            s.push_str(CRLF);
+impl Response {
+    /// Reads the whole body, including any that is streamed, as tests do.
+    #[cfg(test)]
+    pub fn into_body(mut self) -> io::Result<String> {
+        if let Some(mut stream) = self.stream.take() {
+            stream.read_to_string(&mut self.body)?;
         }
-        s
+        Ok(self.body)
+    }
+}
+impl std::fmt::Display for Response {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{}{}", self.header, CRLF, self.body)
+    }
+}
+
+/// A response body read from its source as it is sent.
+pub struct BodyStream {
+    reader: Box<dyn Read + Send>,
+    /// The file descriptor the body is read from, for a source whose reads can be waited on, such as a pipe.
+    fd: Option<RawFd>,
+    /// Whether the stream is the whole response, status line and headers included, as written by a non-parsed-header
+    /// CGI script. Such responses are sent as they are and the `ResponseHeader` only describes them.
+    pub non_parsed: bool,
+}
+impl BodyStream {
+    pub fn new(reader: impl Read + Send + 'static, non_parsed: bool) -> BodyStream {
+        BodyStream { reader: Box::new(reader), fd: None, non_parsed }
+    }
+
+    /// A body read straight from a file descriptor, which the select model waits on rather than reading it to the end
+    /// at once.
+    pub fn pollable(reader: impl Read + AsRawFd + Send + 'static, non_parsed: bool) -> BodyStream {
+        let fd = Some(reader.as_raw_fd());
+        BodyStream { reader: Box::new(reader), fd, non_parsed }
+    }
+
+    pub fn as_raw_fd(&self) -> Option<RawFd> {
+        self.fd
+    }
+}
+impl Read for BodyStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+impl std::fmt::Debug for BodyStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BodyStream").field("non_parsed", &self.non_parsed).finish()
     }
 }
 
@@ -229,34 +357,47 @@ pub struct ResponseHeader {
     pub status_line: StatusLine,
     pub header_lines: HashMap<ResponseHeaderField, String>,
 }
-impl ToString for ResponseHeader {
-    fn to_string(&self) -> String {
-        let mut s = String::new();
-        s.push_str(&self.status_line.to_string());
+impl std::fmt::Display for ResponseHeader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.status_line)?;
         for (field, value) in self.header_lines.iter() {
-            s.push_str(&field.to_string());
-            s.push_str(": ");
-            s.push_str(value.trim());
-            s.push_str(CRLF);
+            write!(f, "{}: {}{}", field, value.trim(), CRLF)?;
         }
-        s
+        Ok(())
     }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum ResponseHeaderField {
-    ContentLength, ContentType, Date, LastModified, Server, TransferEncoding
+    ContentLength, ContentType, Date, LastModified, Server, TransferEncoding, Other(String)
 }
-impl ToString for ResponseHeaderField {
-    fn to_string(&self) -> String {
-        match self {
+impl ResponseHeaderField {
+    /// Looks up a header by name, ignoring case, for headers named in configuration.
+    pub fn from_name(name: &str) -> ResponseHeaderField {
+        [
+            ResponseHeaderField::ContentLength,
+            ResponseHeaderField::ContentType,
+            ResponseHeaderField::Date,
+            ResponseHeaderField::LastModified,
+            ResponseHeaderField::Server,
+            ResponseHeaderField::TransferEncoding,
+        ].iter()
+            .find(|field| field.to_string().eq_ignore_ascii_case(name))
+            .cloned()
+            .unwrap_or_else(|| ResponseHeaderField::Other(name.to_string()))
+    }
+}
+impl std::fmt::Display for ResponseHeaderField {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
             ResponseHeaderField::ContentLength => "Content-Length",
             ResponseHeaderField::ContentType => "Content-Type",
             ResponseHeaderField::Date => "Date",
             ResponseHeaderField::LastModified => "Last-Modified",
             ResponseHeaderField::Server => "Server",
             ResponseHeaderField::TransferEncoding => "Transfer-Encoding",
-        }.to_string()
+            ResponseHeaderField::Other(name) => name,
+        })
     }
 }
 impl FromStr for ResponseHeaderField {
@@ -280,32 +421,77 @@ pub struct StatusLine {
     pub http_version: String,
     pub status_code: StatusCode,
 }
-impl ToString for StatusLine {
-    fn to_string(&self) -> String {
-        let mut s = String::new();
-        s.push_str(&self.http_version);
-        s.push_str(" ");
-        s.push_str(&self.status_code.to_string());
-        s.push_str(CRLF);
-        s
+impl std::fmt::Display for StatusLine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}{}", self.http_version, self.status_code, CRLF)
     }
 }
 
 #[derive(Clone, Debug)]
 pub enum StatusCode {
-    Ok, NotModified, BadRequest, Forbidden, NotFound, InternalServerError, ServiceUnavailable
+    Ok, Found, NotModified, BadRequest, Forbidden, NotFound, MethodNotAllowed, PayloadTooLarge, InternalServerError, BadGateway,
+    ServiceUnavailable, GatewayTimeout,
+    /// A status the server does not produce itself, as given by a CGI script: the code and its reason phrase.
+    Other(u16, String),
 }
-impl ToString for StatusCode {
-    fn to_string(&self) -> String {
+impl StatusCode {
+    /// Looks up a status by code, keeping `reason` for codes the server does not know.
+    pub fn from_code(code: u16, reason: &str) -> StatusCode {
+        [
+            StatusCode::Ok,
+            StatusCode::Found,
+            StatusCode::NotModified,
+            StatusCode::BadRequest,
+            StatusCode::Forbidden,
+            StatusCode::NotFound,
+            StatusCode::MethodNotAllowed,
+            StatusCode::PayloadTooLarge,
+            StatusCode::InternalServerError,
+            StatusCode::BadGateway,
+            StatusCode::ServiceUnavailable,
+            StatusCode::GatewayTimeout,
+        ].iter()
+            .find(|status| status.code() == code)
+            .cloned()
+            .unwrap_or_else(|| StatusCode::Other(code, reason.to_string()))
+    }
+
+    pub fn code(&self) -> u16 {
         match self {
-            StatusCode::Ok => "200 OK",
-            StatusCode::NotModified => "304 Not Modified",
-            StatusCode::BadRequest => "400 Bad Request",
-            StatusCode::Forbidden => "403 Forbidden",
-            StatusCode::NotFound => "404 Not Found",
-            StatusCode::InternalServerError => "500 Internal Server Error",
-            StatusCode::ServiceUnavailable => "503 Service Unavailable",
-        }.to_string()
+            StatusCode::Ok => 200,
+            StatusCode::Found => 302,
+            StatusCode::NotModified => 304,
+            StatusCode::BadRequest => 400,
+            StatusCode::Forbidden => 403,
+            StatusCode::NotFound => 404,
+            StatusCode::MethodNotAllowed => 405,
+            StatusCode::PayloadTooLarge => 413,
+            StatusCode::InternalServerError => 500,
+            StatusCode::BadGateway => 502,
+            StatusCode::ServiceUnavailable => 503,
+            StatusCode::GatewayTimeout => 504,
+            StatusCode::Other(code, _) => *code,
+        }
+    }
+}
+impl std::fmt::Display for StatusCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let reason = match self {
+            StatusCode::Ok => "OK",
+            StatusCode::Found => "Found",
+            StatusCode::NotModified => "Not Modified",
+            StatusCode::BadRequest => "Bad Request",
+            StatusCode::Forbidden => "Forbidden",
+            StatusCode::NotFound => "Not Found",
+            StatusCode::MethodNotAllowed => "Method Not Allowed",
+            StatusCode::PayloadTooLarge => "Payload Too Large",
+            StatusCode::InternalServerError => "Internal Server Error",
+            StatusCode::BadGateway => "Bad Gateway",
+            StatusCode::ServiceUnavailable => "Service Unavailable",
+            StatusCode::GatewayTimeout => "Gateway Timeout",
+            StatusCode::Other(_, reason) => reason,
+        };
+        write!(f, "{} {}", self.code(), reason)
     }
 }
 
@@ -324,64 +510,99 @@ pub struct RequestNoRemote {
     pub body: String,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Request {
     pub header: RequestHeader,
     pub remote: Remote,
     pub body: String,
+    /// How many internal redirects led to this request, none for a request read from a client.
+    pub redirects: usize,
+    /// Identifies the request in the log; internal redirects keep the ID of the request they came from.
+    pub id: u64,
 }
 impl Request {
-    pub fn from_no_remote(request: RequestNoRemote, addr: SocketAddr) -> Self {
-        Request { header: request.header, remote: Remote { addr }, body: request.body }
+    pub fn from_no_remote(request: RequestNoRemote, addr: SocketAddr, local_addr: SocketAddr) -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        Request { header: request.header, remote: Remote { addr, local_addr }, body: request.body, redirects: 0, id }
     }
 }
 
-#[derive(Debug)]
+/// Parses a whole request, as a client at `remote` would send it to the listener at `local`, for tests.
+#[cfg(test)]
+pub fn parse_test_request(raw: &str, remote: &str, local: &str) -> Request {
+    match try_parse_request(raw.as_bytes(), IncrementalRequest::None(Box::new([]))).unwrap() {
+        IncrementalRequest::FullRequest(request) => Request::from_no_remote(request, remote.parse().unwrap(), local.parse().unwrap()),
+        _ => panic!("request did not parse: {}", raw),
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct RequestHeader {
     pub request_line: RequestLine,
     pub header_lines: HashMap<RequestHeaderField, String>,
 }
 
-#[derive(Debug,PartialEq,Eq,Hash)]
+#[derive(Clone,Debug,PartialEq,Eq,Hash)]
 pub enum RequestHeaderField {
-    ContentLength, Host, IfModifiedSince, UserAgent, NotSupported
+    Authorization, ContentLength, ContentType, Host, IfModifiedSince, UserAgent, Other(String)
+}
+impl std::fmt::Display for RequestHeaderField {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            RequestHeaderField::Authorization => "Authorization",
+            RequestHeaderField::ContentLength => "Content-Length",
+            RequestHeaderField::ContentType => "Content-Type",
+            RequestHeaderField::Host => "Host",
+            RequestHeaderField::IfModifiedSince => "If-Modified-Since",
+            RequestHeaderField::UserAgent => "User-Agent",
+            RequestHeaderField::Other(name) => name,
+        })
+    }
 }
 impl FromStr for RequestHeaderField {
     type Err = ();
 
+    /// Header names are case-insensitive; headers the server does not interpret keep their name as sent.
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(match s {
-            "Content-Length" => RequestHeaderField::ContentLength,
-            "If-Modified-Since" => RequestHeaderField::IfModifiedSince,
-            "Host" => RequestHeaderField::Host,
-            "User-Agent" => RequestHeaderField::UserAgent,
-            _ => RequestHeaderField::NotSupported,
-        })
+        Ok([
+            RequestHeaderField::Authorization,
+            RequestHeaderField::ContentLength,
+            RequestHeaderField::ContentType,
+            RequestHeaderField::Host,
+            RequestHeaderField::IfModifiedSince,
+            RequestHeaderField::UserAgent,
+        ].iter()
+            .find(|field| field.to_string().eq_ignore_ascii_case(s))
+            .cloned()
+            .unwrap_or_else(|| RequestHeaderField::Other(s.to_string())))
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct RequestLine {
     pub method: Method,
     pub request_path: String,
     pub query_string: String,
-    http_version: String,
+    pub http_version: String,
 }
 
-#[derive(Debug,PartialEq)]
+#[derive(Clone,Debug,PartialEq)]
 pub enum Method {
     Get, Post
 }
-impl ToString for Method {
-    fn to_string(&self) -> String {
-        match self {
-            Method::Get => "GET".to_string(),
-            Method::Post => "POST".to_string(),
-        }
+impl std::fmt::Display for Method {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Method::Get => "GET",
+            Method::Post => "POST",
+        })
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Remote {
     pub addr: SocketAddr,
+    /// The address of the listener that accepted the connection.
+    pub local_addr: SocketAddr,
 }
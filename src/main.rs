@@ -1,55 +1,122 @@
-use std::{ops::Deref, sync::mpsc, thread};
+use std::{collections::VecDeque, net::SocketAddr, os::unix::io::{FromRawFd, IntoRawFd}, sync::{Arc, atomic::AtomicUsize, mpsc}, thread};
 
 use crate::error::Error;
 
+mod cache;
 mod cgi;
+mod cli;
 mod config;
 mod error;
+mod fastcgi;
 mod files;
 mod host;
 mod http;
 mod parse;
 mod pool;
+mod scgi;
 mod select;
 mod seq;
+mod signal;
+mod ssi;
 mod time;
-
-#[derive(Debug)]
-enum MultiModel {
-    Single, ThreadPool, SelectMultiplex
-}
+mod uwsgi;
+mod vfs;
+mod vhost_alias;
+mod watch;
 
 fn main() -> Result<(), Error> {
-    let args = std::env::args().collect::<Vec<String>>();
-    let config_file_arg = args.get(1).map(|s| s.as_str()).unwrap_or("httpd.conf");
-    let config_file = std::env::current_dir()?.join(config_file_arg);
-    let server_config = config::load_config(&config_file)?;
-
-    let multi_model = args.get(2)
-        .and_then(|s| match s.deref() {
-            "single" => Some(MultiModel::Single),
-            "pool" => Some(MultiModel::ThreadPool),
-            "select" => Some(MultiModel::SelectMultiplex),
-            _ => None,
-        })
-        .unwrap_or(MultiModel::Single);
+    let options = match cli::parse_args(std::env::args().skip(1), |key| std::env::var(key).ok()) {
+        Ok(options) => options,
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(2);
+        },
+    };
+    match options.command {
+        cli::Command::Help => {
+            println!("{}", cli::USAGE);
+            return Ok(());
+        },
+        cli::Command::Version => {
+            println!("p1 {}", env!("CARGO_PKG_VERSION"));
+            return Ok(());
+        },
+        _ => {},
+    }
+
+    let server_config = match load_server_config(&options).and_then(|server_config| match options.command {
+        cli::Command::ConfigTest => check_document_roots(&server_config).map(|_| server_config),
+        _ => Ok(server_config),
+    }) {
+        Ok(server_config) => server_config,
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(1);
+        },
+    };
+
+    match options.command {
+        cli::Command::ConfigTest => {
+            println!("Syntax OK");
+            return Ok(());
+        },
+        cli::Command::DumpVirtualHosts => {
+            print!("{}", server_config.virtual_host_summary());
+            return Ok(());
+        },
+        cli::Command::DumpConfig => {
+            print!("{}", server_config);
+            return Ok(());
+        },
+        _ => {},
+    }
+
+    let multi_model = options.model.clone();
     println!("Chose concurrency model: {:#?}", multi_model);
 
     match multi_model {
-        MultiModel::Single => single(server_config),
-        MultiModel::ThreadPool => thread_pool(server_config),
-        MultiModel::SelectMultiplex => select_multiplex(server_config),
+        cli::MultiModel::Single => single(server_config, options),
+        cli::MultiModel::ThreadPool => thread_pool(server_config, options),
+        cli::MultiModel::SelectMultiplex => select_multiplex(server_config, options),
     }
 }
 
-fn single(server_config: config::ServerConfig) -> Result<(), Error> {
-    let port = server_config.directives.get(&config::Directive::ListenPort).ok_or(Error::new("Config did not include a ListenPort entry".to_string()))?;
-    println!("Listening on port {}...", port);
-    let listener = std::net::TcpListener::bind(format!("127.0.0.1:{}", port))?;
+/// Loads the configuration file named on the command line and applies the command-line overrides to it.
+fn load_server_config(options: &cli::Options) -> Result<config::ServerConfig, Error> {
+    let config_file = std::env::current_dir()?.join(&options.config);
+    let mut server_config = config::load_config(&config_file, &options.defines)?;
+    if let Some(address) = options.listen {
+        server_config.listen = vec!(address);
+    }
+    if let Some(threads) = options.threads {
+        server_config.thread_pool_size = threads;
+    }
+    Ok(server_config)
+}
 
-    let request_handler = host::Host::new(server_config);
+/// Opens every document root, as serving would, so that a configuration test also catches unreadable archives.
+fn check_document_roots(server_config: &config::ServerConfig) -> Result<(), Error> {
+    for virtual_host in server_config.virtual_hosts.iter().chain(std::iter::once(&server_config.main_server)) {
+        if let Some(document_root) = &virtual_host.document_root {
+            vfs::open_document_root(document_root)?;
+        }
+    }
+    Ok(())
+}
+
+fn single(server_config: config::ServerConfig, options: cli::Options) -> Result<(), Error> {
+    let incoming = accept_all(&server_config)?;
 
-    for stream in listener.incoming() {
+    let mut request_handler = Arc::new(host::Host::new(server_config));
+    request_handler.warm_cache();
+    spawn_watcher(&request_handler)?;
+    let (send_host, recv_host) = mpsc::channel();
+    spawn_reloader(options, request_handler.clone(), move |host| send_host.send(host).map_err(|e| e.into()))?;
+
+    for stream in incoming {
+        for host in recv_host.try_iter() {
+            request_handler = host;
+        }
         if let Err(e) = seq::process(&request_handler, stream?, false) {
             println!("Error processing request: {}", e);
         }
@@ -57,37 +124,65 @@ fn single(server_config: config::ServerConfig) -> Result<(), Error> {
     Ok(())
 }
 
-fn select_multiplex(server_config: config::ServerConfig) -> Result<(), Error> {
+fn select_multiplex(server_config: config::ServerConfig, options: cli::Options) -> Result<(), Error> {
     let (send_cmd, recv_cmd) = mpsc::channel::<select::Command>();
-    let mut event_loop = select::EventLoop::new(select::CommandQueue::new(send_cmd.clone(), recv_cmd))?;
+    let stdin_token = mio::Token(0);
+    let watcher_token = mio::Token(1);
+    let listener_tokens: Vec<mio::Token> = (0..server_config.listen.len()).map(|i| mio::Token(2 + i)).collect();
+    let token_counter = Arc::new(AtomicUsize::new(1 + listener_tokens.len()));
+
+    let mut event_loop = select::EventLoop::new(select::CommandQueue::new(send_cmd.clone(), recv_cmd), token_counter.clone())?;
     println!("Starting event loop...");
     let event_loop_thread = thread::spawn(move || { event_loop.run() });
 
-    let stdin_token = mio::Token(0);
-    let listener_token = mio::Token(1);
-    let token_counter = 1;
-
-    let port = server_config.directives.get(&config::Directive::ListenPort).ok_or(Error::new("Config did not include a ListenPort entry".to_string()))?;
-    println!("Listening on port {}...", port);
-    let listener = mio::net::TcpListener::bind(format!("127.0.0.1:{}", port).parse()?)?;
-    let listener_source = select::EventSource::TcpListener(listener, token_counter, server_config);
-    send_cmd.send(Box::new(move |_| { Ok(Some(select::CommandResponse::NewSource(listener_token, listener_source, mio::Interest::READABLE))) }))?;
+    let mut listeners = Vec::new();
+    for address in server_config.listen.iter() {
+        println!("Listening on {}...", address);
+        let listener = bind(address)?;
+        listener.set_nonblocking(true)?;
+        listeners.push(mio::net::TcpListener::from_std(listener));
+    }
+    let request_handler = Arc::new(host::Host::new(server_config));
+    request_handler.warm_cache();
+    if let Some(watcher) = request_handler.watch_document_roots()? {
+        let watcher_source = select::EventSource::Watcher(watcher);
+        send_cmd.send(Box::new(move |_| { Ok(Some(select::CommandResponse::NewSource(watcher_token, watcher_source, mio::Interest::READABLE))) }))?;
+    }
+    let reload_cmd = send_cmd.clone();
+    let reload_tokens = listener_tokens.clone();
+    spawn_reloader(options, request_handler.clone(), move |host| {
+        for listener_token in reload_tokens.iter().copied() {
+            let host = host.clone();
+            reload_cmd.send(Box::new(move |_| { Ok(Some(select::CommandResponse::SwapHost(listener_token, host))) }))?;
+        }
+        Ok(())
+    })?;
+    for (listener, listener_token) in listeners.into_iter().zip(listener_tokens.iter().copied()) {
+        let listener_source = select::EventSource::TcpListener(listener, token_counter.clone(), request_handler.clone());
+        send_cmd.send(Box::new(move |_| { Ok(Some(select::CommandResponse::NewSource(listener_token, listener_source, mio::Interest::READABLE))) }))?;
+    }
 
-    println!("Type 'shutdown' to close the listener.");
+    println!("Type 'shutdown' to close the listeners.");
     let stdin = std::io::stdin();
-    let stdin_source = select::EventSource::Stdin(select::Stdin::new(stdin), listener_token);
+    let stdin_source = select::EventSource::Stdin(select::Stdin::new(stdin), listener_tokens);
     send_cmd.send(Box::new(move |_| { Ok(Some(select::CommandResponse::NewSource(stdin_token, stdin_source, mio::Interest::READABLE))) }))?;
 
     event_loop_thread.join().map_err(|_| Error::new("Event loop thread panicked".to_string()))?
 }
 
-fn thread_pool(server_config: config::ServerConfig) -> Result<(), Error> {
-    let port = server_config.directives.get(&config::Directive::ListenPort).ok_or(Error::new("Config did not include a ListenPort entry".to_string()))?;
-    println!("Listening on port {}...", port);
-    let listener = std::net::TcpListener::bind(format!("127.0.0.1:{}", port))?;
+fn thread_pool(server_config: config::ServerConfig, options: cli::Options) -> Result<(), Error> {
+    let incoming = accept_all(&server_config)?;
 
+    let mut request_handler = Arc::new(host::Host::new(server_config.clone()));
+    request_handler.warm_cache();
+    spawn_watcher(&request_handler)?;
+    let (send_host, recv_host) = mpsc::channel();
+    spawn_reloader(options, request_handler.clone(), move |host| send_host.send(host).map_err(|e| e.into()))?;
     let (send_ready, recv_ready, threads) = pool::spawn_threads(&server_config)?;
-    for stream in listener.incoming() {
+    for stream in incoming {
+        for host in recv_host.try_iter() {
+            request_handler = host;
+        }
         // println!("-- main: accepted new stream");
         let pass_to_worker = || -> Result<(), error::Error> {
             let thread_num = recv_ready.recv()?;
@@ -96,13 +191,13 @@ fn thread_pool(server_config: config::ServerConfig) -> Result<(), Error> {
             for thread in recv_ready.try_iter() {
                 ready.push(thread);
             }
-            let overloaded = ready.len() == 0;
+            let overloaded = ready.is_empty();
             for thread in ready.drain(..) {
                 send_ready.send(thread)?;
             }
 
             let thread = threads.get(thread_num).ok_or_else(|| error::Error::new("Received out of bounds thread number, somehow...".to_string()))?;
-            thread.send_stream.send((stream?, overloaded))?;
+            thread.send_stream.send((stream?, overloaded, request_handler.clone()))?;
             Ok(())
         };
         if let Err(e) = pass_to_worker() {
@@ -111,3 +206,232 @@ fn thread_pool(server_config: config::ServerConfig) -> Result<(), Error> {
     }
     Ok(())
 }
+
+/// Binds every `Listen` address. The connections are accepted, from all of the listeners in turn, by iterating over
+/// the result on the calling thread, so that the models without an event loop need no thread per listener.
+fn accept_all(server_config: &config::ServerConfig) -> Result<Incoming, Error> {
+    let poll = mio::Poll::new()?;
+    let mut listeners = Vec::new();
+    for (i, address) in server_config.listen.iter().enumerate() {
+        println!("Listening on {}...", address);
+        let listener = bind(address)?;
+        listener.set_nonblocking(true)?;
+        let mut listener = mio::net::TcpListener::from_std(listener);
+        poll.registry().register(&mut listener, mio::Token(i), mio::Interest::READABLE)?;
+        listeners.push(listener);
+    }
+    Ok(Incoming { poll, events: mio::Events::with_capacity(listeners.len()), listeners, accepted: VecDeque::new() })
+}
+
+/// The connections accepted on every listener, as blocking streams, waiting on all of the listeners at once.
+struct Incoming {
+    poll: mio::Poll,
+    events: mio::Events,
+    listeners: Vec<mio::net::TcpListener>,
+    /// Connections accepted but not yet handed out, as several listeners may be ready at once.
+    accepted: VecDeque<std::io::Result<std::net::TcpStream>>,
+}
+
+impl Iterator for Incoming {
+    type Item = std::io::Result<std::net::TcpStream>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.accepted.is_empty() {
+            if let Err(e) = self.poll.poll(&mut self.events, None) {
+                if e.kind() != std::io::ErrorKind::Interrupted {
+                    return Some(Err(e));
+                }
+            }
+            for event in self.events.iter() {
+                let listener = &self.listeners[event.token().0];
+                loop {
+                    match listener.accept() {
+                        Ok((stream, _)) => {
+                            let stream = unsafe { std::net::TcpStream::from_raw_fd(stream.into_raw_fd()) };
+                            self.accepted.push_back(stream.set_nonblocking(false).map(|_| stream));
+                        },
+                        Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                        Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                        Err(e) => {
+                            self.accepted.push_back(Err(e));
+                            break;
+                        },
+                    }
+                }
+            }
+        }
+        self.accepted.pop_front()
+    }
+}
+
+/// Opens a listener on `address`. An IPv6 listener only accepts IPv6 connections, as in Apache, so that `Listen 80`
+/// and `Listen [::]:80` can be given together rather than the second failing with the port already in use.
+fn bind(address: &SocketAddr) -> std::io::Result<std::net::TcpListener> {
+    let address = match address {
+        SocketAddr::V4(_) => return std::net::TcpListener::bind(address),
+        SocketAddr::V6(address) => address,
+    };
+    let fd = unsafe { libc::socket(libc::AF_INET6, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    // the listener owns the socket from here on, and closes it on error
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+    let set_option = |level, name| {
+        let on: libc::c_int = 1;
+        let size = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        match unsafe { libc::setsockopt(fd, level, name, &on as *const libc::c_int as *const libc::c_void, size) } {
+            0 => Ok(()),
+            _ => Err(std::io::Error::last_os_error()),
+        }
+    };
+    // as `std::net::TcpListener::bind` does, so that the server can be restarted while old connections linger
+    set_option(libc::SOL_SOCKET, libc::SO_REUSEADDR)?;
+    set_option(libc::IPPROTO_IPV6, libc::IPV6_V6ONLY)?;
+    let mut sockaddr: libc::sockaddr_in6 = unsafe { std::mem::zeroed() };
+    sockaddr.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+    sockaddr.sin6_port = address.port().to_be();
+    sockaddr.sin6_flowinfo = address.flowinfo();
+    sockaddr.sin6_addr.s6_addr = address.ip().octets();
+    sockaddr.sin6_scope_id = address.scope_id();
+    let size = std::mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t;
+    if unsafe { libc::bind(fd, &sockaddr as *const libc::sockaddr_in6 as *const libc::sockaddr, size) } < 0
+        || unsafe { libc::listen(fd, 128) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(listener)
+}
+
+fn spawn_watcher(request_handler: &host::Host) -> Result<(), Error> {
+    if let Some(mut watcher) = request_handler.watch_document_roots()? {
+        thread::spawn(move || {
+            if let Err(e) = watcher.run() {
+                println!("Watcher: thread error: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Reloads the configuration each time the server receives `SIGHUP`, handing a host for the new configuration to
+/// `swap` so that connections accepted afterwards use it. A configuration that fails to load is reported and the
+/// current one stays in use.
+fn spawn_reloader(options: cli::Options, request_handler: Arc<host::Host>, swap: impl Fn(Arc<host::Host>) -> Result<(), Error> + Send + 'static) -> Result<(), Error> {
+    let hangup = signal::Hangup::install()?;
+    thread::spawn(move || {
+        let mut current = request_handler;
+        loop {
+            if let Err(e) = hangup.wait() {
+                println!("Reloader: thread error: {}", e);
+                return;
+            }
+            println!("Reloading configuration from {}...", options.config.display());
+            match reload_server_config(&options, current.server_config()) {
+                Ok(server_config) => {
+                    let reloaded = Arc::new(current.reload(server_config));
+                    reloaded.warm_cache();
+                    match swap(reloaded.clone()) {
+                        Ok(()) => {
+                            println!("Configuration reloaded");
+                            current = reloaded;
+                        },
+                        Err(e) => println!("Reloader: thread error: {}", e),
+                    }
+                },
+                Err(e) => println!("Configuration not reloaded; keeping the current configuration:\n{}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Loads the configuration again for a reload. Directives that only take effect on restart keep their current values.
+fn reload_server_config(options: &cli::Options, current: &config::ServerConfig) -> Result<config::ServerConfig, Error> {
+    let mut server_config = load_server_config(options)?;
+    check_document_roots(&server_config)?;
+    if server_config.listen != current.listen {
+        println!("Listen changed; restart the server to apply it");
+        server_config.listen = current.listen.clone();
+    }
+    if server_config.thread_pool_size != current.thread_pool_size {
+        println!("ThreadPoolSize changed to {}; restart the server to apply it", server_config.thread_pool_size);
+        server_config.thread_pool_size = current.thread_pool_size;
+    }
+    if server_config.cache != current.cache {
+        println!("Cache directives changed; restart the server to apply them");
+        server_config.cache = current.cache.clone();
+    }
+    Ok(server_config)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use super::*;
+
+    /// Finds distinct ports that are free on IPv4 loopback, and with `ipv6` on IPv6 loopback as well, by letting the
+    /// system choose them.
+    fn free_ports(count: usize, ipv6: bool) -> Vec<u16> {
+        let mut listeners = Vec::new();
+        while listeners.len() < count {
+            let ipv4 = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let port = ipv4.local_addr().unwrap().port();
+            match ipv6.then(|| std::net::TcpListener::bind(("::1", port))) {
+                Some(Err(_)) => continue,
+                ipv6 => listeners.push((port, ipv4, ipv6)),
+            }
+        }
+        listeners.into_iter().map(|(port, _, _)| port).collect()
+    }
+
+    fn server_config(listen: &[String]) -> config::ServerConfig {
+        let listen: String = listen.iter().map(|address| format!("Listen {}\n", address)).collect();
+        let root = concat!(env!("CARGO_MANIFEST_DIR"), "/www");
+        let config = format!("{}DocumentRoot {1}\nScriptAlias /cgi-bin/ {1}/cgi-bin/\n", listen, root);
+        let config_path = std::env::temp_dir().join(format!("main-test-{}-{:?}.conf", std::process::id(), thread::current().id()));
+        std::fs::write(&config_path, config).unwrap();
+        let server_config = config::load_config(&config_path, &[]).unwrap();
+        std::fs::remove_file(config_path).unwrap();
+        server_config
+    }
+
+    #[test]
+    fn every_listener_accepts_and_scripts_see_its_port() {
+        let ports = free_ports(2, false);
+        let server_config = server_config(&ports.iter().map(|port| format!("127.0.0.1:{}", port)).collect::<Vec<_>>());
+        let incoming = accept_all(&server_config).unwrap();
+        let host = host::Host::new(server_config);
+        // both clients connect before either is accepted, and are served in turn from the one thread
+        let clients: Vec<_> = ports.iter().copied().map(|port| (port, thread::spawn(move || {
+            let mut stream = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+            stream.write_all(b"GET /cgi-bin/printenv.pl HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        }))).collect();
+        for stream in incoming.take(clients.len()) {
+            seq::process(&host, stream.unwrap(), false).unwrap();
+        }
+        for (port, client) in clients {
+            let response = client.join().unwrap();
+            assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+            assert!(response.contains(&format!("SERVER_PORT=\"{}\"\n", port)), "{}", response);
+        }
+    }
+
+    #[test]
+    fn ipv4_and_ipv6_listeners_share_a_port() {
+        if std::net::TcpListener::bind("[::1]:0").is_err() {
+            println!("IPv6 is not available; skipping");
+            return;
+        }
+        let port = free_ports(1, true)[0];
+        let server_config = server_config(&[port.to_string(), format!("[::]:{}", port)]);
+        let listeners: Vec<_> = server_config.listen.iter().map(|address| bind(address).unwrap()).collect();
+        for (listener, address) in listeners.iter().zip(["127.0.0.1", "::1"]) {
+            let client = std::net::TcpStream::connect((address, port)).unwrap();
+            let (_, peer) = listener.accept().unwrap();
+            assert_eq!(peer, client.local_addr().unwrap());
+        }
+    }
+}
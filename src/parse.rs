@@ -1,15 +1,7 @@
 pub fn discard_string<'a>(to_find: &str, s: &'a str) -> Result<&'a str, ()> {
-    if s.starts_with(to_find) {
-        Ok(&s[to_find.len()..])
-    } else {
-        Err(())
-    }
+    s.strip_prefix(to_find).ok_or(())
 }
 
 pub fn discard_char(to_find: char, s: &str) -> Result<&str, ()> {
-    if s.starts_with(to_find) {
-        Ok(&s[1..])
-    } else {
-        Err(())
-    }
+    s.strip_prefix(to_find).ok_or(())
 }
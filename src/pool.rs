@@ -1,5 +1,5 @@
-use std::str::FromStr;
-use std::sync::mpsc;
+use std::cell::Cell;
+use std::sync::{Arc, Mutex, mpsc};
 use std::thread;
 
 use crate::config;
@@ -8,31 +8,29 @@ use crate::host;
 use crate::seq;
 
 pub struct Thread {
-    pub send_stream: mpsc::Sender<(std::net::TcpStream, bool)>,
+    pub send_stream: mpsc::Sender<(std::net::TcpStream, bool, Arc<host::Host>)>,
 }
 
-pub fn spawn_threads(server_config: &config::ServerConfig) -> Result<(mpsc::Sender<usize>, mpsc::Receiver<usize>, Vec<Thread>), Error> {
-    let num_threads: usize = server_config.directives.get(&config::Directive::ThreadPoolSize)
-        .map(|size| usize::from_str(size))
-        .unwrap_or(Ok(1))?;
+pub type Pool = (mpsc::Sender<usize>, mpsc::Receiver<usize>, Vec<Thread>);
+
+pub fn spawn_threads(server_config: &config::ServerConfig) -> Result<Pool, Error> {
+    let num_threads = server_config.thread_pool_size;
     let mut threads: Vec<Thread> = Vec::with_capacity(num_threads);
     let (send_ready, recv_ready) = mpsc::channel();
     for thread_num in 0..num_threads {
         let (send_stream, recv_stream) = mpsc::channel();
         let send_ready = send_ready.clone();
-        let server_config = server_config.clone();
-        thread::spawn(move || worker(thread_num, send_ready, recv_stream, server_config));
+        thread::spawn(move || worker(thread_num, send_ready, recv_stream));
         threads.push(Thread { send_stream });
     }
     Ok((send_ready, recv_ready, threads))
 }
 
-fn worker(thread_num: usize, send_ready: mpsc::Sender<usize>, recv_stream: mpsc::Receiver<(std::net::TcpStream, bool)>, server_config: config::ServerConfig) -> () {
-    let request_handler = host::Host::new(server_config);
+fn worker(thread_num: usize, send_ready: mpsc::Sender<usize>, recv_stream: mpsc::Receiver<(std::net::TcpStream, bool, Arc<host::Host>)>) {
     let do_work = || -> Result<(), Error> {
         // println!("-- worker {}: ready", thread_num);
         send_ready.send(thread_num)?;
-        let (stream, overloaded) = recv_stream.recv()?;
+        let (stream, overloaded, request_handler) = recv_stream.recv()?;
         // println!("-- worker {}: received stream", thread_num);
         seq::process(&request_handler, stream, overloaded)
     };
@@ -42,3 +40,50 @@ fn worker(thread_num: usize, send_ready: mpsc::Sender<usize>, recv_stream: mpsc:
         }
     }
 }
+
+/// A job run by one of the `Workers`.
+pub type Job = Box<dyn FnOnce() + Send>;
+
+thread_local! {
+    static IS_WORKER: Cell<bool> = const { Cell::new(false) };
+}
+
+/// A fixed number of threads that take the work that would otherwise hold up a connection, such as expanding
+/// server-side includes, in the order it is handed to them. Once they are all busy, jobs wait for one to become free.
+pub struct Workers {
+    send_job: mpsc::Sender<Job>,
+}
+
+impl Workers {
+    pub fn new(num_threads: usize) -> Workers {
+        let (send_job, recv_job) = mpsc::channel::<Job>();
+        let recv_job = Arc::new(Mutex::new(recv_job));
+        for _ in 0..num_threads {
+            let recv_job = recv_job.clone();
+            thread::spawn(move || {
+                IS_WORKER.with(|is_worker| is_worker.set(true));
+                loop {
+                    // the lock is only held while waiting, so that the other threads can take jobs meanwhile
+                    let job = match recv_job.lock().map(|recv_job| recv_job.recv()) {
+                        Ok(Ok(job)) => job,
+                        // the workers have been dropped
+                        _ => return,
+                    };
+                    job();
+                }
+            });
+        }
+        Workers { send_job }
+    }
+
+    pub fn spawn(&self, job: impl FnOnce() + Send + 'static) {
+        // the threads only stop once this end is dropped
+        let _ = self.send_job.send(Box::new(job));
+    }
+
+    /// Whether the current thread is one of the workers. A job must not wait on another job, which might be queued
+    /// behind it, so work started from a worker is done in place instead.
+    pub fn on_worker() -> bool {
+        IS_WORKER.with(|is_worker| is_worker.get())
+    }
+}
@@ -0,0 +1,229 @@
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time;
+use crate::cgi;
+use crate::config::*;
+use crate::error::{Error, HttpError};
+use crate::fastcgi::Stream;
+use crate::http::*;
+
+/// Sends requests to [SCGI](https://python.ca/scgi/protocol.txt) applications, over a connection of their own as the
+/// protocol requires.
+#[derive(Clone, Default)]
+pub struct Scgi {}
+
+impl Scgi {
+    /// Sends a request to the application that a `ProxyPass` names and reads its output up to the end of its headers,
+    /// which are processed as a CGI script's would be; the rest of the output is streamed as the body of the response.
+    /// The path of the `ProxyPass` is passed as `SCRIPT_NAME` and the rest of `url_path`, the normalized path of the
+    /// request, as `PATH_INFO`, as WSGI applications expect. The request ends with `504 Gateway Timeout` if it takes
+    /// longer than `CGITimeout`.
+    pub fn send(&self, proxy_pass: &ProxyPass, url_path: &str, request: &Request, virtual_host: &VirtualHost) -> Result<cgi::Output, HttpError> {
+        let params = meta_variables(proxy_pass, url_path, request, virtual_host);
+        let headers = encode_headers(&params, request.body.len());
+        exchange("SCGI", proxy_pass, request, virtual_host, &headers, |output| cgi::process_cgi_output(output, false))
+    }
+}
+
+/// The meta-variables passed to an application that speaks SCGI or a protocol like it: those of a CGI script, with the
+/// path of the `ProxyPass` as `SCRIPT_NAME` and the rest of `url_path` as `PATH_INFO`.
+pub fn meta_variables(proxy_pass: &ProxyPass, url_path: &str, request: &Request, virtual_host: &VirtualHost) -> HashMap<String, String> {
+    let script_name = proxy_pass.path.trim_end_matches('/');
+    let document_root = path::PathBuf::from(virtual_host.document_root.as_deref().unwrap_or(""));
+    let script = cgi::Script {
+        path: path::PathBuf::from(&proxy_pass.target),
+        script_name: script_name.to_string(),
+        path_info: &url_path[script_name.len()..],
+        document_root: &document_root,
+    };
+    cgi::meta_variables(&script, request, virtual_host)
+}
+
+/// Sends `headers` and the request body to an application over a connection of its own, and reads its answer with
+/// `read_output`. Failures are logged and reported as `503 Service Unavailable` if the application cannot be reached,
+/// `504 Gateway Timeout` after `CGITimeout` and `502 Bad Gateway` otherwise.
+pub fn exchange(
+    protocol: &'static str,
+    proxy_pass: &ProxyPass,
+    request: &Request,
+    virtual_host: &VirtualHost,
+    headers: &[u8],
+    read_output: impl FnOnce(ApplicationOutput) -> Result<cgi::Output, Error>,
+) -> Result<cgi::Output, HttpError> {
+    let context = format!(
+        "[request {}] [client {}] [{}] {}",
+        request.id,
+        request.remote.addr,
+        virtual_host.server_name.as_deref().unwrap_or("_default_"),
+        proxy_pass.url(),
+    );
+
+    let stream = Stream::connect(&proxy_pass.address).map_err(|e| {
+        println!("{}: could not connect: {}", context, e);
+        HttpError { status: StatusCode::ServiceUnavailable, message: Some(format!("Could not connect to {} application: {}", protocol, e)) }
+    })?;
+    let timed_out = Arc::new(AtomicBool::new(false));
+    let mut output = ApplicationOutput {
+        stream,
+        protocol,
+        deadline: virtual_host.cgi_limits.timeout.map(|timeout| time::Instant::now() + timeout),
+        timed_out: timed_out.clone(),
+    };
+    output.send(headers, request.body.as_bytes())
+        .map_err(|e| e.into())
+        .and_then(|_| read_output(output))
+        .map_err(|e| {
+            println!("{}: {}", context, e);
+            let status = if timed_out.load(Ordering::SeqCst) { StatusCode::GatewayTimeout } else { StatusCode::BadGateway };
+            HttpError { status, message: Some(e.to_string()) }
+        })
+}
+
+/// Encodes the request headers as a netstring of NUL-terminated names and values. `CONTENT_LENGTH` comes first and is
+/// always given, followed by `SCGI`, as the protocol requires. Values cannot contain NUL, so pairs that do are left out.
+fn encode_headers(params: &HashMap<String, String>, content_length: usize) -> Vec<u8> {
+    let content_length = content_length.to_string();
+    let mut headers = Vec::new();
+    let required = [("CONTENT_LENGTH", content_length.as_str()), ("SCGI", "1")];
+    let rest = params.iter()
+        .filter(|(name, value)| *name != "CONTENT_LENGTH" && !name.contains('\0') && !value.contains('\0'))
+        .map(|(name, value)| (name.as_str(), value.as_str()));
+    for (name, value) in required.iter().copied().chain(rest) {
+        headers.extend_from_slice(name.as_bytes());
+        headers.push(0);
+        headers.extend_from_slice(value.as_bytes());
+        headers.push(0);
+    }
+    let mut netstring = format!("{}:", headers.len()).into_bytes();
+    netstring.extend_from_slice(&headers);
+    netstring.push(b',');
+    netstring
+}
+
+/// The connection to an application, from which its output is read until it closes the connection.
+pub struct ApplicationOutput {
+    stream: Stream,
+    /// The name of the protocol, for messages.
+    protocol: &'static str,
+    deadline: Option<time::Instant>,
+    timed_out: Arc<AtomicBool>,
+}
+
+impl ApplicationOutput {
+    fn send(&mut self, headers: &[u8], body: &[u8]) -> io::Result<()> {
+        self.stream.set_write_timeout(self.time_left()?)?;
+        self.stream.write_all(headers)
+            .and_then(|_| self.stream.write_all(body))
+            .map_err(|e| self.check_timeout(e))
+    }
+
+    /// The time until the deadline, if there is one, or an error if it has passed.
+    fn time_left(&self) -> io::Result<Option<time::Duration>> {
+        match self.deadline.map(|deadline| deadline.saturating_duration_since(time::Instant::now())) {
+            Some(time_left) if time_left.is_zero() => Err(self.check_timeout(io::ErrorKind::TimedOut.into())),
+            time_left => Ok(time_left),
+        }
+    }
+
+    /// Notes that the application has timed out if the error says so.
+    fn check_timeout(&self, e: io::Error) -> io::Error {
+        if e.kind() != io::ErrorKind::WouldBlock && e.kind() != io::ErrorKind::TimedOut {
+            return e;
+        }
+        self.timed_out.store(true, Ordering::SeqCst);
+        io::Error::new(io::ErrorKind::TimedOut, format!("{} application timed out", self.protocol))
+    }
+}
+
+impl Read for ApplicationOutput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.set_read_timeout(self.time_left()?)?;
+        self.stream.read(buf).map_err(|e| self.check_timeout(e))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::BufRead;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use super::*;
+
+    /// Serves an SCGI stand-in on a local port that echoes the request back, except for the paths `/redirect`, which
+    /// redirects to `/index.html`, and `/hang`, which never answers.
+    pub(crate) fn serve() -> BackendAddress {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = BackendAddress::Tcp(listener.local_addr().unwrap().to_string());
+        thread::spawn(move || for stream in listener.incoming() {
+            let stream = stream.unwrap();
+            thread::spawn(move || echo(stream));
+        });
+        address
+    }
+
+    fn echo(mut stream: TcpStream) {
+        let mut reader = io::BufReader::new(stream.try_clone().unwrap());
+        let mut length = Vec::new();
+        reader.read_until(b':', &mut length).unwrap();
+        let length: usize = String::from_utf8_lossy(&length[..length.len() - 1]).parse().unwrap();
+        let mut netstring = vec![0; length + 1];
+        reader.read_exact(&mut netstring).unwrap();
+        assert_eq!(netstring.pop(), Some(b','));
+        let fields: Vec<String> = netstring.split(|byte| *byte == 0).map(|field| String::from_utf8_lossy(field).into_owned()).collect();
+        let headers: Vec<(&str, &str)> = fields.chunks_exact(2).map(|pair| (pair[0].as_str(), pair[1].as_str())).collect();
+        let get = |name: &str| headers.iter().find(|(field, _)| *field == name).map(|(_, value)| *value).unwrap_or_default();
+        let mut body = vec![0; get("CONTENT_LENGTH").parse().unwrap()];
+        reader.read_exact(&mut body).unwrap();
+
+        let output = match get("PATH_INFO") {
+            "/redirect" => "Location: /index.html\r\n\r\n".to_string(),
+            "/hang" => {
+                let _ = reader.read(&mut [0]);
+                return;
+            },
+            path_info => format!(
+                "Status: 202 Accepted\nContent-Type: text/plain\n\n{} SCGI={} {} {} {}\n{}",
+                headers[0].0, get("SCGI"), get("SCRIPT_NAME"), path_info, get("QUERY_STRING"), String::from_utf8_lossy(&body),
+            ),
+        };
+        stream.write_all(output.as_bytes()).unwrap();
+    }
+
+    fn post(target: &str, body: &str) -> Request {
+        let raw = format!("POST {} HTTP/1.1\r\nHost: www.example.com\r\nContent-Length: {}\r\n\r\n{}", target, body.len(), body);
+        parse_test_request(&raw, "10.0.0.2:50000", "10.0.0.1:3333")
+    }
+
+    fn proxy_pass(address: BackendAddress) -> ProxyPass {
+        ProxyPass { path: "/app/".to_string(), protocol: Protocol::Scgi, address, target: "/".to_string() }
+    }
+
+    #[test]
+    fn requests_pass_through_cgi_header_processing() {
+        let proxy_pass = proxy_pass(serve());
+        let response = match Scgi::default().send(&proxy_pass, "/app/users/42", &post("/app//users/%34%32?x=1", "a=1"), &VirtualHost::default()).unwrap() {
+            cgi::Output::Response(response) => response,
+            cgi::Output::LocalRedirect(location) => panic!("unexpected redirect to {}", location),
+        };
+        assert_eq!(response.header.status_line.status_code.code(), 202);
+        assert_eq!(response.into_body().unwrap(), "CONTENT_LENGTH SCGI=1 /app /users/42 x=1\na=1");
+
+        let redirect = Scgi::default().send(&proxy_pass, "/app/redirect", &post("/app/redirect", ""), &VirtualHost::default()).unwrap();
+        assert!(matches!(redirect, cgi::Output::LocalRedirect(location) if location == "/index.html"));
+    }
+
+    #[test]
+    fn reports_applications_that_time_out_or_cannot_be_reached() {
+        let mut virtual_host = VirtualHost::default();
+        virtual_host.cgi_limits.timeout = Some(time::Duration::from_secs(1));
+        let error = Scgi::default().send(&proxy_pass(serve()), "/app/hang", &post("/app/hang", ""), &virtual_host).err().unwrap();
+        assert!(matches!(error.status, StatusCode::GatewayTimeout));
+
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let error = Scgi::default().send(&proxy_pass(BackendAddress::Tcp(closed.to_string())), "/app/", &post("/app/", ""), &virtual_host).err().unwrap();
+        assert!(matches!(error.status, StatusCode::ServiceUnavailable));
+    }
+}
@@ -1,33 +1,51 @@
-use std::collections::HashMap;
-use std::io::{Read, Write};
+use std::collections::{HashMap, HashSet};
+use std::io::{self, BufRead, Read, Write};
 use std::os::unix::prelude::AsRawFd;
+use std::process::{ChildStderr, ChildStdin, ChildStdout};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc::{Receiver, Sender, TryRecvError};
 use std::time::{Duration, Instant};
-use mio::{Events, Interest, Poll, Registry, Token, event};
+use mio::{Events, Interest, Poll, Registry, Token, Waker, event};
 use mio::event::Event;
 use mio::net::{TcpListener, TcpStream};
 use mio::unix::SourceFd;
-use crate::config;
+use crate::cgi;
 use crate::error::Error;
 use crate::host;
 use crate::http;
+use crate::watch;
 
 const POLL_TIMEOUT: Duration = Duration::from_millis(1000);
 
+/// The token of the waker through which workers have the loop take the commands they send. The counter that other
+/// tokens are taken from never reaches it.
+const WAKE_TOKEN: Token = Token(usize::MAX);
+
+/// The most output read from a CGI script at once; the rest is read once the connection has sent what was read.
+const SCRIPT_OUTPUT_LEN: usize = 64 * 1024;
+
 pub struct EventLoop {
     poll: Poll,
     events: Events,
     event_sources: HashMap<Token, EventSource>,
+    /// The outputs of scripts that are not read, as their connections have yet to send what was read before.
+    paused_outputs: HashSet<Token>,
     command_queue: CommandQueue,
+    /// The counter from which the listeners assign tokens to accepted streams, and the loop to the pipes of scripts.
+    token_counter: Arc<AtomicUsize>,
+    waker: Arc<Waker>,
 }
 
 impl EventLoop {
-    pub fn new(command_queue: CommandQueue) -> Result<Self, Error> {
+    pub fn new(command_queue: CommandQueue, token_counter: Arc<AtomicUsize>) -> Result<Self, Error> {
         let poll = Poll::new()?;
         let events = Events::with_capacity(128);
         let event_sources = HashMap::new();
+        let paused_outputs = HashSet::new();
+        let waker = Arc::new(Waker::new(poll.registry(), WAKE_TOKEN)?);
 
-        Ok(EventLoop { poll, events, event_sources, command_queue })
+        Ok(EventLoop { poll, events, event_sources, paused_outputs, command_queue, token_counter, waker })
     }
 
     pub fn run(&mut self) -> Result<(), Error> {
@@ -47,8 +65,12 @@ impl EventLoop {
         // println!("-- polling");
         self.poll.poll(&mut self.events, Some(POLL_TIMEOUT))?;
 
+        let mut failed = Vec::new();
         for event in self.events.iter() {
             let token = event.token();
+            if token == WAKE_TOKEN {
+                continue;
+            }
             let source = self.event_sources.remove(&token).ok_or(Error::new(format!("Could not find handler for token: {}", token.0)))?;
             match source.handle_event(event, token) {
                 Ok((new_source, mut responses)) => {
@@ -62,9 +84,13 @@ impl EventLoop {
                 },
                 Err(e) => {
                     eprintln!("Handler for token {} produced error: {:#?}", token.0, e);
+                    failed.push(token);
                 },
             }
         }
+        for token in failed {
+            self.release_outputs_of(token)?;
+        }
 
         let mut new_commands: Vec<Command> = Vec::new();
         loop {
@@ -90,47 +116,292 @@ impl EventLoop {
 
     fn execute_command(&mut self, command: Command) -> Result<Option<Command>, Error> {
         match (command)(&self.event_sources) {
-            Ok(response) => if let Some(response) = response {
-                match response {
-                    CommandResponse::NewSource(token, mut source, interests) => {
-                        source.register(self.poll.registry(), token, interests)?;
-                        self.event_sources.insert(token, source);
-                        Ok(None)
-                    },
-                    CommandResponse::ModifyInterests(token, interests) => {
-                        if let Some(source) = self.event_sources.get_mut(&token) {
-                            source.reregister(self.poll.registry(), token, interests)?;
-                        } else {
-                            eprintln!("Could not find source associated with token {}", token.0);
-                        }
-                        Ok(None)
-                    },
-                    CommandResponse::CloseSource(token) => {
-                        if let Some(mut source) = self.event_sources.remove(&token) {
-                            source.deregister(self.poll.registry())?;
-                        } else {
-                            eprintln!("Source {} has already been closed", token.0)
-                        }
-                        Ok(None)
-                    },
-                    CommandResponse::SubmitCommand(command) => Ok(Some(command)),
+            Ok(Some(response)) => self.execute_response(response),
+            Ok(None) => Ok(None),
+            Err(e) => {
+                eprintln!("Command produced error: {:#?}", e);
+                Ok(None)
+            },
+        }
+    }
+
+    fn execute_response(&mut self, response: CommandResponse) -> Result<Option<Command>, Error> {
+        match response {
+            CommandResponse::NewSource(token, source, interests) => {
+                self.add_source(token, source, interests)?;
+                Ok(None)
+            },
+            CommandResponse::ModifyInterests(token, interests) => {
+                if let Some(source) = self.event_sources.get_mut(&token) {
+                    source.reregister(self.poll.registry(), token, interests)?;
+                } else {
+                    eprintln!("Could not find source associated with token {}", token.0);
                 }
-            } else {
                 Ok(None)
             },
-            Err(e) => {
-                eprintln!("Command produced error: {:#?}", e);
+            CommandResponse::CloseSource(token) => {
+                if let Some(mut source) = self.event_sources.remove(&token) {
+                    if !self.paused_outputs.remove(&token) {
+                        source.deregister(self.poll.registry())?;
+                    }
+                    self.release_outputs_of(token)?;
+                } else {
+                    eprintln!("Source {} has already been closed", token.0)
+                }
+                Ok(None)
+            },
+            CommandResponse::SubmitCommand(command) => Ok(Some(command)),
+            CommandResponse::SwapHost(token, host) => {
+                if let Some(EventSource::TcpListener(_, _, request_handler)) = self.event_sources.get_mut(&token) {
+                    *request_handler = host;
+                } else {
+                    eprintln!("Could not find listener associated with token {}", token.0);
+                }
+                Ok(None)
+            },
+            CommandResponse::StartScript(connection, pipes) => {
+                self.start_script(connection, pipes)?;
+                Ok(None)
+            },
+            CommandResponse::RelayScriptOutput(connection, output, bytes, finished) => {
+                self.relay_script_output(connection, output, bytes, finished)?;
+                Ok(None)
+            },
+            CommandResponse::StartProxy(connection, proxy) => {
+                self.start_proxy(connection, proxy);
+                Ok(None)
+            },
+            CommandResponse::Respond(connection, handled) => {
+                self.respond(connection, handled)?;
+                Ok(None)
+            },
+            CommandResponse::StreamBody(connection, body) => {
+                self.stream_body(connection, body)?;
+                Ok(None)
+            },
+            CommandResponse::RelayBody(connection, body, bytes, finished) => {
+                self.relay_body(connection, body, bytes, finished)?;
+                Ok(None)
+            },
+            CommandResponse::ResumeOutput(output) => {
+                self.resume_output(output)?;
                 Ok(None)
             },
         }
     }
+
+    fn add_source(&mut self, token: Token, mut source: EventSource, interests: Interest) -> Result<(), Error> {
+        source.register(self.poll.registry(), token, interests)?;
+        self.event_sources.insert(token, source);
+        Ok(())
+    }
+
+    fn next_token(&self) -> Token {
+        Token(self.token_counter.fetch_add(1, Ordering::Relaxed) + 1)
+    }
+
+    /// Registers the pipes of a script started for a connection, and tells the connection which of them its output
+    /// comes from.
+    fn start_script(&mut self, connection: Token, pipes: cgi::ScriptPipes) -> Result<(), Error> {
+        let output = self.next_token();
+        match self.event_sources.get_mut(&connection) {
+            Some(EventSource::TcpStream(_, ConnectionState::Script(state), _, _)) => state.output = Some(output),
+            // the client has gone, and the script with it
+            _ => return Ok(()),
+        }
+        let cgi::ScriptPipes { input, output: stdout, errors: (stderr, error_log) } = pipes;
+        if let Some((stdin, body)) = input {
+            self.add_source(self.next_token(), EventSource::ScriptInput(stdin, io::Cursor::new(body)), Interest::WRITABLE)?;
+        }
+        self.add_source(output, EventSource::ScriptOutput(stdout, connection), Interest::READABLE)?;
+        self.add_source(self.next_token(), EventSource::ScriptErrors(stderr, error_log), Interest::READABLE)
+    }
+
+    /// Passes output read from a script to the connection waiting on it. More output is read once the connection has
+    /// sent what it was given, or straight away if the output was discarded or only held back until the script's headers
+    /// are complete.
+    fn relay_script_output(&mut self, connection: Token, output: Token, bytes: Vec<u8>, finished: bool) -> Result<(), Error> {
+        let mut relayed = false;
+        match self.event_sources.remove(&connection) {
+            Some(EventSource::TcpStream(stream, ConnectionState::Script(state), request_handler, accept_time)) if state.output == Some(output) => {
+                let (connection_state, start) = state.relay(connection, &request_handler, bytes, finished);
+                let sending = match &connection_state {
+                    ConnectionState::Script(state) => {
+                        relayed = state.output == Some(output) && !state.pending.is_empty();
+                        !state.pending.is_empty() || state.finished
+                    },
+                    ConnectionState::Proxy(_) => false,
+                    _ => true,
+                };
+                let mut source = EventSource::TcpStream(stream, connection_state, request_handler, accept_time);
+                if sending {
+                    source.reregister(self.poll.registry(), connection, Interest::WRITABLE)?;
+                }
+                self.event_sources.insert(connection, source);
+                if let Some(start) = start {
+                    self.execute_response(start)?;
+                }
+            },
+            // output from a script that made a local redirect is read to its end and discarded
+            Some(source) => { self.event_sources.insert(connection, source); },
+            None => {},
+        }
+        self.read_on(output, finished, relayed)
+    }
+
+    /// Hands a request for an application to a worker, which sends it without holding up the loop and has the loop
+    /// answer the connection once the application has answered.
+    fn start_proxy(&mut self, connection: Token, proxy: Box<host::PendingProxy>) {
+        let request_handler = match self.event_sources.get(&connection) {
+            Some(EventSource::TcpStream(_, ConnectionState::Proxy(_), request_handler, _)) => request_handler.clone(),
+            _ => return,
+        };
+        let send = self.command_queue.send.clone();
+        let waker = self.waker.clone();
+        request_handler.forward_on_worker(proxy, move |handled| {
+            // the loop is only gone once the server is shutting down
+            let _ = send.send(Box::new(move |_| Ok(Some(CommandResponse::Respond(connection, handled)))));
+            let _ = waker.wake();
+        });
+    }
+
+    /// Answers a connection that was waiting on a worker with what the worker handed back.
+    fn respond(&mut self, connection: Token, handled: host::Handled) -> Result<(), Error> {
+        match self.event_sources.remove(&connection) {
+            Some(EventSource::TcpStream(stream, ConnectionState::Proxy(request), request_handler, accept_time)) => {
+                let (connection_state, start) = begin(connection, *request, handled);
+                let sending = matches!(connection_state, ConnectionState::Write(_) | ConnectionState::Stream(_));
+                let mut source = EventSource::TcpStream(stream, connection_state, request_handler, accept_time);
+                if sending {
+                    source.reregister(self.poll.registry(), connection, Interest::WRITABLE)?;
+                }
+                self.event_sources.insert(connection, source);
+                if let Some(start) = start {
+                    self.execute_response(start)?;
+                }
+            },
+            Some(source) => { self.event_sources.insert(connection, source); },
+            // the client has gone, and dropping the response lets the worker know
+            None => {},
+        }
+        Ok(())
+    }
+
+    /// Registers the streamed body of a connection's response, and tells the connection that its body comes from it.
+    fn stream_body(&mut self, connection: Token, body: http::BodyStream) -> Result<(), Error> {
+        let token = self.next_token();
+        let fd = match (self.event_sources.get_mut(&connection), body.as_raw_fd()) {
+            (Some(EventSource::TcpStream(_, ConnectionState::Stream(state), _, _)), Some(fd)) => {
+                state.body = Some(token);
+                fd
+            },
+            // the client has gone, and dropping the body lets its writer know
+            _ => return Ok(()),
+        };
+        cgi::set_nonblocking(fd)?;
+        self.add_source(token, EventSource::Body(body, connection), Interest::READABLE)
+    }
+
+    /// Passes a piece of a streamed body to the connection sending it, framed for the client.
+    fn relay_body(&mut self, connection: Token, body: Token, bytes: Vec<u8>, finished: bool) -> Result<(), Error> {
+        let mut relayed = false;
+        if let Some(EventSource::TcpStream(stream, ConnectionState::Stream(state), _, _)) = self.event_sources.get_mut(&connection) {
+            if state.body == Some(body) && !state.finished {
+                // writing to memory cannot fail
+                let _ = state.framing.write(&mut state.pending, &bytes);
+                if finished || state.framing.is_complete() {
+                    let _ = state.framing.finish(&mut state.pending);
+                    state.finished = true;
+                }
+                relayed = !state.pending.is_empty();
+                if relayed || state.finished {
+                    self.poll.registry().reregister(stream, connection, Interest::WRITABLE)?;
+                }
+            }
+        }
+        self.read_on(body, finished, relayed)
+    }
+
+    /// Goes on reading an output once what was relayed from it has been sent, or straight away if nothing was.
+    fn read_on(&mut self, output: Token, finished: bool, relayed: bool) -> Result<(), Error> {
+        if !finished {
+            if relayed {
+                self.pause_output(output)?;
+            } else if !self.paused_outputs.contains(&output) {
+                if let Some(source) = self.event_sources.get_mut(&output) {
+                    source.reregister(self.poll.registry(), output, Interest::READABLE)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Stops reading a script's output or a streamed body until its connection has sent what was read. The pipe is
+    /// deregistered rather than left to wake the loop, so that its writer blocks once the pipe is full instead of the
+    /// server buffering the output.
+    fn pause_output(&mut self, output: Token) -> Result<(), Error> {
+        if let Some(source) = self.event_sources.get_mut(&output) {
+            if self.paused_outputs.insert(output) {
+                source.deregister(self.poll.registry())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads a paused output again; anything written in the meantime is reported as soon as it is registered.
+    fn resume_output(&mut self, output: Token) -> Result<(), Error> {
+        if self.paused_outputs.remove(&output) {
+            if let Some(source) = self.event_sources.get_mut(&output) {
+                source.register(self.poll.registry(), output, Interest::READABLE)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Lets go of the outputs of a connection that has gone. Paused script outputs are resumed, so that the scripts
+    /// are read to their end and closed, while streamed bodies are closed at once, so that their writers stop.
+    fn release_outputs_of(&mut self, connection: Token) -> Result<(), Error> {
+        let outputs: Vec<Token> = self.paused_outputs.iter().copied()
+            .filter(|output| matches!(self.event_sources.get(output), Some(EventSource::ScriptOutput(_, c)) if *c == connection))
+            .collect();
+        for output in outputs {
+            self.resume_output(output)?;
+        }
+        let bodies: Vec<Token> = self.event_sources.iter()
+            .filter(|(_, source)| matches!(source, EventSource::Body(_, c) if *c == connection))
+            .map(|(token, _)| *token)
+            .collect();
+        for body in bodies {
+            self.execute_response(CommandResponse::CloseSource(body))?;
+        }
+        Ok(())
+    }
 }
 
+#[allow(clippy::large_enum_variant)]
 pub enum CommandResponse {
     NewSource(Token, EventSource, Interest),
     ModifyInterests(Token, Interest),
     CloseSource(Token),
     SubmitCommand(Command),
+    /// Serves connections accepted from now on by the listener with the given host; open connections keep theirs.
+    SwapHost(Token, Arc<host::Host>),
+    /// Registers the pipes of a CGI script started for the connection with the given token.
+    StartScript(Token, cgi::ScriptPipes),
+    /// Relays output read from a CGI script, the source with the second token, to the connection with the first; the
+    /// flag is set once the script has closed its output.
+    RelayScriptOutput(Token, Token, Vec<u8>, bool),
+    /// Hands a request for an application to a worker, for the connection with the given token.
+    StartProxy(Token, Box<host::PendingProxy>),
+    /// Answers the connection with the given token, which was waiting on a worker.
+    Respond(Token, host::Handled),
+    /// Registers the streamed body of the response to the connection with the given token.
+    StreamBody(Token, http::BodyStream),
+    /// Relays a piece of a streamed body, the source with the second token, to the connection with the first; the flag
+    /// is set once the body has ended.
+    RelayBody(Token, Token, Vec<u8>, bool),
+    /// Reads the output of a CGI script or a streamed body again, once its connection has sent what was read before.
+    ResumeOutput(Token),
 }
 pub type Command = Box<dyn FnOnce(&HashMap<Token, EventSource>) -> Result<Option<CommandResponse>, Error> + Send>;
 pub struct CommandQueue {
@@ -149,6 +420,7 @@ impl CommandQueue {
     }
 }
 
+#[allow(clippy::large_enum_variant)]
 enum HandleEventResponse {
     EmptyCommand(CommandResponse),
     Command(Command)
@@ -181,17 +453,33 @@ impl event::Source for Stdin {
 }
 
 pub enum EventSource {
-    TcpListener(TcpListener, usize, config::ServerConfig),
-    TcpStream(TcpStream, ConnectionState, host::Host, Instant),
-    Stdin(Stdin, Token),
+    /// A listener, the counter from which it and every other listener assign tokens to accepted streams, and the host
+    /// that serves them.
+    TcpListener(TcpListener, Arc<AtomicUsize>, Arc<host::Host>),
+    TcpStream(TcpStream, ConnectionState, Arc<host::Host>, Instant),
+    Stdin(Stdin, Vec<Token>),
+    Watcher(watch::Watcher),
+    /// The input of a CGI script, and the request body to be written to it.
+    ScriptInput(ChildStdin, io::Cursor<Vec<u8>>),
+    /// The output of a CGI script, relayed to the connection with the given token.
+    ScriptOutput(ChildStdout, Token),
+    /// The error output of a CGI script, which is logged.
+    ScriptErrors(ChildStderr, cgi::ErrorLog),
+    /// The streamed body of a response, relayed to the connection with the given token.
+    Body(http::BodyStream, Token),
 }
 
 impl EventSource {
     fn handle_event(self, event: &Event, token: Token) -> Result<(EventSource, Vec<HandleEventResponse>), Error> {
         match self {
-            Self::TcpListener(listener, token_counter, server_config) => handle_listener_event(event, listener, token_counter, server_config),
+            Self::TcpListener(listener, token_counter, request_handler) => handle_listener_event(event, listener, token_counter, request_handler),
             Self::TcpStream(stream, connection_state, request_handler, accept_time) => handle_stream_event(event, token, stream, connection_state, request_handler, accept_time),
-            Self::Stdin(stdin, listener_token) => handle_stdin_event(event, stdin, listener_token),
+            Self::Stdin(stdin, listener_tokens) => handle_stdin_event(event, stdin, listener_tokens),
+            Self::Watcher(watcher) => handle_watcher_event(event, watcher),
+            Self::ScriptInput(stdin, body) => handle_script_input_event(event, token, stdin, body),
+            Self::ScriptOutput(stdout, connection) => handle_script_output_event(event, token, stdout, connection),
+            Self::ScriptErrors(stderr, error_log) => handle_script_errors_event(event, token, stderr, error_log),
+            Self::Body(body, connection) => handle_body_event(event, token, body, connection),
         }
     }
     fn register(&mut self, registry: &Registry, token: Token, interests: Interest) -> Result<(), Error> {
@@ -199,6 +487,14 @@ impl EventSource {
             Self::TcpListener(listener, _, _) => registry.register(listener, token, interests),
             Self::TcpStream(stream, _, _, _) => registry.register(stream, token, interests),
             Self::Stdin(stdin, _) => registry.register(stdin, token, interests),
+            Self::Watcher(watcher) => registry.register(watcher, token, interests),
+            Self::ScriptInput(stdin, _) => registry.register(&mut SourceFd(&stdin.as_raw_fd()), token, interests),
+            Self::ScriptOutput(stdout, _) => registry.register(&mut SourceFd(&stdout.as_raw_fd()), token, interests),
+            Self::ScriptErrors(stderr, _) => registry.register(&mut SourceFd(&stderr.as_raw_fd()), token, interests),
+            Self::Body(body, _) => match body.as_raw_fd() {
+                Some(fd) => registry.register(&mut SourceFd(&fd), token, interests),
+                None => Err(io::ErrorKind::Unsupported.into()),
+            },
         }.map_err(|e| e.into())
     }
     fn reregister(&mut self, registry: &Registry, token: Token, interests: Interest) -> Result<(), Error> {
@@ -206,6 +502,14 @@ impl EventSource {
             Self::TcpListener(listener, _, _) => registry.reregister(listener, token, interests),
             Self::TcpStream(stream, _, _, _) => registry.reregister(stream, token, interests),
             Self::Stdin(stdin, _) => registry.reregister(stdin, token, interests),
+            Self::Watcher(watcher) => registry.reregister(watcher, token, interests),
+            Self::ScriptInput(stdin, _) => registry.reregister(&mut SourceFd(&stdin.as_raw_fd()), token, interests),
+            Self::ScriptOutput(stdout, _) => registry.reregister(&mut SourceFd(&stdout.as_raw_fd()), token, interests),
+            Self::ScriptErrors(stderr, _) => registry.reregister(&mut SourceFd(&stderr.as_raw_fd()), token, interests),
+            Self::Body(body, _) => match body.as_raw_fd() {
+                Some(fd) => registry.reregister(&mut SourceFd(&fd), token, interests),
+                None => Err(io::ErrorKind::Unsupported.into()),
+            },
         }.map_err(|e| e.into())
     }
     fn deregister(&mut self, registry: &Registry) -> Result<(), Error> {
@@ -213,11 +517,19 @@ impl EventSource {
             Self::TcpListener(listener, _, _) => registry.deregister(listener),
             Self::TcpStream(stream, _, _, _) => registry.deregister(stream),
             Self::Stdin(stdin, _) => registry.deregister(stdin),
+            Self::Watcher(watcher) => registry.deregister(watcher),
+            Self::ScriptInput(stdin, _) => registry.deregister(&mut SourceFd(&stdin.as_raw_fd())),
+            Self::ScriptOutput(stdout, _) => registry.deregister(&mut SourceFd(&stdout.as_raw_fd())),
+            Self::ScriptErrors(stderr, _) => registry.deregister(&mut SourceFd(&stderr.as_raw_fd())),
+            Self::Body(body, _) => match body.as_raw_fd() {
+                Some(fd) => registry.deregister(&mut SourceFd(&fd)),
+                None => Err(io::ErrorKind::Unsupported.into()),
+            },
         }.map_err(|e| e.into())
     }
 }
 
-fn handle_stream_event(event: &Event, token: Token, mut stream: TcpStream, connection_state: ConnectionState, request_handler: host::Host, accept_time: Instant) -> Result<(EventSource, Vec<HandleEventResponse>), Error> {
+fn handle_stream_event(event: &Event, token: Token, mut stream: TcpStream, connection_state: ConnectionState, request_handler: Arc<host::Host>, accept_time: Instant) -> Result<(EventSource, Vec<HandleEventResponse>), Error> {
     match connection_state {
         ConnectionState::Read(mut incremental_request) => {
             if event.is_readable() {
@@ -258,11 +570,15 @@ fn handle_stream_event(event: &Event, token: Token, mut stream: TcpStream, conne
                 }
 
                 if let http::IncrementalRequest::FullRequest(request) = incremental_request {
-                    let response = request_handler.handle(&http::Request::from_no_remote(request, stream.peer_addr()?), false);
-                    Ok((
-                        EventSource::TcpStream(stream, ConnectionState::Write(http::IncrementalResponse::Struct(response)), request_handler, accept_time),
-                        vec!(HandleEventResponse::EmptyCommand(CommandResponse::ModifyInterests(token, Interest::WRITABLE))))
-                    )
+                    let request = http::Request::from_no_remote(request, stream.peer_addr()?, stream.local_addr()?);
+                    let handled = request_handler.handle_nonblocking(&request, false);
+                    let (connection_state, start) = begin(token, request, handled);
+                    let mut responses = Vec::new();
+                    if matches!(connection_state, ConnectionState::Write(_) | ConnectionState::Stream(_)) {
+                        responses.push(HandleEventResponse::EmptyCommand(CommandResponse::ModifyInterests(token, Interest::WRITABLE)));
+                    }
+                    responses.extend(start.map(HandleEventResponse::EmptyCommand));
+                    Ok((EventSource::TcpStream(stream, connection_state, request_handler, accept_time), responses))
                 } else {
                     Ok((EventSource::TcpStream(stream, ConnectionState::Read(incremental_request), request_handler, accept_time), vec!()))
                 }
@@ -307,28 +623,200 @@ fn handle_stream_event(event: &Event, token: Token, mut stream: TcpStream, conne
                 Ok((EventSource::TcpStream(stream, ConnectionState::Write(response), request_handler, accept_time), vec!()))
             }
         },
+        ConnectionState::Script(mut state) => {
+            if event.is_writable() {
+                if !send_pending(&mut stream, &mut state.pending)? {
+                    return Ok((EventSource::TcpStream(stream, ConnectionState::Script(state), request_handler, accept_time), vec!()));
+                }
+                if state.finished {
+                    return Ok((
+                        EventSource::TcpStream(stream, ConnectionState::Close, request_handler, accept_time),
+                        vec!(HandleEventResponse::EmptyCommand(CommandResponse::CloseSource(token)))
+                    ));
+                }
+                // the script's output is only read while the client keeps up with it
+                let responses = state.output
+                    .map(|output| HandleEventResponse::EmptyCommand(CommandResponse::ResumeOutput(output)))
+                    .into_iter().collect();
+                Ok((EventSource::TcpStream(stream, ConnectionState::Script(state), request_handler, accept_time), responses))
+            } else {
+                Ok((EventSource::TcpStream(stream, ConnectionState::Script(state), request_handler, accept_time), vec!()))
+            }
+        },
+        ConnectionState::Stream(mut state) => {
+            if event.is_writable() {
+                if !send_pending(&mut stream, &mut state.pending)? {
+                    return Ok((EventSource::TcpStream(stream, ConnectionState::Stream(state), request_handler, accept_time), vec!()));
+                }
+                if state.finished {
+                    return Ok((
+                        EventSource::TcpStream(stream, ConnectionState::Close, request_handler, accept_time),
+                        vec!(HandleEventResponse::EmptyCommand(CommandResponse::CloseSource(token)))
+                    ));
+                }
+                let responses = state.body
+                    .map(|body| HandleEventResponse::EmptyCommand(CommandResponse::ResumeOutput(body)))
+                    .into_iter().collect();
+                Ok((EventSource::TcpStream(stream, ConnectionState::Stream(state), request_handler, accept_time), responses))
+            } else {
+                Ok((EventSource::TcpStream(stream, ConnectionState::Stream(state), request_handler, accept_time), vec!()))
+            }
+        },
+        // the connection is answered once the worker hands back a response
+        ConnectionState::Proxy(request) => Ok((EventSource::TcpStream(stream, ConnectionState::Proxy(request), request_handler, accept_time), vec!())),
         ConnectionState::Close => Ok((EventSource::TcpStream(stream, ConnectionState::Close, request_handler, accept_time), vec!())),
     }
 }
 
-// TODO: see if we can add Handle for async request handling
-#[derive(Debug)]
+/// Writes what a connection has pending for as long as the client takes it; returns whether all of it was sent.
+fn send_pending(stream: &mut TcpStream, pending: &mut Vec<u8>) -> io::Result<bool> {
+    while !pending.is_empty() {
+        match stream.write(pending) {
+            Ok(bytes_written) => { pending.drain(..bytes_written); },
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(true)
+}
+
+/// Starts answering a connection's request as it has been handled. Returns the connection's new state, along with
+/// what the loop is to register for it: the pipes of a script, a body that is read as it becomes available, or a request
+/// to be sent to an application.
+fn begin(connection: Token, request: http::Request, handled: host::Handled) -> (ConnectionState, Option<CommandResponse>) {
+    match handled {
+        host::Handled::Response(mut response) => match response.stream.take() {
+            Some(body) if body.as_raw_fd().is_some() => {
+                let mut pending = Vec::new();
+                // writing to memory cannot fail
+                let framing = http::Framing::write_head(&mut pending, response.header, body.non_parsed).unwrap_or(http::Framing::Raw);
+                let state = StreamState { body: None, framing, pending, finished: false };
+                (ConnectionState::Stream(state), Some(CommandResponse::StreamBody(connection, body)))
+            },
+            stream => {
+                response.stream = stream;
+                (ConnectionState::Write(http::IncrementalResponse::Struct(response)), None)
+            },
+        },
+        host::Handled::Script(mut script) => match script.script.take_pipes(true) {
+            Ok(pipes) => (ConnectionState::Script(Box::new(ScriptState::new(request, script))), Some(CommandResponse::StartScript(connection, pipes))),
+            Err(e) => {
+                let response = http::error_response(http::StatusCode::InternalServerError, Some(e.to_string()));
+                (ConnectionState::Write(http::IncrementalResponse::Struct(response)), None)
+            },
+        },
+        host::Handled::Proxy(proxy) => (ConnectionState::Proxy(Box::new(request)), Some(CommandResponse::StartProxy(connection, proxy))),
+    }
+}
+
 pub enum ConnectionState {
-    Read(http::IncrementalRequest), Write(http::IncrementalResponse), Close
+    Read(http::IncrementalRequest),
+    /// Waiting on a CGI script, whose output is relayed to the client as it arrives.
+    Script(Box<ScriptState>),
+    /// Sending a body that is relayed to the client as it becomes available, such as a document whose server-side
+    /// includes are being expanded.
+    Stream(StreamState),
+    /// Waiting on a worker sending the request to an application.
+    Proxy(Box<http::Request>),
+    Write(http::IncrementalResponse),
+    Close,
+}
+
+/// A response whose body is streamed, and the part of it that is still to be sent.
+pub struct StreamState {
+    /// The token of the body, once it is registered.
+    body: Option<Token>,
+    framing: http::Framing,
+    /// The part of the response ready to be sent.
+    pending: Vec<u8>,
+    /// Whether the response is complete once `pending` has been sent.
+    finished: bool,
+}
+
+/// A connection's CGI script and what it has written that is still to be sent.
+pub struct ScriptState {
+    request: http::Request,
+    script: host::PendingScript,
+    /// The token of the script's output, once its pipes are registered. Output from earlier scripts, which made local
+    /// redirects, is discarded.
+    output: Option<Token>,
+    /// The script's output until its headers are complete.
+    head: Vec<u8>,
+    /// How the body is framed, once the head of the response is in `pending`.
+    framing: Option<http::Framing>,
+    /// The part of the response ready to be sent.
+    pending: Vec<u8>,
+    /// Whether the response is complete once `pending` has been sent.
+    finished: bool,
 }
 
-fn handle_listener_event(_: &Event, listener: TcpListener, mut token_counter: usize, server_config: config::ServerConfig) -> Result<(EventSource, Vec<HandleEventResponse>), Error> {
+impl ScriptState {
+    fn new(request: http::Request, script: host::PendingScript) -> ScriptState {
+        ScriptState { request, script, output: None, head: Vec::new(), framing: None, pending: Vec::new(), finished: false }
+    }
+
+    /// Takes output from the script: the headers are parsed once they have all been read, and the body is framed for
+    /// the client as it arrives. Returns the connection's new state, along with what is to be registered for the
+    /// response that a local redirect leads to, as with `begin`.
+    fn relay(mut self: Box<Self>, connection: Token, request_handler: &host::Host, bytes: Vec<u8>, finished: bool) -> (ConnectionState, Option<CommandResponse>) {
+        self.script.script.record_output(&bytes);
+        let mut framing = match self.framing.take() {
+            Some(mut framing) => {
+                // writing to memory cannot fail
+                let _ = framing.write(&mut self.pending, &bytes);
+                framing
+            },
+            None => {
+                self.head.extend(bytes);
+                let output = match self.script.script.parse_output(&self.head, finished) {
+                    Some(output) => output,
+                    None => return (ConnectionState::Script(self), None),
+                };
+                let body_start = output.as_ref().map(|(_, body_start)| *body_start).unwrap_or(0);
+                let streamed = matches!(output, Ok((cgi::Output::Response(_), _)));
+                if matches!(output, Ok((cgi::Output::LocalRedirect(_), _))) {
+                    // the script is left to finish, as its output is read to the end and discarded
+                    self.script.script.release();
+                }
+                match request_handler.script_output(&self.request, &self.script, output.map(|(output, _)| output)) {
+                    host::Handled::Response(response) if streamed => {
+                        let head = std::mem::take(&mut self.head);
+                        let mut framing = http::Framing::write_head(&mut self.pending, response.header, self.script.script.non_parsed)
+                            .unwrap_or(http::Framing::Raw);
+                        let _ = framing.write(&mut self.pending, &head[body_start..]);
+                        framing
+                    },
+                    handled => return begin(connection, self.request, handled),
+                }
+            },
+        };
+
+        if finished || framing.is_complete() {
+            // the output of a script that was killed is cut short, and is sent without the end of the body
+            if !self.script.script.timed_out() {
+                let _ = framing.finish(&mut self.pending);
+            }
+            if finished {
+                self.script.script.release();
+            }
+            self.finished = true;
+        }
+        self.framing = Some(framing);
+        (ConnectionState::Script(self), None)
+    }
+}
+
+fn handle_listener_event(_: &Event, listener: TcpListener, token_counter: Arc<AtomicUsize>, request_handler: Arc<host::Host>) -> Result<(EventSource, Vec<HandleEventResponse>), Error> {
     let mut responses = Vec::new();
     loop {
         match listener.accept() {
             Ok((stream, _)) => {
-                token_counter += 1;
-                let token = Token(token_counter);
+                let token = Token(token_counter.fetch_add(1, Ordering::Relaxed) + 1);
                 // println!("-- accepting connection {}", token.0);
                 let stream_source = EventSource::TcpStream(
                     stream,
                     ConnectionState::Read(http::IncrementalRequest::None(Box::new([]))),
-                    host::Host::new(server_config.clone()),
+                    request_handler.clone(),
                     Instant::now()
                 );
                 /*
@@ -348,7 +836,7 @@ fn handle_listener_event(_: &Event, listener: TcpListener, mut token_counter: us
             },
         }
     }
-    Ok((EventSource::TcpListener(listener, token_counter, server_config), responses))
+    Ok((EventSource::TcpListener(listener, token_counter, request_handler), responses))
 }
 
 const STREAM_TIMEOUT: Duration = Duration::from_secs(60);
@@ -379,17 +867,302 @@ fn check_stream_timeout(token: Token, event_sources: &HashMap<Token, EventSource
     Ok(None)
 }
 
-fn handle_stdin_event(event: &Event, stdin: Stdin, listener_token: Token) -> Result<(EventSource, Vec<HandleEventResponse>), Error> {
+fn handle_stdin_event(event: &Event, stdin: Stdin, listener_tokens: Vec<Token>) -> Result<(EventSource, Vec<HandleEventResponse>), Error> {
     if event.is_readable() {
         let mut input = String::new();
         stdin.raw.read_line(&mut input)?;
         input = input.trim().to_string();
         if input == "shutdown" {
-            println!("Closing the listener...");
-            return Ok((EventSource::Stdin(stdin, listener_token), vec!(HandleEventResponse::EmptyCommand(CommandResponse::CloseSource(listener_token)))));
+            println!("Closing the listeners...");
+            let responses = listener_tokens.iter().map(|token| HandleEventResponse::EmptyCommand(CommandResponse::CloseSource(*token))).collect();
+            return Ok((EventSource::Stdin(stdin, listener_tokens), responses));
         } else {
-            println!("Command not recognized. Type 'shutdown' to close the listener.");
+            println!("Command not recognized. Type 'shutdown' to close the listeners.");
+        }
+    }
+    Ok((EventSource::Stdin(stdin, listener_tokens), vec!()))
+}
+
+fn handle_script_input_event(event: &Event, token: Token, mut stdin: ChildStdin, mut body: io::Cursor<Vec<u8>>) -> Result<(EventSource, Vec<HandleEventResponse>), Error> {
+    if event.is_writable() || event.is_write_closed() || event.is_error() {
+        loop {
+            let bytes = body.fill_buf()?;
+            if bytes.is_empty() {
+                break;
+            }
+            match stdin.write(bytes) {
+                Ok(bytes_written) => body.consume(bytes_written),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok((EventSource::ScriptInput(stdin, body), vec!())),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                // a script need not read its input, so a broken pipe is not an error
+                Err(e) if e.kind() == io::ErrorKind::BrokenPipe => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        // closing the script's input ends the body
+        return Ok((EventSource::ScriptInput(stdin, body), vec!(HandleEventResponse::EmptyCommand(CommandResponse::CloseSource(token)))));
+    }
+    Ok((EventSource::ScriptInput(stdin, body), vec!()))
+}
+
+/// Reads what is available from a pipe, up to `SCRIPT_OUTPUT_LEN`; the flag is set once the pipe has been closed.
+fn read_output(reader: &mut impl Read, description: &str) -> (Vec<u8>, bool) {
+    let mut output = vec![0; SCRIPT_OUTPUT_LEN];
+    let mut output_len = 0;
+    let mut finished = false;
+    while output_len < output.len() {
+        match reader.read(&mut output[output_len..]) {
+            Ok(0) => {
+                finished = true;
+                break;
+            },
+            Ok(bytes_read) => output_len += bytes_read,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                eprintln!("Could not read {}: {}", description, e);
+                finished = true;
+                break;
+            },
+        }
+    }
+    output.truncate(output_len);
+    (output, finished)
+}
+
+fn handle_script_output_event(event: &Event, token: Token, mut stdout: ChildStdout, connection: Token) -> Result<(EventSource, Vec<HandleEventResponse>), Error> {
+    // a pipe whose writer has closed it is only reported as closed
+    if !event.is_readable() && !event.is_read_closed() {
+        return Ok((EventSource::ScriptOutput(stdout, connection), vec!()));
+    }
+    let (output, finished) = read_output(&mut stdout, "output of CGI script");
+    if output.is_empty() && !finished {
+        return Ok((EventSource::ScriptOutput(stdout, connection), vec!()));
+    }
+    let mut responses = vec!(HandleEventResponse::EmptyCommand(CommandResponse::RelayScriptOutput(connection, token, output, finished)));
+    if finished {
+        responses.push(HandleEventResponse::EmptyCommand(CommandResponse::CloseSource(token)));
+    }
+    Ok((EventSource::ScriptOutput(stdout, connection), responses))
+}
+
+fn handle_body_event(event: &Event, token: Token, mut body: http::BodyStream, connection: Token) -> Result<(EventSource, Vec<HandleEventResponse>), Error> {
+    if !event.is_readable() && !event.is_read_closed() {
+        return Ok((EventSource::Body(body, connection), vec!()));
+    }
+    let (bytes, finished) = read_output(&mut body, "streamed body");
+    if bytes.is_empty() && !finished {
+        return Ok((EventSource::Body(body, connection), vec!()));
+    }
+    let mut responses = vec!(HandleEventResponse::EmptyCommand(CommandResponse::RelayBody(connection, token, bytes, finished)));
+    if finished {
+        responses.push(HandleEventResponse::EmptyCommand(CommandResponse::CloseSource(token)));
+    }
+    Ok((EventSource::Body(body, connection), responses))
+}
+
+fn handle_script_errors_event(event: &Event, token: Token, mut stderr: ChildStderr, mut error_log: cgi::ErrorLog) -> Result<(EventSource, Vec<HandleEventResponse>), Error> {
+    if event.is_readable() || event.is_read_closed() {
+        let mut buf = [0; 4096];
+        loop {
+            match stderr.read(&mut buf) {
+                Ok(0) => {
+                    error_log.close();
+                    return Ok((EventSource::ScriptErrors(stderr, error_log), vec!(HandleEventResponse::EmptyCommand(CommandResponse::CloseSource(token)))));
+                },
+                Ok(bytes_read) => error_log.write(&buf[..bytes_read]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+    Ok((EventSource::ScriptErrors(stderr, error_log), vec!()))
+}
+
+fn handle_watcher_event(event: &Event, mut watcher: watch::Watcher) -> Result<(EventSource, Vec<HandleEventResponse>), Error> {
+    if event.is_readable() {
+        watcher.read_events()?;
+    }
+    Ok((EventSource::Watcher(watcher), vec!()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Shutdown, TcpStream as StdTcpStream};
+    use std::os::unix::fs::PermissionsExt;
+    use std::sync::mpsc;
+    use crate::{config, fastcgi, scgi};
+    use super::*;
+
+    fn pending_len(event_loop: &EventLoop) -> usize {
+        event_loop.event_sources.values().filter_map(|source| match source {
+            EventSource::TcpStream(_, ConnectionState::Script(state), _, _) => Some(state.pending.len()),
+            _ => None,
+        }).sum()
+    }
+
+    fn run_for(event_loop: &mut EventLoop, duration: Duration) {
+        let start = Instant::now();
+        while start.elapsed() < duration {
+            event_loop.next().unwrap();
         }
     }
-    Ok((EventSource::Stdin(stdin, listener_token), vec!()))
+
+    fn serve(host: host::Host) -> (EventLoop, std::net::SocketAddr) {
+        let (send, recv) = mpsc::channel();
+        let token_counter = Arc::new(AtomicUsize::new(1));
+        let mut event_loop = EventLoop::new(CommandQueue::new(send, recv), token_counter.clone()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let address = listener.local_addr().unwrap();
+        event_loop.add_source(Token(1), EventSource::TcpListener(listener, token_counter, Arc::new(host)), Interest::READABLE).unwrap();
+        (event_loop, address)
+    }
+
+    /// Runs the loop until `count` connections are waiting on a worker or a streamed body.
+    fn run_until_waiting(event_loop: &mut EventLoop, count: usize) {
+        let waiting = |event_loop: &EventLoop| event_loop.event_sources.values()
+            .filter(|source| matches!(source, EventSource::TcpStream(_, ConnectionState::Stream(_) | ConnectionState::Proxy(_), _, _)))
+            .count();
+        while waiting(event_loop) < count {
+            event_loop.next().unwrap();
+        }
+    }
+
+    /// Runs the loop until the server closes the connection, and returns what it sent.
+    fn read_response(event_loop: &mut EventLoop, client: &mut StdTcpStream) -> String {
+        let mut response = Vec::new();
+        let mut buf = [0; 4096];
+        client.set_nonblocking(true).unwrap();
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(10) {
+            event_loop.next().unwrap();
+            loop {
+                match client.read(&mut buf) {
+                    Ok(0) => return String::from_utf8(response).unwrap(),
+                    Ok(bytes_read) => response.extend_from_slice(&buf[..bytes_read]),
+                    Err(_) => break,
+                }
+            }
+        }
+        panic!("no complete response: {}", String::from_utf8_lossy(&response));
+    }
+
+    #[test]
+    fn script_output_is_not_read_while_the_client_is_not_reading() {
+        let root = std::env::temp_dir().join(format!("select-backpressure-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("cgi-bin")).unwrap();
+        let script = root.join("cgi-bin/flood.sh");
+        std::fs::write(&script, "#!/bin/sh\nprintf 'Content-Type: text/plain\\n\\n'\nexec yes\n").unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let config_path = root.join("httpd.conf");
+        std::fs::write(&config_path, format!("Listen 127.0.0.1:3333\nDocumentRoot {0}\nScriptAlias /cgi-bin/ {0}/cgi-bin/\n", root.display())).unwrap();
+        let (mut event_loop, address) = serve(host::Host::new(config::load_config(&config_path, &[]).unwrap()));
+
+        // the client sends its request and reads nothing
+        let mut client = StdTcpStream::connect(address).unwrap();
+        client.write_all(b"GET /cgi-bin/flood.sh HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        run_for(&mut event_loop, Duration::from_secs(2));
+        assert_eq!(event_loop.paused_outputs.len(), 1);
+        assert!(pending_len(&event_loop) <= 2 * SCRIPT_OUTPUT_LEN, "{} bytes held", pending_len(&event_loop));
+
+        // reading from the client lets more of the output through
+        let mut buf = vec![0; 1024 * 1024];
+        let mut read = 0;
+        client.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        for _ in 0..20 {
+            read += client.read(&mut buf).unwrap_or(0);
+            run_for(&mut event_loop, Duration::from_millis(50));
+        }
+        assert!(read > 4 * 1024 * 1024, "only {} bytes read", read);
+        assert!(pending_len(&event_loop) <= 2 * SCRIPT_OUTPUT_LEN, "{} bytes held", pending_len(&event_loop));
+
+        // once the client has gone, the script's pipes are closed
+        client.shutdown(Shutdown::Both).unwrap();
+        drop(client);
+        run_for(&mut event_loop, Duration::from_secs(2));
+        assert!(event_loop.paused_outputs.is_empty());
+        assert_eq!(event_loop.event_sources.len(), 1);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn includes_are_streamed_without_holding_up_other_connections() {
+        let root = std::env::temp_dir().join(format!("select-includes-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("cgi-bin")).unwrap();
+        std::fs::write(root.join("index.html"), "static").unwrap();
+        std::fs::write(root.join("slow.shtml"), "before|<!--#include virtual=\"/cgi-bin/slow.sh\" -->|after").unwrap();
+        let script = root.join("cgi-bin/slow.sh");
+        std::fs::write(&script, "#!/bin/sh\nsleep 2\nprintf 'Content-Type: text/plain\\n\\nslow'\n").unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let config_path = root.join("httpd.conf");
+        std::fs::write(&config_path, format!(
+            "Listen 127.0.0.1:3333\nDocumentRoot {0}\nScriptAlias /cgi-bin/ {0}/cgi-bin/\nOptions +Includes\nAddOutputFilter INCLUDES .shtml\n",
+            root.display(),
+        )).unwrap();
+        let (mut event_loop, address) = serve(host::Host::new(config::load_config(&config_path, &[]).unwrap()));
+
+        let start = Instant::now();
+        let mut slow = StdTcpStream::connect(address).unwrap();
+        slow.write_all(b"GET /slow.shtml HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        run_until_waiting(&mut event_loop, 1);
+
+        // the loop answers other clients while the include is running
+        let mut quick = StdTcpStream::connect(address).unwrap();
+        quick.write_all(b"GET /index.html HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let response = read_response(&mut event_loop, &mut quick);
+        assert!(response.ends_with("\r\n\r\nstatic"), "{}", response);
+        assert!(start.elapsed() < Duration::from_secs(2), "answered after {:?}", start.elapsed());
+
+        let response = read_response(&mut event_loop, &mut slow);
+        assert!(response.contains("Transfer-Encoding: chunked\r\n"), "{}", response);
+        let body = response.split_once("\r\n\r\n").unwrap().1;
+        let chunks: Vec<&str> = body.split("\r\n").skip(1).step_by(2).collect();
+        assert_eq!(chunks.concat(), "before|slow|after");
+        assert!(body.ends_with("0\r\n\r\n"), "{}", body);
+        assert_eq!(event_loop.event_sources.len(), 1);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn applications_are_waited_on_without_holding_up_other_connections() {
+        let root = std::env::temp_dir().join(format!("select-proxy-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("index.html"), "static").unwrap();
+        let tcp = |address| match address {
+            config::BackendAddress::Tcp(address) => address,
+            config::BackendAddress::Unix(path) => panic!("unexpected socket {}", path.display()),
+        };
+        let config_path = root.join("httpd.conf");
+        std::fs::write(&config_path, format!(
+            "Listen 127.0.0.1:3333\nDocumentRoot {}\nCGITimeout 1\nProxyPass /app/ fcgi://{}/srv/app/\nProxyPass /wsgi/ scgi://{}/\n",
+            root.display(), tcp(fastcgi::tests::serve_tcp(false).0), tcp(scgi::tests::serve()),
+        )).unwrap();
+        let (mut event_loop, address) = serve(host::Host::new(config::load_config(&config_path, &[]).unwrap()));
+
+        let start = Instant::now();
+        let mut slow = StdTcpStream::connect(address).unwrap();
+        slow.write_all(b"GET /app/slow?800 HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut hung = StdTcpStream::connect(address).unwrap();
+        hung.write_all(b"GET /wsgi/hang HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        run_until_waiting(&mut event_loop, 2);
+
+        // the loop answers other clients while the applications are working
+        let mut quick = StdTcpStream::connect(address).unwrap();
+        quick.write_all(b"GET /index.html HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let response = read_response(&mut event_loop, &mut quick);
+        assert!(response.ends_with("\r\n\r\nstatic"), "{}", response);
+        assert!(start.elapsed() < Duration::from_millis(800), "answered after {:?}", start.elapsed());
+
+        let response = read_response(&mut event_loop, &mut slow);
+        assert!(response.starts_with("HTTP/1.1 201 Created\r\n"), "{}", response);
+        assert!(response.contains("/srv/app/slow 0\n"), "{}", response);
+        let response = read_response(&mut event_loop, &mut hung);
+        assert!(response.starts_with("HTTP/1.1 504 "), "{}", response);
+        assert_eq!(event_loop.event_sources.len(), 1);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
 }
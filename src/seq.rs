@@ -1,4 +1,4 @@
-use std::io::{Read, Write};
+use std::io::Read;
 
 use crate::error::Error;
 use crate::host;
@@ -33,9 +33,8 @@ pub fn process(request_handler: &host::Host, mut stream: std::net::TcpStream, ov
     // println!("-- worker {}: finished read stream", thread_num);
 
     if let http::IncrementalRequest::FullRequest(request) = incremental_request {
-        let response = request_handler.handle(&http::Request::from_no_remote(request, stream.peer_addr()?), overloaded);
-        stream.write_all(&http::write_response(response)?)?;
-        Ok(())
+        let response = request_handler.handle(&http::Request::from_no_remote(request, stream.peer_addr()?, stream.local_addr()?), overloaded);
+        http::write_response_to(&mut stream, response)
     } else {
         Err(Error::new("Could not parse a full request using all available data".to_string()))
     }
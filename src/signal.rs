@@ -0,0 +1,105 @@
+use std::io;
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicI32, Ordering};
+use crate::error::Error;
+
+/// The write end of the pipe the signal handler writes to. Signal handlers can only use async-signal-safe functions,
+/// so the handler does nothing but wake up whoever is reading the other end.
+static HANGUP_WRITE_FD: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn on_hangup(_: libc::c_int) {
+    // the write may fail, e.g. when the pipe is already full, and must not leave its error in `errno` for the code that
+    // the signal interrupted
+    let errno = unsafe { *libc::__errno_location() };
+    let fd = HANGUP_WRITE_FD.load(Ordering::Relaxed);
+    if fd >= 0 {
+        unsafe { libc::write(fd, [1u8].as_ptr() as *const libc::c_void, 1) };
+    }
+    unsafe { *libc::__errno_location() = errno };
+}
+
+/// Delivers `SIGHUP` through a pipe, so that a thread can wait for it outside of the signal handler.
+pub struct Hangup {
+    fd: RawFd,
+}
+
+impl Hangup {
+    /// Installs the `SIGHUP` handler. Only one `Hangup` should exist at a time.
+    pub fn install() -> Result<Hangup, Error> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        HANGUP_WRITE_FD.store(fds[1], Ordering::Relaxed);
+        let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+        action.sa_sigaction = on_hangup as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        if unsafe { libc::sigaction(libc::SIGHUP, &action, std::ptr::null_mut()) } < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(Hangup { fd: fds[0] })
+    }
+
+    /// Consumes every pending signal without blocking. Returns whether there were any.
+    fn drain(&self) -> Result<bool, Error> {
+        let mut received = false;
+        let mut buf = [0u8; 64];
+        loop {
+            let bytes_read = unsafe { libc::read(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+            if bytes_read > 0 {
+                received = true;
+                continue;
+            }
+            let e = io::Error::last_os_error();
+            return match e.kind() {
+                _ if bytes_read == 0 => Ok(received),
+                io::ErrorKind::WouldBlock => Ok(received),
+                io::ErrorKind::Interrupted => continue,
+                _ => Err(e.into()),
+            };
+        }
+    }
+
+    /// Blocks the calling thread until at least one signal has arrived. Signals that arrive together are reported once.
+    pub fn wait(&self) -> Result<(), Error> {
+        loop {
+            let mut poll_fd = libc::pollfd { fd: self.fd, events: libc::POLLIN, revents: 0 };
+            if unsafe { libc::poll(&mut poll_fd, 1, -1) } < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() != io::ErrorKind::Interrupted {
+                    return Err(e.into());
+                }
+            }
+            if self.drain()? {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wait_returns_once_for_pending_signals() {
+        let hangup = Hangup::install().unwrap();
+        unsafe {
+            libc::raise(libc::SIGHUP);
+            libc::raise(libc::SIGHUP);
+        }
+        hangup.wait().unwrap();
+        assert!(!hangup.drain().unwrap());
+
+        // with the pipe full, the handler's write fails without touching the interrupted code's `errno`
+        let fd = HANGUP_WRITE_FD.load(Ordering::Relaxed);
+        while unsafe { libc::write(fd, [1u8].as_ptr() as *const libc::c_void, 1) } > 0 {}
+        unsafe {
+            *libc::__errno_location() = libc::ENOENT;
+            libc::raise(libc::SIGHUP);
+            assert_eq!(*libc::__errno_location(), libc::ENOENT);
+        }
+        hangup.wait().unwrap();
+        assert!(!hangup.drain().unwrap());
+    }
+}
@@ -0,0 +1,604 @@
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::path;
+use std::sync::Arc;
+use std::time;
+use crate::error::Error;
+
+const MAX_INCLUDE_DEPTH: usize = 16;
+const DEFAULT_ERRMSG: &str = "[an error occurred while processing this directive]";
+const DEFAULT_TIMEFMT: &str = "%A, %d-%b-%Y %H:%M:%S %Z";
+const DIRECTIVE_START: &str = "<!--#";
+const DIRECTIVE_END: &str = "-->";
+
+/// Provides the documents and script output that server-side include directives refer to.
+pub trait Resolver {
+    /// Fetches the document at a URL path, e.g. for `<!--#include virtual="/footer.html" -->`. If the path names a CGI
+    /// script, the script is run instead and the body of its response written to `output`, and there is no document.
+    fn include_virtual(&self, uri: &str, output: &mut dyn Write) -> Result<Option<Document>, Error>;
+    /// Runs the CGI script at a URL path and writes the body of its response to `output`.
+    fn exec_cgi(&self, uri: &str, output: &mut dyn Write) -> Result<(), Error>;
+}
+
+pub struct Document {
+    pub uri: String,
+    pub path: path::PathBuf,
+    pub content: Arc<String>,
+    pub modified: time::SystemTime,
+    /// Whether the document should itself be scanned for directives when it is included.
+    pub parsed: bool,
+}
+
+/// Expands the server-side include directives in a document, writing the result to `output` as it is produced.
+///
+/// Supports `include` (`virtual` and `file`), `echo`, `set`, `config` (`timefmt` and `errmsg`), `exec cgi` and the
+/// `if`/`elif`/`else`/`endif` conditionals. Directives that fail are replaced with the configured error message.
+/// Processing stops at the first error writing to `output`, which is returned.
+pub fn process(resolver: &dyn Resolver, document: Document, query_string: &str, output: &mut dyn Write) -> io::Result<()> {
+    let mut state = State {
+        errmsg: DEFAULT_ERRMSG.to_string(),
+        timefmt: DEFAULT_TIMEFMT.to_string(),
+        vars: HashMap::new(),
+        stack: Vec::new(),
+        conditions: Vec::new(),
+    };
+    state.vars.insert("DOCUMENT_URI".to_string(), document.uri.clone());
+    state.vars.insert("QUERY_STRING_UNESCAPED".to_string(), percent_decode(query_string));
+    let mut output = Output { writer: output, error: None };
+    state.process_document(resolver, &document, &mut output);
+    output.error.map_or(Ok(()), Err)
+}
+
+/// Where the expanded document is written. The first error writing to it is kept, and nothing more is written.
+struct Output<'a> {
+    writer: &'a mut dyn Write,
+    error: Option<io::Error>,
+}
+
+impl Output<'_> {
+    fn push_str(&mut self, s: &str) {
+        // an error is kept by `write`
+        let _ = self.write_all(s.as_bytes());
+    }
+}
+
+impl Write for Output<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(e) = &self.error {
+            return Err(e.kind().into());
+        }
+        self.writer.write(buf).map_err(|e| {
+            let kind = e.kind();
+            if kind != io::ErrorKind::Interrupted {
+                self.error = Some(e);
+            }
+            kind.into()
+        })
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+struct State {
+    errmsg: String,
+    timefmt: String,
+    vars: HashMap<String, String>,
+    stack: Vec<path::PathBuf>,
+    conditions: Vec<Condition>,
+}
+
+struct Condition {
+    /// Whether the current branch of this conditional is being output.
+    active: bool,
+    /// Whether any branch of this conditional has been taken yet.
+    taken: bool,
+}
+
+impl State {
+    fn process_document(&mut self, resolver: &dyn Resolver, document: &Document, output: &mut Output) {
+        if self.stack.len() >= MAX_INCLUDE_DEPTH || self.stack.contains(&document.path) {
+            output.push_str(&self.errmsg);
+            return;
+        }
+        self.stack.push(document.path.clone());
+        let conditions_depth = self.conditions.len();
+
+        let mut s = document.content.as_str();
+        while let Some(start) = s.find(DIRECTIVE_START) {
+            // the rest of a document that can no longer be sent is not worth processing
+            if output.error.is_some() {
+                break;
+            }
+            if self.active() {
+                output.push_str(&s[..start]);
+            }
+            let rest = &s[start + DIRECTIVE_START.len()..];
+            let end = match rest.find(DIRECTIVE_END) {
+                Some(end) => end,
+                None => {
+                    // an unterminated directive is passed through untouched
+                    s = &s[start..];
+                    break;
+                },
+            };
+            let was_active = self.active();
+            let result = parse_directive(&rest[..end])
+                .and_then(|(element, attributes)| self.execute(resolver, document, &element, &attributes, output));
+            if let Err(e) = result {
+                println!("SSI error in {}: {}", document.path.display(), e);
+                if was_active {
+                    output.push_str(&self.errmsg);
+                }
+            }
+            s = &rest[end + DIRECTIVE_END.len()..];
+        }
+        if self.active() {
+            output.push_str(s);
+        }
+
+        // conditionals must not span documents
+        self.conditions.truncate(conditions_depth);
+        self.stack.pop();
+    }
+
+    fn active(&self) -> bool {
+        self.conditions.iter().all(|condition| condition.active)
+    }
+
+    fn execute(&mut self, resolver: &dyn Resolver, document: &Document, element: &str, attributes: &[(String, String)], output: &mut Output) -> Result<(), Error> {
+        match element {
+            // a condition is pushed even when the expression is invalid, so that the matching endif still balances
+            "if" => {
+                let result = if self.active() { self.evaluate_attribute(attributes) } else { Ok(false) };
+                let active = *result.as_ref().unwrap_or(&false);
+                self.conditions.push(Condition { active, taken: active });
+                result.map(|_| ())
+            },
+            "elif" => {
+                let condition = self.conditions.pop().ok_or_else(|| Error::new("elif without if".to_string()))?;
+                let result = if !condition.taken && self.active() { self.evaluate_attribute(attributes) } else { Ok(false) };
+                let active = *result.as_ref().unwrap_or(&false);
+                self.conditions.push(Condition { active, taken: condition.taken || active });
+                result.map(|_| ())
+            },
+            "else" => {
+                let condition = self.conditions.pop().ok_or_else(|| Error::new("else without if".to_string()))?;
+                let active = !condition.taken && self.active();
+                self.conditions.push(Condition { active, taken: true });
+                Ok(())
+            },
+            "endif" => {
+                self.conditions.pop().ok_or_else(|| Error::new("endif without if".to_string()))?;
+                Ok(())
+            },
+            _ if !self.active() => Ok(()),
+            "include" => {
+                for (name, value) in attributes.iter() {
+                    let uri = match name.as_str() {
+                        "virtual" => self.substitute(value),
+                        "file" => relative_uri(&document.uri, &self.substitute(value))?,
+                        _ => return Err(Error::new(format!("Unknown include attribute {}", name))),
+                    };
+                    match resolver.include_virtual(&uri, output)? {
+                        Some(included) if included.parsed => self.process_document(resolver, &included, output),
+                        Some(included) => output.push_str(&included.content),
+                        None => {},
+                    }
+                }
+                Ok(())
+            },
+            "exec" => {
+                let uri = self.substitute(attribute(attributes, "cgi")?);
+                resolver.exec_cgi(&uri, output)
+            },
+            "echo" => {
+                let mut encoding = "entity".to_string();
+                for (name, value) in attributes.iter() {
+                    match name.as_str() {
+                        "encoding" => encoding = value.to_string(),
+                        "var" => {
+                            let value = self.variable(value, document).unwrap_or_else(|| "(none)".to_string());
+                            match encoding.as_str() {
+                                "none" => output.push_str(&value),
+                                "entity" => output.push_str(&escape_html(&value)),
+                                _ => return Err(Error::new(format!("Unknown echo encoding {}", encoding))),
+                            }
+                        },
+                        _ => return Err(Error::new(format!("Unknown echo attribute {}", name))),
+                    }
+                }
+                Ok(())
+            },
+            "set" => {
+                let name = attribute(attributes, "var")?.to_string();
+                let value = self.substitute(attribute(attributes, "value")?);
+                self.vars.insert(name, value);
+                Ok(())
+            },
+            "config" => {
+                for (name, value) in attributes.iter() {
+                    match name.as_str() {
+                        "timefmt" => self.timefmt = value.to_string(),
+                        "errmsg" => self.errmsg = value.to_string(),
+                        _ => return Err(Error::new(format!("Unknown config attribute {}", name))),
+                    }
+                }
+                Ok(())
+            },
+            _ => Err(Error::new(format!("Unknown directive {}", element))),
+        }
+    }
+
+    fn variable(&self, name: &str, document: &Document) -> Option<String> {
+        match name {
+            "DATE_LOCAL" => self.format_time(chrono::Local::now()),
+            "DATE_GMT" => self.format_time(chrono::Utc::now()),
+            "LAST_MODIFIED" => self.format_time(chrono::DateTime::<chrono::Local>::from(document.modified)),
+            "DOCUMENT_NAME" => document.path.file_name().map(|name| name.to_string_lossy().to_string()),
+            _ => self.vars.get(name).cloned(),
+        }
+    }
+
+    fn format_time<Tz: chrono::TimeZone>(&self, time: chrono::DateTime<Tz>) -> Option<String> where Tz::Offset: std::fmt::Display {
+        let mut s = String::new();
+        write!(s, "{}", time.format(&self.timefmt)).ok()?;
+        Some(s)
+    }
+
+    /// Replaces `$name` and `${name}` with the values of variables; `\$` is a literal dollar sign.
+    fn substitute(&self, s: &str) -> String {
+        let mut substituted = String::new();
+        let mut chars = s.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' if chars.peek() == Some(&'$') => {
+                    substituted.push('$');
+                    chars.next();
+                },
+                '$' => {
+                    let mut name = String::new();
+                    if chars.peek() == Some(&'{') {
+                        chars.next();
+                        for c in chars.by_ref() {
+                            if c == '}' {
+                                break;
+                            }
+                            name.push(c);
+                        }
+                    } else {
+                        while let Some(c) = chars.peek().filter(|c| c.is_ascii_alphanumeric() || **c == '_') {
+                            name.push(*c);
+                            chars.next();
+                        }
+                    }
+                    substituted.push_str(self.vars.get(&name).map(|value| value.as_str()).unwrap_or(""));
+                },
+                c => substituted.push(c),
+            }
+        }
+        substituted
+    }
+
+    fn evaluate_attribute(&self, attributes: &[(String, String)]) -> Result<bool, Error> {
+        self.evaluate(attribute(attributes, "expr")?)
+    }
+
+    fn evaluate(&self, expr: &str) -> Result<bool, Error> {
+        let tokens = tokenize(expr)?;
+        let mut parser = ExprParser { state: self, tokens: &tokens, position: 0 };
+        let result = parser.or()?;
+        if parser.position != tokens.len() {
+            return Err(Error::new(format!("Unexpected trailing tokens in expression: {}", expr)));
+        }
+        Ok(result)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Str(String),
+    Compare(String),
+    Not, And, Or, Open, Close,
+}
+
+fn tokenize(expr: &str) -> Result<Vec<Token>, Error> {
+    let mut tokens = Vec::new();
+    let mut chars = expr.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {},
+            '(' => tokens.push(Token::Open),
+            ')' => tokens.push(Token::Close),
+            '&' if chars.next_if_eq(&'&').is_some() => tokens.push(Token::And),
+            '|' if chars.next_if_eq(&'|').is_some() => tokens.push(Token::Or),
+            '!' if chars.next_if_eq(&'=').is_some() => tokens.push(Token::Compare("!=".to_string())),
+            '!' => tokens.push(Token::Not),
+            '=' => {
+                chars.next_if_eq(&'=');
+                tokens.push(Token::Compare("=".to_string()));
+            },
+            '<' | '>' => {
+                let mut op = c.to_string();
+                if chars.next_if_eq(&'=').is_some() {
+                    op.push('=');
+                }
+                tokens.push(Token::Compare(op));
+            },
+            '\'' | '"' => {
+                let mut s = String::new();
+                loop {
+                    match chars.next() {
+                        Some(end) if end == c => break,
+                        Some('\\') => s.extend(chars.next()),
+                        Some(c) => s.push(c),
+                        None => return Err(Error::new(format!("Unterminated string in expression: {}", expr))),
+                    }
+                }
+                tokens.push(Token::Str(s));
+            },
+            c => {
+                let mut s = c.to_string();
+                while let Some(c) = chars.next_if(|c| !c.is_whitespace() && !"()&|!=<>".contains(*c)) {
+                    s.push(c);
+                }
+                tokens.push(Token::Str(s));
+            },
+        }
+    }
+    Ok(tokens)
+}
+
+/// A recursive descent parser over Apache's legacy SSI expression syntax, evaluating as it parses.
+struct ExprParser<'a> {
+    state: &'a State,
+    tokens: &'a [Token],
+    position: usize,
+}
+
+impl ExprParser<'_> {
+    fn or(&mut self) -> Result<bool, Error> {
+        let mut result = self.and()?;
+        while self.eat(&Token::Or) {
+            result = self.and()? || result;
+        }
+        Ok(result)
+    }
+
+    fn and(&mut self) -> Result<bool, Error> {
+        let mut result = self.unary()?;
+        while self.eat(&Token::And) {
+            result = self.unary()? && result;
+        }
+        Ok(result)
+    }
+
+    fn unary(&mut self) -> Result<bool, Error> {
+        if self.eat(&Token::Not) {
+            return Ok(!self.unary()?);
+        }
+        if self.eat(&Token::Open) {
+            let result = self.or()?;
+            if !self.eat(&Token::Close) {
+                return Err(Error::new("Expected ) in expression".to_string()));
+            }
+            return Ok(result);
+        }
+        let left = self.string()?;
+        if let Some(Token::Compare(op)) = self.tokens.get(self.position) {
+            self.position += 1;
+            let right = self.string()?;
+            return Ok(match op.as_str() {
+                "=" => left == right,
+                "!=" => left != right,
+                "<" => left < right,
+                "<=" => left <= right,
+                ">" => left > right,
+                _ => left >= right,
+            });
+        }
+        Ok(!left.is_empty())
+    }
+
+    /// Adjacent strings are concatenated with a space, as Apache does.
+    fn string(&mut self) -> Result<String, Error> {
+        let mut parts = Vec::new();
+        while let Some(Token::Str(s)) = self.tokens.get(self.position) {
+            parts.push(self.state.substitute(s));
+            self.position += 1;
+        }
+        if parts.is_empty() {
+            return Err(Error::new("Expected a string in expression".to_string()));
+        }
+        Ok(parts.join(" "))
+    }
+
+    fn eat(&mut self, token: &Token) -> bool {
+        if self.tokens.get(self.position) == Some(token) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Splits `element attr="value" ...` into the element name and its attributes.
+fn parse_directive(s: &str) -> Result<(String, Vec<(String, String)>), Error> {
+    let s = s.trim();
+    let (element, mut s) = s.split_once(char::is_whitespace).unwrap_or((s, ""));
+    let mut attributes = Vec::new();
+    loop {
+        s = s.trim_start();
+        if s.is_empty() {
+            break;
+        }
+        let (name, rest) = s.split_once('=').ok_or_else(|| Error::new(format!("Malformed attribute in directive {}", element)))?;
+        let rest = rest.trim_start();
+        let quote = rest.chars().next().filter(|c| *c == '"' || *c == '\'' || *c == '`');
+        let (value, rest) = match quote {
+            Some(quote) => {
+                let rest = &rest[1..];
+                let end = rest.find(quote).ok_or_else(|| Error::new(format!("Unterminated attribute value in directive {}", element)))?;
+                (&rest[..end], &rest[end + 1..])
+            },
+            None => rest.split_once(char::is_whitespace).unwrap_or((rest, "")),
+        };
+        attributes.push((name.trim().to_lowercase(), value.to_string()));
+        s = rest;
+    }
+    Ok((element.to_lowercase(), attributes))
+}
+
+fn attribute<'a>(attributes: &'a [(String, String)], name: &str) -> Result<&'a str, Error> {
+    attributes.iter()
+        .find(|(attribute, _)| attribute == name)
+        .map(|(_, value)| value.as_str())
+        .ok_or_else(|| Error::new(format!("Missing attribute {}", name)))
+}
+
+/// Resolves an `include file` path against the URL of the including document. Like Apache, the path may not be
+/// absolute or refer to a parent directory.
+fn relative_uri(document_uri: &str, file: &str) -> Result<String, Error> {
+    if file.starts_with('/') || file.split('/').any(|segment| segment == "..") {
+        return Err(Error::new(format!("include file may not leave the current directory: {}", file)));
+    }
+    let directory = &document_uri[..document_uri.rfind('/').map(|i| i + 1).unwrap_or(0)];
+    Ok(format!("{}{}", directory, file))
+}
+
+pub fn escape_html(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|hex| std::str::from_utf8(hex).ok()).and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            },
+            (b'+', _) => {
+                decoded.push(b' ');
+                i += 1;
+            },
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            },
+        }
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct MapResolver {
+        documents: HashMap<String, String>,
+    }
+
+    impl Resolver for MapResolver {
+        fn include_virtual(&self, uri: &str, output: &mut dyn Write) -> Result<Option<Document>, Error> {
+            if uri.starts_with("/cgi-bin/") {
+                return self.exec_cgi(uri, output).map(|_| None);
+            }
+            let content = self.documents.get(uri).ok_or_else(|| Error::new(format!("{} not found", uri)))?;
+            Ok(Some(document(uri, content)))
+        }
+        fn exec_cgi(&self, uri: &str, output: &mut dyn Write) -> Result<(), Error> {
+            write!(output, "output of {}", uri)?;
+            Ok(())
+        }
+    }
+
+    fn document(uri: &str, content: &str) -> Document {
+        Document {
+            uri: uri.to_string(),
+            path: path::PathBuf::from(uri),
+            content: Arc::new(content.to_string()),
+            modified: time::UNIX_EPOCH,
+            parsed: uri.ends_with(".shtml"),
+        }
+    }
+
+    fn resolver(documents: &[(&str, &str)]) -> MapResolver {
+        MapResolver { documents: documents.iter().map(|(uri, content)| (uri.to_string(), content.to_string())).collect() }
+    }
+
+    fn expand(resolver: &dyn Resolver, document: Document, query_string: &str) -> String {
+        let mut output = Vec::new();
+        process(resolver, document, query_string, &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn expands_includes_echo_and_exec() {
+        let resolver = resolver(&[("/parts/header.shtml", "<h1><!--#echo var=\"title\" --></h1>"), ("/parts/footer.html", "<!--#echo var=\"x\" -->")]);
+        let page = document("/parts/page.shtml", "<!--#set var=\"title\" value=\"A & B\" --><!--#include virtual=\"/parts/header.shtml\" -->|<!--#include file=\"footer.html\" -->|<!--#exec cgi=\"/cgi-bin/price.pl\" -->");
+        assert_eq!(expand(&resolver, page, ""), "<h1>A &amp; B</h1>|<!--#echo var=\"x\" -->|output of /cgi-bin/price.pl");
+        let page = document("/page.shtml", "<!--#include virtual=\"/cgi-bin/price.pl\" -->");
+        assert_eq!(expand(&resolver, page, ""), "output of /cgi-bin/price.pl");
+    }
+
+    /// Takes a few bytes, and then fails as a client that has gone away would.
+    struct ClosedAfter(usize, Vec<u8>);
+
+    impl Write for ClosedAfter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.1.len() + buf.len() > self.0 {
+                return Err(io::ErrorKind::BrokenPipe.into());
+            }
+            self.1.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn stops_when_the_output_fails() {
+        let page = document("/page.shtml", "first<!--#exec cgi=\"/cgi-bin/a\" --><!--#exec cgi=\"/cgi-bin/b\" -->");
+        let mut output = ClosedAfter(10, Vec::new());
+        let error = process(&resolver(&[]), page, "", &mut output).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(output.1, b"first");
+    }
+
+    #[test]
+    fn evaluates_conditionals() {
+        let page = document("/page.shtml", "<!--#set var=\"a\" value=\"x\" --><!--#if expr=\"$a = y\" -->1<!--#elif expr=\"!($a = y) && $QUERY_STRING_UNESCAPED\" -->2<!--#else -->3<!--#endif -->");
+        assert_eq!(expand(&resolver(&[]), page, "q%20s"), "2");
+        let page = document("/page.shtml", "<!--#if expr=\"$missing\" -->1<!--#if expr=\"x\" -->2<!--#endif --><!--#else -->3<!--#endif -->");
+        assert_eq!(expand(&resolver(&[]), page, ""), "3");
+        let page = document("/page.shtml", "<!--#config errmsg=\"!\" --><!--#if expr=\"(\" -->1<!--#else -->2<!--#endif -->");
+        assert_eq!(expand(&resolver(&[]), page, ""), "!2");
+    }
+
+    #[test]
+    fn stops_recursive_includes() {
+        let resolver = resolver(&[("/loop.shtml", "a<!--#include virtual=\"/loop.shtml\" -->")]);
+        let page = document("/loop.shtml", "<!--#config errmsg=\"[loop]\" --><!--#include virtual=\"/loop.shtml\" -->");
+        assert_eq!(expand(&resolver, page, ""), "[loop]");
+        let page = document("/page.shtml", "<!--#include file=\"../secret.html\" -->");
+        assert_eq!(expand(&resolver, page, ""), DEFAULT_ERRMSG);
+    }
+}
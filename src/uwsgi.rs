@@ -0,0 +1,190 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io::{self, Read};
+use crate::cgi;
+use crate::config::*;
+use crate::error::{Error, HttpError};
+use crate::http::*;
+use crate::scgi;
+
+/// The longest status line read from an application.
+const MAX_STATUS_LINE: usize = 8 * 1024;
+
+/// Sends requests to [uWSGI](https://uwsgi-docs.readthedocs.io/en/latest/Protocol.html) applications, over a
+/// connection of their own as SCGI ones are.
+#[derive(Clone, Default)]
+pub struct Uwsgi {}
+
+impl Uwsgi {
+    /// Sends a request to the application that a `ProxyPass` names, with the same meta-variables as SCGI. The
+    /// application answers with an HTTP status line, which is read as a `Status` header, followed by headers and a
+    /// body that are processed as a CGI script's would be.
+    pub fn send(&self, proxy_pass: &ProxyPass, url_path: &str, request: &Request, virtual_host: &VirtualHost) -> Result<cgi::Output, HttpError> {
+        let params = scgi::meta_variables(proxy_pass, url_path, request, virtual_host);
+        let packet = encode_packet(&params, request.body.len()).ok_or_else(|| HttpError {
+            status: StatusCode::InternalServerError,
+            message: Some("Request is too large for a uWSGI packet".to_string()),
+        })?;
+        scgi::exchange("uWSGI", proxy_pass, request, virtual_host, &packet, |output| {
+            cgi::process_cgi_output(read_status_line(output)?, false)
+        })
+    }
+}
+
+/// Encodes the request headers as a uWSGI packet: a header of modifier 0, the size of the variables as a little-endian
+/// `u16` and another 0, followed by each name and value prefixed by its length in the same way. `CONTENT_LENGTH` is
+/// always given. `None` if the variables do not fit in the packet.
+fn encode_packet(params: &HashMap<String, String>, content_length: usize) -> Option<Vec<u8>> {
+    let content_length = content_length.to_string();
+    let mut vars = Vec::new();
+    let rest = params.iter()
+        .filter(|(name, _)| *name != "CONTENT_LENGTH")
+        .map(|(name, value)| (name.as_str(), value.as_str()));
+    for (name, value) in std::iter::once(("CONTENT_LENGTH", content_length.as_str())).chain(rest) {
+        for string in [name, value].iter() {
+            vars.extend_from_slice(&u16::try_from(string.len()).ok()?.to_le_bytes());
+            vars.extend_from_slice(string.as_bytes());
+        }
+    }
+    let mut packet = vec![0];
+    packet.extend_from_slice(&u16::try_from(vars.len()).ok()?.to_le_bytes());
+    packet.push(0);
+    packet.extend_from_slice(&vars);
+    Some(packet)
+}
+
+/// Reads the status line that starts an application's output, such as `HTTP/1.1 200 OK`, and turns it into the
+/// `Status` header of a CGI script. Output that does not start with a status line is left as it is.
+fn read_status_line(mut output: scgi::ApplicationOutput) -> Result<impl Read + Send + 'static, Error> {
+    let mut buffered = Vec::new();
+    let mut chunk = [0; 1024];
+    let line_len = loop {
+        if let Some(i) = buffered.iter().position(|byte| *byte == b'\n') {
+            break i + 1;
+        }
+        if buffered.len() >= MAX_STATUS_LINE {
+            return Err(Error::new("Malformed status line from uWSGI application: too long".to_string()));
+        }
+        match output.read(&mut chunk) {
+            Ok(0) => return Err(Error::new("uWSGI application closed the connection without a response".to_string())),
+            Ok(bytes_read) => buffered.extend_from_slice(&chunk[..bytes_read]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+    };
+    let line = String::from_utf8_lossy(&buffered[..line_len]).into_owned();
+    let head = match line.split_once(' ') {
+        Some((version, status)) if version.starts_with("HTTP/") => {
+            let mut head = format!("Status: {}", status).into_bytes();
+            head.extend_from_slice(&buffered[line_len..]);
+            head
+        },
+        _ => buffered,
+    };
+    Ok(io::Cursor::new(head).chain(output))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use std::time;
+    use super::*;
+
+    /// Serves a uWSGI stand-in on a local port that echoes the request back, except for the paths `/redirect`, which
+    /// redirects the client to `/index.html`, and `/hang`, which never answers.
+    fn serve() -> BackendAddress {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = BackendAddress::Tcp(listener.local_addr().unwrap().to_string());
+        thread::spawn(move || for stream in listener.incoming() {
+            let stream = stream.unwrap();
+            thread::spawn(move || echo(stream));
+        });
+        address
+    }
+
+    fn echo(mut stream: TcpStream) {
+        let mut header = [0; 4];
+        stream.read_exact(&mut header).unwrap();
+        assert_eq!((header[0], header[3]), (0, 0));
+        let mut vars = vec![0; u16::from_le_bytes([header[1], header[2]]) as usize];
+        stream.read_exact(&mut vars).unwrap();
+        let mut strings = Vec::new();
+        let mut rest = vars.as_slice();
+        while !rest.is_empty() {
+            let len = u16::from_le_bytes([rest[0], rest[1]]) as usize;
+            strings.push(String::from_utf8_lossy(&rest[2..2 + len]).into_owned());
+            rest = &rest[2 + len..];
+        }
+        let vars: Vec<(&str, &str)> = strings.chunks_exact(2).map(|pair| (pair[0].as_str(), pair[1].as_str())).collect();
+        let get = |name: &str| vars.iter().find(|(var, _)| *var == name).map(|(_, value)| *value).unwrap_or_default();
+        let mut body = vec![0; get("CONTENT_LENGTH").parse().unwrap()];
+        stream.read_exact(&mut body).unwrap();
+
+        let output = match get("PATH_INFO") {
+            "/redirect" => "HTTP/1.1 302 Found\r\nLocation: /index.html\r\n\r\n".to_string(),
+            "/hang" => {
+                let _ = stream.read(&mut [0]);
+                return;
+            },
+            path_info => format!(
+                "HTTP/1.1 202 Accepted\r\nContent-Type: text/plain\r\n\r\n{} {} {} {}\n{}",
+                vars[0].0, get("SCRIPT_NAME"), path_info, get("QUERY_STRING"), String::from_utf8_lossy(&body),
+            ),
+        };
+        stream.write_all(output.as_bytes()).unwrap();
+    }
+
+    fn post(target: &str, body: &str) -> Request {
+        let raw = format!("POST {} HTTP/1.1\r\nHost: www.example.com\r\nContent-Length: {}\r\n\r\n{}", target, body.len(), body);
+        parse_test_request(&raw, "10.0.0.2:50000", "10.0.0.1:3333")
+    }
+
+    fn proxy_pass(address: BackendAddress) -> ProxyPass {
+        ProxyPass { path: "/app/".to_string(), protocol: Protocol::Uwsgi, address, target: "/".to_string() }
+    }
+
+    #[test]
+    fn requests_pass_through_status_line_and_cgi_header_processing() {
+        let proxy_pass = proxy_pass(serve());
+        let response = match Uwsgi::default().send(&proxy_pass, "/app/users/42", &post("/app//users/%34%32?x=1", "a=1"), &VirtualHost::default()).unwrap() {
+            cgi::Output::Response(response) => response,
+            cgi::Output::LocalRedirect(location) => panic!("unexpected redirect to {}", location),
+        };
+        assert_eq!(response.header.status_line.status_code.code(), 202);
+        assert_eq!(response.header.header_lines.get(&ResponseHeaderField::ContentType).map(String::as_str), Some("text/plain"));
+        assert_eq!(response.into_body().unwrap(), "CONTENT_LENGTH /app /users/42 x=1\na=1");
+
+        // with a status given, a local path redirects the client rather than the server
+        let redirect = match Uwsgi::default().send(&proxy_pass, "/app/redirect", &post("/app/redirect", ""), &VirtualHost::default()).unwrap() {
+            cgi::Output::Response(response) => response,
+            cgi::Output::LocalRedirect(location) => panic!("unexpected local redirect to {}", location),
+        };
+        assert_eq!(redirect.header.status_line.status_code.code(), 302);
+        assert_eq!(redirect.header.header_lines.get(&ResponseHeaderField::Other("Location".to_string())).map(String::as_str), Some("/index.html"));
+    }
+
+    #[test]
+    fn reports_applications_that_time_out_or_cannot_be_reached() {
+        let mut virtual_host = VirtualHost::default();
+        virtual_host.cgi_limits.timeout = Some(time::Duration::from_secs(1));
+        let error = Uwsgi::default().send(&proxy_pass(serve()), "/app/hang", &post("/app/hang", ""), &virtual_host).err().unwrap();
+        assert!(matches!(error.status, StatusCode::GatewayTimeout));
+
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let error = Uwsgi::default().send(&proxy_pass(BackendAddress::Tcp(closed.to_string())), "/app/", &post("/app/", ""), &virtual_host).err().unwrap();
+        assert!(matches!(error.status, StatusCode::ServiceUnavailable));
+    }
+
+    #[test]
+    fn requests_too_large_for_a_packet_are_refused() {
+        let mut params = HashMap::new();
+        params.insert("HTTP_X_LONG".to_string(), "x".repeat(70_000));
+        assert!(encode_packet(&params, 0).is_none());
+        params.insert("HTTP_X_LONG".to_string(), "x".repeat(100));
+        let packet = encode_packet(&params, 3).unwrap();
+        assert_eq!(packet[..4], [0, (packet.len() - 4) as u8, 0, 0]);
+        assert_eq!(packet[4..23], *b"\x0e\x00CONTENT_LENGTH\x01\x003");
+    }
+}
@@ -0,0 +1,367 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path;
+use std::sync::Arc;
+use std::time;
+use crate::error::Error;
+
+pub const ARCHIVE_PREFIX: &str = "archive:";
+const TAR_BLOCK_LEN: usize = 512;
+
+/// A read-only view of a tree of files that a document root can be served from.
+///
+/// Paths are always absolute. Implementations other than the real disk mount their tree at a root path of their
+/// choosing, so that paths from different file systems never collide in the shared file cache.
+pub trait Vfs: Send + Sync {
+    fn stat(&self, path: &path::Path) -> io::Result<Metadata>;
+    fn open(&self, path: &path::Path) -> io::Result<Box<dyn Read + Send>>;
+    fn read_range(&self, path: &path::Path, start: u64, len: u64) -> io::Result<Vec<u8>>;
+    /// Returns the full paths of the entries of a directory, in no particular order.
+    fn list_dir(&self, path: &path::Path) -> io::Result<Vec<path::PathBuf>>;
+    fn canonicalize(&self, path: &path::Path) -> io::Result<path::PathBuf>;
+    /// The location of a file on the real disk, if it has one. Only such files can be executed as CGI scripts.
+    fn local_path(&self, _path: &path::Path) -> Option<path::PathBuf> {
+        None
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Metadata {
+    pub is_dir: bool,
+    pub len: u64,
+    pub modified: time::SystemTime,
+    pub ino: u64,
+    pub mode: u32,
+}
+
+/// Opens the file system for a `DocumentRoot` value, returning it along with the path of the root within it.
+/// `archive:/srv/site.tar` serves the contents of a tar archive; anything else is a directory on disk.
+pub fn open_document_root(value: &str) -> Result<(path::PathBuf, Arc<dyn Vfs>), Error> {
+    if let Some(archive_path) = value.strip_prefix(ARCHIVE_PREFIX) {
+        let archive = TarVfs::open(path::Path::new(archive_path))?;
+        Ok((archive.root.clone(), Arc::new(archive)))
+    } else {
+        let root = path::Path::new(value).canonicalize()
+            .map_err(|e| Error::new(format!("Could not open document root {}: {}", value, e)))?;
+        Ok((root, Arc::new(DiskVfs)))
+    }
+}
+
+/// Resolves `.` and `..` components without consulting any file system.
+fn normalize(path: &path::Path) -> path::PathBuf {
+    let mut normalized = path::PathBuf::from("/");
+    for component in path.components() {
+        match component {
+            path::Component::Normal(name) => normalized.push(name),
+            path::Component::ParentDir => { normalized.pop(); },
+            _ => {},
+        }
+    }
+    normalized
+}
+
+fn not_found(path: &path::Path) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("{} does not exist", path.display()))
+}
+
+pub struct DiskVfs;
+
+impl Vfs for DiskVfs {
+    fn stat(&self, path: &path::Path) -> io::Result<Metadata> {
+        let metadata = fs::metadata(path)?;
+        Ok(Metadata {
+            is_dir: metadata.is_dir(),
+            len: metadata.len(),
+            modified: metadata.modified()?,
+            ino: metadata.ino(),
+            mode: metadata.mode(),
+        })
+    }
+    fn open(&self, path: &path::Path) -> io::Result<Box<dyn Read + Send>> {
+        Ok(Box::new(fs::File::open(path)?))
+    }
+    fn read_range(&self, path: &path::Path, start: u64, len: u64) -> io::Result<Vec<u8>> {
+        let file = fs::File::open(path)?;
+        let mut buf = vec![0; len as usize];
+        let mut filled = 0;
+        while filled < buf.len() {
+            match file.read_at(&mut buf[filled..], start + filled as u64)? {
+                0 => break,
+                bytes_read => filled += bytes_read,
+            }
+        }
+        buf.truncate(filled);
+        Ok(buf)
+    }
+    fn list_dir(&self, path: &path::Path) -> io::Result<Vec<path::PathBuf>> {
+        fs::read_dir(path)?.map(|entry| entry.map(|entry| entry.path())).collect()
+    }
+    fn canonicalize(&self, path: &path::Path) -> io::Result<path::PathBuf> {
+        path.canonicalize()
+    }
+    fn local_path(&self, path: &path::Path) -> Option<path::PathBuf> {
+        Some(path.to_path_buf())
+    }
+}
+
+/// A file system held entirely in memory, intended for tests.
+#[cfg(test)]
+pub mod memory {
+    use std::collections::BTreeMap;
+    use std::io;
+    use std::io::Read;
+    use std::path;
+    use std::sync::Arc;
+    use std::time;
+    use super::{Metadata, Vfs, normalize, not_found};
+
+    fn slice_range(content: &[u8], start: u64, len: u64) -> Vec<u8> {
+        let start = (start as usize).min(content.len());
+        let end = start.saturating_add(len as usize).min(content.len());
+        content[start..end].to_vec()
+    }
+
+    enum Node {
+        File { content: Arc<Vec<u8>>, modified: time::SystemTime, mode: u32 },
+        Directory,
+    }
+
+    pub struct MemoryVfs {
+        nodes: BTreeMap<path::PathBuf, (u64, Node)>,
+    }
+
+    impl MemoryVfs {
+        pub fn new() -> MemoryVfs {
+            let mut nodes = BTreeMap::new();
+            nodes.insert(path::PathBuf::from("/"), (0, Node::Directory));
+            MemoryVfs { nodes }
+        }
+
+        /// Adds a file, creating any missing parent directories.
+        pub fn insert(&mut self, path: &str, content: &[u8], mode: u32) {
+            let path = normalize(path::Path::new(path));
+            for ancestor in path.ancestors().skip(1) {
+                if !self.nodes.contains_key(ancestor) {
+                    let ino = self.nodes.len() as u64;
+                    self.nodes.insert(ancestor.to_path_buf(), (ino, Node::Directory));
+                }
+            }
+            let ino = self.nodes.len() as u64;
+            let node = Node::File { content: Arc::new(content.to_vec()), modified: time::SystemTime::now(), mode };
+            self.nodes.insert(path, (ino, node));
+        }
+
+        fn node(&self, path: &path::Path) -> io::Result<&(u64, Node)> {
+            self.nodes.get(&normalize(path)).ok_or_else(|| not_found(path))
+        }
+
+        fn content(&self, path: &path::Path) -> io::Result<Arc<Vec<u8>>> {
+            match self.node(path)? {
+                (_, Node::File { content, .. }) => Ok(content.clone()),
+                (_, Node::Directory) => Err(io::Error::other(format!("{} is a directory", path.display()))),
+            }
+        }
+    }
+
+    impl Vfs for MemoryVfs {
+        fn stat(&self, path: &path::Path) -> io::Result<Metadata> {
+            Ok(match self.node(path)? {
+                (ino, Node::File { content, modified, mode }) => {
+                    Metadata { is_dir: false, len: content.len() as u64, modified: *modified, ino: *ino, mode: *mode }
+                },
+                (ino, Node::Directory) => Metadata { is_dir: true, len: 0, modified: time::UNIX_EPOCH, ino: *ino, mode: 0o755 },
+            })
+        }
+        fn open(&self, path: &path::Path) -> io::Result<Box<dyn Read + Send>> {
+            Ok(Box::new(io::Cursor::new(self.content(path)?.to_vec())))
+        }
+        fn read_range(&self, path: &path::Path, start: u64, len: u64) -> io::Result<Vec<u8>> {
+            Ok(slice_range(&self.content(path)?, start, len))
+        }
+        fn list_dir(&self, path: &path::Path) -> io::Result<Vec<path::PathBuf>> {
+            let path = normalize(path);
+            Ok(self.nodes.keys().filter(|child| child.parent() == Some(path.as_path())).cloned().collect())
+        }
+        fn canonicalize(&self, path: &path::Path) -> io::Result<path::PathBuf> {
+            self.node(path)?;
+            Ok(normalize(path))
+        }
+    }
+}
+
+struct TarEntry {
+    ino: u64,
+    offset: u64,
+    len: u64,
+    modified: time::SystemTime,
+    mode: u32,
+    is_dir: bool,
+}
+
+/// A read-only ustar archive, mounted at the path of the archive itself. Only regular files and directories are
+/// served; links and other entry types are skipped.
+pub struct TarVfs {
+    root: path::PathBuf,
+    file: fs::File,
+    entries: BTreeMap<path::PathBuf, TarEntry>,
+}
+
+impl TarVfs {
+    pub fn open(archive_path: &path::Path) -> Result<TarVfs, Error> {
+        let root = archive_path.canonicalize()
+            .map_err(|e| Error::new(format!("Could not open archive {}: {}", archive_path.display(), e)))?;
+        let file = fs::File::open(&root)?;
+        let mut entries = BTreeMap::new();
+        entries.insert(root.clone(), TarEntry { ino: 0, offset: 0, len: 0, modified: time::UNIX_EPOCH, mode: 0o755, is_dir: true });
+
+        let mut offset = 0;
+        let mut long_name = None;
+        let mut header = [0u8; TAR_BLOCK_LEN];
+        loop {
+            file.read_exact_at(&mut header, offset)
+                .map_err(|e| Error::new(format!("Truncated tar archive {}: {}", root.display(), e)))?;
+            if header.iter().all(|b| *b == 0) {
+                break;
+            }
+            let len = parse_octal(&header[124..136])?;
+            let data_offset = offset + TAR_BLOCK_LEN as u64;
+            offset = data_offset + len.div_ceil(TAR_BLOCK_LEN as u64) * TAR_BLOCK_LEN as u64;
+
+            let name = match long_name.take() {
+                Some(name) => name,
+                None => {
+                    let name = parse_string(&header[0..100]);
+                    let prefix = if &header[257..262] == b"ustar" { parse_string(&header[345..500]) } else { String::new() };
+                    if prefix.is_empty() { name } else { format!("{}/{}", prefix, name) }
+                },
+            };
+            let is_dir = match header[156] {
+                b'0' | 0 => false,
+                b'5' => true,
+                b'L' => {
+                    let mut buf = vec![0; len as usize];
+                    file.read_exact_at(&mut buf, data_offset)?;
+                    long_name = Some(parse_string(&buf));
+                    continue;
+                },
+                _ => continue,
+            };
+            let path = normalize(&root.join(name.trim_start_matches('/')));
+            if !path.starts_with(&root) || path == root {
+                continue;
+            }
+            let modified = time::UNIX_EPOCH + time::Duration::from_secs(parse_octal(&header[136..148])?);
+            let mode = parse_octal(&header[100..108])? as u32;
+            for ancestor in path.ancestors().skip(1).take_while(|ancestor| ancestor.starts_with(&root)) {
+                let ino = entries.len() as u64;
+                entries.entry(ancestor.to_path_buf())
+                    .or_insert(TarEntry { ino, offset: 0, len: 0, modified, mode: 0o755, is_dir: true });
+            }
+            let ino = entries.len() as u64;
+            entries.insert(path, TarEntry { ino, offset: data_offset, len: if is_dir { 0 } else { len }, modified, mode, is_dir });
+        }
+        Ok(TarVfs { root, file, entries })
+    }
+
+    fn entry(&self, path: &path::Path) -> io::Result<&TarEntry> {
+        self.entries.get(&normalize(path)).ok_or_else(|| not_found(path))
+    }
+}
+
+impl Vfs for TarVfs {
+    fn stat(&self, path: &path::Path) -> io::Result<Metadata> {
+        let entry = self.entry(path)?;
+        Ok(Metadata { is_dir: entry.is_dir, len: entry.len, modified: entry.modified, ino: entry.ino, mode: entry.mode })
+    }
+    fn open(&self, path: &path::Path) -> io::Result<Box<dyn Read + Send>> {
+        let len = self.entry(path)?.len;
+        Ok(Box::new(io::Cursor::new(self.read_range(path, 0, len)?)))
+    }
+    fn read_range(&self, path: &path::Path, start: u64, len: u64) -> io::Result<Vec<u8>> {
+        let entry = self.entry(path)?;
+        let start = start.min(entry.len);
+        let mut buf = vec![0; len.min(entry.len - start) as usize];
+        self.file.read_exact_at(&mut buf, entry.offset + start)?;
+        Ok(buf)
+    }
+    fn list_dir(&self, path: &path::Path) -> io::Result<Vec<path::PathBuf>> {
+        let path = normalize(path);
+        self.entry(&path)?;
+        Ok(self.entries.keys().filter(|child| child.parent() == Some(path.as_path())).cloned().collect())
+    }
+    fn canonicalize(&self, path: &path::Path) -> io::Result<path::PathBuf> {
+        let path = normalize(path);
+        self.entry(&path)?;
+        Ok(path)
+    }
+}
+
+fn parse_string(field: &[u8]) -> String {
+    let end = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).to_string()
+}
+
+fn parse_octal(field: &[u8]) -> Result<u64, Error> {
+    let s = parse_string(field);
+    let s = s.trim_matches(|c: char| c == ' ' || c == '\0');
+    if s.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(s, 8).map_err(|e| e.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::memory::MemoryVfs;
+
+    fn tar_header(name: &str, len: usize, typeflag: u8) -> Vec<u8> {
+        let mut header = vec![0u8; TAR_BLOCK_LEN];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[100..107].copy_from_slice(b"0000644");
+        header[124..135].copy_from_slice(format!("{:011o}", len).as_bytes());
+        header[136..147].copy_from_slice(b"00000000000");
+        header[156] = typeflag;
+        header[257..262].copy_from_slice(b"ustar");
+        header
+    }
+
+    #[test]
+    fn memory_vfs_resolves_paths_and_lists_directories() {
+        let mut vfs = MemoryVfs::new();
+        vfs.insert("/site/index.html", b"<html></html>", 0o644);
+        vfs.insert("/site/nested/page.html", b"page", 0o644);
+
+        assert_eq!(vfs.canonicalize(path::Path::new("/site/nested/../index.html")).unwrap(), path::PathBuf::from("/site/index.html"));
+        assert!(vfs.stat(path::Path::new("/site/nested")).unwrap().is_dir);
+        assert_eq!(vfs.read_range(path::Path::new("/site/nested/page.html"), 1, 2).unwrap(), b"ag");
+        let mut listing = vfs.list_dir(path::Path::new("/site")).unwrap();
+        listing.sort();
+        assert_eq!(listing, vec!(path::PathBuf::from("/site/index.html"), path::PathBuf::from("/site/nested")));
+        assert_eq!(vfs.canonicalize(path::Path::new("/site/missing.html")).unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn tar_vfs_serves_archive_members() {
+        let mut archive = Vec::new();
+        archive.extend(tar_header("nested/", 0, b'5'));
+        archive.extend(tar_header("nested/index.html", 5, b'0'));
+        let mut content = b"hello".to_vec();
+        content.resize(TAR_BLOCK_LEN, 0);
+        archive.extend(content);
+        archive.extend(vec![0u8; 2 * TAR_BLOCK_LEN]);
+        let archive_path = std::env::temp_dir().join(format!("vfs-test-{}.tar", std::process::id()));
+        fs::write(&archive_path, archive).unwrap();
+
+        let (root, vfs) = open_document_root(&format!("archive:{}", archive_path.display())).unwrap();
+        let index = vfs.canonicalize(&root.join("nested/index.html")).unwrap();
+        assert_eq!(vfs.stat(&index).unwrap().len, 5);
+        assert_eq!(vfs.read_range(&index, 0, 5).unwrap(), b"hello");
+        assert!(vfs.stat(&root.join("nested")).unwrap().is_dir);
+        assert!(vfs.local_path(&index).is_none());
+        fs::remove_file(archive_path).unwrap();
+    }
+}
@@ -0,0 +1,178 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// A `VirtualDocumentRoot` path, interpolated with the name of the host each request is for, as in Apache's
+/// [mod_vhost_alias](https://httpd.apache.org/docs/2.4/mod/mod_vhost_alias.html). `%0` is the whole name, `%N` its
+/// Nth dot-separated part, `%-N` the Nth part from the end, `%N+` the Nth part and those after it, and `%-N+` the Nth
+/// part from the end and those before it. `%N.M` selects characters of a part in the same way. A part or character
+/// that does not exist becomes `_`. `%p` is the port the request was accepted on and `%%` a literal `%`.
+#[derive(Clone, Debug)]
+pub struct Template {
+    text: String,
+    pieces: Vec<Piece>,
+}
+
+#[derive(Clone, Debug)]
+enum Piece {
+    Literal(String),
+    Port,
+    Name { part: Selector, chars: Option<Selector> },
+}
+
+/// Which items of a sequence to take: the Nth, counted from the end when negative and meaning all of them when zero,
+/// and with `rest`, also those after it (or before it, from the end).
+#[derive(Clone, Copy, Debug)]
+struct Selector {
+    index: i32,
+    rest: bool,
+}
+
+impl Selector {
+    fn select<'a, T>(&self, items: &'a [T]) -> Option<&'a [T]> {
+        let count = self.index.unsigned_abs() as usize;
+        if self.index == 0 {
+            Some(items)
+        } else if count > items.len() {
+            None
+        } else if self.index > 0 {
+            Some(if self.rest { &items[count - 1..] } else { &items[count - 1..count] })
+        } else {
+            let i = items.len() - count;
+            Some(if self.rest { &items[..=i] } else { &items[i..=i] })
+        }
+    }
+}
+
+impl Template {
+    /// Interpolates the template for a request to `host` (as given in the `Host` header) accepted on `port`. Returns
+    /// `None` for names that are not plain host names, so that a request cannot escape the directory the template
+    /// describes.
+    pub fn interpolate(&self, host: &str, port: u16) -> Option<String> {
+        let host = host.split(':').next().unwrap_or(host).trim_end_matches('.').to_ascii_lowercase();
+        let valid = !host.is_empty()
+            && host.split('.').all(|label| !label.is_empty())
+            && host.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.');
+        if !valid {
+            return None;
+        }
+        let parts: Vec<&str> = host.split('.').collect();
+        let mut path = String::new();
+        for piece in self.pieces.iter() {
+            match piece {
+                Piece::Literal(text) => path.push_str(text),
+                Piece::Port => path.push_str(&port.to_string()),
+                Piece::Name { part, chars } => {
+                    let selected = part.select(&parts).map(|parts| parts.join("."));
+                    let selected = match (selected, chars) {
+                        (Some(name), Some(chars)) => {
+                            let name: Vec<char> = name.chars().collect();
+                            chars.select(&name).map(|chars| chars.iter().collect())
+                        },
+                        (selected, _) => selected,
+                    };
+                    path.push_str(selected.as_deref().unwrap_or("_"));
+                },
+            }
+        }
+        Some(path)
+    }
+}
+
+impl FromStr for Template {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut pieces = vec!();
+        let mut literal = String::new();
+        let mut chars = s.chars().peekable();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                literal.push(c);
+                continue;
+            }
+            let piece = match chars.peek() {
+                Some('%') => {
+                    chars.next();
+                    literal.push('%');
+                    continue;
+                },
+                Some('p') => {
+                    chars.next();
+                    Piece::Port
+                },
+                _ => {
+                    let part = parse_selector(&mut chars)
+                        .ok_or_else(|| format!("expected `%N`, `%p` or `%%` in `{}`", s))?;
+                    // a `.` is only a character selector when a number follows it
+                    let mut lookahead = chars.clone();
+                    let char_selector = match lookahead.next() {
+                        Some('.') => parse_selector(&mut lookahead),
+                        _ => None,
+                    };
+                    if char_selector.is_some() {
+                        chars = lookahead;
+                    }
+                    Piece::Name { part, chars: char_selector }
+                },
+            };
+            if !literal.is_empty() {
+                pieces.push(Piece::Literal(std::mem::take(&mut literal)));
+            }
+            pieces.push(piece);
+        }
+        if !literal.is_empty() {
+            pieces.push(Piece::Literal(literal));
+        }
+        Ok(Template { text: s.to_string(), pieces })
+    }
+}
+
+/// Parses `N`, `-N`, `N+` or `-N+`.
+fn parse_selector(chars: &mut (impl Iterator<Item = char> + Clone)) -> Option<Selector> {
+    let mut lookahead = chars.clone();
+    let mut text = String::new();
+    let mut next = lookahead.next();
+    if next == Some('-') {
+        text.push('-');
+        next = lookahead.next();
+    }
+    while let Some(digit) = next.filter(|c| c.is_ascii_digit()) {
+        text.push(digit);
+        *chars = lookahead.clone();
+        next = lookahead.next();
+    }
+    let index = i32::from_str(&text).ok()?;
+    let rest = next == Some('+');
+    if rest {
+        *chars = lookahead;
+    }
+    Some(Selector { index, rest })
+}
+
+impl fmt::Display for Template {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn interpolate(template: &str, host: &str) -> Option<String> {
+        Template::from_str(template).unwrap().interpolate(host, 8080)
+    }
+
+    #[test]
+    fn interpolates_name_parts_and_characters() {
+        assert_eq!(interpolate("/srv/%0/www", "WWW.Example.com.:8080"), Some("/srv/www.example.com/www".to_string()));
+        assert_eq!(interpolate("/srv/%-1/%-2/%1", "www.example.com"), Some("/srv/com/example/www".to_string()));
+        assert_eq!(interpolate("/srv/%2+", "www.example.com"), Some("/srv/example.com".to_string()));
+        assert_eq!(interpolate("/srv/%-2+", "www.example.com"), Some("/srv/www.example".to_string()));
+        assert_eq!(interpolate("/srv/%-2.1/%-2.2/%-2.3+", "www.example.com"), Some("/srv/e/x/ample".to_string()));
+        assert_eq!(interpolate("/srv/%4/%p%%.d", "example.com"), Some("/srv/_/8080%.d".to_string()));
+        assert_eq!(interpolate("/srv/%1.www", "example.com"), Some("/srv/example.www".to_string()));
+        assert_eq!(interpolate("/srv/%0", "../etc"), None);
+        assert_eq!(interpolate("/srv/%0", "a/b.com"), None);
+        assert!(Template::from_str("/srv/%x").is_err());
+    }
+}
@@ -0,0 +1,192 @@
+use std::collections::HashMap;
+use std::ffi::{CString, OsStr};
+use std::fs;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+use std::path;
+use std::ptr;
+use std::sync::Arc;
+use mio::{Interest, Registry, Token, event};
+use mio::unix::SourceFd;
+use crate::error::Error;
+use crate::files;
+
+const WATCH_MASK: u32 = libc::IN_CLOSE_WRITE | libc::IN_MODIFY | libc::IN_ATTRIB | libc::IN_CREATE | libc::IN_DELETE
+    | libc::IN_MOVED_FROM | libc::IN_MOVED_TO | libc::IN_DELETE_SELF | libc::IN_MOVE_SELF;
+const EVENT_BUFFER_LEN: usize = 4096;
+
+/// Watches directory trees with inotify and evicts files from the file cache as soon as they change on disk, rather
+/// than waiting for the cache to revalidate them.
+///
+/// inotify watches are not recursive, so every directory below a watched root has its own watch; directories created
+/// after the watcher starts are added as their creation events arrive.
+pub struct Watcher {
+    fd: RawFd,
+    directories: HashMap<i32, path::PathBuf>,
+    files: Arc<files::Files>,
+}
+
+impl Watcher {
+    pub fn new(files: Arc<files::Files>) -> Result<Watcher, Error> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(Watcher { fd, directories: HashMap::new(), files })
+    }
+
+    pub fn watch_tree(&mut self, root: &path::Path) -> Result<(), Error> {
+        self.watch_directory(root)?;
+        for entry in fs::read_dir(root)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                self.watch_tree(&entry.path())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads and applies every pending event without blocking. Returns the number of events that were read.
+    pub fn read_events(&mut self) -> Result<usize, Error> {
+        let mut count = 0;
+        let mut buf = [0u8; EVENT_BUFFER_LEN];
+        loop {
+            let bytes_read = unsafe { libc::read(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+            if bytes_read < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::WouldBlock {
+                    return Ok(count);
+                }
+                return Err(e.into());
+            }
+            let bytes_read = bytes_read as usize;
+            let mut offset = 0;
+            while offset + mem::size_of::<libc::inotify_event>() <= bytes_read {
+                let event: libc::inotify_event = unsafe { ptr::read_unaligned(buf[offset..].as_ptr() as *const libc::inotify_event) };
+                let name_start = offset + mem::size_of::<libc::inotify_event>();
+                let name_end = name_start + event.len as usize;
+                let name = buf[name_start..name_end].split(|b| *b == 0).next().unwrap_or(&[]);
+                self.handle_event(&event, OsStr::from_bytes(name));
+                offset = name_end;
+                count += 1;
+            }
+        }
+    }
+
+    /// Blocks the calling thread, applying events as they arrive. Used by the models that have no event loop.
+    pub fn run(&mut self) -> Result<(), Error> {
+        loop {
+            let mut poll_fd = libc::pollfd { fd: self.fd, events: libc::POLLIN, revents: 0 };
+            if unsafe { libc::poll(&mut poll_fd, 1, -1) } < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() != io::ErrorKind::Interrupted {
+                    return Err(e.into());
+                }
+            }
+            self.read_events()?;
+        }
+    }
+
+    fn watch_directory(&mut self, directory: &path::Path) -> Result<(), Error> {
+        let c_path = CString::new(directory.as_os_str().as_bytes()).map_err(|e| Error::new(e.to_string()))?;
+        let wd = unsafe { libc::inotify_add_watch(self.fd, c_path.as_ptr(), WATCH_MASK) };
+        if wd < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        self.directories.insert(wd, directory.to_path_buf());
+        Ok(())
+    }
+
+    fn handle_event(&mut self, event: &libc::inotify_event, name: &OsStr) {
+        if event.mask & libc::IN_Q_OVERFLOW != 0 {
+            // events were dropped, so nothing in the cache can be trusted
+            self.files.invalidate_tree(path::Path::new("/"));
+            return;
+        }
+        let directory = match self.directories.get(&event.wd) {
+            Some(directory) => directory.clone(),
+            None => return,
+        };
+        if event.mask & libc::IN_IGNORED != 0 {
+            self.directories.remove(&event.wd);
+            return;
+        }
+        if event.mask & (libc::IN_DELETE_SELF | libc::IN_MOVE_SELF) != 0 {
+            self.files.invalidate_tree(&directory);
+            return;
+        }
+        let path = directory.join(name);
+        if event.mask & libc::IN_ISDIR != 0 {
+            self.files.invalidate_tree(&path);
+            if event.mask & (libc::IN_CREATE | libc::IN_MOVED_TO) != 0 {
+                if let Err(e) = self.watch_tree(&path) {
+                    println!("Could not watch new directory {}: {}", path.display(), e);
+                }
+            }
+        } else {
+            self.files.invalidate(&path);
+        }
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+impl event::Source for Watcher {
+    fn register(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+        SourceFd(&self.fd).register(registry, token, interests)
+    }
+    fn reregister(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+        SourceFd(&self.fd).reregister(registry, token, interests)
+    }
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        SourceFd(&self.fd).deregister(registry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time;
+    use super::*;
+    use crate::vfs::DiskVfs;
+
+    #[test]
+    fn changed_and_renamed_files_are_evicted() {
+        let root = std::env::temp_dir().join(format!("watch-test-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let root = root.canonicalize().unwrap();
+        fs::write(root.join("a.txt"), "a").unwrap();
+        fs::write(root.join("b.txt"), "b").unwrap();
+        // revalidation is left to the watcher
+        let files = Arc::new(files::Files::new(1024, 1024, time::Duration::from_secs(3600)));
+        let mut watcher = Watcher::new(files.clone()).unwrap();
+        watcher.watch_tree(&root).unwrap();
+        let get = |name: &str| files.get_content(&DiskVfs, root.join(name)).unwrap().into_body().unwrap();
+        assert_eq!((get("a.txt"), get("b.txt")), ("a".to_string(), "b".to_string()));
+        assert_eq!(files.stats().entries, 2);
+
+        fs::write(root.join("a.txt"), "changed").unwrap();
+        assert!(watcher.read_events().unwrap() > 0);
+        assert_eq!((files.stats().entries, files.stats().invalidations), (1, 1));
+        assert_eq!(get("a.txt"), "changed");
+
+        fs::rename(root.join("b.txt"), root.join("c.txt")).unwrap();
+        watcher.read_events().unwrap();
+        assert_eq!((files.stats().entries, files.stats().invalidations), (1, 2));
+
+        // directories created after the watcher started are watched too
+        fs::create_dir(root.join("new")).unwrap();
+        watcher.read_events().unwrap();
+        fs::write(root.join("new/d.txt"), "d").unwrap();
+        assert_eq!(get("new/d.txt"), "d");
+        fs::remove_file(root.join("new/d.txt")).unwrap();
+        watcher.read_events().unwrap();
+        assert_eq!((files.stats().entries, files.stats().invalidations), (1, 3));
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,12 @@
+#!/usr/bin/env perl
+
+=head1 DESCRIPTION
+
+nph-hello — a non-parsed-header CGI program that writes its whole response, status line included
+
+=cut
+$| = 1;
+print "$ENV{SERVER_PROTOCOL} 202 Accepted\r\n";
+print "Content-Type: text/plain\r\n";
+print "Connection: close\r\n\r\n";
+print "hello\n";
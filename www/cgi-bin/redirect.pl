@@ -0,0 +1,8 @@
+#!/usr/bin/env perl
+
+=head1 DESCRIPTION
+
+redirect — a CGI program that redirects to the location given as its query string
+
+=cut
+print "Location: $ENV{QUERY_STRING}\r\n\r\n";